@@ -0,0 +1,153 @@
+// Scripted joypad input for headless runs. Each line gives a frame number
+// and the buttons held from that frame until the next line:
+//   # Skip the title screen, then walk right
+//   60 start
+//   64
+//   120 right a
+// A line with no buttons lets go of everything.
+use std::fs;
+
+use gbrs_core::joypad::Joypad;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Buttons {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub a: bool,
+    pub b: bool,
+    pub start: bool,
+    pub select: bool,
+}
+
+impl Buttons {
+    fn press(&mut self, name: &str) -> Result<(), String> {
+        let button = match name {
+            "up" => &mut self.up,
+            "down" => &mut self.down,
+            "left" => &mut self.left,
+            "right" => &mut self.right,
+            "a" => &mut self.a,
+            "b" => &mut self.b,
+            "start" => &mut self.start,
+            "select" => &mut self.select,
+            _ => return Err(format!("Unknown button \"{}\"", name)),
+        };
+        *button = true;
+        Ok(())
+    }
+
+    pub fn apply(&self, joypad: &mut Joypad) {
+        joypad.up_pressed = self.up;
+        joypad.down_pressed = self.down;
+        joypad.left_pressed = self.left;
+        joypad.right_pressed = self.right;
+        joypad.a_pressed = self.a;
+        joypad.b_pressed = self.b;
+        joypad.start_pressed = self.start;
+        joypad.select_pressed = self.select;
+    }
+}
+
+pub struct InputScript {
+    // Sorted by frame
+    changes: Vec<(usize, Buttons)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut changes = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let mut words = line.split_whitespace();
+            let Some(frame) = words.next() else {
+                continue;
+            };
+            let frame = frame.parse().map_err(|_| {
+                format!("Line {}: \"{}\" isn't a frame", number + 1, frame)
+            })?;
+
+            let mut buttons = Buttons::default();
+            for name in words {
+                buttons
+                    .press(&name.to_lowercase())
+                    .map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            }
+            changes.push((frame, buttons));
+        }
+
+        changes.sort_by_key(|(frame, _)| *frame);
+        Ok(InputScript { changes })
+    }
+
+    pub fn load(path: &str) -> Result<InputScript, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path, e))?;
+        InputScript::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // What's held during `frame`
+    pub fn buttons_at(&self, frame: usize) -> Buttons {
+        self.changes
+            .iter()
+            .take_while(|(start, _)| *start <= frame)
+            .last()
+            .map(|(_, buttons)| *buttons)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons_are_held_until_the_next_line() {
+        let script = InputScript::parse(
+            "# Skip the title screen\n\
+             60 start # Comments can go after a line too\n\
+             \n\
+             64\n\
+             120 Right A\n",
+        )
+        .unwrap();
+
+        assert_eq!(script.buttons_at(0), Buttons::default());
+        let start = Buttons {
+            start: true,
+            ..Default::default()
+        };
+        assert_eq!(script.buttons_at(60), start);
+        assert_eq!(script.buttons_at(63), start);
+        // An empty line lets go
+        assert_eq!(script.buttons_at(64), Buttons::default());
+        let walking = Buttons {
+            right: true,
+            a: true,
+            ..Default::default()
+        };
+        assert_eq!(script.buttons_at(120), walking);
+        assert_eq!(script.buttons_at(10_000), walking);
+    }
+
+    #[test]
+    fn lines_can_be_out_of_order() {
+        let script = InputScript::parse("30 b\n10 up down\n20").unwrap();
+        assert!(script.buttons_at(15).up && script.buttons_at(15).down);
+        assert_eq!(script.buttons_at(25), Buttons::default());
+        assert!(script.buttons_at(30).b);
+    }
+
+    #[test]
+    fn bad_lines_are_errors() {
+        assert_eq!(
+            InputScript::parse("10 a\n20 turbo").err(),
+            Some("Line 2: Unknown button \"turbo\"".to_string())
+        );
+        assert_eq!(
+            InputScript::parse("soon start").err(),
+            Some("Line 1: \"soon\" isn't a frame".to_string())
+        );
+    }
+}
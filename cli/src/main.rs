@@ -0,0 +1,381 @@
+pub mod input_script;
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::rc::Rc;
+
+use gbrs_core::callbacks::{set_callbacks, Callbacks, CALLBACKS};
+use gbrs_core::cartridge::{CGBSupportType, Cartridge};
+use gbrs_core::config::{Config, Model};
+use gbrs_core::constants::*;
+use gbrs_core::cpu::Cpu;
+use gbrs_core::debugger::{Breakpoint, StopReason};
+use gbrs_core::memory::mbcs::cart_type_name;
+use gbrs_core::memory::rom::Rom;
+use gbrs_core::scaling::Scaler;
+use gbrs_core::serial_cable::SerialDevice;
+use input_script::InputScript;
+
+const USAGE: &str = "Usage:
+  gbrs-cli info ROM_PATH
+  gbrs-cli run ROM_PATH [OPTIONS]
+  gbrs-cli dump ROM_PATH [OPTIONS]
+
+Options for run and dump:
+  --frames N             Run at most N frames (default 600)
+  --until-pc ADDRESS     Stop when PC reaches ADDRESS, eg. 0x0150
+  --until-breakpoint     Stop on an `ld b,b`
+  --until-serial TEXT    Stop once TEXT has been sent over the link cable
+  --input FILE           Press buttons from a script (see input_script.rs)
+  --screenshot FRAMES    Save these frames as PNGs, eg. 60,120,last
+  --screenshot-every N   Save every Nth frame as well
+  --out DIR              Where screenshots and dumps go (default .)
+  --model NAME           Run on dmg, sgb, cgb or agb (default from the header)";
+
+const DEFAULT_FRAMES: usize = 600;
+
+#[derive(Default)]
+struct RunOptions {
+    frames: Option<usize>,
+    until_pc: Option<u16>,
+    until_breakpoint: bool,
+    until_serial: Option<String>,
+    input: Option<InputScript>,
+    screenshot_frames: Vec<usize>,
+    screenshot_last: bool,
+    screenshot_every: Option<usize>,
+    out_dir: PathBuf,
+    model: Option<Model>,
+}
+
+impl RunOptions {
+    fn has_condition(&self) -> bool {
+        self.until_pc.is_some()
+            || self.until_breakpoint
+            || self.until_serial.is_some()
+    }
+}
+
+// Keeps every byte the ROM sends over the link cable
+struct SerialCapture(Rc<RefCell<Vec<u8>>>);
+
+impl SerialDevice for SerialCapture {
+    fn exchange_byte(&mut self, outgoing: u8) -> u8 {
+        self.0.borrow_mut().push(outgoing);
+        0xFF
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(
+    value: &str,
+    what: &str,
+) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} \"{}\"", what, value))
+}
+
+fn parse_run_options(
+    args: &mut impl Iterator<Item = String>,
+) -> Result<RunOptions, String> {
+    let mut options = RunOptions {
+        out_dir: PathBuf::from("."),
+        ..Default::default()
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value =
+            || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match &arg[..] {
+            "--frames" => {
+                options.frames = Some(parse_value(&value()?, "frame count")?)
+            },
+            "--until-pc" => {
+                let address = value()?;
+                let digits = address.trim_start_matches("0x");
+                options.until_pc =
+                    Some(u16::from_str_radix(digits, 16).map_err(|_| {
+                        format!("Invalid address \"{}\"", address)
+                    })?);
+            },
+            "--until-breakpoint" => options.until_breakpoint = true,
+            "--until-serial" => options.until_serial = Some(value()?),
+            "--input" => options.input = Some(InputScript::load(&value()?)?),
+            "--screenshot" => {
+                for frame in value()?.split(',') {
+                    if frame == "last" {
+                        options.screenshot_last = true;
+                    } else {
+                        options
+                            .screenshot_frames
+                            .push(parse_value(frame, "screenshot frame")?);
+                    }
+                }
+            },
+            "--screenshot-every" => {
+                let every = parse_value(&value()?, "frame count")?;
+                if every == 0 {
+                    return Err("--screenshot-every must be above 0".into());
+                }
+                options.screenshot_every = Some(every);
+            },
+            "--out" => options.out_dir = PathBuf::from(value()?),
+            "--model" => {
+                let name = value()?;
+                options.model =
+                    Some(Model::from_name(&name).ok_or_else(|| {
+                        format!("Unknown model \"{}\"", name)
+                    })?);
+            },
+            _ => return Err(format!("Unknown argument \"{}\"", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn print_info(rom_path: &str) {
+    let bytes = fs::read(rom_path).expect("Unable to read ROM file");
+    let cart = Cartridge::parse(&bytes, rom_path.to_string());
+
+    let cgb_support = match cart.cgb_support {
+        CGBSupportType::None => "No",
+        CGBSupportType::Optional => "Yes",
+        CGBSupportType::Required => "Required",
+    };
+    let yes_no = |flag: bool| if flag { "Yes" } else { "No" };
+
+    println!("Title:          {}", cart.title);
+    println!(
+        "Cartridge type: {} ({:#04x})",
+        cart_type_name(&cart),
+        cart.cart_type
+    );
+    println!("ROM size:       {}KB", cart.rom_size / 1024);
+    println!("RAM size:       {}KB", cart.ram_size / 1024);
+    println!("CGB support:    {}", cgb_support);
+    println!("SGB support:    {}", yes_no(cart.sgb_support));
+    println!(
+        "Licensed by:    {}",
+        if cart.nintendo_licensed {
+            "Nintendo"
+        } else {
+            "Third party"
+        }
+    );
+    println!("Title checksum: {:#04x}", cart.title_checksum);
+    println!("CRC32:          {:08x}", cart.rom_crc32);
+}
+
+fn save_screenshot(cpu: &Cpu, path: &Path) {
+    fs::write(path, cpu.screenshot_png(Scaler::Nearest, false))
+        .expect("Failed to write screenshot");
+    println!("Saved {}", path.display());
+}
+
+// Runs one frame's worth of cycles. Gives back why it stopped early if
+// the debugger stopped it.
+fn run_frame(cpu: &mut Cpu, use_debugger: bool) -> Option<StopReason> {
+    if !use_debugger {
+        cpu.step_one_frame();
+        return None;
+    }
+
+    let mut cycles_per_frame = CLOCK_SPEED / cpu.frame_rate;
+    if cpu.mem.speed_switch.current_speed_is_double {
+        cycles_per_frame *= 2;
+    }
+    let mut cycles = 0;
+    while cycles < cycles_per_frame {
+        let (ran, reason) = cpu.run_until_stop(cycles_per_frame - cycles);
+        cycles += ran;
+        if reason != StopReason::CycleLimit {
+            return Some(reason);
+        }
+    }
+    None
+}
+
+// Runs the game and takes screenshots. Returns false if there was a stop
+// condition that never happened.
+fn run(cpu: &mut Cpu, options: &RunOptions, stem: &str) -> bool {
+    let serial = Rc::new(RefCell::new(vec![]));
+    if options.until_serial.is_some() {
+        cpu.connect_serial_device(Box::new(SerialCapture(serial.clone())));
+    }
+    if let Some(address) = options.until_pc {
+        cpu.debugger.add_breakpoint(Breakpoint::new(address));
+    }
+    cpu.homebrew.breakpoints = options.until_breakpoint;
+    let use_debugger = options.until_pc.is_some() || options.until_breakpoint;
+
+    let frames = options.frames.unwrap_or(DEFAULT_FRAMES);
+    let screenshot_path =
+        |frame: usize| options.out_dir.join(format!("{}-{}.png", stem, frame));
+
+    // Frames are numbered from 1, for input scripts and screenshots alike
+    let mut frame = 0;
+    let mut stopped = false;
+    while frame < frames && !stopped {
+        frame += 1;
+        if let Some(input) = &options.input {
+            input.buttons_at(frame).apply(&mut cpu.mem.joypad);
+        }
+
+        let reason = run_frame(cpu, use_debugger);
+
+        if let Some(reason) = reason {
+            println!("Stopped in frame {}: {:?}", frame, reason);
+            stopped = true;
+        }
+        if let Some(text) = &options.until_serial {
+            let sent = String::from_utf8_lossy(&serial.borrow()).to_string();
+            if sent.contains(text.as_str()) {
+                println!("Stopped in frame {}: serial output matched", frame);
+                stopped = true;
+            }
+        }
+
+        let every = options
+            .screenshot_every
+            .is_some_and(|every| frame % every == 0);
+        if every || options.screenshot_frames.contains(&frame) {
+            save_screenshot(cpu, &screenshot_path(frame));
+        }
+    }
+
+    if options.screenshot_last {
+        save_screenshot(
+            cpu,
+            &options.out_dir.join(format!("{}-last.png", stem)),
+        );
+    }
+
+    if options.has_condition() && !stopped {
+        eprintln!("No stop condition was met in {} frames", frames);
+        return false;
+    }
+    true
+}
+
+fn dump(cpu: &Cpu, out_dir: &Path, stem: &str) {
+    let vram: Vec<u8> = (0..cpu.mem.vram.bank_count())
+        .flat_map(|bank| cpu.mem.vram.bank_contents(bank))
+        .collect();
+    let dumps = [
+        ("sav", cpu.mem.save_ram()),
+        ("vram", &vram[..]),
+        ("oam", cpu.gpu.oam_contents()),
+    ];
+
+    for (extension, contents) in dumps {
+        let path = out_dir.join(format!("{}.{}", stem, extension));
+        fs::write(&path, contents).expect("Failed to write dump");
+        println!("Saved {}", path.display());
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let (Some(command), Some(rom_path)) = (args.next(), args.next()) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    if command == "info" {
+        print_info(&rom_path);
+        return ExitCode::SUCCESS;
+    }
+    if command != "run" && command != "dump" {
+        eprintln!("Unknown command \"{}\"\n{}", command, USAGE);
+        return ExitCode::FAILURE;
+    }
+    let options = match parse_run_options(&mut args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n{}", error, USAGE);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    // Headless runs shouldn't touch the game's real save file
+    let log = CALLBACKS.lock().log;
+    set_callbacks(Callbacks {
+        log,
+        save: |_game_name, _rom_path, _save_data| {},
+        load: |_game_name, _rom_path, expected_size| vec![0; expected_size],
+    });
+
+    let mut cpu = Cpu::from_config(Config {
+        sound_buffer_size: SOUND_BUFFER_SIZE,
+        sound_sample_rate: SOUND_SAMPLE_RATE,
+        rom: Rom::from_file(&rom_path),
+        model: options.model,
+    });
+    let stem = Path::new(&rom_path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    fs::create_dir_all(&options.out_dir)
+        .expect("Failed to create output directory");
+    let finished = run(&mut cpu, &options, &stem);
+    if command == "dump" {
+        dump(&cpu, &options.out_dir, &stem);
+    }
+
+    if finished {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RunOptions, String> {
+        parse_run_options(&mut args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn run_options_parse() {
+        let options = parse(&[
+            "--frames",
+            "120",
+            "--until-pc",
+            "0x0150",
+            "--screenshot",
+            "60,last",
+            "--model",
+            "cgb",
+        ])
+        .unwrap();
+        assert_eq!(options.frames, Some(120));
+        assert_eq!(options.until_pc, Some(0x0150));
+        assert_eq!(options.screenshot_frames, vec![60]);
+        assert!(options.screenshot_last);
+        assert!(options.model == Some(Model::Cgb));
+    }
+
+    #[test]
+    fn bad_run_options_are_errors() {
+        let error = |args: &[&str]| parse(args).err().unwrap();
+        assert_eq!(error(&["--frames"]), "--frames needs a value");
+        assert_eq!(
+            error(&["--frames", "lots"]),
+            "Invalid frame count \"lots\""
+        );
+        assert_eq!(error(&["--until-pc", "xyz"]), "Invalid address \"xyz\"");
+        assert_eq!(
+            error(&["--screenshot-every", "0"]),
+            "--screenshot-every must be above 0"
+        );
+        assert_eq!(error(&["--model", "gba"]), "Unknown model \"gba\"");
+        assert_eq!(error(&["--fast"]), "Unknown argument \"--fast\"");
+    }
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::panic;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use gbrs_conformance::sm83::{find_test_files, run_test_file, Mismatch};
+use gbrs_core::callbacks::{set_callbacks, Callbacks};
+
+const USAGE: &str = "Usage: gbrs-sm83 TEST_DIR [--verbose]";
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let test_dir = PathBuf::from(args.next().expect(USAGE));
+    let verbose = match args.next().as_deref() {
+        None => false,
+        Some("--verbose") => true,
+        Some(arg) => panic!("Unknown argument \"{}\"\n{}", arg, USAGE),
+    };
+
+    // Every case builds a new CPU, which would log the blank cartridge
+    set_callbacks(Callbacks {
+        log: |_log_str| {},
+        save: |_game_name, _rom_path, _save_data| {},
+        load: |_game_name, _rom_path, expected_size| vec![0; expected_size],
+    });
+    // Crashes are reported as failures instead
+    panic::set_hook(Box::new(|_info| {}));
+
+    let files = find_test_files(&test_dir);
+    if files.is_empty() {
+        eprintln!("No .json test files in {}", test_dir.display());
+        return ExitCode::FAILURE;
+    }
+
+    let mut failed_opcodes = 0;
+    for file in &files {
+        let result = match run_test_file(file) {
+            Ok(result) => result,
+            Err(error) => {
+                eprintln!("{}", error);
+                return ExitCode::FAILURE;
+            },
+        };
+        if result.failures.is_empty() {
+            continue;
+        }
+        failed_opcodes += 1;
+
+        let counts: Vec<String> = Mismatch::ALL
+            .iter()
+            .map(|mismatch| (mismatch, result.count(*mismatch)))
+            .filter(|(_, count)| *count > 0)
+            .map(|(mismatch, count)| format!("{} {}", mismatch.name(), count))
+            .collect();
+        println!(
+            "{:<6} {:>4}/{:<4} failed  {}",
+            result.opcode,
+            result.failures.len(),
+            result.cases,
+            counts.join(", ")
+        );
+
+        let shown = if verbose { result.failures.len() } else { 1 };
+        for failure in result.failures.iter().take(shown) {
+            println!("    {}", failure.name);
+            for line in failure.detail.lines() {
+                println!("        {}", line);
+            }
+        }
+    }
+
+    println!(
+        "\n{} of {} opcodes passed",
+        files.len() - failed_opcodes,
+        files.len()
+    );
+    if failed_opcodes == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,532 @@
+// Runs test ROMs headlessly and works out whether they passed.
+// Each suite reports its result differently:
+//   Blargg - prints "Passed" or "Failed" over serial, and newer ROMs also
+//            write a status byte to 0xA000 and text to 0xA004
+//   Mooneye - runs `ld b,b`, with B/C/D/E/H/L holding 3/5/8/13/21/34 on a pass
+//   Acid2 - runs `ld b,b` once the screen is drawn, which is compared with a
+//           reference image next to the ROM
+pub mod report;
+pub mod sm83;
+
+use std::cell::RefCell;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use gbrs_core::colour::colour::Colour;
+use gbrs_core::colour::dmg_palettes::DmgPalettes;
+use gbrs_core::config::Config;
+use gbrs_core::constants::*;
+use gbrs_core::cpu::Cpu;
+use gbrs_core::debugger::StopReason;
+use gbrs_core::memory::rom::Rom;
+use gbrs_core::serial_cable::SerialDevice;
+
+// How far each colour channel can be from the reference image. CGB colours
+// are expanded from 5 bits, and emulators don't all do that the same way.
+const ACID2_CHANNEL_TOLERANCE: u8 = 8;
+// The shades dmg-acid2's reference image uses
+const ACID2_DMG_SHADES: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+const MOONEYE_PASS_REGISTERS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Blargg's memory output is only valid after this signature at 0xA001
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_STILL_RUNNING: u8 = 0x80;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Suite {
+    Blargg,
+    Mooneye,
+    Acid2,
+}
+
+impl Suite {
+    pub const ALL: [Suite; 3] = [Suite::Blargg, Suite::Mooneye, Suite::Acid2];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Suite::Blargg => "blargg",
+            Suite::Mooneye => "mooneye",
+            Suite::Acid2 => "acid2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Suite> {
+        Suite::ALL
+            .iter()
+            .find(|suite| suite.name() == name)
+            .copied()
+    }
+
+    // Guesses the suite from the ROM's file name and the directories it's
+    // in, falling back to Blargg's serial output. Mooneye's releases unpack
+    // to folders like `mts-20240127-1204-74ae166`.
+    pub fn detect(rom_path: &Path) -> Suite {
+        let path = rom_path.to_string_lossy().to_lowercase();
+        let in_mts_folder = rom_path.components().any(|component| {
+            let name = component.as_os_str().to_string_lossy().to_lowercase();
+            name == "mts" || name.starts_with("mts-")
+        });
+
+        if path.contains("acid2") {
+            Suite::Acid2
+        } else if path.contains("mooneye") || in_mts_folder {
+            Suite::Mooneye
+        } else {
+            Suite::Blargg
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Passed,
+    // Along with whatever the ROM said about why
+    Failed(String),
+    // Didn't finish within the time limit
+    TimedOut,
+    // The emulator panicked, usually on an unsupported opcode
+    Crashed(String),
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Passed => "passed",
+            Outcome::Failed(_) => "failed",
+            Outcome::TimedOut => "timeout",
+            Outcome::Crashed(_) => "crashed",
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            Outcome::Failed(detail) | Outcome::Crashed(detail) => detail,
+            _ => "",
+        }
+    }
+}
+
+pub struct TestResult {
+    pub rom_path: PathBuf,
+    pub suite: Suite,
+    pub outcome: Outcome,
+    pub emulated_seconds: f64,
+}
+
+pub struct RunOptions {
+    // Uses `Suite::detect` when `None`
+    pub suite: Option<Suite>,
+    // In emulated time, so results don't depend on how fast the machine is
+    pub timeout_seconds: u32,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            suite: None,
+            timeout_seconds: 120,
+        }
+    }
+}
+
+// Keeps every byte the ROM sends over the link cable
+struct SerialCapture(Rc<RefCell<Vec<u8>>>);
+
+impl SerialDevice for SerialCapture {
+    fn exchange_byte(&mut self, outgoing: u8) -> u8 {
+        self.0.borrow_mut().push(outgoing);
+        0xFF
+    }
+}
+
+// Every .gb and .gbc file under `dir`, in a stable order
+pub fn find_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let extension =
+                path.extension().map(|e| e.to_string_lossy().to_lowercase());
+            if path.is_dir() {
+                pending.push(path);
+            } else if matches!(extension.as_deref(), Some("gb" | "gbc")) {
+                roms.push(path);
+            }
+        }
+    }
+    roms.sort();
+    roms
+}
+
+pub fn run_rom(rom_path: &Path, options: &RunOptions) -> TestResult {
+    let suite = options.suite.unwrap_or_else(|| Suite::detect(rom_path));
+    let mut cycles = 0;
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        run_suite(rom_path, suite, options.timeout_seconds, &mut cycles)
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        Outcome::Crashed(message)
+    });
+
+    TestResult {
+        rom_path: rom_path.to_path_buf(),
+        suite,
+        outcome,
+        emulated_seconds: cycles as f64 / CLOCK_SPEED as f64,
+    }
+}
+
+fn run_suite(
+    rom_path: &Path,
+    suite: Suite,
+    timeout_seconds: u32,
+    cycles: &mut usize,
+) -> Outcome {
+    let path = rom_path.to_string_lossy().to_string();
+    let mut cpu = Cpu::from_config(Config {
+        sound_buffer_size: SOUND_BUFFER_SIZE,
+        sound_sample_rate: SOUND_SAMPLE_RATE,
+        rom: Rom::from_file(&path),
+        model: None,
+    });
+
+    let serial = Rc::new(RefCell::new(vec![]));
+    cpu.connect_serial_device(Box::new(SerialCapture(serial.clone())));
+    cpu.homebrew.breakpoints = suite != Suite::Blargg;
+    cpu.homebrew.messages = false;
+    if suite == Suite::Acid2 {
+        let shades = ACID2_DMG_SHADES.map(|hex| {
+            Colour::new((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)
+        });
+        cpu.gpu.set_dmg_palettes(DmgPalettes::all(shades));
+    }
+
+    let cycles_per_frame = CLOCK_SPEED / DEFAULT_FRAME_RATE;
+    let max_cycles = CLOCK_SPEED * timeout_seconds as usize;
+    while *cycles < max_cycles {
+        let (ran, reason) = cpu.run_until_stop(cycles_per_frame);
+        *cycles += ran;
+
+        let finished = matches!(reason, StopReason::SoftwareBreakpoint { .. });
+        let outcome = match suite {
+            Suite::Blargg => blargg_outcome(&cpu, &serial.borrow()),
+            Suite::Mooneye if finished => Some(mooneye_outcome(&cpu)),
+            Suite::Acid2 if finished => {
+                // Let the frame that's on screen finish drawing
+                for _ in 0..2 {
+                    *cycles += cpu.step_one_frame();
+                }
+                Some(acid2_outcome(&cpu, rom_path))
+            },
+            _ => None,
+        };
+        if let Some(outcome) = outcome {
+            return outcome;
+        }
+    }
+
+    Outcome::TimedOut
+}
+
+fn blargg_outcome(cpu: &Cpu, serial: &[u8]) -> Option<Outcome> {
+    let read = |address: u16| cpu.mem.read(&cpu.ints, &cpu.gpu, address);
+
+    let serial_text = String::from_utf8_lossy(serial);
+    if serial_text.contains("Passed") {
+        return Some(Outcome::Passed);
+    }
+    if serial_text.contains("Failed") {
+        return Some(Outcome::Failed(serial_text.trim().to_string()));
+    }
+
+    let signature = [read(0xA001), read(0xA002), read(0xA003)];
+    let status = read(0xA000);
+    if signature != BLARGG_SIGNATURE || status == BLARGG_STILL_RUNNING {
+        return None;
+    }
+    if status == 0 {
+        return Some(Outcome::Passed);
+    }
+
+    let text: Vec<u8> = (0xA004..0xC000)
+        .map(read)
+        .take_while(|byte| *byte != 0)
+        .collect();
+    let text = String::from_utf8_lossy(&text).trim().to_string();
+    Some(Outcome::Failed(format!("Result code {}: {}", status, text)))
+}
+
+fn mooneye_outcome(cpu: &Cpu) -> Outcome {
+    let regs = &cpu.regs;
+    let values = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+    if values == MOONEYE_PASS_REGISTERS {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(format!(
+            "B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}",
+            values[0], values[1], values[2], values[3], values[4], values[5]
+        ))
+    }
+}
+
+// The reference is `ROM_NAME.png` or `ROM_NAME-reference.png`
+fn acid2_reference_path(rom_path: &Path) -> Option<PathBuf> {
+    let stem = rom_path.file_stem()?.to_string_lossy().to_string();
+    [format!("{}.png", stem), format!("{}-reference.png", stem)]
+        .iter()
+        .map(|name| rom_path.with_file_name(name))
+        .find(|path| path.exists())
+}
+
+fn acid2_outcome(cpu: &Cpu, rom_path: &Path) -> Outcome {
+    let Some(reference_path) = acid2_reference_path(rom_path) else {
+        return Outcome::Failed("No reference image next to the ROM".into());
+    };
+    let reference = match load_rgb_image(&reference_path) {
+        Ok(reference) => reference,
+        Err(error) => return Outcome::Failed(error),
+    };
+
+    let frame = cpu.gpu.get_rgba_frame();
+    let wrong_pixels = frame
+        .chunks_exact(4)
+        .zip(reference.chunks_exact(3))
+        .filter(|(ours, theirs)| {
+            ours.iter()
+                .zip(theirs.iter())
+                .any(|(a, b)| a.abs_diff(*b) > ACID2_CHANNEL_TOLERANCE)
+        })
+        .count();
+
+    if wrong_pixels == 0 {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(format!(
+            "{} of {} pixels differ from {}",
+            wrong_pixels,
+            SCREEN_BUFFER_SIZE,
+            reference_path.to_string_lossy()
+        ))
+    }
+}
+
+// Loads a screen-sized PNG as 8-bit RGB
+fn load_rgb_image(path: &Path) -> Result<Vec<u8>, String> {
+    let data = fs::read(path)
+        .map_err(|e| format!("Can't open {}: {}", path.display(), e))?;
+    decode_rgb_image(&data, path)
+}
+
+// `path` is only for error messages
+fn decode_rgb_image(data: &[u8], path: &Path) -> Result<Vec<u8>, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(
+        png::Transformations::EXPAND | png::Transformations::STRIP_16,
+    );
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| format!("Can't decode {}: {}", path.display(), e))?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buffer)
+        .map_err(|e| format!("Can't decode {}: {}", path.display(), e))?;
+
+    if info.width as usize != SCREEN_WIDTH
+        || info.height as usize != SCREEN_HEIGHT
+    {
+        return Err(format!(
+            "{} is {}x{}, not {}x{}",
+            path.display(),
+            info.width,
+            info.height,
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT
+        ));
+    }
+
+    let pixels = &buffer[..info.buffer_size()];
+    let rgb = match info.color_type {
+        png::ColorType::Rgb => pixels.to_vec(),
+        png::ColorType::Rgba => pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect(),
+        png::ColorType::Grayscale => {
+            pixels.iter().flat_map(|grey| [*grey; 3]).collect()
+        },
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .flat_map(|pixel| [pixel[0]; 3])
+            .collect(),
+        png::ColorType::Indexed => {
+            return Err(format!("{} wasn't expanded", path.display()))
+        },
+    };
+    Ok(rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An MBC1 cart with RAM at 0xA000, enabled, for Blargg's memory output
+    fn cpu_with_ram() -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x02;
+        rom[0x0149] = 0x02;
+        let mut cpu = Cpu::from_config(Config {
+            sound_buffer_size: SOUND_BUFFER_SIZE,
+            sound_sample_rate: SOUND_SAMPLE_RATE,
+            rom: Rom::from_bytes(rom),
+            model: None,
+        });
+        write(&mut cpu, 0x0000, 0x0A);
+        cpu
+    }
+
+    fn write(cpu: &mut Cpu, address: u16, value: u8) {
+        cpu.mem.write(&mut cpu.ints, &mut cpu.gpu, address, value);
+    }
+
+    fn write_bytes(cpu: &mut Cpu, address: u16, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            write(cpu, address + offset as u16, *byte);
+        }
+    }
+
+    fn grey_png(width: u32, height: u32, grey: u8) -> Vec<u8> {
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let pixels = vec![grey; (width * height) as usize];
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&pixels).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    // A folder of its own in the system's temp folder
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "gbrs-conformance-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn detect_goes_by_path() {
+        let detect = |path: &str| Suite::detect(Path::new(path));
+        assert_eq!(detect("roms/dmg-acid2.gb"), Suite::Acid2);
+        assert_eq!(
+            detect("roms/mooneye/acceptance/di_timing.gb"),
+            Suite::Mooneye
+        );
+        assert_eq!(detect("mts/acceptance/di_timing.gb"), Suite::Mooneye);
+        assert_eq!(
+            detect("roms/mts-20240127-1204-74ae166/acceptance/ei_timing.gb"),
+            Suite::Mooneye
+        );
+        // "mts" has to be a whole folder name
+        assert_eq!(detect("roms/summits/timing.gb"), Suite::Blargg);
+        assert_eq!(detect("roms/cpu_instrs/mts.gb"), Suite::Blargg);
+        assert_eq!(detect("roms/cpu_instrs/01-special.gb"), Suite::Blargg);
+    }
+
+    #[test]
+    fn blargg_reports_what_it_printed_over_serial() {
+        let cpu = cpu_with_ram();
+        assert_eq!(blargg_outcome(&cpu, b"cpu_instrs\n\n"), None);
+        assert_eq!(
+            blargg_outcome(&cpu, b"01-special\n\n\nPassed\n"),
+            Some(Outcome::Passed)
+        );
+        assert_eq!(
+            blargg_outcome(&cpu, b"01-special\n\nDAA\n\nFailed #6\n"),
+            Some(Outcome::Failed("01-special\n\nDAA\n\nFailed #6".into()))
+        );
+    }
+
+    #[test]
+    fn blargg_reports_its_memory_output_once_it_finishes() {
+        let mut cpu = cpu_with_ram();
+        // Without the signature 0xA000 could be anything
+        write(&mut cpu, 0xA000, 0x00);
+        assert_eq!(blargg_outcome(&cpu, b""), None);
+
+        write_bytes(&mut cpu, 0xA001, &BLARGG_SIGNATURE);
+        write(&mut cpu, 0xA000, BLARGG_STILL_RUNNING);
+        assert_eq!(blargg_outcome(&cpu, b""), None);
+
+        write(&mut cpu, 0xA000, 0x00);
+        assert_eq!(blargg_outcome(&cpu, b""), Some(Outcome::Passed));
+
+        write(&mut cpu, 0xA000, 0x03);
+        write_bytes(&mut cpu, 0xA004, b"  Timer doesn't work\n\0");
+        assert_eq!(
+            blargg_outcome(&cpu, b""),
+            Some(Outcome::Failed("Result code 3: Timer doesn't work".into()))
+        );
+    }
+
+    #[test]
+    fn mooneye_passes_on_fibonacci_registers() {
+        let mut cpu = cpu_with_ram();
+        let regs = &mut cpu.regs;
+        (regs.b, regs.c, regs.d, regs.e, regs.h, regs.l) =
+            (3, 5, 8, 13, 21, 34);
+        assert_eq!(mooneye_outcome(&cpu), Outcome::Passed);
+
+        cpu.regs.l = 0x42;
+        assert_eq!(
+            mooneye_outcome(&cpu),
+            Outcome::Failed("B:03 C:05 D:08 E:0D H:15 L:42".into())
+        );
+    }
+
+    #[test]
+    fn acid2_reference_sits_next_to_the_rom() {
+        let dir = temp_dir("acid2");
+        let rom = dir.join("dmg-acid2.gb");
+        assert_eq!(acid2_reference_path(&rom), None);
+
+        let png = grey_png(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, 0x55);
+        let reference = dir.join("dmg-acid2-reference.png");
+        fs::write(&reference, &png).unwrap();
+        assert_eq!(acid2_reference_path(&rom), Some(reference.clone()));
+        // The plain name wins
+        fs::write(dir.join("dmg-acid2.png"), &png).unwrap();
+        assert_eq!(acid2_reference_path(&rom), Some(dir.join("dmg-acid2.png")));
+
+        assert_eq!(
+            load_rgb_image(&reference).unwrap(),
+            vec![0x55; SCREEN_BUFFER_SIZE * 3]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reference_images_must_be_screen_sized() {
+        let path = Path::new("tiny.png");
+        let grey = decode_rgb_image(&grey_png(2, 2, 0xFF), path);
+        assert_eq!(grey, Err("tiny.png is 2x2, not 160x144".to_string()));
+        assert!(decode_rgb_image(b"not a png", path).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use gbrs_conformance::report::Report;
+use gbrs_conformance::{find_roms, run_rom, RunOptions, Suite};
+use gbrs_core::callbacks::{set_callbacks, Callbacks};
+
+const USAGE: &str = "Usage: gbrs-conformance ROM_DIR [--suite blargg|mooneye|acid2] [--timeout SECONDS] [--json REPORT_PATH]";
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let rom_dir = PathBuf::from(args.next().expect(USAGE));
+
+    let mut options = RunOptions::default();
+    let mut json_path = None;
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--suite" => {
+                let name = args.next().expect(USAGE);
+                options.suite =
+                    Some(Suite::from_name(&name).unwrap_or_else(|| {
+                        panic!("Unknown suite \"{}\"", name)
+                    }));
+            },
+            "--timeout" => {
+                let seconds = args.next().expect(USAGE);
+                options.timeout_seconds =
+                    seconds.parse().expect("Invalid timeout");
+            },
+            "--json" => json_path = Some(args.next().expect(USAGE)),
+            _ => panic!("Unknown argument \"{}\"\n{}", arg, USAGE),
+        }
+    }
+
+    // Test ROMs shouldn't leave save files behind, and the emulator's own
+    // logging would bury the results
+    set_callbacks(Callbacks {
+        log: |_log_str| {},
+        save: |_game_name, _rom_path, _save_data| {},
+        load: |_game_name, _rom_path, expected_size| vec![0; expected_size],
+    });
+    // Crashes are reported as results instead
+    panic::set_hook(Box::new(|_info| {}));
+
+    let roms = find_roms(&rom_dir);
+    if roms.is_empty() {
+        eprintln!("No .gb or .gbc files in {}", rom_dir.display());
+        return ExitCode::FAILURE;
+    }
+
+    let mut results = vec![];
+    for rom in roms {
+        let result = run_rom(&rom, &options);
+        let name = rom.strip_prefix(&rom_dir).unwrap_or(&rom);
+        let line = format!(
+            "{:<8} {:<8} {} ({:.1}s) {}",
+            result.outcome.name(),
+            result.suite.name(),
+            name.display(),
+            result.emulated_seconds,
+            result.outcome.detail().lines().next().unwrap_or("")
+        );
+        println!("{}", line.trim_end());
+        results.push(result);
+    }
+
+    let report = Report { results };
+    println!();
+    print!("{}", report.summary_table());
+
+    if let Some(path) = json_path {
+        fs::write(&path, report.to_json()).expect("Failed to write report");
+    }
+
+    if report.all_passed() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,160 @@
+// Summaries of a run, as a table for people and JSON for tracking accuracy
+// across gbrs versions
+use std::fmt::Write;
+
+use crate::{Outcome, Suite, TestResult};
+use gbrs_core::constants::GBRS_VERSION;
+use serde_json::{json, Value};
+
+pub struct Report {
+    pub results: Vec<TestResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| r.outcome == Outcome::Passed)
+            .count()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.passed() == self.results.len()
+    }
+
+    // One row per suite that had ROMs, then the totals
+    pub fn summary_table(&self) -> String {
+        let mut table = String::new();
+        let _ = writeln!(
+            table,
+            "{:<8} {:>6} {:>6} {:>7} {:>7} {:>6}",
+            "Suite", "Passed", "Failed", "Timeout", "Crashed", "Total"
+        );
+
+        let mut rows: Vec<(&str, Vec<&TestResult>)> = Suite::ALL
+            .iter()
+            .map(|suite| {
+                let results =
+                    self.results.iter().filter(|r| r.suite == *suite).collect();
+                (suite.name(), results)
+            })
+            .filter(|(_, results): &(_, Vec<_>)| !results.is_empty())
+            .collect();
+        rows.push(("total", self.results.iter().collect()));
+
+        for (name, results) in rows {
+            let count = |outcome: &str| {
+                results
+                    .iter()
+                    .filter(|r| r.outcome.name() == outcome)
+                    .count()
+            };
+            let _ = writeln!(
+                table,
+                "{:<8} {:>6} {:>6} {:>7} {:>7} {:>6}",
+                name,
+                count("passed"),
+                count("failed"),
+                count("timeout"),
+                count("crashed"),
+                results.len()
+            );
+        }
+        table
+    }
+
+    pub fn to_json(&self) -> String {
+        let results: Vec<Value> = self
+            .results
+            .iter()
+            .map(|result| {
+                json!({
+                    "rom": result.rom_path.to_string_lossy(),
+                    "suite": result.suite.name(),
+                    "outcome": result.outcome.name(),
+                    "detail": result.outcome.detail(),
+                    // Hundredths of a second are plenty to spot a regression
+                    "emulated_seconds":
+                        (result.emulated_seconds * 100.).round() / 100.,
+                })
+            })
+            .collect();
+
+        let report = json!({
+            "gbrs_version": GBRS_VERSION,
+            "total": self.results.len(),
+            "passed": self.passed(),
+            "results": results,
+        });
+        serde_json::to_string_pretty(&report).unwrap() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn result(rom: &str, suite: Suite, outcome: Outcome) -> TestResult {
+        TestResult {
+            rom_path: PathBuf::from(rom),
+            suite,
+            outcome,
+            emulated_seconds: 1.234,
+        }
+    }
+
+    fn report() -> Report {
+        Report {
+            results: vec![
+                result("a.gb", Suite::Blargg, Outcome::Passed),
+                result("b.gb", Suite::Blargg, Outcome::Failed("#2".into())),
+                result("c.gb", Suite::Mooneye, Outcome::TimedOut),
+                result(
+                    "d.gb",
+                    Suite::Mooneye,
+                    Outcome::Crashed("\"x\"".into()),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn summary_table_has_a_row_per_suite_that_ran() {
+        let report = report();
+        assert_eq!(report.passed(), 1);
+        assert!(!report.all_passed());
+        assert_eq!(
+            report.summary_table(),
+            "Suite    Passed Failed Timeout Crashed  Total\n\
+             blargg        1      1       0       0      2\n\
+             mooneye       0      0       1       1      2\n\
+             total         1      1       1       1      4\n"
+        );
+    }
+
+    #[test]
+    fn json_has_every_result() {
+        let json: Value = serde_json::from_str(&report().to_json()).unwrap();
+        assert_eq!(json["gbrs_version"], GBRS_VERSION);
+        assert_eq!(
+            (json["total"].as_u64(), json["passed"].as_u64()),
+            (Some(4), Some(1))
+        );
+
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(
+            results[1],
+            json!({
+                "rom": "b.gb",
+                "suite": "blargg",
+                "outcome": "failed",
+                "detail": "#2",
+                "emulated_seconds": 1.23,
+            })
+        );
+        // Details are escaped properly
+        assert_eq!(results[3]["detail"], "\"x\"");
+    }
+}
@@ -0,0 +1,421 @@
+// Runs the community SM83 single-step tests against the CPU on a test bus:
+//   https://github.com/SingleStepTests/sm83
+// Each JSON file holds cases for one opcode, with the state before and after
+// the instruction and what was on the bus each M-cycle. gbrs only counts
+// cycles rather than timing each access, so bus activity is compared as the
+// order of reads and writes.
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use gbrs_core::cpu::Cpu;
+use gbrs_core::test_bus::{BusAccess, BusAccessKind, TestBus};
+use serde_json::Value;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mismatch {
+    Registers,
+    Flags,
+    Memory,
+    // The wrong number of M-cycles, or the wrong reads and writes
+    Cycles,
+    // The emulator panicked
+    Crashed,
+}
+
+impl Mismatch {
+    pub const ALL: [Mismatch; 5] = [
+        Mismatch::Registers,
+        Mismatch::Flags,
+        Mismatch::Memory,
+        Mismatch::Cycles,
+        Mismatch::Crashed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mismatch::Registers => "registers",
+            Mismatch::Flags => "flags",
+            Mismatch::Memory => "memory",
+            Mismatch::Cycles => "cycles",
+            Mismatch::Crashed => "crashed",
+        }
+    }
+}
+
+pub struct CaseFailure {
+    pub name: String,
+    pub mismatches: Vec<Mismatch>,
+    // What was expected and what we got, one difference per line
+    pub detail: String,
+}
+
+pub struct OpcodeResult {
+    // The test file's name, eg. `27` or `cb 11`
+    pub opcode: String,
+    pub cases: usize,
+    pub failures: Vec<CaseFailure>,
+}
+
+impl OpcodeResult {
+    pub fn count(&self, mismatch: Mismatch) -> usize {
+        self.failures
+            .iter()
+            .filter(|f| f.mismatches.contains(&mismatch))
+            .count()
+    }
+}
+
+struct CpuState {
+    registers: [(&'static str, u16); 9],
+    f: u8,
+    ime: bool,
+    ram: Vec<(u16, u8)>,
+}
+
+// Every .json file in `dir`, sorted so opcodes come out in order
+pub fn find_test_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|e| e == "json"))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+pub fn run_test_file(path: &Path) -> Result<OpcodeResult, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let cases: Value = serde_json::from_str(&text)
+        .map_err(|e| format!("Can't parse {}: {}", path.display(), e))?;
+    let cases = cases
+        .as_array()
+        .ok_or_else(|| format!("{} isn't a list of tests", path.display()))?;
+
+    let mut failures = vec![];
+    for case in cases {
+        if let Some(failure) =
+            run_case(case).map_err(|e| format!("{}: {}", path.display(), e))?
+        {
+            failures.push(failure);
+        }
+    }
+
+    Ok(OpcodeResult {
+        opcode: path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        cases: cases.len(),
+        failures,
+    })
+}
+
+fn run_case(case: &Value) -> Result<Option<CaseFailure>, String> {
+    let name = case["name"].as_str().unwrap_or("unnamed").to_string();
+    let initial = parse_state(&case["initial"])?;
+    let expected = parse_state(&case["final"])?;
+    let expected_bus = parse_bus_activity(&case["cycles"])?;
+    let expected_m_cycles = case["cycles"].as_array().map_or(0, |c| c.len());
+
+    let mut cpu = Cpu::with_test_bus();
+    load_state(&mut cpu, &initial);
+
+    let cycles =
+        panic::catch_unwind(AssertUnwindSafe(|| cpu.single_speed_step()));
+    let Ok(cycles) = cycles else {
+        return Ok(Some(CaseFailure {
+            name,
+            mismatches: vec![Mismatch::Crashed],
+            detail: String::from("The emulator panicked"),
+        }));
+    };
+
+    let mut mismatches = vec![];
+    let mut detail = vec![];
+    let actual = save_state(&cpu, &expected);
+
+    for (expected, actual) in
+        expected.registers.iter().zip(actual.registers.iter())
+    {
+        if expected.1 != actual.1 {
+            push_unique(&mut mismatches, Mismatch::Registers);
+            detail.push(format!(
+                "{}: expected {:#06x}, got {:#06x}",
+                expected.0, expected.1, actual.1
+            ));
+        }
+    }
+    if expected.ime != actual.ime {
+        push_unique(&mut mismatches, Mismatch::Registers);
+        detail.push(format!(
+            "IME: expected {}, got {}",
+            expected.ime, actual.ime
+        ));
+    }
+    if expected.f != actual.f {
+        mismatches.push(Mismatch::Flags);
+        detail.push(format!(
+            "F: expected {:08b}, got {:08b}",
+            expected.f, actual.f
+        ));
+    }
+    for (&(address, expected), &(_, actual)) in
+        expected.ram.iter().zip(actual.ram.iter())
+    {
+        if expected != actual {
+            push_unique(&mut mismatches, Mismatch::Memory);
+            detail.push(format!(
+                "({:#06x}): expected {:#04x}, got {:#04x}",
+                address, expected, actual
+            ));
+        }
+    }
+
+    let m_cycles = cycles / 4;
+    if m_cycles != expected_m_cycles {
+        mismatches.push(Mismatch::Cycles);
+        detail.push(format!(
+            "M-cycles: expected {}, got {}",
+            expected_m_cycles, m_cycles
+        ));
+    }
+    let bus = &cpu.mem.accesses;
+    if *bus != expected_bus {
+        push_unique(&mut mismatches, Mismatch::Cycles);
+        detail.push(format!(
+            "Bus: expected {}, got {}",
+            describe_bus(&expected_bus),
+            describe_bus(bus)
+        ));
+    }
+
+    if mismatches.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(CaseFailure {
+        name,
+        mismatches,
+        detail: detail.join("\n"),
+    }))
+}
+
+fn push_unique(mismatches: &mut Vec<Mismatch>, mismatch: Mismatch) {
+    if !mismatches.contains(&mismatch) {
+        mismatches.push(mismatch);
+    }
+}
+
+fn number(state: &Value, key: &str) -> Result<u16, String> {
+    state[key]
+        .as_u64()
+        .map(|n| n as u16)
+        .ok_or_else(|| format!("Missing \"{}\"", key))
+}
+
+fn parse_state(state: &Value) -> Result<CpuState, String> {
+    let mut registers = [
+        ("A", 0),
+        ("B", 0),
+        ("C", 0),
+        ("D", 0),
+        ("E", 0),
+        ("H", 0),
+        ("L", 0),
+        ("SP", 0),
+        ("PC", 0),
+    ];
+    for (name, value) in registers.iter_mut() {
+        *value = number(state, &name.to_lowercase())?;
+    }
+
+    let ram = state["ram"]
+        .as_array()
+        .ok_or("Missing \"ram\"")?
+        .iter()
+        .map(|entry| {
+            let address = entry[0].as_u64().ok_or("Bad RAM address")?;
+            let value = entry[1].as_u64().ok_or("Bad RAM value")?;
+            Ok((address as u16, value as u8))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(CpuState {
+        registers,
+        f: number(state, "f")? as u8,
+        ime: state["ime"].as_u64().unwrap_or(0) != 0,
+        ram,
+    })
+}
+
+// Cycles are `[address, value, "r-m"]`, with `r` or `w` for an access.
+// Internal cycles have neither, or are `null`.
+fn parse_bus_activity(cycles: &Value) -> Result<Vec<BusAccess>, String> {
+    let mut accesses = vec![];
+    for cycle in cycles.as_array().ok_or("Missing \"cycles\"")? {
+        let pins = cycle[2].as_str().unwrap_or("");
+        let kind = if pins.contains('r') {
+            BusAccessKind::Read
+        } else if pins.contains('w') {
+            BusAccessKind::Write
+        } else {
+            continue;
+        };
+        accesses.push(BusAccess {
+            kind,
+            address: cycle[0].as_u64().ok_or("Bad cycle address")? as u16,
+            value: cycle[1].as_u64().ok_or("Bad cycle value")? as u8,
+        });
+    }
+    Ok(accesses)
+}
+
+fn describe_bus(accesses: &[BusAccess]) -> String {
+    accesses
+        .iter()
+        .map(|access| {
+            let kind = match access.kind {
+                BusAccessKind::Read => 'R',
+                BusAccessKind::Write => 'W',
+            };
+            format!("{}:{:04X}={:02X}", kind, access.address, access.value)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn load_state(cpu: &mut Cpu<TestBus>, state: &CpuState) {
+    let value = |name: &str| {
+        state.registers.iter().find(|(n, _)| *n == name).unwrap().1
+    };
+    cpu.regs.a = value("A") as u8;
+    cpu.regs.b = value("B") as u8;
+    cpu.regs.c = value("C") as u8;
+    cpu.regs.d = value("D") as u8;
+    cpu.regs.e = value("E") as u8;
+    cpu.regs.h = value("H") as u8;
+    cpu.regs.l = value("L") as u8;
+    cpu.regs.sp = value("SP");
+    cpu.regs.pc = value("PC");
+    cpu.regs.f = state.f;
+    cpu.ints.ime = state.ime;
+
+    let bus = &mut cpu.mem;
+    for &(address, value) in &state.ram {
+        bus.poke(address, value);
+    }
+}
+
+// The CPU's state, looking at the same RAM addresses as `expected`
+fn save_state(cpu: &Cpu<TestBus>, expected: &CpuState) -> CpuState {
+    let regs = &cpu.regs;
+    let bus = &cpu.mem;
+    CpuState {
+        registers: [
+            ("A", regs.a as u16),
+            ("B", regs.b as u16),
+            ("C", regs.c as u16),
+            ("D", regs.d as u16),
+            ("E", regs.e as u16),
+            ("H", regs.h as u16),
+            ("L", regs.l as u16),
+            ("SP", regs.sp),
+            ("PC", regs.pc),
+        ],
+        f: regs.f,
+        ime: cpu.ints.ime,
+        ram: expected
+            .ram
+            .iter()
+            .map(|&(address, _)| (address, bus.peek(address)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn state(pc: u16, a: u8, f: u8, ram: Value) -> Value {
+        json!({
+            "pc": pc, "sp": 0xFFFE, "a": a, "b": 0, "c": 0, "d": 0, "e": 0,
+            "f": f, "h": 0, "l": 0, "ime": 0, "ram": ram,
+        })
+    }
+
+    #[test]
+    fn nop_passes() {
+        let case = json!({
+            "name": "00 0000",
+            "initial": state(0x0100, 0x12, 0xB0, json!([[0x0100, 0x00]])),
+            "final": state(0x0101, 0x12, 0xB0, json!([[0x0100, 0x00]])),
+            "cycles": [[0x0100, 0x00, "r-m"]],
+        });
+        assert!(run_case(&case).unwrap().is_none());
+    }
+
+    #[test]
+    fn wrong_flags_are_reported_as_flags() {
+        let case = json!({
+            "name": "00 0001",
+            "initial": state(0x0100, 0x12, 0xB0, json!([[0x0100, 0x00]])),
+            "final": state(0x0101, 0x12, 0x80, json!([[0x0100, 0x00]])),
+            "cycles": [[0x0100, 0x00, "r-m"]],
+        });
+        let failure = run_case(&case).unwrap().unwrap();
+        assert_eq!(failure.name, "00 0001");
+        assert_eq!(failure.mismatches, vec![Mismatch::Flags]);
+        assert_eq!(failure.detail, "F: expected 10000000, got 10110000");
+    }
+
+    #[test]
+    fn memory_writes_are_checked_against_the_bus() {
+        // ld [$C000], a
+        let program = json!([[0x0100, 0xEA], [0x0101, 0x00], [0x0102, 0xC0]]);
+        let case = |written: u8| {
+            let mut ram = program.as_array().unwrap().clone();
+            ram.push(json!([0xC000, written]));
+            json!({
+                "name": "ea 0000",
+                "initial": state(0x0100, 0x42, 0x00, program.clone()),
+                "final": state(0x0103, 0x42, 0x00, json!(ram)),
+                "cycles": [
+                    [0x0100, 0xEA, "r-m"],
+                    [0x0101, 0x00, "r-m"],
+                    [0x0102, 0xC0, "r-m"],
+                    [0xC000, written, "-wm"],
+                ],
+            })
+        };
+        assert!(run_case(&case(0x42)).unwrap().is_none());
+
+        let failure = run_case(&case(0x43)).unwrap().unwrap();
+        assert_eq!(
+            failure.mismatches,
+            vec![Mismatch::Memory, Mismatch::Cycles]
+        );
+        assert!(failure.detail.contains("Bus: expected"));
+        assert!(failure.detail.ends_with("W:C000=42"));
+    }
+
+    #[test]
+    fn internal_cycles_are_left_out_of_the_bus_activity() {
+        let cycles =
+            json!([[0x0100, 0x00, "r-m"], null, [0xC000, 0x01, "---"]]);
+        let accesses = parse_bus_activity(&cycles).unwrap();
+        assert_eq!(
+            accesses,
+            vec![BusAccess {
+                kind: BusAccessKind::Read,
+                address: 0x0100,
+                value: 0x00,
+            }]
+        );
+    }
+}
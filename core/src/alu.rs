@@ -1,4 +1,5 @@
 // CPU Arithmetic Logic Unit
+use crate::bus::Bus;
 use crate::cpu::Cpu;
 
 const ALU_ADD: u8 = 0b000;
@@ -10,7 +11,7 @@ const ALU_XOR: u8 = 0b101;
 const ALU_OR: u8 = 0b110;
 const ALU_CP: u8 = 0b111;
 
-impl Cpu {
+impl<M: Bus> Cpu<M> {
     pub fn alu(&mut self, operation: u8, n: u8) {
         let a = self.regs.a;
         let c = self.regs.get_carry_flag();
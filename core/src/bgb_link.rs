@@ -0,0 +1,541 @@
+// A link cable over TCP that speaks BGB's link protocol (version 1.4), so
+// gbrs can link with BGB, other emulators that implement it, or another gbrs.
+// https://bgb.bircd.org/bgblink.html
+use crate::constants::*;
+use crate::log;
+use crate::serial_cable::SerialDevice;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+// BGB's default port
+pub const BGB_DEFAULT_PORT: u16 = 8765;
+
+const CMD_VERSION: u8 = 1;
+const CMD_JOYPAD: u8 = 101;
+const CMD_SYNC1: u8 = 104;
+const CMD_SYNC2: u8 = 105;
+const CMD_SYNC3: u8 = 106;
+const CMD_STATUS: u8 = 108;
+const CMD_WANT_DISCONNECT: u8 = 109;
+
+const STATUS_RUNNING: u8 = 0b001;
+const STATUS_PAUSED: u8 = 0b010;
+
+// Timestamps are in 2MiHz ticks, so half the DMG clock, and are 31 bits wide
+const TIMESTAMP_MASK: u32 = 0x7FFF_FFFF;
+
+// Sync1's control byte is always SC with a transfer started on the internal
+// clock, plus these for CGB speeds
+const CONTROL_BASE: u8 = 0x81;
+const CONTROL_FAST_CLOCK: u8 = 0b010;
+const CONTROL_DOUBLE_SPEED: u8 = 0b100;
+
+// Reading the socket every instruction would be far too slow
+const POLL_INTERVAL_CYCLES: usize = 512;
+// How often we tell the other side what time it is
+const TIMESTAMP_INTERVAL_CYCLES: usize = CLOCK_SPEED / 64;
+// How far ahead of the other side we can get before transfers wait for it
+const MAX_LEAD_CYCLES: usize = CLOCK_SPEED / 16;
+// How long we wait on the other side before giving up on it, whether for the
+// slave to answer a transfer or for a lagging peer to catch up
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+struct Packet {
+    command: u8,
+    b2: u8,
+    b3: u8,
+    b4: u8,
+    timestamp: u32,
+}
+
+impl Packet {
+    fn new(command: u8, b2: u8, b3: u8, b4: u8, timestamp: u32) -> Packet {
+        Packet {
+            command,
+            b2,
+            b3,
+            b4,
+            timestamp,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 8] {
+        let t = (self.timestamp & TIMESTAMP_MASK).to_le_bytes();
+        [
+            self.command,
+            self.b2,
+            self.b3,
+            self.b4,
+            t[0],
+            t[1],
+            t[2],
+            t[3],
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Packet {
+        Packet {
+            command: bytes[0],
+            b2: bytes[1],
+            b3: bytes[2],
+            b4: bytes[3],
+            timestamp: u32::from_le_bytes([
+                bytes[4], bytes[5], bytes[6], bytes[7],
+            ]),
+        }
+    }
+}
+
+pub struct BgbLink {
+    stream: Option<TcpStream>,
+    recv_buffer: Vec<u8>,
+    // The socket is nonblocking, so anything it wouldn't take yet waits here
+    send_buffer: Vec<u8>,
+
+    // Our clock, in double speed cycles so it runs at the same rate in
+    // either speed
+    clock: usize,
+    fast_clock: bool,
+    double_speed: bool,
+    poll_counter: usize,
+    timestamp_counter: usize,
+
+    // The other side's last reported time and state
+    pub remote_timestamp: u32,
+    pub remote_paused: bool,
+    // Added to our timestamps to line them up with the other side's, which
+    // started counting at a different time. Set by its first timestamp.
+    timestamp_offset: Option<u32>,
+
+    // A byte the remote master clocked at us that we haven't taken yet
+    pending_sync1: Option<u8>,
+    // Were we waiting on an external clock as of the last step?
+    waiting: bool,
+    // When the slave has to have answered the byte we sent by
+    reply_deadline: Option<Instant>,
+    // The slave's answer, once it's arrived
+    reply: Option<u8>,
+    // When we stop holding a transfer back for the other side to catch up
+    catch_up_deadline: Option<Instant>,
+}
+
+impl BgbLink {
+    // Waits for a BGB-compatible emulator to connect to us
+    pub fn host<A: ToSocketAddrs>(address: A) -> io::Result<BgbLink> {
+        let listener = TcpListener::bind(address)?;
+        log!(
+            "[BGB] Waiting for a connection on {}",
+            listener.local_addr()?
+        );
+        BgbLink::accept(&listener)
+    }
+
+    fn accept(listener: &TcpListener) -> io::Result<BgbLink> {
+        let (stream, remote) = listener.accept()?;
+        log!("[BGB] {} connected", remote);
+        BgbLink::from_stream(stream)
+    }
+
+    // Connects to a BGB-compatible emulator that is hosting
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<BgbLink> {
+        let stream = TcpStream::connect(address)?;
+        log!("[BGB] Connected to {}", stream.peer_addr()?);
+        BgbLink::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<BgbLink> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+
+        let mut link = BgbLink {
+            stream: Some(stream),
+            recv_buffer: vec![],
+            send_buffer: vec![],
+            clock: 0,
+            fast_clock: false,
+            double_speed: false,
+            poll_counter: 0,
+            timestamp_counter: 0,
+            remote_timestamp: 0,
+            remote_paused: false,
+            timestamp_offset: None,
+            pending_sync1: None,
+            waiting: false,
+            reply_deadline: None,
+            reply: None,
+            catch_up_deadline: None,
+        };
+
+        // Both sides open with their version, then their status
+        link.send(Packet::new(CMD_VERSION, 1, 4, 0, 0));
+        match link.wait_for_packet(|p| p.command == CMD_VERSION) {
+            Some(p) if p.b2 == 1 && p.b3 == 4 && p.b4 == 0 => {},
+            _ => {
+                link.disconnect();
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Remote doesn't speak BGB link protocol 1.4",
+                ));
+            },
+        }
+        link.send(Packet::new(CMD_STATUS, STATUS_RUNNING, 0, 0, 0));
+
+        Ok(link)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn timestamp(&self) -> u32 {
+        ((self.clock / 4) as u32) & TIMESTAMP_MASK
+    }
+
+    fn control(&self) -> u8 {
+        let mut control = CONTROL_BASE;
+        if self.fast_clock {
+            control |= CONTROL_FAST_CLOCK;
+        }
+        if self.double_speed {
+            control |= CONTROL_DOUBLE_SPEED;
+        }
+        control
+    }
+
+    fn disconnect(&mut self) {
+        if self.stream.take().is_some() {
+            log!("[BGB] Link disconnected");
+        }
+    }
+
+    fn send(&mut self, packet: Packet) {
+        if self.is_connected() {
+            self.send_buffer.extend_from_slice(&packet.to_bytes());
+            self.flush();
+        }
+    }
+
+    // Writes as much of `send_buffer` as the socket will take
+    fn flush(&mut self) {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let mut closed = false;
+        while !closed && !self.send_buffer.is_empty() {
+            match stream.write(&self.send_buffer) {
+                Ok(0) => closed = true,
+                Ok(n) => {
+                    self.send_buffer.drain(..n);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(_) => closed = true,
+            }
+        }
+
+        if closed {
+            self.disconnect();
+        }
+    }
+
+    // Buffers whatever has arrived without blocking
+    fn receive(&mut self) {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let mut closed = false;
+        let mut chunk = [0; 64];
+        while !closed {
+            match stream.read(&mut chunk) {
+                Ok(0) => closed = true,
+                Ok(n) => self.recv_buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(_) => closed = true,
+            }
+        }
+
+        if closed {
+            self.disconnect();
+        }
+    }
+
+    // Sends anything still queued and handles everything that's arrived
+    fn poll(&mut self) {
+        self.flush();
+        self.receive();
+        while let Some(packet) = self.next_packet() {
+            self.handle_packet(packet);
+        }
+    }
+
+    fn next_packet(&mut self) -> Option<Packet> {
+        if self.recv_buffer.len() < 8 {
+            return None;
+        }
+        let packet = Packet::from_bytes(&self.recv_buffer[..8]);
+        self.recv_buffer.drain(..8);
+        Some(packet)
+    }
+
+    // Blocks until a packet matching `wanted` arrives, handling anything
+    // else that turns up in the meantime. Gives up after REPLY_TIMEOUT.
+    fn wait_for_packet<F: Fn(&Packet) -> bool>(
+        &mut self,
+        wanted: F,
+    ) -> Option<Packet> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+
+        loop {
+            while let Some(packet) = self.next_packet() {
+                if wanted(&packet) {
+                    return Some(packet);
+                }
+                self.handle_packet(packet);
+            }
+
+            if !self.is_connected() || Instant::now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_micros(100));
+            self.flush();
+            self.receive();
+        }
+    }
+
+    fn handle_packet(&mut self, packet: Packet) {
+        match packet.command {
+            CMD_SYNC1 => {
+                self.pending_sync1 = Some(packet.b2);
+                self.update_remote_timestamp(packet.timestamp);
+            },
+            CMD_SYNC2 if self.reply_deadline.is_some() => {
+                self.reply = Some(packet.b2)
+            },
+            // The slave wasn't listening, so the line reads high
+            CMD_SYNC3 if packet.b2 == 1 && self.reply_deadline.is_some() => {
+                self.reply = Some(0xFF)
+            },
+            CMD_SYNC3 if packet.b2 == 0 => {
+                self.update_remote_timestamp(packet.timestamp)
+            },
+            CMD_STATUS => self.remote_paused = (packet.b2 & STATUS_PAUSED) > 0,
+            CMD_WANT_DISCONNECT => self.disconnect(),
+            // We don't let the other side press our buttons, and stray
+            // sync2/sync3 replies arrive after we've given up waiting.
+            CMD_JOYPAD | CMD_SYNC2 | CMD_SYNC3 => {},
+            _ => log!("[BGB] Unknown packet command {}", packet.command),
+        }
+    }
+
+    fn update_remote_timestamp(&mut self, timestamp: u32) {
+        self.remote_timestamp = timestamp & TIMESTAMP_MASK;
+        if self.timestamp_offset.is_none() {
+            self.timestamp_offset =
+                Some(self.remote_timestamp.wrapping_sub(self.timestamp()));
+        }
+    }
+
+    // How many ticks ahead of the other side we are. Negative if behind.
+    fn lead(&self) -> i32 {
+        let offset = match self.timestamp_offset {
+            Some(offset) => offset,
+            None => return 0,
+        };
+        let ours = self.timestamp().wrapping_add(offset);
+        let lead = ours.wrapping_sub(self.remote_timestamp) & TIMESTAMP_MASK;
+        // Sign extend from 31 bits
+        ((lead << 1) as i32) >> 1
+    }
+
+    // Whether to hold a transfer back while we're too far ahead of the
+    // other side, so it happens at the same point in time on both. Gives up
+    // after REPLY_TIMEOUT and carries on from the other side's current time.
+    fn waiting_for_remote(&mut self) -> bool {
+        let max_lead = (MAX_LEAD_CYCLES / 2) as i32;
+        if self.lead() <= max_lead || self.remote_paused {
+            self.catch_up_deadline = None;
+            return false;
+        }
+
+        let deadline = *self
+            .catch_up_deadline
+            .get_or_insert_with(|| Instant::now() + REPLY_TIMEOUT);
+        if Instant::now() >= deadline {
+            self.timestamp_offset = None;
+            self.catch_up_deadline = None;
+            return false;
+        }
+        true
+    }
+}
+
+impl SerialDevice for BgbLink {
+    // The cable uses `poll_exchange`, which doesn't hold up emulation. This
+    // is for anything that needs the answer straight away.
+    fn exchange_byte(&mut self, outgoing: u8) -> u8 {
+        loop {
+            if let Some(incoming) = self.poll_exchange(outgoing) {
+                return incoming;
+            }
+            std::thread::sleep(Duration::from_micros(100));
+            self.poll();
+        }
+    }
+
+    fn poll_exchange(&mut self, outgoing: u8) -> Option<u8> {
+        // The slave answers with its SB, or sync3 if it wasn't listening
+        if let Some(incoming) = self.reply.take() {
+            self.reply_deadline = None;
+            return Some(incoming);
+        }
+        if !self.is_connected() {
+            self.reply_deadline = None;
+            return Some(0xFF);
+        }
+
+        match self.reply_deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                self.reply_deadline = None;
+                Some(0xFF)
+            },
+            Some(_) => None,
+            None => {
+                if self.waiting_for_remote() {
+                    return None;
+                }
+                let timestamp = self.timestamp();
+                let control = self.control();
+                self.send(Packet::new(
+                    CMD_SYNC1, outgoing, control, 0, timestamp,
+                ));
+                self.reply_deadline = Some(Instant::now() + REPLY_TIMEOUT);
+                None
+            },
+        }
+    }
+
+    fn external_clock(&mut self, outgoing: u8) -> Option<u8> {
+        self.waiting = true;
+
+        let incoming = self.pending_sync1.take()?;
+        self.send(Packet::new(CMD_SYNC2, outgoing, 0x80, 0, 0));
+        Some(incoming)
+    }
+
+    fn step(&mut self, cycles: usize) {
+        self.clock += if self.double_speed {
+            cycles
+        } else {
+            cycles * 2
+        };
+        self.poll_counter += cycles;
+        self.timestamp_counter += cycles;
+
+        if self.poll_counter >= POLL_INTERVAL_CYCLES {
+            self.poll_counter = 0;
+            self.poll();
+        }
+
+        if self.timestamp_counter >= TIMESTAMP_INTERVAL_CYCLES {
+            self.timestamp_counter = 0;
+            let timestamp = self.timestamp();
+            self.send(Packet::new(CMD_SYNC3, 0, 0, 0, timestamp));
+        }
+
+        // The master clocked us while we weren't listening
+        if self.pending_sync1.is_some() && !self.waiting {
+            self.pending_sync1 = None;
+            self.send(Packet::new(CMD_SYNC3, 1, 0, 0, 0));
+        }
+        // external_clock sets this again straight after if we're waiting
+        self.waiting = false;
+    }
+
+    fn set_speed(&mut self, fast_clock: bool, double_speed: bool) {
+        self.fast_clock = fast_clock;
+        self.double_speed = double_speed;
+    }
+}
+
+impl Drop for BgbLink {
+    fn drop(&mut self) {
+        self.send(Packet::new(CMD_WANT_DISCONNECT, 0, 0, 0, 0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Two links talking to each other over loopback
+    fn linked_pair() -> (BgbLink, BgbLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || BgbLink::connect(address).unwrap());
+        let host = BgbLink::accept(&listener).unwrap();
+        (host, client.join().unwrap())
+    }
+
+    // Clocks a byte from `master` into `slave` and one back the other way,
+    // giving back what (master, slave) received
+    fn transfer(
+        master: &mut BgbLink,
+        slave: &mut BgbLink,
+        master_byte: u8,
+        slave_byte: u8,
+    ) -> (u8, u8) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (mut to_master, mut to_slave) = (None, None);
+        while to_master.is_none() || to_slave.is_none() {
+            assert!(Instant::now() < deadline, "Transfer timed out");
+            if to_master.is_none() {
+                to_master = master.poll_exchange(master_byte);
+            }
+            if to_slave.is_none() {
+                to_slave = slave.external_clock(slave_byte);
+            }
+            master.poll();
+            slave.poll();
+            thread::sleep(Duration::from_micros(100));
+        }
+        (to_master.unwrap(), to_slave.unwrap())
+    }
+
+    #[test]
+    fn bytes_cross_over_loopback_both_ways() {
+        let (mut host, mut client) = linked_pair();
+        assert_eq!(transfer(&mut host, &mut client, 0x12, 0x34), (0x34, 0x12));
+        assert_eq!(transfer(&mut client, &mut host, 0x56, 0x78), (0x78, 0x56));
+        assert!(host.is_connected() && client.is_connected());
+    }
+
+    #[test]
+    fn control_byte_follows_speeds() {
+        let (mut host, _client) = linked_pair();
+        assert_eq!(host.control(), 0x81);
+        host.set_speed(true, false);
+        assert_eq!(host.control(), 0x83);
+        host.set_speed(true, true);
+        assert_eq!(host.control(), 0x87);
+    }
+
+    #[test]
+    fn timestamps_are_in_single_speed_ticks() {
+        let (mut host, _client) = linked_pair();
+        host.step(1000);
+        assert_eq!(host.timestamp(), 500);
+        // Double speed fits twice the cycles into the same time
+        host.set_speed(false, true);
+        host.step(1000);
+        assert_eq!(host.timestamp(), 750);
+    }
+}
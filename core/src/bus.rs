@@ -0,0 +1,76 @@
+// Everything the CPU reaches through its memory bus. The CPU is generic over
+// this, so `Cpu` (on `Memory`) is the real Gameboy and `Cpu<TestBus>` runs
+// instructions against plain RAM without building the rest of the machine.
+use crate::constants::*;
+use crate::gpu::Gpu;
+use crate::interrupts::Interrupts;
+use crate::log;
+use crate::memory::memory::Memory;
+
+pub trait Bus {
+    // The rest of the hardware that memory accesses can reach. That's the
+    // GPU's registers and OAM on a real Gameboy.
+    type Gpu;
+
+    fn read(&mut self, ints: &Interrupts, gpu: &Self::Gpu, address: u16) -> u8;
+    fn write(
+        &mut self,
+        ints: &mut Interrupts,
+        gpu: &mut Self::Gpu,
+        address: u16,
+        value: u8,
+    );
+    // Looks at memory without it counting as an access, for debugging
+    fn peek(&self, ints: &Interrupts, gpu: &Self::Gpu, address: u16) -> u8;
+
+    // Runs a STOP instruction, giving back how many cycles it took
+    fn stop(&mut self) -> usize;
+
+    // For traces and debug messages
+    fn bank_at(&self, address: u16) -> u16;
+    fn ly(&self, gpu: &Self::Gpu) -> u8;
+    fn double_speed(&self) -> bool;
+}
+
+impl Bus for Memory {
+    type Gpu = Gpu;
+
+    #[inline(always)]
+    fn read(&mut self, ints: &Interrupts, gpu: &Gpu, address: u16) -> u8 {
+        Memory::read(self, ints, gpu, address)
+    }
+    #[inline(always)]
+    fn write(
+        &mut self,
+        ints: &mut Interrupts,
+        gpu: &mut Gpu,
+        address: u16,
+        value: u8,
+    ) {
+        Memory::write(self, ints, gpu, address, value)
+    }
+    fn peek(&self, ints: &Interrupts, gpu: &Gpu, address: u16) -> u8 {
+        Memory::read(self, ints, gpu, address)
+    }
+
+    fn stop(&mut self) -> usize {
+        if self.speed_switch.armed {
+            self.speed_switch.execute_speed_switch();
+            // CPU halts for a really long time during speed switch
+            SPEED_SWITCH_HALT_CYCLES
+        } else {
+            log!("[WARN] STOP with un-armed CGB Speed Switch. Not used in commercial games.");
+            4
+        }
+    }
+
+    fn bank_at(&self, address: u16) -> u16 {
+        Memory::bank_at(self, address)
+    }
+    fn ly(&self, gpu: &Gpu) -> u8 {
+        gpu.ly()
+    }
+    fn double_speed(&self) -> bool {
+        self.speed_switch.current_speed_is_double
+    }
+}
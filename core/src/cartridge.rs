@@ -21,6 +21,15 @@ pub struct Cartridge {
     pub ram_size: usize,
 
     pub cgb_support: CGBSupportType,
+    // Does the game use Super GameBoy features?
+    pub sgb_support: bool,
+
+    // The CGB boot rom uses these to pick colours for DMG games
+    pub title_checksum: u8,
+    pub nintendo_licensed: bool,
+
+    // CRC32 of the whole rom, for telling games apart
+    pub rom_crc32: u32,
 }
 
 impl Cartridge {
@@ -57,6 +66,18 @@ impl Cartridge {
             _ => CGBSupportType::None,
         };
 
+        // The SGB only listens to games that set the flag and use the new
+        // licensee code
+        let sgb_support = buffer[0x0146] == 0x03 && buffer[0x014B] == 0x33;
+
+        let title_checksum = buffer[0x0134..=0x0143]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        // Either the old licensee code, or the new one when the old one
+        // says to look there
+        let nintendo_licensed = buffer[0x014B] == 0x01
+            || (buffer[0x014B] == 0x33 && &buffer[0x0144..=0x0145] == b"01");
+
         Cartridge {
             title,
             rom_path,
@@ -64,10 +85,41 @@ impl Cartridge {
             rom_size,
             ram_size,
             cgb_support,
+            sgb_support,
+            title_checksum,
+            nintendo_licensed,
+            rom_crc32: crc32(buffer),
         }
     }
 }
 
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 > 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(buffer: &[u8]) -> u32 {
+    let crc = buffer.iter().fold(0xFFFF_FFFF, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
 fn get_title(buffer: &Vec<u8>) -> String {
     let mut out_buff = vec![];
     for i in 0x0134..=0x0143 {
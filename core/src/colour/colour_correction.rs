@@ -0,0 +1,195 @@
+// Real CGB and AGB screens don't show RGB555 colours the way a PC monitor
+// does. The CGB's LCD is washed out, with each channel bleeding into the
+// others, and the AGB's is much darker. Games were tuned for those screens,
+// so these modes approximate them.
+// Each 5-bit channel is turned into linear light with a power curve, gamma
+// 1.5 for the CGB and 4.0 for the AGB. The channels are mixed together and
+// dimmed, then encoded with gamma 2.0 (a square root) rather than 2.2 so
+// everything stays in integer maths, which keeps it working without std.
+// The numbers are tuned by eye, not taken from measurements.
+use super::colour::Colour;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+// Fixed point 1.0
+const ONE: u64 = 0xFFFF;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ColourCorrection {
+    // Channels are scaled straight from 5 to 8 bits
+    Raw,
+    CgbLcd,
+    AgbLcd,
+}
+
+impl ColourCorrection {
+    pub const ALL: [ColourCorrection; 3] = [
+        ColourCorrection::Raw,
+        ColourCorrection::CgbLcd,
+        ColourCorrection::AgbLcd,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColourCorrection::Raw => "raw",
+            ColourCorrection::CgbLcd => "cgb",
+            ColourCorrection::AgbLcd => "agb",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ColourCorrection> {
+        ColourCorrection::ALL
+            .iter()
+            .find(|mode| mode.name() == name)
+            .copied()
+    }
+
+    // For frontends that cycle through modes with a hotkey
+    pub fn next(&self) -> ColourCorrection {
+        let index = ColourCorrection::ALL
+            .iter()
+            .position(|mode| mode == self)
+            .unwrap();
+        ColourCorrection::ALL[(index + 1) % ColourCorrection::ALL.len()]
+    }
+
+    // How much of each input channel ends up in each output channel, out of
+    // `mix_total`, and how bright the screen gets at full white (out of 255)
+    fn mix(&self) -> ([[u64; 3]; 3], u64, u64) {
+        match self {
+            ColourCorrection::Raw => {
+                ([[1, 0, 0], [0, 1, 0], [0, 0, 1]], 1, 255)
+            },
+            ColourCorrection::CgbLcd => {
+                ([[26, 4, 2], [0, 24, 8], [6, 4, 22]], 32, 240)
+            },
+            ColourCorrection::AgbLcd => {
+                ([[255, 50, 0], [10, 230, 30], [50, 10, 220]], 255, 232)
+            },
+        }
+    }
+
+    // The LCD's response to a 5-bit channel, in linear light
+    fn lcd_response(&self, channel: u8) -> u64 {
+        let x = channel as u64 * ONE / 31;
+        match self {
+            ColourCorrection::Raw => x * x / ONE,
+            // Gamma 1.5 lifts the midtones
+            ColourCorrection::CgbLcd => x * isqrt(x * ONE) / ONE,
+            // Gamma 4.0 crushes them
+            ColourCorrection::AgbLcd => x * x / ONE * x / ONE * x / ONE,
+        }
+    }
+
+    pub fn correct(&self, rgb555: u16) -> Colour {
+        if *self == ColourCorrection::Raw {
+            return Colour::from_16_bit_colour(rgb555);
+        }
+
+        let channels = [
+            self.lcd_response((rgb555 & 0x1F) as u8),
+            self.lcd_response(((rgb555 >> 5) & 0x1F) as u8),
+            self.lcd_response(((rgb555 >> 10) & 0x1F) as u8),
+        ];
+        let (matrix, mix_total, brightness) = self.mix();
+
+        let out = matrix.map(|weights| {
+            let linear = weights
+                .iter()
+                .zip(channels.iter())
+                .map(|(weight, channel)| weight * channel)
+                .sum::<u64>()
+                / mix_total;
+            let encoded = isqrt(linear.min(ONE) * ONE);
+            (encoded * brightness / ONE) as u8
+        });
+
+        Colour::new(out[0], out[1], out[2])
+    }
+
+    // Every RGB555 colour, corrected, so it's cheap to do a whole frame.
+    // Raw doesn't need one.
+    pub fn build_lut(&self) -> Vec<Colour> {
+        if *self == ColourCorrection::Raw {
+            return Vec::new();
+        }
+
+        (0..0x8000).map(|rgb555| self.correct(rgb555)).collect()
+    }
+}
+
+// Colours made by `Colour::from_16_bit_colour` keep the original 5 bits at
+// the top of each channel
+pub fn rgb555_from_colour(colour: &Colour) -> u16 {
+    (colour.red >> 3) as u16
+        | ((colour.green >> 3) as u16) << 5
+        | ((colour.blue >> 3) as u16) << 10
+}
+
+fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+
+    // Newton's method, starting above the root
+    let mut x = n;
+    let mut y = n / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(mode: ColourCorrection, rgb555: u16) -> (u8, u8, u8) {
+        let colour = mode.correct(rgb555);
+        (colour.red, colour.green, colour.blue)
+    }
+
+    #[test]
+    fn raw_scales_channels_straight() {
+        assert_eq!(rgb(ColourCorrection::Raw, 0x7FFF), (255, 255, 255));
+        assert_eq!(rgb(ColourCorrection::Raw, 0x001F), (255, 0, 0));
+        assert_eq!(rgb(ColourCorrection::Raw, 0x4210), (132, 132, 132));
+    }
+
+    #[test]
+    fn cgb_washes_colours_out() {
+        let cgb = ColourCorrection::CgbLcd;
+        assert_eq!(rgb(cgb, 0x0000), (0, 0, 0));
+        assert_eq!(rgb(cgb, 0x7FFF), (240, 240, 240));
+        assert_eq!(rgb(cgb, 0x001F), (216, 0, 103));
+        assert_eq!(rgb(cgb, 0x03E0), (84, 207, 84));
+        assert_eq!(rgb(cgb, 0x7C00), (59, 119, 198));
+        // Gamma 1.5 in and 2.0 out brightens the midtones
+        assert_eq!(rgb(cgb, 0x4210), (146, 146, 146));
+    }
+
+    #[test]
+    fn agb_darkens_colours() {
+        let agb = ColourCorrection::AgbLcd;
+        assert_eq!(rgb(agb, 0x0000), (0, 0, 0));
+        assert_eq!(rgb(agb, 0x7FFF), (232, 232, 232));
+        assert_eq!(rgb(agb, 0x001F), (232, 45, 102));
+        assert_eq!(rgb(agb, 0x03E0), (102, 220, 45));
+        assert_eq!(rgb(agb, 0x7C00), (0, 79, 215));
+        assert_eq!(rgb(agb, 0x4210), (67, 63, 64));
+    }
+
+    #[test]
+    fn luts_match_correcting_each_colour() {
+        let lut = ColourCorrection::CgbLcd.build_lut();
+        assert_eq!(lut.len(), 0x8000);
+        for rgb555 in [0x0000, 0x1234, 0x7FFF] {
+            assert!(
+                lut[rgb555] == ColourCorrection::CgbLcd.correct(rgb555 as u16)
+            );
+        }
+        assert!(ColourCorrection::Raw.build_lut().is_empty());
+    }
+}
@@ -0,0 +1,530 @@
+// The palettes the CGB boot rom gives DMG games, so they're shown in colour.
+// The boot rom picks them from a table keyed by the sum of the title's bytes
+// (only for games Nintendo published), or the player can hold a button combo.
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+use crate::cartridge::Cartridge;
+use crate::joypad::Joypad;
+
+// Colours are RGB888, lightest shade first
+pub struct CompatibilityPalettes {
+    pub bg: [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+impl CompatibilityPalettes {
+    const fn new(bg: [u32; 4], obj0: [u32; 4], obj1: [u32; 4]) -> Self {
+        CompatibilityPalettes { bg, obj0, obj1 }
+    }
+
+    const fn all(colours: [u32; 4]) -> Self {
+        CompatibilityPalettes::new(colours, colours, colours)
+    }
+}
+
+const WHITE: u32 = 0xFFFFFF;
+const BLACK: u32 = 0x000000;
+
+const AMBER: [u32; 4] = [WHITE, 0xFF9C00, 0xFF0000, BLACK];
+const BEACH: [u32; 4] = [WHITE, 0xFFFF7B, 0x0084FF, 0xFF0000];
+const BLUE: [u32; 4] = [WHITE, 0x63A5FF, 0x0000FF, BLACK];
+const BROWN: [u32; 4] = [WHITE, 0xFFAD63, 0x843100, BLACK];
+const CRIMSON: [u32; 4] = [0xFF6352, 0xD60000, 0x630000, BLACK];
+const DARK_RED: [u32; 4] = [BLACK, WHITE, 0xFF8484, 0x943A3A];
+const FIELD: [u32; 4] = [0x52DE00, 0xFF8400, 0xFFFF00, WHITE];
+const FIRE: [u32; 4] = [WHITE, 0xFFFF00, 0xFF0000, BLACK];
+const FLAME: [u32; 4] = [0xFFFF00, 0xFF0000, 0x630000, BLACK];
+const GOLD: [u32; 4] = [0xFFC542, 0xFFD600, 0x943A00, 0x4A0000];
+const GREEN: [u32; 4] = [WHITE, 0x7BFF31, 0x008400, BLACK];
+const JUNGLE: [u32; 4] = [0xFFFF9C, 0x94B5FF, 0x639473, 0x003A3A];
+const LAVENDER: [u32; 4] = [WHITE, 0x8C8CDE, 0x52528C, BLACK];
+const LIGHT_GREEN: [u32; 4] = [WHITE, 0x52FF00, 0xFF4200, BLACK];
+const LILAC: [u32; 4] = [0xA59CFF, 0xFFFF00, 0x006300, BLACK];
+const LIME: [u32; 4] = [WHITE, 0x00FF00, 0x318400, 0x004A00];
+const MEADOW: [u32; 4] = [WHITE, 0x7BFF00, 0xB57300, BLACK];
+const NIGHT_SKY: [u32; 4] = [0x0000FF, WHITE, 0xFFFF7B, 0x0084FF];
+const OLIVE: [u32; 4] = [WHITE, 0xADAD84, 0x42737B, BLACK];
+const ORANGE: [u32; 4] = [WHITE, 0xFF7300, 0x944200, BLACK];
+const PALE_BLUE: [u32; 4] = [WHITE, WHITE, 0x63A5FF, 0x0000FF];
+const PERIWINKLE: [u32; 4] = [0xB5B5FF, 0xFFFF94, 0xAD5A42, BLACK];
+const PITCH: [u32; 4] = [0x6BFF00, WHITE, 0xFF524A, BLACK];
+const RED: [u32; 4] = [WHITE, 0xFF8484, 0x943A3A, BLACK];
+const RED_BLUE: [u32; 4] = [WHITE, 0x5ABDFF, 0xFF0000, 0x0000FF];
+const SAND: [u32; 4] = [0xFFFFCE, 0x63EFEF, 0x9C8431, 0x5A5A5A];
+const SEA_GREEN: [u32; 4] = [WHITE, 0x7BFF31, 0x0063C5, BLACK];
+
+pub const BROWN_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::all(BROWN);
+pub const RED_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::new(RED, GREEN, BLUE);
+pub const DARK_BROWN_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::new(
+        [0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108],
+        BROWN,
+        BROWN,
+    );
+pub const BLUE_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::new(BLUE, RED, GREEN);
+pub const DARK_BLUE_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::new(LAVENDER, RED, BROWN);
+pub const GREY_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::all([WHITE, 0xA5A5A5, 0x525252, BLACK]);
+pub const PASTEL_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::all([0xFFFFA5, 0xFF9494, 0x9494FF, BLACK]);
+pub const ORANGE_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::all(FIRE);
+pub const YELLOW_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::new([WHITE, 0xFFFF00, 0x7B4A00, BLACK], BLUE, GREEN);
+pub const GREEN_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::all(LIGHT_GREEN);
+// What every game the boot rom doesn't recognise gets
+pub const DARK_GREEN_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::new(SEA_GREEN, RED, RED);
+pub const INVERTED_PALETTES: CompatibilityPalettes =
+    CompatibilityPalettes::all([BLACK, 0x008484, 0xFFDE00, WHITE]);
+
+// Title checksum, the title's 4th letter for checksums more than one game
+// shares, and the palettes that game gets. Anything not in here gets
+// DARK_GREEN_PALETTES.
+const TITLE_PALETTES: [(u8, Option<u8>, &CompatibilityPalettes); 90] = [
+    // ALLEY WAY
+    (0x88, None, &CompatibilityPalettes::all(LILAC)),
+    // YAKUMAN
+    (0x16, None, &BROWN_PALETTES),
+    // BASEBALL
+    (
+        0x36,
+        None,
+        &CompatibilityPalettes::new(FIELD, PALE_BLUE, RED),
+    ),
+    // TENNIS
+    (
+        0xD1,
+        None,
+        &CompatibilityPalettes::new(PITCH, PALE_BLUE, BROWN),
+    ),
+    // TETRIS
+    (0xDB, None, &ORANGE_PALETTES),
+    // QIX
+    (
+        0xF2,
+        None,
+        &CompatibilityPalettes::new(FIRE, FIRE, RED_BLUE),
+    ),
+    // DR.MARIO
+    (0x3C, None, &CompatibilityPalettes::new(BLUE, BLUE, RED)),
+    // RADARMISSION
+    (
+        0x8C,
+        None,
+        &CompatibilityPalettes::new(OLIVE, ORANGE, OLIVE),
+    ),
+    // F1RACE
+    (0x92, None, &BROWN_PALETTES),
+    // YOSSY NO TAMAGO
+    (
+        0x3D,
+        None,
+        &CompatibilityPalettes::new(LIGHT_GREEN, RED, RED),
+    ),
+    // HOSHINOKA-BI
+    (
+        0x5C,
+        None,
+        &CompatibilityPalettes::new(LILAC, CRIMSON, NIGHT_SKY),
+    ),
+    // X
+    (0x58, None, &GREY_PALETTES),
+    // MARIOLAND2
+    (0xC9, None, &CompatibilityPalettes::new(SAND, ORANGE, BLUE)),
+    // YOSSY NO COOKIE
+    (
+        0x3E,
+        None,
+        &CompatibilityPalettes::new(AMBER, AMBER, RED_BLUE),
+    ),
+    // ZELDA
+    (0x70, None, &CompatibilityPalettes::new(RED, LIME, BLUE)),
+    // KIRBY'S PINBALL
+    (
+        0x1D,
+        None,
+        &CompatibilityPalettes::new(LILAC, CRIMSON, CRIMSON),
+    ),
+    // SUPERMARIOLAND3
+    (
+        0x59,
+        None,
+        &CompatibilityPalettes::new(OLIVE, ORANGE, RED_BLUE),
+    ),
+    // TETRIS FLASH
+    (
+        0x69,
+        None,
+        &CompatibilityPalettes::new(FIRE, FIRE, RED_BLUE),
+    ),
+    // DONKEY KONG
+    (0x19, None, &CompatibilityPalettes::new(AMBER, RED, RED)),
+    // MARIO'S PICROSS
+    (0x35, None, &BROWN_PALETTES),
+    // POKEMON RED
+    (0x14, None, &CompatibilityPalettes::new(RED, GREEN, RED)),
+    // POKEMON GREEN
+    (
+        0xAA,
+        None,
+        &CompatibilityPalettes::new(SEA_GREEN, RED, SEA_GREEN),
+    ),
+    // PICROSS 2
+    (0x75, None, &BROWN_PALETTES),
+    // YOSSY NO PANEPON
+    (
+        0x95,
+        None,
+        &CompatibilityPalettes::new(LIGHT_GREEN, LIGHT_GREEN, RED_BLUE),
+    ),
+    // KIRAKIRA KIDS
+    (0x99, None, &BROWN_PALETTES),
+    // GAMEBOY GALLERY
+    (0x34, None, &CompatibilityPalettes::new(MEADOW, RED, RED)),
+    // POKEMON YELLOW
+    (0x15, None, &ORANGE_PALETTES),
+    // BALLOON KID
+    (0xFF, None, &CompatibilityPalettes::all(AMBER)),
+    // STAR WARS
+    (0x97, None, &CompatibilityPalettes::new(BROWN, BLUE, GREEN)),
+    // DMG FOOTBALL
+    (0x4B, None, &CompatibilityPalettes::new(GREEN, RED, RED)),
+    // WORLD CUP
+    (0x90, None, &CompatibilityPalettes::new(GREEN, RED, RED)),
+    // OTHELLO
+    (0x17, None, &CompatibilityPalettes::new(GREEN, RED, BLUE)),
+    // SUPER RC PRO-AM
+    (0x10, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // DYNABLASTER
+    (0x39, None, &CompatibilityPalettes::new(BROWN, BLUE, BLUE)),
+    // BOY AND BLOB GB2
+    (0xF7, None, &CompatibilityPalettes::new(BROWN, BLUE, GREEN)),
+    // MEGAMAN
+    (0xF6, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // STAR WARS-NOA
+    (0xA2, None, &CompatibilityPalettes::new(BROWN, BLUE, GREEN)),
+    // KIRBY DREAM LAND
+    (
+        0x49,
+        None,
+        &CompatibilityPalettes::new(LILAC, CRIMSON, NIGHT_SKY),
+    ),
+    // WAVERACE
+    (0x4E, None, &CompatibilityPalettes::new(BEACH, LIME, BLUE)),
+    // THE CHESSMASTER
+    (0x43, None, &CompatibilityPalettes::new(BROWN, BLUE, BLUE)),
+    // LOLO2
+    (0x68, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // YOSHI'S COOKIE
+    (
+        0xE0,
+        None,
+        &CompatibilityPalettes::new(AMBER, AMBER, RED_BLUE),
+    ),
+    // MYSTIC QUEST
+    (0x8B, None, &CompatibilityPalettes::new(GREEN, RED, BLUE)),
+    // TOPRANKTENNIS
+    (
+        0xF0,
+        None,
+        &CompatibilityPalettes::new(PITCH, PALE_BLUE, BROWN),
+    ),
+    // TOPRANKINGTENNIS
+    (
+        0xCE,
+        None,
+        &CompatibilityPalettes::new(PITCH, PALE_BLUE, BROWN),
+    ),
+    // MANSELL
+    (0x0C, None, &BROWN_PALETTES),
+    // MEGAMAN3
+    (0x29, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // SPACE INVADERS
+    (0xE8, None, &INVERTED_PALETTES),
+    // GAME&WATCH
+    (0xB7, None, &BROWN_PALETTES),
+    // DONKEYKONGLAND95
+    (0x86, None, &CompatibilityPalettes::new(JUNGLE, GOLD, RED)),
+    // ASTEROIDS/MISCMD
+    (0x9A, None, &CompatibilityPalettes::new(GREEN, RED, RED)),
+    // STREET FIGHTER 2
+    (0x52, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // DEFENDER/JOUST
+    (0x01, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // KILLERINSTINCT95
+    (0x9D, None, &DARK_BLUE_PALETTES),
+    // TETRIS BLAST
+    (0x71, None, &CompatibilityPalettes::all(AMBER)),
+    // PINOCCHIO
+    (
+        0x9C,
+        None,
+        &CompatibilityPalettes::new(LAVENDER, LAVENDER, GOLD),
+    ),
+    // TOY STORY
+    (0xBD, None, &CompatibilityPalettes::new(GREEN, RED, RED)),
+    // BA.TOSHINDEN
+    (0x5D, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // NETTOU KOF 95
+    (0x6D, None, &CompatibilityPalettes::new(BROWN, GREEN, BLUE)),
+    // STAR STACKER
+    (0x67, None, &BROWN_PALETTES),
+    // TETRIS PLUS
+    (0x3F, None, &DARK_GREEN_PALETTES),
+    // DONKEYKONGLAND 3
+    (
+        0x6B,
+        None,
+        &CompatibilityPalettes::new(LAVENDER, GOLD, RED_BLUE),
+    ),
+    // KIRBY2
+    (
+        0xB3,
+        Some(b'B'),
+        &CompatibilityPalettes::new(LILAC, CRIMSON, NIGHT_SKY),
+    ),
+    // SUPER MARIOLAND
+    (
+        0x46,
+        Some(b'E'),
+        &CompatibilityPalettes::new(PERIWINKLE, DARK_RED, DARK_RED),
+    ),
+    // GOLF
+    (
+        0x28,
+        Some(b'F'),
+        &CompatibilityPalettes::new(GREEN, RED, RED),
+    ),
+    // SOLARSTRIKER
+    (0xA5, Some(b'A'), &INVERTED_PALETTES),
+    // GBWARS
+    (
+        0xC6,
+        Some(b'A'),
+        &CompatibilityPalettes::new(OLIVE, ORANGE, RED_BLUE),
+    ),
+    // KAERUNOTAMENI
+    (
+        0xD3,
+        Some(b'R'),
+        &CompatibilityPalettes::new(LAVENDER, RED, LAVENDER),
+    ),
+    // KIRBY BLOCKBALL
+    (
+        0x27,
+        Some(b'B'),
+        &CompatibilityPalettes::new(LILAC, CRIMSON, NIGHT_SKY),
+    ),
+    // POKEMON BLUE
+    (
+        0x61,
+        Some(b'E'),
+        &CompatibilityPalettes::new(BLUE, RED, BLUE),
+    ),
+    // DONKEYKONGLAND
+    (
+        0x18,
+        Some(b'K'),
+        &CompatibilityPalettes::new(LAVENDER, GOLD, RED_BLUE),
+    ),
+    // GAMEBOY GALLERY2
+    (
+        0x66,
+        Some(b'E'),
+        &CompatibilityPalettes::new(MEADOW, RED, RED),
+    ),
+    // DONKEYKONGLAND 2
+    (
+        0x6A,
+        Some(b'K'),
+        &CompatibilityPalettes::new(LAVENDER, GOLD, RED_BLUE),
+    ),
+    // KID ICARUS
+    (
+        0xBF,
+        Some(b' '),
+        &CompatibilityPalettes::new(LAVENDER, RED, RED),
+    ),
+    // TETRIS2
+    (
+        0x0D,
+        Some(b'R'),
+        &CompatibilityPalettes::new(FIRE, FIRE, RED_BLUE),
+    ),
+    // PAC-IN-TIME
+    (
+        0xF4,
+        Some(b'-'),
+        &CompatibilityPalettes::new(SEA_GREEN, RED, BLUE),
+    ),
+    // MOGURANYA
+    (
+        0xB3,
+        Some(b'U'),
+        &CompatibilityPalettes::new(OLIVE, ORANGE, ORANGE),
+    ),
+    // METROID2
+    (
+        0x46,
+        Some(b'R'),
+        &CompatibilityPalettes::new(BLUE, FLAME, GREEN),
+    ),
+    // GALAGA&GALAXIAN
+    (0x28, Some(b'A'), &INVERTED_PALETTES),
+    // KEN GRIFFEY JR
+    (0xC6, Some(b' '), &DARK_GREEN_PALETTES),
+    // WARIOLAND2
+    (
+        0xD3,
+        Some(b'I'),
+        &CompatibilityPalettes::new(OLIVE, BROWN, BLUE),
+    ),
+    // MAGNETIC SOCCER
+    (
+        0x27,
+        Some(b'N'),
+        &CompatibilityPalettes::new(GREEN, RED, BLUE),
+    ),
+    // VEGAS STAKES
+    (
+        0x61,
+        Some(b'A'),
+        &CompatibilityPalettes::new(GREEN, RED, BLUE),
+    ),
+    // WARIO BLAST
+    (0x18, Some(b'I'), &DARK_GREEN_PALETTES),
+    // MILLI/CENTI/PEDE
+    (0x66, Some(b'L'), &DARK_GREEN_PALETTES),
+    // MARIO & YOSHI
+    (
+        0x6A,
+        Some(b'I'),
+        &CompatibilityPalettes::new(LIGHT_GREEN, RED, RED),
+    ),
+    // SOCCER
+    (
+        0xBF,
+        Some(b'C'),
+        &CompatibilityPalettes::new(PITCH, PALE_BLUE, BROWN),
+    ),
+    // POKEBOM
+    (
+        0x0D,
+        Some(b'E'),
+        &CompatibilityPalettes::new(LAVENDER, GOLD, GOLD),
+    ),
+    // G&W GALLERY
+    (
+        0xF4,
+        Some(b' '),
+        &CompatibilityPalettes::new(MEADOW, RED, RED),
+    ),
+    // TETRIS ATTACK
+    (
+        0xB3,
+        Some(b'R'),
+        &CompatibilityPalettes::new(LIGHT_GREEN, LIGHT_GREEN, RED_BLUE),
+    ),
+];
+
+pub fn compatibility_palettes_for_cart(
+    cart_info: &Cartridge,
+) -> &'static CompatibilityPalettes {
+    if !cart_info.nintendo_licensed {
+        return &DARK_GREEN_PALETTES;
+    }
+
+    let fourth_letter = cart_info.title.as_bytes().get(3).copied();
+    TITLE_PALETTES
+        .iter()
+        .find(|(checksum, letter, _)| {
+            *checksum == cart_info.title_checksum
+                && (letter.is_none() || *letter == fourth_letter)
+        })
+        .map_or(&DARK_GREEN_PALETTES, |(_, _, palettes)| *palettes)
+}
+
+// Holding a direction, optionally with A or B, while the boot rom's logo is
+// up overrides whatever it would have picked
+pub fn compatibility_palettes_for_buttons(
+    joypad: &Joypad,
+) -> Option<&'static CompatibilityPalettes> {
+    let a = joypad.a_pressed;
+    let b = joypad.b_pressed;
+
+    let (plain, with_a, with_b) = if joypad.up_pressed {
+        (&BROWN_PALETTES, &RED_PALETTES, &DARK_BROWN_PALETTES)
+    } else if joypad.left_pressed {
+        (&BLUE_PALETTES, &DARK_BLUE_PALETTES, &GREY_PALETTES)
+    } else if joypad.down_pressed {
+        (&PASTEL_PALETTES, &ORANGE_PALETTES, &YELLOW_PALETTES)
+    } else if joypad.right_pressed {
+        (&GREEN_PALETTES, &DARK_GREEN_PALETTES, &INVERTED_PALETTES)
+    } else {
+        return None;
+    };
+
+    Some(match (a, b) {
+        (true, false) => with_a,
+        (false, true) => with_b,
+        _ => plain,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::vec;
+
+    fn colours(palettes: &CompatibilityPalettes) -> [[u32; 4]; 3] {
+        [palettes.bg, palettes.obj0, palettes.obj1]
+    }
+
+    fn lookup(title: &str) -> [[u32; 4]; 3] {
+        let mut header = vec![0; 0x150];
+        header[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        // Nintendo as the licensee
+        header[0x14B] = 0x01;
+        let cart_info = Cartridge::parse(&header, "".into());
+        colours(compatibility_palettes_for_cart(&cart_info))
+    }
+
+    #[test]
+    fn checksums_only_repeat_with_different_fourth_letters() {
+        for (i, (checksum, letter, _)) in TITLE_PALETTES.iter().enumerate() {
+            for (other_checksum, other_letter, _) in &TITLE_PALETTES[i + 1..] {
+                assert!(
+                    checksum != other_checksum
+                        || (letter.is_some() && letter != other_letter),
+                    "Checksum {:#04x} is ambiguous",
+                    checksum
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn games_are_found_by_title() {
+        assert_eq!(lookup("TETRIS"), colours(&ORANGE_PALETTES));
+        assert_eq!(lookup("F1RACE"), colours(&BROWN_PALETTES));
+
+        assert_eq!(lookup("POKEMON BLUE"), [BLUE, RED, BLUE]);
+        // These two share a checksum
+        assert_eq!(lookup("KAERUNOTAMENI")[0], LAVENDER);
+        assert_eq!(lookup("WARIOLAND2")[0], OLIVE);
+    }
+
+    #[test]
+    fn unknown_games_get_the_default() {
+        assert_eq!(lookup("GBRS TEST"), colours(&DARK_GREEN_PALETTES));
+        // SUPER MARIOLAND's checksum, but not its 4th letter
+        assert_eq!(lookup("SUEPR MARIOLAND"), colours(&DARK_GREEN_PALETTES));
+    }
+}
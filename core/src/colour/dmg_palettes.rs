@@ -0,0 +1,508 @@
+// Colours for DMG games. Each of the three DMG palette registers (BGP, OBP0
+// and OBP1) picks from its own set of four colours, lightest shade first.
+// Profiles remember which palette a player chose for each game.
+use super::colour::Colour;
+use super::grey_shades;
+use crate::cartridge::Cartridge;
+#[cfg(feature = "std")]
+use crate::gpu::Gpu;
+use crate::log;
+
+#[cfg(not(feature = "std"))]
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DmgPalettes {
+    pub bg: [Colour; 4],
+    pub obj0: [Colour; 4],
+    pub obj1: [Colour; 4],
+}
+
+impl DmgPalettes {
+    // The same four colours for everything
+    pub fn all(colours: [Colour; 4]) -> DmgPalettes {
+        DmgPalettes {
+            bg: colours,
+            obj0: colours,
+            obj1: colours,
+        }
+    }
+}
+
+fn rgb(hex: u32) -> Colour {
+    Colour::new((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)
+}
+
+fn ramp(hexes: [u32; 4]) -> [Colour; 4] {
+    hexes.map(rgb)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DmgPalettePreset {
+    // What gbrs has always used
+    Grey,
+    // The original DMG's pea soup screen
+    DmgGreen,
+    // The Gameboy Pocket
+    Pocket,
+    // The Gameboy Light with its backlight on
+    Light,
+    HighContrast,
+    // Shades that stay distinct with common kinds of colour blindness
+    ColourBlind,
+}
+
+impl DmgPalettePreset {
+    pub const ALL: [DmgPalettePreset; 6] = [
+        DmgPalettePreset::Grey,
+        DmgPalettePreset::DmgGreen,
+        DmgPalettePreset::Pocket,
+        DmgPalettePreset::Light,
+        DmgPalettePreset::HighContrast,
+        DmgPalettePreset::ColourBlind,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DmgPalettePreset::Grey => "grey",
+            DmgPalettePreset::DmgGreen => "dmg-green",
+            DmgPalettePreset::Pocket => "pocket",
+            DmgPalettePreset::Light => "light",
+            DmgPalettePreset::HighContrast => "high-contrast",
+            DmgPalettePreset::ColourBlind => "colour-blind",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DmgPalettePreset> {
+        DmgPalettePreset::ALL
+            .iter()
+            .find(|preset| preset.name() == name)
+            .copied()
+    }
+
+    // For frontends that cycle through presets with a hotkey
+    pub fn next(&self) -> DmgPalettePreset {
+        let index = DmgPalettePreset::ALL
+            .iter()
+            .position(|preset| preset == self)
+            .unwrap();
+        DmgPalettePreset::ALL[(index + 1) % DmgPalettePreset::ALL.len()]
+    }
+
+    pub fn palettes(&self) -> DmgPalettes {
+        match self {
+            DmgPalettePreset::Grey => DmgPalettes::all([
+                grey_shades::white(),
+                grey_shades::light_grey(),
+                grey_shades::dark_grey(),
+                grey_shades::black(),
+            ]),
+            DmgPalettePreset::DmgGreen => {
+                DmgPalettes::all(ramp([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]))
+            },
+            DmgPalettePreset::Pocket => {
+                DmgPalettes::all(ramp([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]))
+            },
+            DmgPalettePreset::Light => {
+                DmgPalettes::all(ramp([0x00B581, 0x009A71, 0x00694A, 0x004F3B]))
+            },
+            DmgPalettePreset::HighContrast => {
+                DmgPalettes::all(ramp([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]))
+            },
+            // Based on the Okabe-Ito palette. Sprites get their own hues so
+            // they don't get lost in the background.
+            DmgPalettePreset::ColourBlind => DmgPalettes {
+                bg: ramp([0xFFFFFF, 0xF0E442, 0x0072B2, 0x000000]),
+                obj0: ramp([0xFFFFFF, 0x56B4E9, 0xD55E00, 0x000000]),
+                obj1: ramp([0xFFFFFF, 0xE69F00, 0x009E73, 0x000000]),
+            },
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum ProfileKey {
+    Title(String),
+    Crc32(u32),
+}
+
+impl ProfileKey {
+    // Titles are easier to read in the profile file, but not every game has
+    // a unique one
+    pub fn for_cart(cart_info: &Cartridge) -> ProfileKey {
+        if cart_info.title.is_empty() {
+            ProfileKey::Crc32(cart_info.rom_crc32)
+        } else {
+            ProfileKey::Title(cart_info.title.clone())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ProfilePalette {
+    Preset(DmgPalettePreset),
+    Custom(DmgPalettes),
+}
+
+impl ProfilePalette {
+    pub fn palettes(&self) -> DmgPalettes {
+        match self {
+            ProfilePalette::Preset(preset) => preset.palettes(),
+            ProfilePalette::Custom(palettes) => *palettes,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProfilePalette::Preset(preset) => preset.name(),
+            ProfilePalette::Custom(_) => "custom",
+        }
+    }
+}
+
+// A game's custom colours are kept while a preset is picked, so cycling can
+// come back round to them
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Profile {
+    // None when the custom colours are in use
+    preset: Option<DmgPalettePreset>,
+    custom: Option<DmgPalettes>,
+}
+
+impl Profile {
+    fn palette(&self) -> ProfilePalette {
+        match (self.preset, self.custom) {
+            (None, Some(palettes)) => ProfilePalette::Custom(palettes),
+            (preset, _) => {
+                ProfilePalette::Preset(preset.unwrap_or(DmgPalettePreset::Grey))
+            },
+        }
+    }
+}
+
+// One line per palette, blank lines and lines starting with # are ignored:
+//   title:TETRIS = dmg-green
+//   crc32:46DF91AD = FFFFFF AAAAAA 555555 000000
+// A palette is a preset name, 4 colours used for everything, or 12 colours
+// for BG, OBP0 and OBP1 in that order. A game can have both custom colours
+// and a preset, and the later line is the one in use.
+pub struct PaletteProfiles {
+    profiles: Vec<(ProfileKey, Profile)>,
+}
+
+impl PaletteProfiles {
+    pub fn parse(text: &str) -> PaletteProfiles {
+        let mut profiles = PaletteProfiles::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_profile_line(line) {
+                Some((key, palette)) => profiles.set(key, palette),
+                None => {
+                    log!("[WARN] Invalid palette profile on line {}", i + 1)
+                },
+            }
+        }
+
+        profiles
+    }
+
+    pub fn serialise(&self) -> String {
+        let mut out = String::from("# gbrs DMG palette profiles\n");
+        for (key, profile) in &self.profiles {
+            let key = match key {
+                ProfileKey::Title(title) => format!("title:{}", title),
+                ProfileKey::Crc32(crc) => format!("crc32:{:08X}", crc),
+            };
+            // Custom colours go first so a chosen preset comes after them
+            if let Some(palettes) = profile.custom {
+                let colours = if palettes == DmgPalettes::all(palettes.bg) {
+                    palettes.bg.to_vec()
+                } else {
+                    [palettes.bg, palettes.obj0, palettes.obj1].concat()
+                };
+                let colours = colours
+                    .iter()
+                    .map(|c| {
+                        format!("{:02X}{:02X}{:02X}", c.red, c.green, c.blue)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                out.push_str(&format!("{} = {}\n", key, colours));
+            }
+            if let Some(preset) = profile.preset {
+                out.push_str(&format!("{} = {}\n", key, preset.name()));
+            }
+        }
+        out
+    }
+
+    fn profile(&self, key: &ProfileKey) -> Option<&Profile> {
+        self.profiles
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, profile)| profile)
+    }
+
+    // A profile for this exact rom wins over one for its title
+    fn find_profile(&self, cart_info: &Cartridge) -> Option<&Profile> {
+        self.profile(&ProfileKey::Crc32(cart_info.rom_crc32))
+            .or_else(|| {
+                self.profile(&ProfileKey::Title(cart_info.title.clone()))
+            })
+    }
+
+    pub fn get(&self, key: &ProfileKey) -> Option<ProfilePalette> {
+        self.profile(key).map(Profile::palette)
+    }
+
+    pub fn find(&self, cart_info: &Cartridge) -> Option<ProfilePalette> {
+        self.find_profile(cart_info).map(Profile::palette)
+    }
+
+    // Remembers the palette a player picked for this game. The rom's own
+    // profile is updated if it has one, since that wins.
+    pub fn choose(&mut self, cart_info: &Cartridge, palette: ProfilePalette) {
+        let crc_key = ProfileKey::Crc32(cart_info.rom_crc32);
+        let key = if self.profile(&crc_key).is_some() {
+            crc_key
+        } else {
+            ProfileKey::for_cart(cart_info)
+        };
+        self.set(key, palette);
+    }
+
+    // The palette after `current` when a player cycles through them: each
+    // preset in turn, then this game's custom colours if it has some
+    pub fn next_palette(
+        &self,
+        cart_info: &Cartridge,
+        current: &DmgPalettes,
+    ) -> ProfilePalette {
+        let custom = self
+            .find_profile(cart_info)
+            .and_then(|profile| profile.custom);
+        if custom == Some(*current) {
+            return ProfilePalette::Preset(DmgPalettePreset::ALL[0]);
+        }
+
+        let preset = DmgPalettePreset::ALL
+            .iter()
+            .copied()
+            .find(|preset| preset.palettes() == *current)
+            .unwrap_or(DmgPalettePreset::Grey);
+        let last = DmgPalettePreset::ALL[DmgPalettePreset::ALL.len() - 1];
+        match custom {
+            Some(palettes) if preset == last => {
+                ProfilePalette::Custom(palettes)
+            },
+            _ => ProfilePalette::Preset(preset.next()),
+        }
+    }
+
+    // Picking a preset keeps any custom colours, picking custom colours
+    // replaces the old ones
+    pub fn set(&mut self, key: ProfileKey, palette: ProfilePalette) {
+        let index = match self.profiles.iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None => {
+                let profile = Profile {
+                    preset: None,
+                    custom: None,
+                };
+                self.profiles.push((key, profile));
+                self.profiles.len() - 1
+            },
+        };
+
+        let profile = &mut self.profiles[index].1;
+        match palette {
+            ProfilePalette::Preset(preset) => profile.preset = Some(preset),
+            ProfilePalette::Custom(palettes) => {
+                profile.preset = None;
+                profile.custom = Some(palettes);
+            },
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load(path: &str) -> PaletteProfiles {
+        match std::fs::read_to_string(path) {
+            Ok(text) => PaletteProfiles::parse(&text),
+            // There's no profile file yet
+            Err(_) => PaletteProfiles::new(),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.serialise())
+    }
+
+    pub fn new() -> PaletteProfiles {
+        PaletteProfiles { profiles: vec![] }
+    }
+}
+
+impl Default for PaletteProfiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Frontends keep one profile file in each rom folder
+#[cfg(feature = "std")]
+pub fn profiles_path_for_rom(rom_path: &str) -> String {
+    let mut path = std::path::PathBuf::from(rom_path);
+    path.set_file_name("gbrs-palettes.txt");
+    path.to_string_lossy().to_string()
+}
+
+// The palette a player chose for this game last time, if any
+#[cfg(feature = "std")]
+pub fn load_palette_choice(cart_info: &Cartridge) -> Option<ProfilePalette> {
+    PaletteProfiles::load(&profiles_path_for_rom(&cart_info.rom_path))
+        .find(cart_info)
+}
+
+#[cfg(feature = "std")]
+pub fn save_palette_choice(
+    cart_info: &Cartridge,
+    palette: ProfilePalette,
+) -> std::io::Result<()> {
+    let path = profiles_path_for_rom(&cart_info.rom_path);
+    let mut profiles = PaletteProfiles::load(&path);
+    profiles.choose(cart_info, palette);
+    profiles.save(&path)
+}
+
+// For a palette hotkey. Moves the game on to the next palette and remembers
+// the choice in its profile file.
+#[cfg(feature = "std")]
+pub fn cycle_palette(gpu: &mut Gpu, cart_info: &Cartridge) -> ProfilePalette {
+    let path = profiles_path_for_rom(&cart_info.rom_path);
+    let mut profiles = PaletteProfiles::load(&path);
+    let next = profiles.next_palette(cart_info, &gpu.dmg_palettes());
+    gpu.set_dmg_palettes(next.palettes());
+
+    profiles.choose(cart_info, next);
+    if let Err(e) = profiles.save(&path) {
+        log!("[WARN] Failed to save palette choice: {}", e);
+    }
+    next
+}
+
+fn parse_profile_line(line: &str) -> Option<(ProfileKey, ProfilePalette)> {
+    let (key, palette) = line.split_once('=')?;
+
+    let key = match key.trim().split_once(':')? {
+        ("title", title) => ProfileKey::Title(title.to_string()),
+        ("crc32", crc) => ProfileKey::Crc32(u32::from_str_radix(crc, 16).ok()?),
+        _ => return None,
+    };
+
+    let palette = palette.trim();
+    if let Some(preset) = DmgPalettePreset::from_name(palette) {
+        return Some((key, ProfilePalette::Preset(preset)));
+    }
+
+    let colours = palette
+        .split_whitespace()
+        .map(|hex| u32::from_str_radix(hex.trim_start_matches('#'), 16).ok())
+        .collect::<Option<Vec<u32>>>()?;
+    let palettes = match colours.len() {
+        4 => DmgPalettes::all(ramp([
+            colours[0], colours[1], colours[2], colours[3],
+        ])),
+        12 => DmgPalettes {
+            bg: ramp([colours[0], colours[1], colours[2], colours[3]]),
+            obj0: ramp([colours[4], colours[5], colours[6], colours[7]]),
+            obj1: ramp([colours[8], colours[9], colours[10], colours[11]]),
+        },
+        _ => return None,
+    };
+
+    Some((key, ProfilePalette::Custom(palettes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::vec;
+
+    fn cart_info() -> Cartridge {
+        let mut header = vec![0; 0x150];
+        header[0x134..0x13A].copy_from_slice(b"TETRIS");
+        Cartridge::parse(&header, "".into())
+    }
+
+    fn custom() -> ProfilePalette {
+        ProfilePalette::Custom(DmgPalettes::all(ramp([
+            0xFF0000, 0xAA0000, 0x550000, 0x000000,
+        ])))
+    }
+
+    #[test]
+    fn presets_are_remembered_alongside_custom_colours() {
+        let cart_info = cart_info();
+        let mut profiles = PaletteProfiles::parse(
+            "title:TETRIS = FF0000 AA0000 550000 000000",
+        );
+        let pocket = ProfilePalette::Preset(DmgPalettePreset::Pocket);
+        profiles.choose(&cart_info, pocket);
+        assert!(profiles.find(&cart_info) == Some(pocket));
+
+        // Reloading keeps both the preset and the colours to cycle back to
+        let profiles = PaletteProfiles::parse(&profiles.serialise());
+        assert!(profiles.find(&cart_info) == Some(pocket));
+        let last = DmgPalettePreset::ALL[DmgPalettePreset::ALL.len() - 1];
+        assert!(
+            profiles.next_palette(&cart_info, &last.palettes()) == custom()
+        );
+
+        let mut profiles = profiles;
+        profiles.choose(&cart_info, custom());
+        let profiles = PaletteProfiles::parse(&profiles.serialise());
+        assert!(profiles.find(&cart_info) == Some(custom()));
+
+        let mut profiles = PaletteProfiles::new();
+        profiles.choose(
+            &cart_info,
+            ProfilePalette::Preset(DmgPalettePreset::Pocket),
+        );
+        assert!(
+            profiles.find(&cart_info)
+                == Some(ProfilePalette::Preset(DmgPalettePreset::Pocket))
+        );
+    }
+
+    #[test]
+    fn cycling_visits_every_preset_then_custom_colours() {
+        let cart_info = cart_info();
+        let mut profiles = PaletteProfiles::new();
+        profiles.choose(&cart_info, custom());
+
+        let mut current = custom().palettes();
+        let mut seen = vec![];
+        for _ in 0..=DmgPalettePreset::ALL.len() {
+            let next = profiles.next_palette(&cart_info, &current);
+            seen.push(next.name());
+            current = next.palettes();
+        }
+
+        let mut expected: Vec<&str> =
+            DmgPalettePreset::ALL.iter().map(|p| p.name()).collect();
+        expected.push("custom");
+        assert_eq!(seen, expected);
+    }
+}
@@ -0,0 +1,126 @@
+// The DMG's LCD is slow to change, so a sprite that's only drawn every other
+// frame looks see-through rather than flickering. Some games rely on this,
+// so finished frames can be blended with the ones before them.
+use super::colour::Colour;
+use crate::constants::*;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+// How much of the previous output each frame keeps in `LcdResponse` mode,
+// out of 256. Pixels keep fading towards their new colour over a few frames.
+const LCD_RESPONSE_KEEP: u16 = 112;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FrameBlending {
+    Off,
+    // Each frame is an even mix of itself and the last one
+    Mix,
+    // Each frame decays exponentially into the next, like the real LCD
+    LcdResponse,
+}
+
+impl FrameBlending {
+    pub const ALL: [FrameBlending; 3] = [
+        FrameBlending::Off,
+        FrameBlending::Mix,
+        FrameBlending::LcdResponse,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FrameBlending::Off => "off",
+            FrameBlending::Mix => "mix",
+            FrameBlending::LcdResponse => "lcd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FrameBlending> {
+        FrameBlending::ALL
+            .iter()
+            .find(|mode| mode.name() == name)
+            .copied()
+    }
+
+    // For frontends that cycle through modes with a hotkey
+    pub fn next(&self) -> FrameBlending {
+        let index = FrameBlending::ALL
+            .iter()
+            .position(|mode| mode == self)
+            .unwrap();
+        FrameBlending::ALL[(index + 1) % FrameBlending::ALL.len()]
+    }
+}
+
+fn blend_channel(previous: u8, current: u8, keep: u16) -> u8 {
+    ((previous as u16 * keep + current as u16 * (256 - keep)) / 256) as u8
+}
+
+fn blend(previous: Colour, current: Colour, keep: u16) -> Colour {
+    Colour::new(
+        blend_channel(previous.red, current.red, keep),
+        blend_channel(previous.green, current.green, keep),
+        blend_channel(previous.blue, current.blue, keep),
+    )
+}
+
+pub struct FrameBlender {
+    mode: FrameBlending,
+    // The last frame before blending for `Mix`, or the last blended output
+    // for `LcdResponse`. Empty until there's a frame to blend with.
+    previous: Vec<Colour>,
+}
+
+impl FrameBlender {
+    // Blends `frame` in place
+    pub fn blend_frame(&mut self, frame: &mut [Colour; SCREEN_BUFFER_SIZE]) {
+        if self.mode == FrameBlending::Off {
+            return;
+        }
+
+        if self.previous.is_empty() {
+            self.previous.extend_from_slice(frame);
+            return;
+        }
+
+        for (pixel, previous) in frame.iter_mut().zip(self.previous.iter_mut())
+        {
+            let current = *pixel;
+            match self.mode {
+                FrameBlending::Mix => {
+                    *pixel = blend(*previous, current, 128);
+                    *previous = current;
+                },
+                FrameBlending::LcdResponse => {
+                    *pixel = blend(*previous, current, LCD_RESPONSE_KEEP);
+                    *previous = *pixel;
+                },
+                FrameBlending::Off => unreachable!(),
+            }
+        }
+    }
+
+    // Takes effect from the next frame, without blending with anything from
+    // the old mode
+    pub fn set_mode(&mut self, mode: FrameBlending) {
+        self.mode = mode;
+        self.previous.clear();
+    }
+
+    pub fn mode(&self) -> FrameBlending {
+        self.mode
+    }
+
+    pub fn new() -> FrameBlender {
+        FrameBlender {
+            mode: FrameBlending::Off,
+            previous: Vec::new(),
+        }
+    }
+}
+
+impl Default for FrameBlender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
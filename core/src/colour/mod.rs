@@ -1,4 +1,8 @@
 pub mod bg_map_attributes;
 pub mod colour;
+pub mod colour_correction;
+pub mod compatibility_palettes;
+pub mod dmg_palettes;
+pub mod frame_blending;
 pub mod grey_shades;
 pub mod palette_ram;
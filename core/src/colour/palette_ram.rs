@@ -1,4 +1,7 @@
 use super::colour::Colour;
+use super::compatibility_palettes::*;
+use crate::joypad::Joypad;
+use crate::log;
 use crate::{combine_u8, cpu::EmulationTarget, memory::ram::Ram};
 
 fn palette_spec_read(address: u16, auto_increment: bool) -> u8 {
@@ -34,6 +37,28 @@ pub struct PaletteRam {
     obj_palette_ram: Ram,
     obj_address: u16,
     obj_auto_increment: bool,
+
+    // In CGB compatibility mode, DMG games are coloured with BG palette 0 and
+    // OBJ palettes 0 & 1, which the boot rom fills in
+    dmg_compatibility: bool,
+    boot_button_combo_chosen: bool,
+}
+
+// Palette RAM holds colours as RGB555
+fn rgb888_to_rgb555(colour: u32) -> u16 {
+    let red = (colour >> 19) & 0x1F;
+    let green = (colour >> 11) & 0x1F;
+    let blue = (colour >> 3) & 0x1F;
+    (red | (green << 5) | (blue << 10)) as u16
+}
+
+fn write_palette(ram: &mut Ram, palette_id: u16, colours: &[u32; 4]) {
+    for (i, colour) in colours.iter().enumerate() {
+        let address = palette_id * 8 + i as u16 * 2;
+        let rgb555 = rgb888_to_rgb555(*colour);
+        ram.write(address, (rgb555 & 0xFF) as u8);
+        ram.write(address + 1, (rgb555 >> 8) as u8);
+    }
 }
 
 impl PaletteRam {
@@ -61,6 +86,29 @@ impl PaletteRam {
         self.read_colour(&self.obj_palette_ram, base_offset + colour_id * 2)
     }
 
+    pub fn load_compatibility_palettes(
+        &mut self,
+        palettes: &CompatibilityPalettes,
+    ) {
+        write_palette(&mut self.bg_palette_ram, 0, &palettes.bg);
+        write_palette(&mut self.obj_palette_ram, 0, &palettes.obj0);
+        write_palette(&mut self.obj_palette_ram, 1, &palettes.obj1);
+    }
+
+    // We don't run the boot rom, so the CPU calls this for a little while
+    // after starting up to let players hold a combo like they would on a CGB
+    pub fn check_boot_button_combo(&mut self, joypad: &Joypad) {
+        if !self.dmg_compatibility || self.boot_button_combo_chosen {
+            return;
+        }
+
+        if let Some(palettes) = compatibility_palettes_for_buttons(joypad) {
+            log!("[INFO] Boot button combo held, changing palettes");
+            self.load_compatibility_palettes(palettes);
+            self.boot_button_combo_chosen = true;
+        }
+    }
+
     pub fn raw_read(&self, address: u16) -> u8 {
         if !self.cgb_features {
             return 0xFF;
@@ -129,6 +177,8 @@ impl PaletteRam {
             obj_palette_ram: Ram::new(64),
             obj_address: 0,
             obj_auto_increment: false,
+            dmg_compatibility: matches!(target, EmulationTarget::CgbDmgMode),
+            boot_button_combo_chosen: false,
         }
     }
 }
@@ -2,9 +2,44 @@
 // This helps with ports
 use crate::memory::rom::Rom;
 
+// Which console to run games on. Games that need a newer model than this
+// still run on the one they need.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model {
+    Dmg,
+    Sgb,
+    // DMG games run in the GameBoy Color's back-compat mode, which colours
+    // them with the boot rom's compatibility palettes
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    pub const ALL: [Model; 4] =
+        [Model::Dmg, Model::Sgb, Model::Cgb, Model::Agb];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::Dmg => "dmg",
+            Model::Sgb => "sgb",
+            Model::Cgb => "cgb",
+            Model::Agb => "agb",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Model> {
+        Model::ALL
+            .iter()
+            .find(|model| model.name() == name)
+            .copied()
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub sound_buffer_size: usize,
     pub sound_sample_rate: usize,
     pub rom: Rom,
+    // None picks a model from the cartridge header
+    pub model: Option<Model>,
 }
@@ -1,3 +1,6 @@
+// For reports and metadata that should say which gbrs made them
+pub const GBRS_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 // "WRAM" is Work RAM, not Wave RAM
 pub const WRAM_BANK_SIZE: usize = 4096;
 pub const VRAM_BANK_SIZE: usize = 8192;
@@ -13,6 +16,11 @@ pub const SCREEN_HEIGHT: usize = 144;
 pub const SCREEN_BUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
 pub const SCREEN_RGBA_SLICE_SIZE: usize = SCREEN_BUFFER_SIZE * 4;
 
+// The Super GameBoy's output, with the border around the Gameboy's screen
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+pub const SGB_SCREEN_BUFFER_SIZE: usize = SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT;
+
 pub const CLOCK_SPEED: usize = 4194304;
 pub const DEFAULT_FRAME_RATE: usize = 60;
 
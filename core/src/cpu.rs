@@ -1,26 +1,52 @@
+use crate::bus::Bus;
 use crate::cartridge::{CGBSupportType, Cartridge};
-use crate::config::Config;
+use crate::config::{Config, Model};
 use crate::constants::*;
+use crate::debugger::{Debugger, StepTarget, StopReason, WatchKind};
 use crate::gpu::Gpu;
+use crate::homebrew_debug::{
+    HomebrewDebug, MessageContext, DEBUG_MESSAGE_OPCODE,
+    SOFTWARE_BREAKPOINT_OPCODE,
+};
 use crate::interrupts::*;
 use crate::log;
 use crate::memory::memory::Memory;
 use crate::registers::Registers;
-use crate::{bitmatch, combine_u8, compute_equal, compute_mask, set_bit};
-
-// This array is allowed to be empty
-// skipcq: RS-W1096
-const BREAKPOINTS: [u16; 0] = [];
-const CPU_DEBUG: bool = false;
+use crate::scaling::Scaler;
+use crate::screenshot::encode_png;
+#[cfg(feature = "std")]
+use crate::screenshot::save_png_next_to_rom;
+use crate::serial_cable::SerialDevice;
+use crate::symbols::SymbolTable;
+use crate::test_bus::TestBus;
+use crate::trace::{TraceEntry, TraceFormat, TraceWriter, Tracer};
+use crate::{
+    bitmatch, combine_u8, compute_equal, compute_mask, set_bit, split_u16,
+};
+
+#[cfg(not(feature = "std"))]
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+// How long after starting up a held button combo can still pick the
+// compatibility palette for DMG games
+const BOOT_BUTTON_COMBO_WINDOW_MS: usize = 1000;
 
 const COND_NZ: u8 = 0b00;
 const COND_Z: u8 = 0b01;
 const COND_NC: u8 = 0b10;
 const COND_C: u8 = 0b11;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum EmulationTarget {
     // Original GameBoy
     Dmg,
+    // Super GameBoy, a DMG in a SNES cartridge
+    Sgb,
     // GameBoy Color in DMG back-compat mode
     CgbDmgMode,
     // GameBoy Color in full colour mode
@@ -33,33 +59,77 @@ impl EmulationTarget {
     pub fn has_cgb_features(&self) -> bool {
         match self {
             EmulationTarget::Dmg => false,
+            EmulationTarget::Sgb => false,
             EmulationTarget::CgbDmgMode => false,
             EmulationTarget::CgbCgbMode => true,
             EmulationTarget::GbaCgbMode => true,
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmulationTarget::Dmg => "DMG",
+            EmulationTarget::Sgb => "SGB",
+            EmulationTarget::CgbDmgMode => "CGB (DMG mode)",
+            EmulationTarget::CgbCgbMode => "CGB",
+            EmulationTarget::GbaCgbMode => "AGB (CGB mode)",
+        }
+    }
 }
 
 // When a game supports DMG, CGB back-compat, and full colour, what should we
 // run it as?
 const TARGET_FOR_CGB_OPTIONAL_GAMES: EmulationTarget =
     EmulationTarget::CgbCgbMode;
-
-fn emulation_target_for_cart_info(cart_info: &Cartridge) -> EmulationTarget {
-    match cart_info.cgb_support {
-        CGBSupportType::None => EmulationTarget::Dmg,
-        CGBSupportType::Optional => TARGET_FOR_CGB_OPTIONAL_GAMES,
-        CGBSupportType::Required => EmulationTarget::CgbCgbMode,
+// What should we run DMG-only games as? CgbDmgMode shows them in colour, like
+// a GameBoy Color would. Config::model can pick that instead.
+const TARGET_FOR_DMG_GAMES: EmulationTarget = EmulationTarget::Dmg;
+// What should we run DMG-only games that have Super GameBoy enhancements as?
+const TARGET_FOR_SGB_GAMES: EmulationTarget = EmulationTarget::Sgb;
+
+fn emulation_target_for_cart_info(
+    cart_info: &Cartridge,
+    model: Option<Model>,
+) -> EmulationTarget {
+    let cgb_game = !matches!(cart_info.cgb_support, CGBSupportType::None);
+    match model {
+        None => match cart_info.cgb_support {
+            CGBSupportType::None if cart_info.sgb_support => {
+                TARGET_FOR_SGB_GAMES
+            },
+            CGBSupportType::None => TARGET_FOR_DMG_GAMES,
+            CGBSupportType::Optional => TARGET_FOR_CGB_OPTIONAL_GAMES,
+            CGBSupportType::Required => EmulationTarget::CgbCgbMode,
+        },
+        Some(Model::Cgb) if cgb_game => EmulationTarget::CgbCgbMode,
+        Some(Model::Agb) if cgb_game => EmulationTarget::GbaCgbMode,
+        Some(Model::Cgb | Model::Agb) => EmulationTarget::CgbDmgMode,
+        Some(model)
+            if matches!(cart_info.cgb_support, CGBSupportType::Required) =>
+        {
+            log!(
+                "[WARN] This game needs a GameBoy Color, so it can't run as {}",
+                model.name()
+            );
+            EmulationTarget::CgbCgbMode
+        },
+        Some(Model::Dmg) => EmulationTarget::Dmg,
+        Some(Model::Sgb) => EmulationTarget::Sgb,
     }
 }
 
-pub struct Cpu {
+// Generic over its memory bus so the CPU can be tested on its own, see
+// `with_test_bus`. Everything else uses the real hardware.
+pub struct Cpu<M: Bus = Memory> {
     pub cart_info: Cartridge,
-    pub mem: Memory,
+    // The model being emulated, picked from the cartridge header unless the
+    // config asks for one
+    pub emulation_target: EmulationTarget,
+    pub mem: M,
 
     pub regs: Registers,
 
-    pub gpu: Gpu,
+    pub gpu: M::Gpu,
     pub frame_rate: usize,
 
     pub ints: Interrupts,
@@ -73,12 +143,34 @@ pub struct Cpu {
     clock_counter: usize,
 
     halted: bool,
+
+    pub debugger: Debugger,
+    // Logs every instruction while set
+    pub tracer: Option<Tracer>,
+    // `ld b,b` breakpoints and `ld d,d` debug messages
+    pub homebrew: HomebrewDebug,
 }
 
-impl Cpu {
+impl<M: Bus> Cpu<M> {
+    // Every memory access the CPU makes ends up here
+    #[inline(always)]
+    fn bus_read(&mut self, address: u16) -> u8 {
+        self.mem.read(&self.ints, &self.gpu, address)
+    }
+    #[inline(always)]
+    fn bus_write(&mut self, address: u16, value: u8) {
+        self.mem
+            .write(&mut self.ints, &mut self.gpu, address, value)
+    }
+    // Looks at memory without it counting as an access, for debugging
+    fn peek(&self, address: u16) -> u8 {
+        self.mem.peek(&self.ints, &self.gpu, address)
+    }
+
     #[inline(always)]
     fn read_next(&mut self) -> u8 {
-        let byte = self.mem_read(self.regs.pc);
+        // Fetches skip `mem_read` so they don't trip read watchpoints
+        let byte = self.bus_read(self.regs.pc);
         // log!("Read address {:#x}, value: {:#x}", self.regs.pc, byte);
         self.regs.pc += 1;
         byte
@@ -92,36 +184,58 @@ impl Cpu {
 
     #[inline(always)]
     fn mem_write(&mut self, address: u16, value: u8) {
-        self.mem
-            .write(&mut self.ints, &mut self.gpu, address, value)
+        if self.debugger.active {
+            self.debugger.check_access(WatchKind::Write, address, value);
+        }
+        self.bus_write(address, value)
     }
     #[inline(always)]
     fn mem_read(&mut self, address: u16) -> u8 {
-        self.mem.read(&self.ints, &self.gpu, address)
+        let value = self.bus_read(address);
+        if self.debugger.active {
+            self.debugger.check_access(WatchKind::Read, address, value);
+        }
+        value
     }
     #[inline(always)]
     fn mem_write_16(&mut self, address: u16, value: u16) {
-        self.mem
-            .write_16(&mut self.ints, &mut self.gpu, address, value)
+        let (low, high) = split_u16!(value);
+        if self.debugger.active {
+            self.debugger.check_access(WatchKind::Write, address, low);
+            self.debugger
+                .check_access(WatchKind::Write, address + 1, high);
+        }
+        self.bus_write(address, low);
+        self.bus_write(address + 1, high);
     }
     #[inline(always)]
     fn mem_read_16(&mut self, address: u16) -> u16 {
-        self.mem.read_16(&self.ints, &self.gpu, address)
+        let low = self.bus_read(address);
+        let high = self.bus_read(address + 1);
+        let value = combine_u8!(high, low);
+        if self.debugger.active {
+            let (low, high) = split_u16!(value);
+            self.debugger.check_access(WatchKind::Read, address, low);
+            self.debugger
+                .check_access(WatchKind::Read, address + 1, high);
+        }
+        value
     }
     #[inline(always)]
     fn set_singular_register(&mut self, register: u8, value: u8) {
-        self.regs.set_singular_register(
-            register,
-            value,
-            &mut self.mem,
-            &mut self.ints,
-            &mut self.gpu,
-        )
+        // Register 0b110 is (HL) in memory
+        if register == 0b110 {
+            self.mem_write(self.regs.get_hl(), value);
+            return;
+        }
+        self.regs.set_singular_register(register, value)
     }
     #[inline(always)]
     fn get_singular_register(&mut self, register: u8) -> u8 {
-        self.regs
-            .get_singular_register(register, &self.mem, &self.ints, &self.gpu)
+        if register == 0b110 {
+            return self.mem_read(self.regs.get_hl());
+        }
+        self.regs.get_singular_register(register)
     }
 
     #[inline(always)]
@@ -185,9 +299,12 @@ impl Cpu {
             }
         }
     }
+}
 
+impl Cpu {
     // Runs enough steps to be ready to render one frame
-    // (GUI implementations should get the frame from gpu.finished_frame)
+    // (GUI implementations should get the frame from gpu.finished_frame, or
+    //   from a VideoSink)
     pub fn step_one_frame(&mut self) -> usize {
         let mut cycles_per_frame = CLOCK_SPEED / self.frame_rate;
         if self.mem.speed_switch.current_speed_is_double {
@@ -203,18 +320,13 @@ impl Cpu {
     }
 
     // Runs the CPU until the APU has filled its buffer defined by the
-    // SOUND_BUFFER_SIZE constant
+    // SOUND_BUFFER_SIZE constant. The buffer is just the APU's built-in
+    // AudioSink, ports with their own sink can step however they like.
     pub fn step_until_full_audio_buffer(&mut self) -> usize {
         let mut cycles = 0;
-
-        loop {
+        while !self.mem.apu.buffer.take_full() {
             cycles += self.step();
-            if self.mem.apu.buffer_full {
-                self.mem.apu.buffer_full = false;
-                break;
-            }
         }
-
         cycles
     }
 
@@ -223,6 +335,13 @@ impl Cpu {
         if self.mem.speed_switch.current_speed_is_double {
             cycles += self.single_speed_step();
         }
+        self.step_hardware(cycles);
+
+        cycles
+    }
+
+    // Catches the GPU and APU up with `cycles` of CPU time
+    fn step_hardware(&mut self, cycles: usize) {
         let half_speed_cycles = if self.mem.speed_switch.current_speed_is_double
         {
             cycles / 2
@@ -238,14 +357,175 @@ impl Cpu {
             #[cfg(feature = "sound")]
             self.mem.apu.step();
         }
+    }
+
+    // Runs until the debugger stops execution, or `max_cycles` have passed.
+    // Unlike `step`, this goes one instruction at a time even in double
+    // speed mode. Breakpoints at the current PC are skipped so execution can
+    // carry on from one that was just hit.
+    pub fn run_until_stop(&mut self, max_cycles: usize) -> (usize, StopReason) {
+        self.debugger.active = true;
+        self.debugger.pending_stop = None;
+
+        let mut cycles = 0;
+        let mut first_instruction = true;
+        let reason = loop {
+            let pc = self.regs.pc;
+            let opcode = self.peek(pc);
+            if !self.halted && !first_instruction {
+                let bank = self.mem.bank_at(pc);
+                if self.debugger.breakpoint_hit(pc, bank, &self.regs) {
+                    break StopReason::Breakpoint { address: pc, bank };
+                }
+                if self.homebrew.breakpoints
+                    && opcode == SOFTWARE_BREAKPOINT_OPCODE
+                {
+                    break StopReason::SoftwareBreakpoint { address: pc };
+                }
+                self.debugger.check_access(WatchKind::Execute, pc, opcode);
+                if let Some(reason) = self.debugger.pending_stop.take() {
+                    break reason;
+                }
+            }
+            // Checked after breakpoints so running in chunks can't skip one
+            // at the start of the next chunk
+            if cycles >= max_cycles {
+                break StopReason::CycleLimit;
+            }
+            first_instruction = false;
+
+            let was_halted = self.halted;
+            let ly_before = self.gpu.ly();
+
+            let instruction_cycles = self.single_speed_step();
+            self.step_hardware(instruction_cycles);
+            cycles += instruction_cycles;
+
+            if let Some(reason) = self.debugger.pending_stop.take() {
+                break reason;
+            }
+
+            match self.debugger.step_target {
+                StepTarget::None => {},
+                StepTarget::Instruction => break StopReason::StepComplete,
+                StepTarget::Return { sp } => {
+                    let returned = !was_halted
+                        && matches!(
+                            opcode,
+                            0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8
+                        );
+                    if returned && self.regs.sp > sp {
+                        break StopReason::StepComplete;
+                    }
+                },
+                StepTarget::Address { pc, sp } => {
+                    if self.regs.pc == pc && self.regs.sp >= sp {
+                        break StopReason::StepComplete;
+                    }
+                },
+                StepTarget::Scanline(line) => {
+                    if ly_before != line && self.gpu.ly() == line {
+                        break StopReason::ScanlineReached(line);
+                    }
+                },
+            }
+        };
+
+        self.debugger.active = false;
+        self.debugger.step_target = StepTarget::None;
+        (cycles, reason)
+    }
+
+    // Runs one instruction, following calls and interrupts into their
+    // handlers. While HALTed, this only waits 4 cycles.
+    pub fn step_into(&mut self) -> (usize, StopReason) {
+        self.debugger.step_target = StepTarget::Instruction;
+        self.run_until_stop(usize::MAX)
+    }
+
+    // Like `step_into`, but CALL and RST run until they return
+    pub fn step_over(&mut self, max_cycles: usize) -> (usize, StopReason) {
+        let pc = self.regs.pc;
+        let opcode = self.peek(pc);
+        let call_length = match opcode {
+            // CALL N, CALL F, N
+            0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => Some(3),
+            // RST N
+            op if op & 0b11_000_111 == 0b11_000_111 => Some(1),
+            _ => None,
+        };
+
+        self.debugger.step_target = match call_length {
+            Some(length) if !self.halted => StepTarget::Address {
+                pc: pc.wrapping_add(length),
+                sp: self.regs.sp,
+            },
+            _ => StepTarget::Instruction,
+        };
+        self.run_until_stop(max_cycles)
+    }
+
+    // Runs until the current function returns to its caller
+    pub fn step_out(&mut self, max_cycles: usize) -> (usize, StopReason) {
+        self.debugger.step_target = StepTarget::Return { sp: self.regs.sp };
+        self.run_until_stop(max_cycles)
+    }
+
+    // Runs until the GPU starts drawing `line` (0-153). If it's already on
+    // that line, this waits until it comes round again.
+    pub fn run_to_scanline(
+        &mut self,
+        line: u8,
+        max_cycles: usize,
+    ) -> (usize, StopReason) {
+        self.debugger.step_target = StepTarget::Scanline(line);
+        self.run_until_stop(max_cycles)
+    }
+
+    // Runs one instruction, then the timers and interrupts it affects
+    pub fn single_speed_step(&mut self) -> usize {
+        let cycles = self.execute();
+
+        self.mem.step(cycles, &mut self.ints, self.ms_since_boot);
+
+        self.process_interrupts();
+
+        self.clock_counter += cycles;
+        if self.clock_counter >= CLOCK_SPEED / 1000 {
+            self.ms_since_boot += 1;
+            self.clock_counter = 0;
+
+            if self.ms_since_boot < BOOT_BUTTON_COMBO_WINDOW_MS {
+                self.mem
+                    .palette_ram
+                    .check_boot_button_combo(&self.mem.joypad);
+            }
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.cycles += cycles as u64;
+        }
+        self.homebrew.cycles += cycles as u64;
 
         cycles
     }
 
+    // Traces to `writer` in Gameboy Doctor's format, with LY stubbed the way
+    // its reference logs expect
+    pub fn start_doctor_trace(&mut self, writer: Box<dyn TraceWriter>) {
+        let mut tracer = Tracer::new(TraceFormat::GameboyDoctor);
+        tracer.set_writer(writer);
+        self.tracer = Some(tracer);
+        self.gpu.stub_ly = true;
+    }
+}
+
+impl<M: Bus> Cpu<M> {
+    // Runs just the instruction, and nothing else in the Gameboy
     // Function complexity warning here is due to the massive switch statement.
     // Such a thing is expected in an emulator.
     // skipcq: RS-R1000
-    pub fn single_speed_step(&mut self) -> usize {
+    fn execute(&mut self) -> usize {
         let p = self.ime_on_pending;
 
         let cycles: usize;
@@ -253,22 +533,11 @@ impl Cpu {
         if self.halted {
             cycles = 4;
         } else {
-            let op = self.read_next();
-
-            if CPU_DEBUG {
-                log!(
-                    "PC: {:#06x} | OPCODE: {:#04x} | {}",
-                    self.regs.pc - 1,
-                    op,
-                    self.regs.debug_dump()
-                );
+            if self.tracer.is_some() {
+                self.trace_instruction();
             }
 
-            for b in BREAKPOINTS.iter() {
-                if self.regs.pc - 1 == *b {
-                    panic!("BREAK");
-                }
-            }
+            let op = self.read_next();
 
             let v_r = (op & 0b00_11_0000) >> 4;
             let v_d = (op & 0b00_111_000) >> 3;
@@ -370,16 +639,7 @@ impl Cpu {
                 },
 
                 // STOP
-                0b00010000 => {
-                    if self.mem.speed_switch.armed {
-                        self.mem.speed_switch.execute_speed_switch();
-                        // CPU halts for a really long time during speed switch
-                        SPEED_SWITCH_HALT_CYCLES
-                    } else {
-                        log!("[WARN] STOP with un-armed CGB Speed Switch. Not used in commercial games.");
-                        4
-                    }
-                },
+                0b00010000 => self.mem.stop(),
 
                 // JR N
                 0b00011000 => {
@@ -500,6 +760,19 @@ impl Cpu {
                         self.halted = true;
                         return 4;
                     }
+                    if op == DEBUG_MESSAGE_OPCODE && self.homebrew.messages {
+                        self.log_debug_message();
+                    }
+                    // The debugger stops before these run instead
+                    if op == SOFTWARE_BREAKPOINT_OPCODE
+                        && self.homebrew.breakpoints
+                        && !self.debugger.active
+                    {
+                        log!(
+                            "[DEBUG] ld b,b breakpoint at {:#06x}",
+                            self.regs.pc - 1
+                        );
+                    }
 
                     if v_d_alt_is_hl {
                         8
@@ -753,17 +1026,58 @@ impl Cpu {
             self.ime_on_pending = false;
         }
 
-        self.mem.step(cycles, &mut self.ints, self.ms_since_boot);
-
-        self.process_interrupts();
+        cycles
+    }
 
-        self.clock_counter += cycles;
-        if self.clock_counter >= CLOCK_SPEED / 1000 {
-            self.ms_since_boot += 1;
-            self.clock_counter = 0;
+    fn trace_instruction(&mut self) {
+        let pc = self.regs.pc;
+        let bank = self.mem.bank_at(pc);
+        let wanted = self
+            .tracer
+            .as_ref()
+            .is_some_and(|tracer| tracer.wants(pc, bank));
+        if !wanted {
+            return;
         }
 
-        return cycles;
+        let pcmem =
+            [0, 1, 2, 3].map(|offset| self.peek(pc.wrapping_add(offset)));
+        let tracer = self.tracer.as_mut().unwrap();
+        let entry = TraceEntry::new(
+            &self.regs,
+            pcmem,
+            tracer.cycles,
+            self.mem.ly(&self.gpu),
+            bank,
+            self.ints.ime,
+        );
+        tracer.record(entry, &self.debugger.symbols);
+    }
+
+    // Called just after an `ld d,d`, with PC on the message block
+    fn log_debug_message(&mut self) {
+        let pc = self.regs.pc;
+        let header =
+            [0, 1, 2, 3, 4, 5].map(|offset| self.peek(pc.wrapping_add(offset)));
+        let Some(length) = HomebrewDebug::message_length(header) else {
+            return;
+        };
+
+        let text: Vec<u8> = (0..length)
+            .map(|offset| {
+                let address = pc.wrapping_add(6).wrapping_add(offset);
+                self.peek(address)
+            })
+            .collect();
+        let context = MessageContext {
+            regs: &self.regs,
+            ime: self.ints.ime,
+            ly: self.mem.ly(&self.gpu),
+            rom_bank: self.mem.bank_at(0x4000),
+            double_speed: self.mem.double_speed(),
+        };
+        let message = self.homebrew.format_message(&text, &context);
+        log!("[DEBUG] {}", message);
     }
 
     fn execute_cb(&mut self, op: u8) -> usize {
@@ -848,22 +1162,123 @@ impl Cpu {
             _ => panic!("Unsupported CB_op {:08b} ({:#04x})", op, op),
         }
     }
+}
+
+impl Cpu {
+    // Plugs a peripheral or link partner into the serial port, returning
+    // whatever was connected before
+    pub fn connect_serial_device(
+        &mut self,
+        device: Box<dyn SerialDevice>,
+    ) -> Box<dyn SerialDevice> {
+        self.mem.serial_cable.connect(device)
+    }
+
+    pub fn disconnect_serial_device(&mut self) -> Box<dyn SerialDevice> {
+        self.mem.serial_cable.disconnect()
+    }
+
+    // The last finished frame as a PNG, upscaled by `scaler`. Super GameBoy
+    // games include their border when `sgb_border` is set. The ROM title,
+    // frame number and model are saved in the file too.
+    pub fn screenshot_png(&self, scaler: Scaler, sgb_border: bool) -> Vec<u8> {
+        let (frame, width, height) = match &self.mem.sgb {
+            Some(sgb) if sgb_border => {
+                (&sgb.frame[..], SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+            },
+            _ => (&self.gpu.finished_frame[..], SCREEN_WIDTH, SCREEN_HEIGHT),
+        };
+        let rgba = scaler.upscale_to_vec(frame, width, height);
+        let (width, height) = scaler.output_size(width, height);
+
+        let frame_number = self.gpu.frame_count.to_string();
+        let software = format!("gbrs {}", GBRS_VERSION);
+        encode_png(
+            &rgba,
+            width,
+            height,
+            &[
+                ("Title", &self.cart_info.title),
+                ("Software", &software),
+                ("Frame", &frame_number),
+                ("Model", self.emulation_target.name()),
+            ],
+        )
+    }
+
+    // Saves `screenshot_png` next to the ROM as ROM_NAME-screenshot-N.png,
+    // keeping the border for Super GameBoy games
+    #[cfg(feature = "std")]
+    pub fn save_screenshot(
+        &self,
+        scaler: Scaler,
+    ) -> std::io::Result<std::path::PathBuf> {
+        save_png_next_to_rom(
+            &self.cart_info.rom_path,
+            "screenshot",
+            &self.screenshot_png(scaler, true),
+        )
+    }
 
     pub fn from_config(config: Config) -> Cpu {
         let cart_info =
             Cartridge::parse(&config.rom.bytes, config.rom.path.clone());
-        let emulation_target = emulation_target_for_cart_info(&cart_info);
+        let emulation_target =
+            emulation_target_for_cart_info(&cart_info, config.model);
+
+        let mut cpu = Cpu::from_parts(
+            Memory::from_info(cart_info.clone(), config.rom, &emulation_target),
+            Gpu::new(&emulation_target),
+            cart_info,
+            emulation_target,
+        );
+        cpu.debugger.symbols =
+            SymbolTable::load_for_rom(&cpu.cart_info.rom_path);
+        cpu
+    }
+}
+
+impl Cpu<TestBus> {
+    // A CPU whose memory is nothing but a `TestBus`, for testing
+    // instructions without the rest of the Gameboy. The registers start as
+    // they would after the boot ROM.
+    pub fn with_test_bus() -> Cpu<TestBus> {
+        let cart_info = Cartridge {
+            title: String::new(),
+            rom_path: String::new(),
+            cart_type: 0,
+            rom_size: 0,
+            ram_size: 0,
+            cgb_support: CGBSupportType::None,
+            sgb_support: false,
+            title_checksum: 0,
+            nintendo_licensed: false,
+            rom_crc32: 0,
+        };
+        Cpu::from_parts(TestBus::new(), (), cart_info, EmulationTarget::Dmg)
+    }
+
+    // Runs one instruction. There's no other hardware to catch up, and
+    // interrupts only happen when a test requests them.
+    pub fn single_speed_step(&mut self) -> usize {
+        self.execute()
+    }
+}
 
+impl<M: Bus> Cpu<M> {
+    fn from_parts(
+        mem: M,
+        gpu: M::Gpu,
+        cart_info: Cartridge,
+        emulation_target: EmulationTarget,
+    ) -> Cpu<M> {
         Cpu {
-            mem: Memory::from_info(
-                cart_info.clone(),
-                config.rom,
-                &emulation_target,
-            ),
+            mem,
             cart_info,
+            emulation_target,
             regs: Registers::new(&emulation_target),
 
-            gpu: Gpu::new(emulation_target.has_cgb_features()),
+            gpu,
             frame_rate: DEFAULT_FRAME_RATE,
 
             ints: Interrupts::new(),
@@ -873,6 +1288,261 @@ impl Cpu {
             clock_counter: 0,
 
             halted: false,
+
+            debugger: Debugger::new(),
+            tracer: None,
+            homebrew: HomebrewDebug::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::{
+        Breakpoint, Comparison, Register, RegisterCondition, Watchpoint,
+    };
+    use crate::memory::rom::Rom;
+
+    #[cfg(not(feature = "std"))]
+    use alloc::vec;
+
+    const MAX_CYCLES: usize = 1_000_000;
+
+    // A 64KB MBC1 ROM. It calls into banks 1 and 2, which each load their
+    // number into B and return, then writes and reads $C000 and ends with
+    // an RST to a RET.
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        // ret
+        rom[0x0000] = 0xC9;
+        // nop, jp $0150
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0169].copy_from_slice(&[
+            0x3E, 0x01, // ld a, 1
+            0xEA, 0x00, 0x20, // ld [$2000], a
+            0xCD, 0x00, 0x40, // call $4000
+            0x3E, 0x02, // ld a, 2
+            0xEA, 0x00, 0x20, // ld [$2000], a
+            0xCD, 0x00, 0x40, // call $4000
+            0x21, 0x00, 0xC0, // ld hl, $C000
+            0x36, 0x55, // ld [hl], $55
+            0x7E, // ld a, [hl]
+            0xC7, // rst $00
+            0x18, 0xFE, // jr @
+        ]);
+        for bank in 1..4 {
+            let start = bank * 0x4000;
+            // ld b, BANK; ret
+            rom[start..start + 3].copy_from_slice(&[0x06, bank as u8, 0xC9]);
+        }
+        rom
+    }
+
+    fn cpu_for(rom: Vec<u8>) -> Cpu {
+        Cpu::from_config(Config {
+            sound_buffer_size: SOUND_BUFFER_SIZE,
+            sound_sample_rate: SOUND_SAMPLE_RATE,
+            rom: Rom::from_bytes(rom),
+            model: None,
+        })
+    }
+
+    fn test_cpu() -> Cpu {
+        cpu_for(test_rom())
+    }
+
+    // Runs to `address` using a breakpoint that's removed again
+    fn run_to(cpu: &mut Cpu, address: u16) {
+        let breakpoint = Breakpoint::new(address);
+        cpu.debugger.add_breakpoint(breakpoint);
+        cpu.run_until_stop(MAX_CYCLES);
+        cpu.debugger.remove_breakpoint(&breakpoint);
+        assert_eq!(cpu.regs.pc, address);
+    }
+
+    #[test]
+    fn breakpoints_only_stop_in_their_bank() {
+        let mut cpu = test_cpu();
+        cpu.debugger.add_breakpoint(Breakpoint {
+            address: 0x4000,
+            bank: Some(2),
+            condition: None,
+        });
+
+        let (_, reason) = cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!(
+            reason,
+            StopReason::Breakpoint {
+                address: 0x4000,
+                bank: 2
+            }
+        );
+        assert_eq!(cpu.regs.a, 2);
+    }
+
+    #[test]
+    fn breakpoints_only_stop_when_their_condition_is_met() {
+        let mut cpu = test_cpu();
+        cpu.debugger.add_breakpoint(Breakpoint {
+            address: 0x4000,
+            bank: None,
+            condition: Some(RegisterCondition {
+                register: Register::A,
+                comparison: Comparison::Greater,
+                value: 1,
+            }),
+        });
+        cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!((cpu.regs.pc, cpu.regs.a), (0x4000, 2));
+
+        let mut cpu = test_cpu();
+        cpu.debugger.add_breakpoint(Breakpoint {
+            address: 0x4000,
+            bank: None,
+            condition: Some(RegisterCondition {
+                register: Register::HL,
+                comparison: Comparison::Equal,
+                value: 0xC000,
+            }),
+        });
+        let (cycles, reason) = cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!(reason, StopReason::CycleLimit);
+        assert!(cycles >= MAX_CYCLES);
+    }
+
+    #[test]
+    fn breakpoint_at_the_first_instruction_is_skipped() {
+        let mut cpu = test_cpu();
+        cpu.debugger.add_breakpoint(Breakpoint::new(0x0100));
+        cpu.debugger.add_breakpoint(Breakpoint::new(0x4000));
+
+        cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!((cpu.regs.pc, cpu.regs.a), (0x4000, 1));
+        // Carrying on from a breakpoint doesn't hit it again straight away
+        cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!((cpu.regs.pc, cpu.regs.a), (0x4000, 2));
+    }
+
+    #[test]
+    fn watchpoints_stop_on_reads_writes_and_execution() {
+        let mut cpu = test_cpu();
+        for kind in [WatchKind::Read, WatchKind::Write] {
+            cpu.debugger.add_watchpoint(Watchpoint {
+                start: 0xC000,
+                end: 0xC0FF,
+                kind,
+            });
         }
+        cpu.debugger.add_watchpoint(Watchpoint {
+            start: 0x0000,
+            end: 0x0007,
+            kind: WatchKind::Execute,
+        });
+
+        // Reads and writes stop after their instruction
+        let (_, reason) = cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!(
+            reason,
+            StopReason::Watchpoint {
+                kind: WatchKind::Write,
+                address: 0xC000,
+                value: 0x55
+            }
+        );
+        assert_eq!(cpu.regs.pc, 0x0165);
+
+        let (_, reason) = cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!(
+            reason,
+            StopReason::Watchpoint {
+                kind: WatchKind::Read,
+                address: 0xC000,
+                value: 0x55
+            }
+        );
+        assert_eq!(cpu.regs.pc, 0x0166);
+
+        // Execution stops before the instruction
+        let (_, reason) = cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!(
+            reason,
+            StopReason::Watchpoint {
+                kind: WatchKind::Execute,
+                address: 0x0000,
+                value: 0xC9
+            }
+        );
+        assert_eq!(cpu.regs.pc, 0x0000);
+    }
+
+    #[test]
+    fn step_over_runs_calls_and_rsts_until_they_return() {
+        let mut cpu = test_cpu();
+        run_to(&mut cpu, 0x0155);
+        let (_, reason) = cpu.step_over(MAX_CYCLES);
+        assert_eq!(reason, StopReason::StepComplete);
+        assert_eq!((cpu.regs.pc, cpu.regs.b), (0x0158, 1));
+
+        run_to(&mut cpu, 0x0166);
+        let (_, reason) = cpu.step_over(MAX_CYCLES);
+        assert_eq!(reason, StopReason::StepComplete);
+        assert_eq!(cpu.regs.pc, 0x0167);
+
+        // Anything else is a single step
+        let mut cpu = test_cpu();
+        run_to(&mut cpu, 0x0150);
+        cpu.step_over(MAX_CYCLES);
+        assert_eq!(cpu.regs.pc, 0x0152);
+    }
+
+    #[test]
+    fn step_into_follows_calls() {
+        let mut cpu = test_cpu();
+        run_to(&mut cpu, 0x0155);
+        let (cycles, reason) = cpu.step_into();
+        assert_eq!(reason, StopReason::StepComplete);
+        assert_eq!((cpu.regs.pc, cycles), (0x4000, 24));
+    }
+
+    #[test]
+    fn step_out_runs_until_the_function_returns() {
+        let mut cpu = test_cpu();
+        run_to(&mut cpu, 0x4000);
+        let (_, reason) = cpu.step_out(MAX_CYCLES);
+        assert_eq!(reason, StopReason::StepComplete);
+        assert_eq!((cpu.regs.pc, cpu.regs.b), (0x0158, 1));
+    }
+
+    #[test]
+    fn run_to_scanline_stops_as_the_line_starts() {
+        let mut cpu = test_cpu();
+        let (_, reason) = cpu.run_to_scanline(100, MAX_CYCLES);
+        assert_eq!(reason, StopReason::ScanlineReached(100));
+        assert_eq!(cpu.gpu.ly(), 100);
+
+        // From partway through the line it waits for the next frame
+        let (cycles, reason) = cpu.run_to_scanline(100, MAX_CYCLES);
+        assert_eq!(reason, StopReason::ScanlineReached(100));
+        assert!(cycles > 150 * 456);
+    }
+
+    #[test]
+    fn ld_b_b_stops_the_debugger_when_enabled() {
+        let mut rom = test_rom();
+        // Bank 2 starts with ld b,b
+        rom[0x8000..0x8004].copy_from_slice(&[0x40, 0x06, 0x02, 0xC9]);
+
+        let mut cpu = cpu_for(rom.clone());
+        let (_, reason) = cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!(reason, StopReason::CycleLimit);
+
+        let mut cpu = cpu_for(rom);
+        cpu.homebrew.breakpoints = true;
+        let (_, reason) = cpu.run_until_stop(MAX_CYCLES);
+        assert_eq!(reason, StopReason::SoftwareBreakpoint { address: 0x4000 });
+        assert_eq!((cpu.regs.a, cpu.regs.b), (2, 1));
     }
 }
@@ -0,0 +1,346 @@
+// Breakpoints, watchpoints and stepping for ports with a debugger UI.
+// The debugger only runs inside `Cpu::run_until_stop` and the stepping
+// functions built on it. `Cpu::step` and `Cpu::step_one_frame` ignore it, so
+// normal emulation doesn't pay for it.
+use crate::registers::Registers;
+use crate::symbols::SymbolTable;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub const ALL: [Register; 14] = [
+        Register::A,
+        Register::B,
+        Register::C,
+        Register::D,
+        Register::E,
+        Register::F,
+        Register::H,
+        Register::L,
+        Register::AF,
+        Register::BC,
+        Register::DE,
+        Register::HL,
+        Register::SP,
+        Register::PC,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::A => "a",
+            Register::B => "b",
+            Register::C => "c",
+            Register::D => "d",
+            Register::E => "e",
+            Register::F => "f",
+            Register::H => "h",
+            Register::L => "l",
+            Register::AF => "af",
+            Register::BC => "bc",
+            Register::DE => "de",
+            Register::HL => "hl",
+            Register::SP => "sp",
+            Register::PC => "pc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Register> {
+        Register::ALL
+            .iter()
+            .find(|register| register.name() == name)
+            .copied()
+    }
+
+    pub fn read(&self, regs: &Registers) -> u16 {
+        match self {
+            Register::A => regs.a as u16,
+            Register::B => regs.b as u16,
+            Register::C => regs.c as u16,
+            Register::D => regs.d as u16,
+            Register::E => regs.e as u16,
+            Register::F => regs.f as u16,
+            Register::H => regs.h as u16,
+            Register::L => regs.l as u16,
+            Register::AF => regs.get_af(),
+            Register::BC => regs.get_bc(),
+            Register::DE => regs.get_de(),
+            Register::HL => regs.get_hl(),
+            Register::SP => regs.sp,
+            Register::PC => regs.pc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// Lets a breakpoint only fire when a register holds a certain value,
+// eg. `a == 0x3C`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegisterCondition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl RegisterCondition {
+    pub fn is_met(&self, regs: &Registers) -> bool {
+        let current = self.register.read(regs);
+        match self.comparison {
+            Comparison::Equal => current == self.value,
+            Comparison::NotEqual => current != self.value,
+            Comparison::Less => current < self.value,
+            Comparison::LessOrEqual => current <= self.value,
+            Comparison::Greater => current > self.value,
+            Comparison::GreaterOrEqual => current >= self.value,
+        }
+    }
+}
+
+// Stops before the instruction at `address` runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Breakpoint {
+    pub address: u16,
+    // Only stop when this bank is mapped at `address` (see
+    // `Memory::bank_at`). `None` stops in any bank.
+    pub bank: Option<u16>,
+    pub condition: Option<RegisterCondition>,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            bank: None,
+            condition: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    // Data reads by instructions. Opcode and operand fetches don't count.
+    Read,
+    Write,
+    // An instruction starting anywhere in the range
+    Execute,
+}
+
+// Watches `start` to `end`, inclusive. Reads and writes stop execution once
+// the instruction that made them has finished; execution stops before the
+// instruction runs, like a breakpoint.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn covers(&self, address: u16, kind: WatchKind) -> bool {
+        self.kind == kind && (self.start..=self.end).contains(&address)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    Breakpoint {
+        address: u16,
+        bank: u16,
+    },
+    // `value` is what was read or written, or the opcode for `Execute`
+    Watchpoint {
+        kind: WatchKind,
+        address: u16,
+        value: u8,
+    },
+    // An `ld b,b` when `Cpu::homebrew.breakpoints` is on
+    SoftwareBreakpoint {
+        address: u16,
+    },
+    // A step into, over or out of an instruction finished
+    StepComplete,
+    ScanlineReached(u8),
+    // `run_until_stop` used up all the cycles it was given
+    CycleLimit,
+}
+
+// What `Cpu::run_until_stop` is stepping towards, other than breakpoints
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StepTarget {
+    None,
+    // Stop after one instruction
+    Instruction,
+    // Stop when the stack is back above `sp` after a return
+    Return { sp: u16 },
+    // Stop on reaching the instruction after a CALL or RST
+    Address { pc: u16, sp: u16 },
+    Scanline(u8),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // Loaded from the ROM's .sym file if it has one
+    pub symbols: SymbolTable,
+
+    // Only set while the CPU is running under the debugger, so memory
+    // accesses skip the watchpoint checks the rest of the time
+    pub(crate) active: bool,
+    pub(crate) step_target: StepTarget,
+    // A read or write watchpoint that was hit partway through an instruction
+    pub(crate) pending_stop: Option<StopReason>,
+}
+
+impl Debugger {
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    // Breaks at a label from `symbols`, in the label's own bank if that
+    // part of memory is banked. Gives back the breakpoint so it can be
+    // removed later, or `None` if there's no such label.
+    pub fn add_label_breakpoint(&mut self, label: &str) -> Option<Breakpoint> {
+        let symbol = self.symbols.lookup(label)?;
+        // ROM, VRAM, cartridge RAM and WRAM all have switchable banks
+        let banked = matches!(
+            symbol.address,
+            0x4000..=0x7FFF | 0x8000..=0x9FFF | 0xA000..=0xBFFF | 0xD000..=0xDFFF
+        );
+        let breakpoint = Breakpoint {
+            address: symbol.address,
+            bank: if banked { Some(symbol.bank) } else { None },
+            condition: None,
+        };
+        self.add_breakpoint(breakpoint);
+        Some(breakpoint)
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) {
+        self.breakpoints.retain(|b| b != breakpoint);
+    }
+
+    // Removes every breakpoint at `address`, whatever its bank or condition
+    pub fn remove_breakpoints_at(&mut self, address: u16) {
+        self.breakpoints.retain(|b| b.address != address);
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) {
+        self.watchpoints.retain(|w| w != watchpoint);
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    pub(crate) fn breakpoint_hit(
+        &self,
+        pc: u16,
+        bank: u16,
+        regs: &Registers,
+    ) -> bool {
+        self.breakpoints.iter().any(|b| {
+            b.address == pc
+                && b.bank.is_none_or(|wanted| wanted == bank)
+                && b.condition.is_none_or(|c| c.is_met(regs))
+        })
+    }
+
+    pub(crate) fn check_access(
+        &mut self,
+        kind: WatchKind,
+        address: u16,
+        value: u8,
+    ) {
+        if self.pending_stop.is_some() {
+            return;
+        }
+        if self.watchpoints.iter().any(|w| w.covers(address, kind)) {
+            self.pending_stop = Some(StopReason::Watchpoint {
+                kind,
+                address,
+                value,
+            });
+        }
+    }
+
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            symbols: SymbolTable::new(),
+            active: false,
+            step_target: StepTarget::None,
+            pending_stop: None,
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_breakpoints_keep_banked_labels_in_their_bank() {
+        let mut debugger = Debugger::new();
+        debugger.symbols =
+            SymbolTable::parse("00:0150 Start\n02:4000 Code\n03:a000 sSave");
+
+        let start = debugger.add_label_breakpoint("Start").unwrap();
+        assert_eq!((start.address, start.bank), (0x0150, None));
+        let code = debugger.add_label_breakpoint("Code").unwrap();
+        assert_eq!((code.address, code.bank), (0x4000, Some(2)));
+        let save = debugger.add_label_breakpoint("sSave").unwrap();
+        assert_eq!((save.address, save.bank), (0xA000, Some(3)));
+        assert!(debugger.add_label_breakpoint("Missing").is_none());
+    }
+}
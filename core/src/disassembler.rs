@@ -0,0 +1,667 @@
+// Turns SM83 machine code back into instructions, for debuggers, trace logs
+// and ROM analysis tools. Text comes out in RGBDS syntax, eg. `ld a, [hl+]`.
+// Opcodes are split up the usual way, as xx_yyy_zzz, with y sometimes
+// further split into pp_q:
+//   https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+use crate::cpu::Cpu;
+use crate::debugger::Register;
+use crate::symbols::SymbolTable;
+use core::fmt::{self, Write};
+use core::ops::RangeInclusive;
+
+#[cfg(not(feature = "std"))]
+use alloc::{string::String, vec, vec::Vec};
+
+const REGISTERS: [Register; 8] = [
+    Register::B,
+    Register::C,
+    Register::D,
+    Register::E,
+    Register::H,
+    Register::L,
+    // Index 6 is (HL), handled by `register_operand`
+    Register::HL,
+    Register::A,
+];
+const REGISTER_PAIRS: [Register; 4] =
+    [Register::BC, Register::DE, Register::HL, Register::SP];
+// PUSH and POP swap SP for AF
+const STACK_REGISTER_PAIRS: [Register; 4] =
+    [Register::BC, Register::DE, Register::HL, Register::AF];
+
+const ACCUMULATOR_OPS: [&str; 8] =
+    ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const ALU_OPS: [&str; 8] =
+    ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const CB_SHIFT_OPS: [&str; 8] =
+    ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+// Where `Instruction`s are decoded from
+pub trait ByteSource {
+    fn read_byte(&self, address: u16) -> u8;
+
+    // Which bank is mapped at `address`, so jump targets can say which bank
+    // they land in
+    fn bank_at(&self, _address: u16) -> u16 {
+        0
+    }
+}
+
+// The CPU's address space as it's mapped right now. Reading it has no side
+// effects.
+impl ByteSource for Cpu {
+    fn read_byte(&self, address: u16) -> u8 {
+        self.mem.read(&self.ints, &self.gpu, address)
+    }
+
+    fn bank_at(&self, address: u16) -> u16 {
+        self.mem.bank_at(address)
+    }
+}
+
+// A ROM file with `bank` mapped at 0x4000-0x7FFF, for looking at code that
+// isn't mapped in yet. Anything outside 0x0000-0x7FFF reads as 0xFF.
+pub struct RomBank<'a> {
+    pub rom: &'a [u8],
+    pub bank: u16,
+}
+
+impl ByteSource for RomBank<'_> {
+    fn read_byte(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => {
+                self.bank as usize * 0x4000 + (address - 0x4000) as usize
+            },
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn bank_at(&self, address: u16) -> u16 {
+        match address {
+            0x4000..=0x7FFF => self.bank,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Condition {
+    NotZero,
+    Zero,
+    NotCarry,
+    Carry,
+}
+
+impl Condition {
+    const ALL: [Condition; 4] = [
+        Condition::NotZero,
+        Condition::Zero,
+        Condition::NotCarry,
+        Condition::Carry,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Condition::NotZero => "nz",
+            Condition::Zero => "z",
+            Condition::NotCarry => "nc",
+            Condition::Carry => "c",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operand {
+    Register(Register),
+    // Memory at the address in a register pair, or at 0xFF00 + C
+    Indirect(Register),
+    // [hl+] and [hl-]
+    HlIncrement,
+    HlDecrement,
+    Immediate8(u8),
+    Immediate16(u16),
+    // Memory at a fixed address
+    Address(u16),
+    // LDH's 0xFF00 + n
+    HighAddress(u8),
+    Condition(Condition),
+    // Where a jump, call or RST goes. JR's offset is already applied.
+    Target(u16),
+    // ADD SP, e
+    SignedImmediate(i8),
+    // LD HL, SP + e
+    SpOffset(i8),
+    Bit(u8),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "{}", register.name()),
+            Operand::Indirect(register) => write!(f, "[{}]", register.name()),
+            Operand::HlIncrement => write!(f, "[hl+]"),
+            Operand::HlDecrement => write!(f, "[hl-]"),
+            Operand::Immediate8(value) => write!(f, "${:02X}", value),
+            Operand::Immediate16(value) | Operand::Target(value) => {
+                write!(f, "${:04X}", value)
+            },
+            Operand::Address(address) => write!(f, "[${:04X}]", address),
+            Operand::HighAddress(offset) => {
+                write!(f, "[${:04X}]", 0xFF00 + *offset as u16)
+            },
+            Operand::Condition(condition) => write!(f, "{}", condition.name()),
+            Operand::SignedImmediate(value) => write!(f, "{}", value),
+            Operand::SpOffset(value) if *value < 0 => {
+                write!(f, "sp - {}", -(*value as i16))
+            },
+            Operand::SpOffset(value) => write!(f, "sp + {}", value),
+            Operand::Bit(bit) => write!(f, "{}", bit),
+        }
+    }
+}
+
+// A jump, call or RST destination along with the bank it lands in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Target {
+    pub bank: u16,
+    pub address: u16,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Instruction {
+    pub address: u16,
+    pub bank: u16,
+    // Only the first `length` bytes are part of the instruction
+    pub bytes: [u8; 3],
+    pub length: u8,
+    // Lower case, as RGBDS writes them. Opcodes the SM83 doesn't have come
+    // out as `db`.
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    // In clock cycles (4 per M-cycle). For conditional jumps, calls and
+    // returns this is when the condition fails.
+    pub cycles: u8,
+    // How long a conditional jump, call or return takes when it's taken
+    pub branch_cycles: Option<u8>,
+    pub target: Option<Target>,
+}
+
+impl Instruction {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+
+    // The address of the instruction after this one
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.length as u16)
+    }
+
+    // Where this instruction is, as the nearest label plus an offset
+    pub fn location(&self, symbols: &SymbolTable) -> Option<String> {
+        symbols.describe(Some(self.bank), self.address)
+    }
+
+    // Like `to_string`, but with addresses given as the nearest label, eg.
+    // `call UpdateSprites` or `ld a, [wScore+$1]`
+    pub fn format_with_symbols(&self, symbols: &SymbolTable) -> String {
+        let mut text = String::from(self.mnemonic);
+        for (i, operand) in self.operands.iter().enumerate() {
+            text.push_str(if i == 0 { " " } else { ", " });
+
+            let label = match operand {
+                Operand::Target(address) => symbols
+                    .describe(self.target.map(|target| target.bank), *address),
+                // We don't know which bank data accesses will see
+                Operand::Address(address) => symbols.describe(None, *address),
+                Operand::HighAddress(offset) => {
+                    symbols.describe(None, 0xFF00 + *offset as u16)
+                },
+                _ => None,
+            };
+
+            // Writing to a String can't fail
+            match (label, operand) {
+                (Some(label), Operand::Target(_)) => text.push_str(&label),
+                (Some(label), _) => write!(text, "[{}]", label).unwrap(),
+                (None, _) => write!(text, "{}", operand).unwrap(),
+            }
+        }
+        text
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", separator, operand)?;
+        }
+        Ok(())
+    }
+}
+
+// What's been worked out about an opcode before its operands are read
+struct Decoded {
+    mnemonic: &'static str,
+    operands: Vec<Operand>,
+    length: u8,
+    cycles: u8,
+    branch_cycles: Option<u8>,
+}
+
+impl Decoded {
+    fn new(
+        mnemonic: &'static str,
+        operands: Vec<Operand>,
+        length: u8,
+        cycles: u8,
+    ) -> Decoded {
+        Decoded {
+            mnemonic,
+            operands,
+            length,
+            cycles,
+            branch_cycles: None,
+        }
+    }
+
+    fn branch(mut self, branch_cycles: u8) -> Decoded {
+        self.branch_cycles = Some(branch_cycles);
+        self
+    }
+}
+
+// Register index 6 is memory at HL, which is slower to get at
+fn register_operand(index: u8) -> Operand {
+    if index == 6 {
+        Operand::Indirect(Register::HL)
+    } else {
+        Operand::Register(REGISTERS[index as usize])
+    }
+}
+
+fn condition_operand(index: u8) -> Operand {
+    Operand::Condition(Condition::ALL[index as usize])
+}
+
+// Decodes the instruction starting at `address`
+pub fn decode(source: &impl ByteSource, address: u16) -> Instruction {
+    let byte_at = |offset: u16| source.read_byte(address.wrapping_add(offset));
+    let op = byte_at(0);
+    let n = byte_at(1);
+    let nn = u16::from_le_bytes([n, byte_at(2)]);
+    let relative = address.wrapping_add(2).wrapping_add(n as i8 as u16);
+
+    let x = op >> 6;
+    let y = (op >> 3) & 0b111;
+    let z = op & 0b111;
+    let p = y >> 1;
+    let q = y & 1;
+
+    use Operand::{
+        Address, HighAddress, HlDecrement, HlIncrement, Immediate16,
+        Immediate8, Indirect, SignedImmediate, SpOffset,
+    };
+    let a = Operand::Register(Register::A);
+    let hl = Operand::Register(Register::HL);
+    let sp = Operand::Register(Register::SP);
+
+    let decoded = match (x, z) {
+        (0, 0) => match y {
+            0 => Decoded::new("nop", vec![], 1, 4),
+            1 => Decoded::new("ld", vec![Address(nn), sp], 3, 20),
+            2 => Decoded::new("stop", vec![], 2, 4),
+            3 => Decoded::new("jr", vec![Operand::Target(relative)], 2, 12),
+            _ => Decoded::new(
+                "jr",
+                vec![condition_operand(y - 4), Operand::Target(relative)],
+                2,
+                8,
+            )
+            .branch(12),
+        },
+        (0, 1) if q == 0 => Decoded::new(
+            "ld",
+            vec![
+                Operand::Register(REGISTER_PAIRS[p as usize]),
+                Immediate16(nn),
+            ],
+            3,
+            12,
+        ),
+        (0, 1) => Decoded::new(
+            "add",
+            vec![hl, Operand::Register(REGISTER_PAIRS[p as usize])],
+            1,
+            8,
+        ),
+        (0, 2) => {
+            let memory = match p {
+                0 => Indirect(Register::BC),
+                1 => Indirect(Register::DE),
+                2 => HlIncrement,
+                _ => HlDecrement,
+            };
+            let operands = if q == 0 {
+                vec![memory, a]
+            } else {
+                vec![a, memory]
+            };
+            Decoded::new("ld", operands, 1, 8)
+        },
+        (0, 3) => Decoded::new(
+            if q == 0 { "inc" } else { "dec" },
+            vec![Operand::Register(REGISTER_PAIRS[p as usize])],
+            1,
+            8,
+        ),
+        (0, 4) | (0, 5) => Decoded::new(
+            if z == 4 { "inc" } else { "dec" },
+            vec![register_operand(y)],
+            1,
+            if y == 6 { 12 } else { 4 },
+        ),
+        (0, 6) => Decoded::new(
+            "ld",
+            vec![register_operand(y), Immediate8(n)],
+            2,
+            if y == 6 { 12 } else { 8 },
+        ),
+        (0, _) => Decoded::new(ACCUMULATOR_OPS[y as usize], vec![], 1, 4),
+
+        (1, 6) if y == 6 => Decoded::new("halt", vec![], 1, 4),
+        (1, _) => Decoded::new(
+            "ld",
+            vec![register_operand(y), register_operand(z)],
+            1,
+            if y == 6 || z == 6 { 8 } else { 4 },
+        ),
+
+        (2, _) => {
+            let cycles = if z == 6 { 8 } else { 4 };
+            alu_instruction(y, register_operand(z), 1, cycles)
+        },
+
+        (_, 0) => match y {
+            0..=3 => {
+                Decoded::new("ret", vec![condition_operand(y)], 1, 8).branch(20)
+            },
+            4 => Decoded::new("ldh", vec![HighAddress(n), a], 2, 12),
+            5 => Decoded::new("add", vec![sp, SignedImmediate(n as i8)], 2, 16),
+            6 => Decoded::new("ldh", vec![a, HighAddress(n)], 2, 12),
+            _ => Decoded::new("ld", vec![hl, SpOffset(n as i8)], 2, 12),
+        },
+        (_, 1) if q == 0 => Decoded::new(
+            "pop",
+            vec![Operand::Register(STACK_REGISTER_PAIRS[p as usize])],
+            1,
+            12,
+        ),
+        (_, 1) => match p {
+            0 => Decoded::new("ret", vec![], 1, 16),
+            1 => Decoded::new("reti", vec![], 1, 16),
+            2 => Decoded::new("jp", vec![hl], 1, 4),
+            _ => Decoded::new("ld", vec![sp, hl], 1, 8),
+        },
+        (_, 2) => match y {
+            0..=3 => Decoded::new(
+                "jp",
+                vec![condition_operand(y), Operand::Target(nn)],
+                3,
+                12,
+            )
+            .branch(16),
+            4 => Decoded::new("ldh", vec![Indirect(Register::C), a], 1, 8),
+            5 => Decoded::new("ld", vec![Address(nn), a], 3, 16),
+            6 => Decoded::new("ldh", vec![a, Indirect(Register::C)], 1, 8),
+            _ => Decoded::new("ld", vec![a, Address(nn)], 3, 16),
+        },
+        (_, 3) => match y {
+            0 => Decoded::new("jp", vec![Operand::Target(nn)], 3, 16),
+            1 => cb_instruction(n),
+            6 => Decoded::new("di", vec![], 1, 4),
+            7 => Decoded::new("ei", vec![], 1, 4),
+            _ => Decoded::new("db", vec![Immediate8(op)], 1, 4),
+        },
+        (_, 4) if y < 4 => Decoded::new(
+            "call",
+            vec![condition_operand(y), Operand::Target(nn)],
+            3,
+            12,
+        )
+        .branch(24),
+        (_, 5) if q == 0 => Decoded::new(
+            "push",
+            vec![Operand::Register(STACK_REGISTER_PAIRS[p as usize])],
+            1,
+            16,
+        ),
+        (_, 5) if p == 0 => {
+            Decoded::new("call", vec![Operand::Target(nn)], 3, 24)
+        },
+        (_, 6) => alu_instruction(y, Immediate8(n), 2, 8),
+        (_, 7) => {
+            Decoded::new("rst", vec![Operand::Target(y as u16 * 8)], 1, 16)
+        },
+        // 0xE4, 0xEC, 0xF4, 0xFC, 0xDD, 0xED and 0xFD lock up the CPU
+        _ => Decoded::new("db", vec![Immediate8(op)], 1, 4),
+    };
+
+    let target = decoded.operands.iter().find_map(|operand| match operand {
+        Operand::Target(address) => Some(Target {
+            bank: source.bank_at(*address),
+            address: *address,
+        }),
+        _ => None,
+    });
+
+    let mut bytes = [0; 3];
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+        if offset < decoded.length as usize {
+            *byte = byte_at(offset as u16);
+        }
+    }
+
+    Instruction {
+        address,
+        bank: source.bank_at(address),
+        bytes,
+        length: decoded.length,
+        mnemonic: decoded.mnemonic,
+        operands: decoded.operands,
+        cycles: decoded.cycles,
+        branch_cycles: decoded.branch_cycles,
+        target,
+    }
+}
+
+// RGBDS writes ADD, ADC and SBC with A, and the rest without
+fn alu_instruction(y: u8, operand: Operand, length: u8, cycles: u8) -> Decoded {
+    let operands = match y {
+        0 | 1 | 3 => vec![Operand::Register(Register::A), operand],
+        _ => vec![operand],
+    };
+    Decoded::new(ALU_OPS[y as usize], operands, length, cycles)
+}
+
+fn cb_instruction(op: u8) -> Decoded {
+    let x = op >> 6;
+    let y = (op >> 3) & 0b111;
+    let z = op & 0b111;
+    let operand = register_operand(z);
+
+    // Instructions on (HL) read, modify and write it back, except BIT which
+    // only reads
+    let cycles = match (x, z) {
+        (_, 0..=5) | (_, 7) => 8,
+        (1, _) => 12,
+        _ => 16,
+    };
+
+    match x {
+        0 => Decoded::new(CB_SHIFT_OPS[y as usize], vec![operand], 2, cycles),
+        1 => Decoded::new("bit", vec![Operand::Bit(y), operand], 2, cycles),
+        2 => Decoded::new("res", vec![Operand::Bit(y), operand], 2, cycles),
+        _ => Decoded::new("set", vec![Operand::Bit(y), operand], 2, cycles),
+    }
+}
+
+// Decodes every instruction that starts in `range`, one after another.
+// Data mixed in with the code will be decoded as instructions too.
+pub fn disassemble_range(
+    source: &impl ByteSource,
+    range: RangeInclusive<u16>,
+) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut address = *range.start() as u32;
+    while address <= *range.end() as u32 {
+        let instruction = decode(source, address as u16);
+        address += instruction.length as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::string::ToString;
+
+    // Lengths and (untaken) cycles of every opcode, laid out as the usual
+    // 16x16 opcode table. CB prefixed ones are covered by 0xCB 0x00.
+    #[rustfmt::skip]
+    const LENGTHS: [u8; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1,
+        1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1,
+        2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1,
+        2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1,
+    ];
+    #[rustfmt::skip]
+    const CYCLES: [u8; 256] = [
+        4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4,
+        4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4,
+        8, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+        8, 12, 8, 8, 12, 12, 12, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        8, 12, 12, 16, 12, 16, 8, 16, 8, 16, 12, 8, 12, 24, 8, 16,
+        8, 12, 12, 4, 12, 16, 8, 16, 8, 16, 12, 4, 12, 4, 8, 16,
+        12, 12, 8, 4, 4, 16, 8, 16, 16, 4, 16, 4, 4, 4, 8, 16,
+        12, 12, 8, 4, 4, 16, 8, 16, 12, 8, 16, 4, 4, 4, 8, 16,
+    ];
+
+    fn decode_bytes(bytes: &[u8]) -> Instruction {
+        decode(
+            &RomBank {
+                rom: bytes,
+                bank: 1,
+            },
+            0,
+        )
+    }
+
+    #[test]
+    fn every_opcode_has_the_right_length_and_timing() {
+        for op in 0..=0xFF {
+            let instruction = decode_bytes(&[op, 0, 0]);
+            assert_eq!(
+                instruction.length, LENGTHS[op as usize],
+                "Length of {:#04x}",
+                op
+            );
+            assert_eq!(
+                instruction.cycles, CYCLES[op as usize],
+                "Cycles of {:#04x}",
+                op
+            );
+        }
+    }
+
+    #[test]
+    fn instructions_are_written_like_rgbds() {
+        let cases: [(&[u8], &str); 16] = [
+            (&[0x00], "nop"),
+            (&[0x01, 0x34, 0x12], "ld bc, $1234"),
+            (&[0x08, 0x00, 0xC0], "ld [$C000], sp"),
+            (&[0x22], "ld [hl+], a"),
+            (&[0x3A], "ld a, [hl-]"),
+            (&[0x36, 0x42], "ld [hl], $42"),
+            (&[0x76], "halt"),
+            (&[0x88], "adc a, b"),
+            (&[0xA6], "and [hl]"),
+            (&[0xE0, 0x40], "ldh [$FF40], a"),
+            (&[0xF2], "ldh a, [c]"),
+            (&[0xE8, 0xFE], "add sp, -2"),
+            (&[0xF8, 0xFF], "ld hl, sp - 1"),
+            (&[0xCB, 0x7E], "bit 7, [hl]"),
+            (&[0xCB, 0x37], "swap a"),
+            (&[0xD3], "db $D3"),
+        ];
+        for (bytes, text) in cases {
+            let instruction = decode_bytes(bytes);
+            assert_eq!(instruction.to_string(), text);
+            assert_eq!(instruction.bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn jumps_know_where_they_land() {
+        // jr -2 jumps back to itself
+        let jr = decode_bytes(&[0x18, 0xFE]);
+        assert_eq!(jr.to_string(), "jr $0000");
+        assert_eq!(
+            jr.target,
+            Some(Target {
+                bank: 0,
+                address: 0
+            })
+        );
+
+        let call = decode_bytes(&[0xC4, 0x00, 0x40]);
+        assert_eq!(call.to_string(), "call nz, $4000");
+        assert_eq!((call.cycles, call.branch_cycles), (12, Some(24)));
+        assert_eq!(
+            call.target,
+            Some(Target {
+                bank: 1,
+                address: 0x4000
+            })
+        );
+
+        let rst = decode_bytes(&[0xFF]);
+        assert_eq!(rst.to_string(), "rst $0038");
+    }
+
+    #[test]
+    fn ranges_are_decoded_one_instruction_after_another() {
+        let rom = [0x3E, 0x01, 0xC9, 0x00];
+        let instructions =
+            disassemble_range(&RomBank { rom: &rom, bank: 1 }, 0..=2);
+        let addresses: Vec<u16> =
+            instructions.iter().map(|i| i.address).collect();
+        assert_eq!(addresses, [0, 2]);
+        assert_eq!(instructions[1].to_string(), "ret");
+    }
+}
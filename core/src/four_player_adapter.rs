@@ -0,0 +1,527 @@
+// DMG-07 Four Player Adapter
+// Used by F-1 Race, Faceball 2000, Wave Race etc. The adapter is always the
+// master and clocks every Gameboy (the slaves) at the same time. It starts in
+// a "ping" phase where it tells each player their ID, then once player 1 asks
+// for it, a "transmission" phase where it shares everyone's data with
+// everyone else.
+// Protocol details: https://shonumi.github.io/dandocs.html#dmg07
+use crate::constants::*;
+use crate::cpu::Cpu;
+use crate::serial_cable::SerialDevice;
+use core::cell::RefCell;
+
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::rc::Rc;
+
+pub const MAX_PLAYERS: usize = 4;
+
+const PING_HEADER: u8 = 0xFE;
+// Gameboys answer the first two ping bytes with this to say they're there
+const PING_ACK: u8 = 0x88;
+// Player 1 answers a whole ping packet with this to start transmission
+const START_REQUEST: u8 = 0xAA;
+// The adapter answers the start request with a packet of these
+const START_CONFIRM: u8 = 0xCC;
+
+// Every packet in the ping phase is 4 bytes long
+const PING_PACKET_SIZE: usize = 4;
+
+// Bytes are clocked at 8192Hz, so a byte takes at least this long
+const BYTE_CYCLES: usize = CLOCK_SPEED / 8192 * 8;
+// The gap between ping bytes gives games time to reload SB
+const PING_BYTE_CYCLES: usize = BYTE_CYCLES * 4;
+// In transmission, the gap between bytes grows with the low nibble of RATE.
+// This is an approximation of the real adapter's timing.
+const RATE_DELAY_CYCLES: usize = BYTE_CYCLES / 2;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Phase {
+    Ping,
+    Starting,
+    Transmission,
+}
+
+struct AdapterState {
+    phase: Phase,
+    // Which byte of the current packet we're on
+    packet_index: usize,
+    // False until we've clocked our very first byte
+    byte_in_flight: bool,
+    cycles_until_next_byte: usize,
+
+    // Players with a Gameboy plugged in
+    plugged_in: [bool; MAX_PLAYERS],
+    // Players that answered the last ping, reported in the status byte
+    connected: [bool; MAX_PLAYERS],
+    acked_this_packet: [bool; MAX_PLAYERS],
+    start_requests_this_packet: usize,
+
+    // Set by player 1 in its ping replies
+    rate: u8,
+    size: u8,
+
+    // The byte each player is about to be clocked with, and their reply
+    outgoing: [Option<u8>; MAX_PLAYERS],
+    replies: [Option<u8>; MAX_PLAYERS],
+
+    // Everyone's data from the last transmission round, which is what we
+    // send out, and the data being collected for the next one
+    last_round: Vec<u8>,
+    this_round: Vec<u8>,
+}
+
+impl AdapterState {
+    fn packet_size(&self) -> usize {
+        match self.phase {
+            Phase::Ping | Phase::Starting => PING_PACKET_SIZE,
+            Phase::Transmission => self.size as usize * MAX_PLAYERS,
+        }
+    }
+
+    fn byte_cycles(&self) -> usize {
+        match self.phase {
+            Phase::Ping | Phase::Starting => PING_BYTE_CYCLES,
+            Phase::Transmission => {
+                BYTE_CYCLES + (self.rate & 0x0F) as usize * RATE_DELAY_CYCLES
+            },
+        }
+    }
+
+    fn status_byte(&self, player: usize) -> u8 {
+        let mut status = (player + 1) as u8;
+        for (i, connected) in self.connected.iter().enumerate() {
+            if *connected {
+                status |= 0b0001_0000 << i;
+            }
+        }
+        status
+    }
+
+    fn byte_for_player(&self, player: usize) -> u8 {
+        match self.phase {
+            Phase::Ping if self.packet_index == 0 => PING_HEADER,
+            Phase::Ping => self.status_byte(player),
+            Phase::Starting => START_CONFIRM,
+            Phase::Transmission => self.last_round[self.packet_index],
+        }
+    }
+
+    // Takes in what each player sent back for the byte we just clocked
+    fn collect_replies(&mut self) {
+        for player in 0..MAX_PLAYERS {
+            // A player that wasn't listening never got their byte
+            self.outgoing[player] = None;
+            let reply = match self.replies[player].take() {
+                Some(reply) => reply,
+                None => continue,
+            };
+
+            match self.phase {
+                Phase::Ping => {
+                    if self.packet_index < 2 && reply == PING_ACK {
+                        self.acked_this_packet[player] = true;
+                    }
+                    // A start request isn't a RATE or SIZE, so it mustn't
+                    // replace the ones player 1 already asked for
+                    if player == 0 && reply == START_REQUEST {
+                        self.start_requests_this_packet += 1;
+                    } else if player == 0 {
+                        match self.packet_index {
+                            2 => self.rate = reply,
+                            3 => self.size = reply,
+                            _ => {},
+                        }
+                    }
+                },
+                Phase::Starting => {},
+                Phase::Transmission => {
+                    // Each player's data goes in their slot of the round
+                    let size = self.size as usize;
+                    if self.packet_index < size {
+                        self.this_round[player * size + self.packet_index] =
+                            reply;
+                    }
+                },
+            }
+        }
+    }
+
+    fn finish_packet(&mut self) {
+        match self.phase {
+            Phase::Ping => {
+                self.connected = self.acked_this_packet;
+                self.acked_this_packet = [false; MAX_PLAYERS];
+
+                if self.start_requests_this_packet == PING_PACKET_SIZE {
+                    self.phase = Phase::Starting;
+                }
+                self.start_requests_this_packet = 0;
+            },
+            Phase::Starting => {
+                // Games only ever ask for 1-4 bytes per player
+                self.size = self.size.clamp(1, 4);
+                let round_size = self.size as usize * MAX_PLAYERS;
+                self.last_round = vec![0; round_size];
+                self.this_round = vec![0; round_size];
+                self.phase = Phase::Transmission;
+            },
+            Phase::Transmission => {
+                let size = self.size as usize;
+                // When everyone connected sends nothing but 0xFF, they want
+                // to go back to the ping phase
+                let everyone_done =
+                    (0..MAX_PLAYERS).filter(|p| self.connected[*p]).all(|p| {
+                        self.this_round[p * size..(p + 1) * size]
+                            .iter()
+                            .all(|byte| *byte == 0xFF)
+                    });
+
+                if everyone_done {
+                    self.phase = Phase::Ping;
+                } else {
+                    core::mem::swap(&mut self.last_round, &mut self.this_round);
+                    // Empty slots read as 0 next round
+                    self.this_round.iter_mut().for_each(|byte| *byte = 0);
+                }
+            },
+        }
+    }
+
+    fn clock_next_byte(&mut self) {
+        if self.byte_in_flight {
+            self.collect_replies();
+
+            self.packet_index += 1;
+            if self.packet_index >= self.packet_size() {
+                self.finish_packet();
+                self.packet_index = 0;
+            }
+        }
+        self.byte_in_flight = true;
+
+        for player in 0..MAX_PLAYERS {
+            if self.plugged_in[player] {
+                self.outgoing[player] = Some(self.byte_for_player(player));
+            }
+        }
+    }
+
+    fn step(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while cycles >= self.cycles_until_next_byte {
+            cycles -= self.cycles_until_next_byte;
+            self.clock_next_byte();
+            self.cycles_until_next_byte = self.byte_cycles();
+        }
+        self.cycles_until_next_byte -= cycles;
+    }
+}
+
+// One of the adapter's four cables, plugged into a Gameboy
+pub struct FourPlayerPort {
+    adapter: Rc<RefCell<AdapterState>>,
+    player: usize,
+}
+
+impl SerialDevice for FourPlayerPort {
+    // The adapter is always the master. If a game tries to clock it, there's
+    // nobody listening.
+    fn exchange_byte(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+
+    fn external_clock(&mut self, outgoing: u8) -> Option<u8> {
+        let mut adapter = self.adapter.borrow_mut();
+        let incoming = adapter.outgoing[self.player].take()?;
+        adapter.replies[self.player] = Some(outgoing);
+        Some(incoming)
+    }
+}
+
+impl Drop for FourPlayerPort {
+    fn drop(&mut self) {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.plugged_in[self.player] = false;
+        adapter.outgoing[self.player] = None;
+    }
+}
+
+// The adapter itself. It doesn't belong to any one Gameboy, so whoever owns
+// it is responsible for stepping it.
+pub struct FourPlayerAdapter {
+    state: Rc<RefCell<AdapterState>>,
+}
+
+impl FourPlayerAdapter {
+    // Gets the cable for a player (0-3) to plug into their serial port
+    pub fn port(&self, player: usize) -> FourPlayerPort {
+        assert!(player < MAX_PLAYERS, "The adapter only has 4 ports");
+        self.state.borrow_mut().plugged_in[player] = true;
+        FourPlayerPort {
+            adapter: self.state.clone(),
+            player,
+        }
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        self.state.borrow_mut().step(cycles);
+    }
+
+    pub fn new() -> FourPlayerAdapter {
+        FourPlayerAdapter {
+            state: Rc::new(RefCell::new(AdapterState {
+                phase: Phase::Ping,
+                packet_index: 0,
+                byte_in_flight: false,
+                cycles_until_next_byte: PING_BYTE_CYCLES,
+
+                plugged_in: [false; MAX_PLAYERS],
+                connected: [false; MAX_PLAYERS],
+                acked_this_packet: [false; MAX_PLAYERS],
+                start_requests_this_packet: 0,
+
+                rate: 0,
+                size: 1,
+
+                outgoing: [None; MAX_PLAYERS],
+                replies: [None; MAX_PLAYERS],
+
+                last_round: vec![],
+                this_round: vec![],
+            })),
+        }
+    }
+}
+
+impl Default for FourPlayerAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Up to four Gameboys plugged into one adapter, stepped in lockstep like
+// link_cable::LinkedPair. Stepping order is fixed, so runs are deterministic.
+pub struct FourPlayerGroup {
+    pub players: Vec<Cpu>,
+    adapter: FourPlayerAdapter,
+
+    // Elapsed single-speed cycles for each machine, and for the adapter
+    clocks: Vec<usize>,
+    adapter_clock: usize,
+}
+
+impl FourPlayerGroup {
+    // Steps whichever machine is furthest behind by one instruction, then
+    // brings the adapter up to the slowest machine
+    pub fn step(&mut self) {
+        let (behind, _) = self
+            .clocks
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, clock)| **clock)
+            .unwrap();
+
+        let cpu = &mut self.players[behind];
+        let mut cycles = cpu.step();
+        if cpu.mem.speed_switch.current_speed_is_double {
+            cycles /= 2;
+        }
+        self.clocks[behind] += cycles;
+
+        let slowest = *self.clocks.iter().min().unwrap();
+        if slowest > self.adapter_clock {
+            self.adapter.step(slowest - self.adapter_clock);
+            self.adapter_clock = slowest;
+        }
+    }
+
+    // Runs every machine until they've each covered another `cycles`
+    // single-speed cycles
+    pub fn step_cycles(&mut self, cycles: usize) {
+        let target = self.adapter_clock + cycles;
+        while self.adapter_clock < target {
+            self.step();
+        }
+    }
+
+    // Runs every machine for one frame of player 1's frame rate
+    pub fn step_one_frame(&mut self) {
+        self.step_cycles(CLOCK_SPEED / self.players[0].frame_rate);
+    }
+
+    // Unplugs everyone, returning the machines with nothing connected
+    pub fn disconnect(mut self) -> Vec<Cpu> {
+        for cpu in &mut self.players {
+            cpu.disconnect_serial_device();
+        }
+        self.players
+    }
+
+    // Players are assigned in order, so the first Cpu is player 1
+    pub fn new(mut players: Vec<Cpu>) -> FourPlayerGroup {
+        assert!(
+            !players.is_empty() && players.len() <= MAX_PLAYERS,
+            "The adapter needs between 1 and 4 players"
+        );
+
+        let adapter = FourPlayerAdapter::new();
+        for (i, cpu) in players.iter_mut().enumerate() {
+            cpu.connect_serial_device(Box::new(adapter.port(i)));
+        }
+
+        FourPlayerGroup {
+            clocks: vec![0; players.len()],
+            players,
+            adapter,
+            adapter_clock: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::memory::rom::Rom;
+
+    const RATE: u8 = 0x00;
+    const SIZE: u8 = 2;
+
+    // What each player sends back for every byte, in order: three ping
+    // packets, player 1's start request, the start confirmation, then two
+    // rounds of transmission
+    fn script(player: usize) -> Vec<u8> {
+        let mut script = vec![];
+        for _ in 0..3 {
+            script.extend_from_slice(&[PING_ACK, PING_ACK, RATE, SIZE]);
+        }
+        if player == 0 {
+            script.extend_from_slice(&[START_REQUEST; 4]);
+        } else {
+            script.extend_from_slice(&[PING_ACK, PING_ACK, 0, 0]);
+        }
+        script.extend_from_slice(&[0; 4]);
+        for _ in 0..2 {
+            let id = 0x10 * (player as u8 + 1);
+            script.extend_from_slice(&[id + 1, id + 2]);
+            script.extend_from_slice(&[0; SIZE as usize * 3]);
+        }
+        script
+    }
+
+    // What every player should be sent for the same bytes
+    fn expected(player: usize) -> Vec<u8> {
+        let id = player as u8 + 1;
+        let mut expected = vec![PING_HEADER, id, id, id];
+        for _ in 0..3 {
+            expected.extend_from_slice(&[PING_HEADER, 0xF0 | id, 0xF0 | id]);
+            expected.push(0xF0 | id);
+        }
+        expected.extend_from_slice(&[START_CONFIRM; 4]);
+        // Nobody has sent anything yet in the first round
+        expected.extend_from_slice(&[0; SIZE as usize * 4]);
+        expected.extend_from_slice(&[
+            0x11, 0x12, 0x21, 0x22, 0x31, 0x32, 0x41, 0x42,
+        ]);
+        expected
+    }
+
+    #[test]
+    fn ports_go_through_ping_start_and_transmission() {
+        let mut adapter = FourPlayerAdapter::new();
+        let mut ports: Vec<_> =
+            (0..MAX_PLAYERS).map(|p| adapter.port(p)).collect();
+        let scripts: Vec<_> = (0..MAX_PLAYERS).map(script).collect();
+        let mut received = vec![vec![]; MAX_PLAYERS];
+
+        while received[0].len() < scripts[0].len() {
+            adapter.step(BYTE_CYCLES);
+            for (player, port) in ports.iter_mut().enumerate() {
+                let reply = scripts[player][received[player].len()];
+                if let Some(byte) = port.external_clock(reply) {
+                    received[player].push(byte);
+                }
+            }
+        }
+
+        for (player, received) in received.iter().enumerate() {
+            assert_eq!(*received, expected(player), "Player {}", player + 1);
+        }
+        let state = adapter.state.borrow();
+        assert!(state.phase == Phase::Transmission);
+        assert_eq!((state.rate, state.size), (RATE, SIZE));
+    }
+
+    #[test]
+    fn start_request_keeps_rate_and_size() {
+        let adapter = FourPlayerAdapter::new();
+        let mut state = adapter.state.borrow_mut();
+        for (index, reply) in [PING_ACK, PING_ACK, 0x13, 3]
+            .into_iter()
+            .chain([START_REQUEST; 4])
+            .enumerate()
+        {
+            state.packet_index = index % PING_PACKET_SIZE;
+            state.replies[0] = Some(reply);
+            state.collect_replies();
+        }
+        assert_eq!((state.rate, state.size), (0x13, 3));
+    }
+
+    // A ROM that answers each byte the adapter clocks with the next one
+    // from a script at 0x0200, saving what it gets back from 0xC000 on
+    fn scripted_rom(script: &[u8]) -> Rom {
+        let mut rom = vec![0; 0x8000];
+        // nop, jp $0150
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0169].copy_from_slice(&[
+            0x21, 0x00, 0xC0, // ld hl, $C000
+            0x11, 0x00, 0x02, // ld de, $0200
+            0x1A, // .next: ld a, [de]
+            0x13, // inc de
+            0xE0, 0x01, // ldh [SB], a
+            0x3E, 0x80, // ld a, $80
+            0xE0, 0x02, // ldh [SC], a
+            0xF0, 0x02, // .wait: ldh a, [SC]
+            0xCB, 0x7F, // bit 7, a
+            0x20, 0xFA, // jr nz, .wait
+            0xF0, 0x01, // ldh a, [SB]
+            0x22, // ld [hl+], a
+            0x18, 0xED, // jr .next
+        ]);
+        rom[0x0200..0x0200 + script.len()].copy_from_slice(script);
+        Rom::from_bytes(rom)
+    }
+
+    #[test]
+    fn group_exchanges_bytes_between_four_machines() {
+        let players = (0..MAX_PLAYERS)
+            .map(|player| {
+                Cpu::from_config(Config {
+                    sound_buffer_size: SOUND_BUFFER_SIZE,
+                    sound_sample_rate: SOUND_SAMPLE_RATE,
+                    rom: scripted_rom(&script(player)),
+                    model: None,
+                })
+            })
+            .collect();
+        let mut group = FourPlayerGroup::new(players);
+
+        let bytes = expected(0).len();
+        let ping_bytes = 5 * PING_PACKET_SIZE;
+        let transmission_bytes = bytes - ping_bytes;
+        group.step_cycles(
+            (ping_bytes + 1) * PING_BYTE_CYCLES
+                + transmission_bytes * BYTE_CYCLES,
+        );
+
+        for (player, cpu) in group.players.iter().enumerate() {
+            let received: Vec<u8> = (0..bytes as u16)
+                .map(|i| cpu.mem.read(&cpu.ints, &cpu.gpu, 0xC000 + i))
+                .collect();
+            assert_eq!(received, expected(player), "Player {}", player + 1);
+        }
+    }
+}
@@ -0,0 +1,681 @@
+// A GDB remote serial protocol server, so ROMs can be debugged with
+// gdb-multiarch or anything else that speaks RSP.
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+// Breakpoints and watchpoints go through the CPU's `Debugger`.
+// GDB doesn't know the SM83, so we describe its registers ourselves: af, bc,
+// de, hl, sp and pc, all 16-bit little-endian, in that order.
+use crate::cpu::Cpu;
+use crate::debugger::{Breakpoint, StopReason, WatchKind, Watchpoint};
+use crate::disassembler::ByteSource;
+use crate::log;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+// The same as mGBA's stub
+pub const GDB_DEFAULT_PORT: u16 = 2345;
+
+// How long `serve` lets the CPU run between checking for a Ctrl-C
+const SERVE_CHUNK_CYCLES: usize = 70224;
+// Memory reads are capped so replies fit in PACKET_SIZE
+const PACKET_SIZE: usize = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gbrs.sm83">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// A TCP or Unix socket. Reads don't block, writes do.
+trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+pub struct GdbStub {
+    connection: Option<Box<dyn Connection>>,
+    recv_buffer: Vec<u8>,
+    // GDB can ask us to stop acknowledging packets, since TCP is reliable
+    no_ack: bool,
+    // Whether GDB has let the CPU run
+    running: bool,
+    // GDB's access watchpoints are a read and a write watchpoint to us, but
+    // it wants them reported as one
+    access_watchpoints: Vec<Watchpoint>,
+}
+
+impl GdbStub {
+    // Waits for GDB to connect, eg. with `target remote localhost:2345`
+    pub fn host_tcp<A: ToSocketAddrs>(address: A) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(address)?;
+        log!(
+            "[GDB] Waiting for a connection on {}",
+            listener.local_addr()?
+        );
+        let (stream, remote) = listener.accept()?;
+        log!("[GDB] {} connected", remote);
+        stream.set_nodelay(true)?;
+        Ok(GdbStub::from_connection(Box::new(stream)))
+    }
+
+    // The same over a Unix socket, eg. `target remote /tmp/gbrs.sock`
+    #[cfg(unix)]
+    pub fn host_unix<P: AsRef<std::path::Path>>(
+        path: P,
+    ) -> io::Result<GdbStub> {
+        // A stale socket from last time would stop us binding
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        log!(
+            "[GDB] Waiting for a connection on {}",
+            path.as_ref().to_string_lossy()
+        );
+        let (stream, _) = listener.accept()?;
+        log!("[GDB] Connected");
+        Ok(GdbStub::from_connection(Box::new(stream)))
+    }
+
+    fn from_connection(connection: Box<dyn Connection>) -> GdbStub {
+        GdbStub {
+            connection: Some(connection),
+            recv_buffer: vec![],
+            no_ack: false,
+            // GDB expects the target to be stopped when it attaches
+            running: false,
+            access_watchpoints: vec![],
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    // Answers GDB and runs the CPU for up to `max_cycles` if GDB has let it.
+    // Never blocks, so a frontend can call this instead of stepping the CPU
+    // and keep drawing while GDB has it stopped. Returns the cycles run.
+    // Once GDB has gone, the CPU just runs.
+    pub fn poll(&mut self, cpu: &mut Cpu, max_cycles: usize) -> usize {
+        if !self.is_connected() {
+            let mut cycles = 0;
+            while cycles < max_cycles {
+                cycles += cpu.step();
+            }
+            return cycles;
+        }
+
+        self.receive();
+        while let Some(packet) = self.next_packet() {
+            self.handle_packet(cpu, &packet);
+        }
+
+        if !self.running || !self.is_connected() {
+            return 0;
+        }
+
+        let (cycles, reason) = cpu.run_until_stop(max_cycles);
+        if reason != StopReason::CycleLimit {
+            self.running = false;
+            let reply = self.stop_reply(reason);
+            self.send(&reply);
+        }
+        cycles
+    }
+
+    // Blocks until GDB detaches or kills the session
+    pub fn serve(&mut self, cpu: &mut Cpu) {
+        while self.is_connected() {
+            if self.poll(cpu, SERVE_CHUNK_CYCLES) == 0 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if self.connection.take().is_some() {
+            log!("[GDB] Disconnected");
+        }
+        self.running = true;
+    }
+
+    fn send_raw(&mut self, bytes: &[u8]) {
+        let result = match &mut self.connection {
+            Some(connection) => {
+                connection.write_all(bytes).and_then(|_| connection.flush())
+            },
+            None => return,
+        };
+        if result.is_err() {
+            self.disconnect();
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        for &byte in data.as_bytes() {
+            // These would end the packet early, so they're escaped
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                packet.push(b'}');
+                packet.push(byte ^ 0x20);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let checksum = checksum(&packet[1..]);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.send_raw(&packet);
+    }
+
+    // Buffers whatever has arrived without blocking
+    fn receive(&mut self) {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let mut closed = connection.set_nonblocking(true).is_err();
+        let mut chunk = [0; 256];
+        while !closed {
+            match connection.read(&mut chunk) {
+                Ok(0) => closed = true,
+                Ok(n) => self.recv_buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(_) => closed = true,
+            }
+        }
+        closed |= connection.set_nonblocking(false).is_err();
+
+        if closed {
+            self.disconnect();
+        }
+    }
+
+    // Takes the next whole packet out of the buffer, acknowledging it.
+    // A Ctrl-C from GDB comes out as "\x03".
+    fn next_packet(&mut self) -> Option<String> {
+        loop {
+            match self.recv_buffer.first()? {
+                b'$' => break,
+                0x03 => {
+                    self.recv_buffer.remove(0);
+                    return Some(String::from("\x03"));
+                },
+                // Acks, and any noise between packets
+                _ => {
+                    self.recv_buffer.remove(0);
+                },
+            }
+        }
+
+        let end = self.recv_buffer.iter().position(|&b| b == b'#')?;
+        if self.recv_buffer.len() < end + 3 {
+            return None;
+        }
+
+        let packet: Vec<u8> = self.recv_buffer.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let expected = core::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if !self.no_ack {
+            if expected != Some(checksum(data)) {
+                self.send_raw(b"-");
+                return self.next_packet();
+            }
+            self.send_raw(b"+");
+        }
+        Some(String::from_utf8_lossy(data).to_string())
+    }
+
+    fn handle_packet(&mut self, cpu: &mut Cpu, packet: &str) {
+        if packet == "\x03" {
+            if self.running {
+                self.running = false;
+                self.send(&format!("S{:02x}", SIGINT));
+            }
+            return;
+        }
+
+        // Commands are a single ASCII character. Anything else, including an
+        // empty packet, gets the empty "not supported" reply.
+        let command = packet.as_bytes().first().copied().unwrap_or(0);
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            b'?' => format!("S{:02x}", SIGTRAP),
+            b'g' => [0, 1, 2, 3, 4, 5]
+                .iter()
+                .map(|&register| hex_u16(read_register(cpu, register)))
+                .collect(),
+            b'G' => {
+                let values = (0..6).map(|register| {
+                    args.get(register * 4..register * 4 + 4)
+                        .and_then(parse_register_value)
+                });
+                for (register, value) in values.enumerate() {
+                    if let Some(value) = value {
+                        write_register(cpu, register, value);
+                    }
+                }
+                String::from("OK")
+            },
+            b'p' => match usize::from_str_radix(args, 16) {
+                Ok(register) if register < 6 => {
+                    hex_u16(read_register(cpu, register))
+                },
+                _ => String::from("E01"),
+            },
+            b'P' => {
+                let parsed = args.split_once('=').and_then(|(reg, value)| {
+                    Some((
+                        usize::from_str_radix(reg, 16).ok()?,
+                        parse_register_value(value)?,
+                    ))
+                });
+                match parsed {
+                    Some((register, value)) if register < 6 => {
+                        write_register(cpu, register, value);
+                        String::from("OK")
+                    },
+                    _ => String::from("E01"),
+                }
+            },
+            b'm' => match parse_address_length(args) {
+                Some((address, length)) => (0..length.min(PACKET_SIZE / 2))
+                    .map(|offset| {
+                        let address = address.wrapping_add(offset as u16);
+                        format!("{:02x}", cpu.read_byte(address))
+                    })
+                    .collect(),
+                None => String::from("E01"),
+            },
+            b'M' => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_address_length(range)?;
+                    let data = data.as_bytes().get(..length * 2)?;
+                    Some((address, parse_hex_bytes(data)?))
+                });
+                match parsed {
+                    Some((address, bytes)) => {
+                        for (offset, byte) in bytes.into_iter().enumerate() {
+                            let address = address.wrapping_add(offset as u16);
+                            cpu.mem.write(
+                                &mut cpu.ints,
+                                &mut cpu.gpu,
+                                address,
+                                byte,
+                            );
+                        }
+                        String::from("OK")
+                    },
+                    _ => String::from("E01"),
+                }
+            },
+            b'c' => {
+                if let Some(address) = parse_address(args) {
+                    cpu.regs.pc = address;
+                }
+                self.running = true;
+                // The reply comes when the CPU stops
+                return;
+            },
+            b's' => {
+                if let Some(address) = parse_address(args) {
+                    cpu.regs.pc = address;
+                }
+                let (_, reason) = cpu.step_into();
+                self.stop_reply(reason)
+            },
+            b'Z' | b'z' => self.set_breakpoint(cpu, command == b'Z', args),
+            b'q' => self.query(args),
+            b'Q' if args == "StartNoAckMode" => {
+                self.send("OK");
+                self.no_ack = true;
+                return;
+            },
+            b'H' | b'T' => String::from("OK"),
+            b'D' => {
+                self.send("OK");
+                self.disconnect();
+                return;
+            },
+            b'k' => {
+                self.disconnect();
+                return;
+            },
+            b'v' if args == "Kill" || args.starts_with("Kill;") => {
+                self.send("OK");
+                self.disconnect();
+                return;
+            },
+            // An empty reply tells GDB we don't support something
+            _ => String::new(),
+        };
+        self.send(&reply);
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+
+        if let Some(range) =
+            query.strip_prefix("Xfer:features:read:target.xml:")
+        {
+            return match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + length).min(TARGET_XML.len());
+                    // 'l' means this is the last of it
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                },
+                None => String::from("E01"),
+            };
+        }
+
+        match query {
+            // We're always attached to something that was already running
+            "Attached" => String::from("1"),
+            // There's only ever one thread
+            "C" => String::from("QC1"),
+            "fThreadInfo" => String::from("m1"),
+            "sThreadInfo" => String::from("l"),
+            "Symbol::" => String::from("OK"),
+            _ => String::new(),
+        }
+    }
+
+    // Z/z TYPE,ADDRESS,KIND. For watchpoints, KIND is how many bytes.
+    fn set_breakpoint(
+        &mut self,
+        cpu: &mut Cpu,
+        add: bool,
+        args: &str,
+    ) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let address = parts.next().and_then(parse_address);
+        let length = parts
+            .next()
+            .and_then(|length| u16::from_str_radix(length, 16).ok())
+            .unwrap_or(1)
+            .max(1);
+
+        let address = match address {
+            Some(address) => address,
+            None => return String::from("E01"),
+        };
+        let watchpoint = |kind| Watchpoint {
+            start: address,
+            end: address.saturating_add(length - 1),
+            kind,
+        };
+        let debugger = &mut cpu.debugger;
+
+        match (kind, add) {
+            // Software and hardware breakpoints are the same thing to us
+            (Some("0") | Some("1"), true) => {
+                debugger.add_breakpoint(Breakpoint::new(address))
+            },
+            (Some("0") | Some("1"), false) => {
+                debugger.remove_breakpoint(&Breakpoint::new(address))
+            },
+            (Some("2"), true) => {
+                debugger.add_watchpoint(watchpoint(WatchKind::Write))
+            },
+            (Some("2"), false) => {
+                debugger.remove_watchpoint(&watchpoint(WatchKind::Write))
+            },
+            (Some("3"), true) => {
+                debugger.add_watchpoint(watchpoint(WatchKind::Read))
+            },
+            (Some("3"), false) => {
+                debugger.remove_watchpoint(&watchpoint(WatchKind::Read))
+            },
+            (Some("4"), true) => {
+                debugger.add_watchpoint(watchpoint(WatchKind::Read));
+                debugger.add_watchpoint(watchpoint(WatchKind::Write));
+                self.access_watchpoints.push(watchpoint(WatchKind::Read));
+            },
+            (Some("4"), false) => {
+                debugger.remove_watchpoint(&watchpoint(WatchKind::Read));
+                debugger.remove_watchpoint(&watchpoint(WatchKind::Write));
+                let access = watchpoint(WatchKind::Read);
+                self.access_watchpoints.retain(|w| *w != access);
+            },
+            _ => return String::new(),
+        }
+        String::from("OK")
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Watchpoint { kind, address, .. } => {
+                let is_access = self
+                    .access_watchpoints
+                    .iter()
+                    .any(|w| (w.start..=w.end).contains(&address));
+                let name = match kind {
+                    _ if is_access => "awatch",
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Execute => {
+                        return format!("S{:02x}", SIGTRAP);
+                    },
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, address)
+            },
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+}
+
+fn read_register(cpu: &Cpu, register: usize) -> u16 {
+    match register {
+        0 => cpu.regs.get_af(),
+        1 => cpu.regs.get_bc(),
+        2 => cpu.regs.get_de(),
+        3 => cpu.regs.get_hl(),
+        4 => cpu.regs.sp,
+        _ => cpu.regs.pc,
+    }
+}
+
+fn write_register(cpu: &mut Cpu, register: usize, value: u16) {
+    match register {
+        0 => cpu.regs.set_af(value),
+        1 => cpu.regs.set_bc(value),
+        2 => cpu.regs.set_de(value),
+        3 => cpu.regs.set_hl(value),
+        4 => cpu.regs.sp = value,
+        _ => cpu.regs.pc = value,
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+// Registers go over the wire in target byte order
+fn hex_u16(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+fn parse_register_value(hex: &str) -> Option<u16> {
+    match hex.len() {
+        // Register values, little-endian
+        4 if hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            let low = u8::from_str_radix(&hex[..2], 16).ok()?;
+            let high = u8::from_str_radix(&hex[2..], 16).ok()?;
+            Some(u16::from_le_bytes([low, high]))
+        },
+        _ => None,
+    }
+}
+
+// Pairs of hex digits, one per byte, as memory is sent
+fn parse_hex_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+    hex.chunks_exact(2)
+        .map(|pair| {
+            let high = (pair[0] as char).to_digit(16)?;
+            let low = (pair[1] as char).to_digit(16)?;
+            Some((high << 4 | low) as u8)
+        })
+        .collect()
+}
+
+// Addresses are ordinary big-endian hex
+fn parse_address(hex: &str) -> Option<u16> {
+    u32::from_str_radix(hex, 16)
+        .ok()
+        .map(|address| address as u16)
+}
+
+// ADDRESS,LENGTH
+fn parse_address_length(args: &str) -> Option<(u16, usize)> {
+    let (address, length) = args.split_once(',')?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    Some((parse_address(address)?, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::constants::*;
+    use crate::memory::rom::Rom;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Hands GDB's side of the conversation to the stub, and keeps what it
+    // sends back
+    struct FakeGdb {
+        incoming: Vec<u8>,
+        sent: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for FakeGdb {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.incoming.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.incoming.len());
+            buf[..n].copy_from_slice(&self.incoming[..n]);
+            self.incoming.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeGdb {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for FakeGdb {
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum(data)).as_bytes());
+        packet
+    }
+
+    // Sends GDB's packets and returns the stub's replies, without acks
+    fn exchange(cpu: &mut Cpu, packets: &[&[u8]]) -> Vec<String> {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let mut stub = GdbStub::from_connection(Box::new(FakeGdb {
+            incoming: packets.iter().flat_map(|data| packet(data)).collect(),
+            sent: sent.clone(),
+        }));
+        stub.poll(cpu, 0);
+
+        let sent = String::from_utf8(sent.borrow().clone()).unwrap();
+        sent.split('$')
+            .skip(1)
+            .map(|reply| reply.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    fn cpu() -> Cpu {
+        Cpu::from_config(Config {
+            sound_buffer_size: SOUND_BUFFER_SIZE,
+            sound_sample_rate: SOUND_SAMPLE_RATE,
+            rom: Rom::from_bytes(vec![0; 0x8000]),
+            model: None,
+        })
+    }
+
+    #[test]
+    fn odd_packets_get_an_empty_reply() {
+        let mut cpu = cpu();
+        let replies = exchange(&mut cpu, &[b"", "é?".as_bytes(), b"\xFF"]);
+        assert_eq!(replies, ["", "", ""]);
+    }
+
+    #[test]
+    fn memory_is_written_and_read_as_hex() {
+        let mut cpu = cpu();
+        let replies =
+            exchange(&mut cpu, &[b"MC000,3:12ab3c", b"mC000,3", b"g"]);
+        assert_eq!(replies[0], "OK");
+        assert_eq!(replies[1], "12ab3c");
+        assert_eq!(replies[2].len(), 6 * 4);
+    }
+
+    #[test]
+    fn bad_memory_writes_are_refused() {
+        let mut cpu = cpu();
+        let replies = exchange(
+            &mut cpu,
+            &[
+                "MC000,2:1é".as_bytes(),
+                b"MC000,2:12",
+                b"MC000,1:zz",
+                b"mC000,1",
+            ],
+        );
+        assert_eq!(replies, ["E01", "E01", "E01", "00"]);
+    }
+}
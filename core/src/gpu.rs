@@ -1,17 +1,24 @@
 use crate::cgb_dma::CgbDmaConfig;
 use crate::colour::colour::Colour;
+use crate::colour::colour_correction::{rgb555_from_colour, ColourCorrection};
+use crate::colour::dmg_palettes::{DmgPalettePreset, DmgPalettes};
+use crate::colour::frame_blending::{FrameBlender, FrameBlending};
 use crate::colour::grey_shades;
-use crate::colour::grey_shades::colour_from_grey_shade_id;
 use crate::combine_u8;
 use crate::constants::*;
+use crate::cpu::EmulationTarget;
 use crate::interrupts::*;
 use crate::lcd::*;
 use crate::log;
 use crate::memory::memory::Memory;
 use crate::memory::ram::Ram;
+use crate::video::{PixelFormat, VideoSink};
 
 use smallvec::SmallVec;
 
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, vec::Vec};
+
 #[derive(Clone)]
 pub struct Sprite {
     pub y_pos: i32,
@@ -30,11 +37,35 @@ pub struct Sprite {
 
 pub struct Gpu {
     cgb_features: bool,
+    // DMG games on a CGB get their shades coloured by palette RAM
+    dmg_compatibility: bool,
+    // Otherwise, DMG shades are turned into these colours
+    dmg_palettes: DmgPalettes,
+    // How colours from palette RAM are shown. Only applied to finished
+    // frames, so it never affects emulation.
+    colour_correction: ColourCorrection,
+    colour_correction_lut: Vec<Colour>,
+    // Blends finished frames together to mimic the LCD's slow response
+    frame_blender: FrameBlender,
     // This is the WIP frame that the GPU draws to
-    frame: [Colour; SCREEN_BUFFER_SIZE],
+    frame: Box<[Colour; SCREEN_BUFFER_SIZE]>,
     // This is the last rendered frame displayed on the LCD, only updated
-    // in VBlank. GUI implementations can read it to show the display.
-    pub finished_frame: [Colour; SCREEN_BUFFER_SIZE],
+    // in VBlank (when it's swapped with `frame`). GUI implementations can
+    // read it to show the display.
+    pub finished_frame: Box<[Colour; SCREEN_BUFFER_SIZE]>,
+    // How many frames have finished since boot
+    pub frame_count: u64,
+    // Makes LY read as 0x90 (the first line of VBlank) to the CPU, which
+    // Gameboy Doctor's reference traces expect. See trace.rs.
+    pub stub_ly: bool,
+    // The DMG shade (0-3) of each pixel in `frame`, which the Super GameBoy
+    // colours in
+    shade_frame: [u8; SCREEN_BUFFER_SIZE],
+    // `shade_frame` as of the last VBlank, to go with `finished_frame`
+    finished_shade_frame: [u8; SCREEN_BUFFER_SIZE],
+    // Given each finished frame in its own pixel format
+    video_sink: Option<Box<dyn VideoSink>>,
+    video_sink_buffer: Vec<u8>,
 
     // X and Y of background position
     scy: u8,
@@ -162,6 +193,7 @@ impl Gpu {
             0xFF41 => u8::from(self.status),
             0xFF42 => self.scy,
             0xFF43 => self.scx,
+            0xFF44 if self.stub_ly => 0x90,
             0xFF44 => self.ly,
             0xFF45 => self.lyc,
 
@@ -312,7 +344,7 @@ impl Gpu {
         }
     }
 
-    fn enter_vblank(&mut self, ints: &mut Interrupts) {
+    fn enter_vblank(&mut self, ints: &mut Interrupts, mem: &mut Memory) {
         ints.raise_interrupt(InterruptReason::VBlank);
 
         // TODO: This seems like odd behaviour to me.
@@ -320,7 +352,36 @@ impl Gpu {
             ints.raise_interrupt(InterruptReason::LCDStat);
         }
 
-        self.finished_frame.clone_from(&self.frame);
+        match &mut mem.sgb {
+            Some(sgb) => {
+                sgb.finish_frame(&self.shade_frame, &mut self.finished_frame)
+            },
+            None => core::mem::swap(&mut self.frame, &mut self.finished_frame),
+        }
+
+        // DMG palettes are already the colours they should be shown as
+        let from_palette_ram = self.cgb_features || self.dmg_compatibility;
+        if from_palette_ram && !self.colour_correction_lut.is_empty() {
+            for pixel in self.finished_frame.iter_mut() {
+                *pixel = self.colour_correction_lut
+                    [rgb555_from_colour(pixel) as usize];
+            }
+        }
+
+        self.frame_blender.blend_frame(&mut self.finished_frame);
+        self.finished_shade_frame = self.shade_frame;
+        self.frame_count += 1;
+
+        if let Some(sink) = &mut self.video_sink {
+            let format = sink.pixel_format();
+            encode_frame(
+                &self.finished_frame,
+                &self.finished_shade_frame,
+                format,
+                &mut self.video_sink_buffer,
+            );
+            sink.frame_ready(&self.video_sink_buffer);
+        }
     }
 
     fn run_ly_compare(&mut self, ints: &mut Interrupts) {
@@ -387,7 +448,7 @@ impl Gpu {
             self.run_ly_compare(ints);
             // Done with frame, enter VBlank
             if self.ly == gpu_timing::VBLANK_ON {
-                self.enter_vblank(ints);
+                self.enter_vblank(ints, mem);
                 self.status.set_mode(LcdMode::VBlank);
             } else {
                 if mode != LcdMode::OAMSearch {
@@ -442,20 +503,21 @@ impl Gpu {
         let uy = y as usize;
         let idx = uy * SCREEN_WIDTH + ux;
 
-        let bg_col: Colour;
-        let bg_col_id = if self.cgb_features || self.control.bg_display {
-            let (new_col, id) = self.get_background_colour_at(ints, mem, x, y);
-            bg_col = new_col;
-            id
-        } else {
-            bg_col = grey_shades::white();
-            0
-        };
+        let (bg_col, bg_col_id, bg_shade) =
+            if self.cgb_features || self.control.bg_display {
+                let (col, id) = self.get_background_colour_at(ints, mem, x, y);
+                (col, id, self.get_shade_id(id, self.bg_pallette))
+            } else {
+                (self.bg_shade_colour(mem, 0), 0, 0)
+            };
 
         // If there's a non-transparent sprite here, use its colour
-        let s_col = self.get_sprite_colour_at(mem, bg_col, bg_col_id, x, y);
+        let (col, shade) = self
+            .get_sprite_colour_at(mem, bg_col_id, x, y)
+            .unwrap_or((bg_col, bg_shade));
 
-        self.frame[idx] = s_col;
+        self.frame[idx] = col;
+        self.shade_frame[idx] = shade;
     }
 
     fn get_colour_id_in_line(&self, tile_line: u16, subx: u8) -> u16 {
@@ -471,15 +533,36 @@ impl Gpu {
         pixel_colour_id
     }
 
-    fn get_shade_from_colour_id(
-        &self,
-        pixel_colour_id: u16,
-        palette: u8,
-    ) -> Colour {
+    fn get_shade_id(&self, pixel_colour_id: u16, palette: u8) -> u8 {
         let shift_2 = pixel_colour_id * 2;
-        let shade = (palette & (0b11 << shift_2)) >> shift_2;
+        (palette & (0b11 << shift_2)) >> shift_2
+    }
 
-        colour_from_grey_shade_id(shade)
+    // The boot rom leaves the compatibility palettes in BG palette 0, and OBJ
+    // palettes 0 & 1 for OBP0 & OBP1
+    fn bg_shade_colour(&self, mem: &Memory, shade: u8) -> Colour {
+        if self.dmg_compatibility {
+            mem.palette_ram.get_bg_palette_colour(0, shade as u16)
+        } else {
+            self.dmg_palettes.bg[shade as usize]
+        }
+    }
+
+    fn obj_shade_colour(
+        &self,
+        mem: &Memory,
+        use_palette_0: bool,
+        shade: u8,
+    ) -> Colour {
+        if self.dmg_compatibility {
+            let palette_id = if use_palette_0 { 0 } else { 1 };
+            mem.palette_ram
+                .get_obj_palette_colour(palette_id, shade as u16)
+        } else if use_palette_0 {
+            self.dmg_palettes.obj0[shade as usize]
+        } else {
+            self.dmg_palettes.obj1[shade as usize]
+        }
     }
 
     fn get_background_colour_at(
@@ -576,24 +659,21 @@ impl Gpu {
                 .get_bg_palette_colour(tile_metadata.palette as u16, col_id);
             (colour, col_id)
         } else {
-            (
-                self.get_shade_from_colour_id(col_id, self.bg_pallette),
-                col_id,
-            )
+            let shade = self.get_shade_id(col_id, self.bg_pallette);
+            (self.bg_shade_colour(mem, shade), col_id)
         }
     }
 
     fn get_sprite_colour_at(
         &self,
         mem: &Memory,
-        bg_col: Colour,
         bg_col_id: u16,
         x: u8,
         y: u8,
-    ) -> Colour {
+    ) -> Option<(Colour, u8)> {
         // Sprites are hidden for this scanline
         if !self.control.obj_enable {
-            return bg_col;
+            return None;
         }
 
         let sprite_height = if self.control.obj_size { 16 } else { 8 };
@@ -601,7 +681,7 @@ impl Gpu {
         let ix = x as i32;
         let iy = y as i32;
 
-        let mut maybe_colour: Option<Colour> = None;
+        let mut maybe_colour: Option<(Colour, u8)> = None;
         let mut min_x: i32 = SCREEN_WIDTH as i32 + 8;
         for sprite in &self.sprites_on_line {
             let mut above_bg = sprite.above_bg;
@@ -677,7 +757,7 @@ impl Gpu {
                             sprite.cgb_palette as u16,
                             col_id,
                         );
-                        maybe_colour = Some(colour)
+                        maybe_colour = Some((colour, 0))
                     } else {
                         let palette = if sprite.use_palette_0 {
                             self.sprite_pallete_1
@@ -686,17 +766,19 @@ impl Gpu {
                         };
 
                         min_x = sprite.x_pos;
-                        maybe_colour =
-                            Some(self.get_shade_from_colour_id(col_id, palette))
+                        let shade = self.get_shade_id(col_id, palette);
+                        let colour = self.obj_shade_colour(
+                            mem,
+                            sprite.use_palette_0,
+                            shade,
+                        );
+                        maybe_colour = Some((colour, shade))
                     }
                 }
             }
         }
 
-        match maybe_colour {
-            Some(col) => col,
-            None => bg_col,
-        }
+        maybe_colour
     }
 
     // Will be used later for get_sprite_pixel
@@ -715,24 +797,104 @@ impl Gpu {
         }
     }
 
+    // Takes effect from the next frame. CGB games, and DMG games running in
+    // colour on a CGB or SGB, aren't affected.
+    pub fn set_dmg_palettes(&mut self, palettes: DmgPalettes) {
+        self.dmg_palettes = palettes;
+    }
+
+    pub fn dmg_palettes(&self) -> DmgPalettes {
+        self.dmg_palettes
+    }
+
+    // Takes effect from the next frame
+    pub fn set_colour_correction(&mut self, mode: ColourCorrection) {
+        if mode != self.colour_correction {
+            self.colour_correction = mode;
+            self.colour_correction_lut = mode.build_lut();
+        }
+    }
+
+    pub fn colour_correction(&self) -> ColourCorrection {
+        self.colour_correction
+    }
+
+    // Takes effect from the next frame
+    pub fn set_frame_blending(&mut self, mode: FrameBlending) {
+        self.frame_blender.set_mode(mode);
+    }
+
+    pub fn frame_blending(&self) -> FrameBlending {
+        self.frame_blender.mode()
+    }
+
+    // Sprite attributes, 4 bytes for each of the 40 sprites
+    pub fn oam_contents(&self) -> &[u8] {
+        &self.oam.bytes
+    }
+
+    // The scanline being drawn (LY), 144 and up during VBlank
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    // Gives `sink` every frame from now on, returning the old one if there
+    // was one
+    pub fn set_video_sink(
+        &mut self,
+        sink: Box<dyn VideoSink>,
+    ) -> Option<Box<dyn VideoSink>> {
+        self.video_sink.replace(sink)
+    }
+
+    pub fn remove_video_sink(&mut self) -> Option<Box<dyn VideoSink>> {
+        self.video_sink.take()
+    }
+
+    // For ports that would rather fetch the last frame than have a sink.
+    // `out` is resized to fit, so the same buffer can be reused.
+    pub fn encode_finished_frame(
+        &self,
+        format: PixelFormat,
+        out: &mut Vec<u8>,
+    ) {
+        encode_frame(
+            &self.finished_frame,
+            &self.finished_shade_frame,
+            format,
+            out,
+        );
+    }
+
     pub fn get_rgba_frame(&self) -> [u8; SCREEN_RGBA_SLICE_SIZE] {
         let mut out_array = [0; SCREEN_RGBA_SLICE_SIZE];
-        for i in 0..SCREEN_BUFFER_SIZE {
-            let start = i * 4;
-            out_array[start] = self.finished_frame[i].red;
-            out_array[start + 1] = self.finished_frame[i].green;
-            out_array[start + 2] = self.finished_frame[i].blue;
-            out_array[start + 3] = 0xFF;
+        for (colour, out) in self
+            .finished_frame
+            .iter()
+            .zip(out_array.chunks_exact_mut(4))
+        {
+            PixelFormat::Rgba8888.encode(colour, 0, out);
         }
         out_array
     }
 
-    pub fn new(cgb_features: bool) -> Gpu {
+    pub fn new(target: &EmulationTarget) -> Gpu {
         let empty_frame = [grey_shades::white(); SCREEN_BUFFER_SIZE];
         Gpu {
-            cgb_features,
-            frame: empty_frame,
-            finished_frame: empty_frame.clone(),
+            cgb_features: target.has_cgb_features(),
+            dmg_compatibility: matches!(target, EmulationTarget::CgbDmgMode),
+            dmg_palettes: DmgPalettePreset::Grey.palettes(),
+            colour_correction: ColourCorrection::Raw,
+            colour_correction_lut: Vec::new(),
+            frame_blender: FrameBlender::new(),
+            frame: Box::new(empty_frame),
+            finished_frame: Box::new(empty_frame),
+            frame_count: 0,
+            stub_ly: false,
+            shade_frame: [0; SCREEN_BUFFER_SIZE],
+            finished_shade_frame: [0; SCREEN_BUFFER_SIZE],
+            video_sink: None,
+            video_sink_buffer: Vec::new(),
             window_line_counter: 0,
             scy: 0,
             scx: 0,
@@ -755,3 +917,20 @@ impl Gpu {
         }
     }
 }
+
+fn encode_frame(
+    frame: &[Colour; SCREEN_BUFFER_SIZE],
+    shade_frame: &[u8; SCREEN_BUFFER_SIZE],
+    format: PixelFormat,
+    out: &mut Vec<u8>,
+) {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    out.resize(SCREEN_BUFFER_SIZE * bytes_per_pixel, 0);
+    for ((colour, shade), pixel) in frame
+        .iter()
+        .zip(shade_frame.iter())
+        .zip(out.chunks_exact_mut(bytes_per_pixel))
+    {
+        format.encode(colour, *shade, pixel);
+    }
+}
@@ -0,0 +1,204 @@
+// In-ROM debugging conventions from BGB and no$gmb that homebrew uses.
+// `ld b,b` (0x40) is a software breakpoint, which stops `Cpu::run_until_stop`
+// like any other breakpoint. `ld d,d` (0x52) followed by this block prints
+// a debug message:
+//   ld d, d
+//   jr .end
+//   dw $6464
+//   dw $0000
+//   db "A is %A%, HL is %HL%"
+// .end:
+// Only the inline message form is supported, not no$gmb's pointer form.
+use crate::debugger::Register;
+use crate::registers::Registers;
+use core::fmt::Write;
+
+#[cfg(not(feature = "std"))]
+use alloc::string::String;
+
+pub const SOFTWARE_BREAKPOINT_OPCODE: u8 = 0x40;
+pub const DEBUG_MESSAGE_OPCODE: u8 = 0x52;
+
+// The JR opcode and signature between `ld d,d` and the message text
+const JR_OPCODE: u8 = 0x18;
+const MESSAGE_SIGNATURE: [u8; 4] = [0x64, 0x64, 0x00, 0x00];
+
+pub struct HomebrewDebug {
+    // Stop the debugger on `ld b,b`. Outside of the debugger they're logged.
+    // Off by default as some commercial games use `ld b,b` as a NOP.
+    pub breakpoints: bool,
+    // Log `ld d,d` messages through `Callbacks::log`
+    pub messages: bool,
+
+    // For `%TOTALCLKS%` and `%LASTCLKS%`
+    pub(crate) cycles: u64,
+    last_message_cycles: u64,
+}
+
+// The machine state that message expressions can refer to
+pub struct MessageContext<'a> {
+    pub regs: &'a Registers,
+    pub ime: bool,
+    pub ly: u8,
+    pub rom_bank: u16,
+    pub double_speed: bool,
+}
+
+impl HomebrewDebug {
+    // `header` is the 6 bytes after the `ld d,d`. Gives back how long the
+    // message text is, or `None` if they aren't a debug message block.
+    pub fn message_length(header: [u8; 6]) -> Option<u16> {
+        let jr_offset = header[1];
+        if header[0] != JR_OPCODE
+            || header[2..] != MESSAGE_SIGNATURE
+            || !(4..0x80).contains(&jr_offset)
+        {
+            return None;
+        }
+        Some(jr_offset as u16 - 4)
+    }
+
+    // Expands the `%EXPRESSION%`s in a message. Unknown expressions are left
+    // as they are so typos are easy to spot.
+    pub fn format_message(
+        &mut self,
+        text: &[u8],
+        context: &MessageContext,
+    ) -> String {
+        let text = String::from_utf8_lossy(text);
+        let mut output = String::new();
+        let mut parts = text.split('%');
+
+        if let Some(first) = parts.next() {
+            output.push_str(first);
+        }
+        while let Some(expression) = parts.next() {
+            // A lone `%` at the end of the message
+            let Some(rest) = parts.next() else {
+                output.push('%');
+                output.push_str(expression);
+                break;
+            };
+            if !self.write_expression(&mut output, expression, context) {
+                output.push('%');
+                output.push_str(expression);
+                output.push('%');
+            }
+            output.push_str(rest);
+        }
+
+        self.last_message_cycles = self.cycles;
+        output
+    }
+
+    fn write_expression(
+        &self,
+        output: &mut String,
+        expression: &str,
+        context: &MessageContext,
+    ) -> bool {
+        let regs = context.regs;
+        let flag = |bit: u8| (regs.f >> bit) & 1;
+        let name = expression.to_ascii_lowercase();
+
+        let _ = match name.as_str() {
+            "zf" => write!(output, "{}", flag(7)),
+            "nf" => write!(output, "{}", flag(6)),
+            "hf" => write!(output, "{}", flag(5)),
+            "cf" => write!(output, "{}", flag(4)),
+            "ime" => write!(output, "{}", context.ime as u8),
+            "ly" | "scanline" => write!(output, "{}", context.ly),
+            "rombank" => write!(output, "{}", context.rom_bank),
+            "doublespeed" => write!(output, "{}", context.double_speed as u8),
+            "totalclks" => write!(output, "{}", self.cycles),
+            "lastclks" => {
+                write!(output, "{}", self.cycles - self.last_message_cycles)
+            },
+            _ => match Register::from_name(&name) {
+                Some(register) if register.name().len() == 1 => {
+                    write!(output, "${:02X}", register.read(regs))
+                },
+                Some(register) => {
+                    write!(output, "${:04X}", register.read(regs))
+                },
+                None => return false,
+            },
+        };
+        true
+    }
+
+    pub fn new() -> HomebrewDebug {
+        HomebrewDebug {
+            breakpoints: false,
+            messages: true,
+            cycles: 0,
+            last_message_cycles: 0,
+        }
+    }
+}
+
+impl Default for HomebrewDebug {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::EmulationTarget;
+
+    #[test]
+    fn message_length_checks_the_block() {
+        // jr .end, dw $6464, dw $0000, db "Hi"
+        let valid = [JR_OPCODE, 6, 0x64, 0x64, 0x00, 0x00];
+        assert_eq!(HomebrewDebug::message_length(valid), Some(2));
+        let empty = [JR_OPCODE, 4, 0x64, 0x64, 0x00, 0x00];
+        assert_eq!(HomebrewDebug::message_length(empty), Some(0));
+
+        let bad_signature = [JR_OPCODE, 6, 0x64, 0x64, 0x00, 0x01];
+        assert_eq!(HomebrewDebug::message_length(bad_signature), None);
+        let not_a_jr = [0x00, 6, 0x64, 0x64, 0x00, 0x00];
+        assert_eq!(HomebrewDebug::message_length(not_a_jr), None);
+        // Jumps that land inside the signature, or backwards
+        let short_jump = [JR_OPCODE, 3, 0x64, 0x64, 0x00, 0x00];
+        assert_eq!(HomebrewDebug::message_length(short_jump), None);
+        let backwards = [JR_OPCODE, 0xFE, 0x64, 0x64, 0x00, 0x00];
+        assert_eq!(HomebrewDebug::message_length(backwards), None);
+    }
+
+    #[test]
+    fn format_message_expands_expressions() {
+        let mut regs = Registers::new(&EmulationTarget::Dmg);
+        regs.a = 0x3C;
+        (regs.h, regs.l) = (0x12, 0x34);
+        regs.f = 0b1000_0000;
+        let context = MessageContext {
+            regs: &regs,
+            ime: true,
+            ly: 90,
+            rom_bank: 3,
+            double_speed: false,
+        };
+        let mut homebrew = HomebrewDebug::new();
+
+        let message = homebrew.format_message(
+            b"A=%A% HL=%hl% Z=%ZF% C=%CF% %FOO% 100%",
+            &context,
+        );
+        assert_eq!(message, "A=$3C HL=$1234 Z=1 C=0 %FOO% 100%");
+        assert_eq!(
+            homebrew.format_message(b"%LY% %ROMBANK% %IME%", &context),
+            "90 3 1"
+        );
+
+        // Clocks since the last message
+        homebrew.cycles = 100;
+        assert_eq!(homebrew.format_message(b"%LASTCLKS%", &context), "100");
+        homebrew.cycles = 150;
+        assert_eq!(
+            homebrew.format_message(b"%LASTCLKS% %TOTALCLKS%", &context),
+            "50 150"
+        );
+    }
+}
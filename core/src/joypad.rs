@@ -4,10 +4,34 @@ enum JoypadReadoutMode {
     Neither,
 }
 
+// The Super GameBoy can read up to 4 controllers after MLT_REQ
+pub const MAX_SGB_PLAYERS: usize = 4;
+
+// Buttons for players 2-4 on the Super GameBoy
+#[derive(Clone, Copy, Default)]
+pub struct ExtraPlayerButtons {
+    pub up_pressed: bool,
+    pub down_pressed: bool,
+    pub left_pressed: bool,
+    pub right_pressed: bool,
+    pub a_pressed: bool,
+    pub b_pressed: bool,
+    pub start_pressed: bool,
+    pub select_pressed: bool,
+}
+
 // TODO: Raise the Joypad interrupt
 pub struct Joypad {
     readout_mode: JoypadReadoutMode,
 
+    // Only ever above 1 on the Super GameBoy. Reads with neither line selected
+    // return which player we're reading, and deselecting both lines moves on
+    // to the next one.
+    player_count: usize,
+    current_player: usize,
+    last_write: u8,
+    pub extra_players: [ExtraPlayerButtons; MAX_SGB_PLAYERS - 1],
+
     // The GUI writes these values directly via the keyboard
     // Every frame.
     pub up_pressed: bool,
@@ -25,6 +49,13 @@ impl Joypad {
     pub fn write(&mut self, n: u8) {
         let masked = n & 0b0011_0000;
 
+        // The SGB moves on to the next controller when P15 goes high
+        let p15_rising = (masked & !self.last_write) & 0b0010_0000 > 0;
+        if p15_rising && self.player_count > 1 {
+            self.current_player = (self.current_player + 1) % self.player_count;
+        }
+        self.last_write = masked;
+
         self.readout_mode = match masked {
             0b0001_0000 => JoypadReadoutMode::Buttons,
             0b0010_0000 => JoypadReadoutMode::Directions,
@@ -32,20 +63,45 @@ impl Joypad {
         }
     }
 
+    // Set by the SGB's MLT_REQ command
+    pub fn set_player_count(&mut self, player_count: usize) {
+        self.player_count = player_count.clamp(1, MAX_SGB_PLAYERS);
+        self.current_player = 0;
+    }
+
+    #[inline(always)]
+    fn current_buttons(&self) -> ExtraPlayerButtons {
+        if self.current_player > 0 {
+            return self.extra_players[self.current_player - 1];
+        }
+        ExtraPlayerButtons {
+            up_pressed: self.up_pressed,
+            down_pressed: self.down_pressed,
+            left_pressed: self.left_pressed,
+            right_pressed: self.right_pressed,
+            a_pressed: self.a_pressed,
+            b_pressed: self.b_pressed,
+            start_pressed: self.start_pressed,
+            select_pressed: self.select_pressed,
+        }
+    }
+
     #[inline(always)]
     fn direction_bits(&self) -> u8 {
-        (!self.right_pressed as u8)
-            | ((!self.left_pressed as u8) << 1)
-            | ((!self.up_pressed as u8) << 2)
-            | ((!self.down_pressed as u8) << 3)
+        let buttons = self.current_buttons();
+        (!buttons.right_pressed as u8)
+            | ((!buttons.left_pressed as u8) << 1)
+            | ((!buttons.up_pressed as u8) << 2)
+            | ((!buttons.down_pressed as u8) << 3)
     }
 
     #[inline(always)]
     fn button_bits(&self) -> u8 {
-        (!self.a_pressed as u8)
-            | ((!self.b_pressed as u8) << 1)
-            | ((!self.select_pressed as u8) << 2)
-            | ((!self.start_pressed as u8) << 3)
+        let buttons = self.current_buttons();
+        (!buttons.a_pressed as u8)
+            | ((!buttons.b_pressed as u8) << 1)
+            | ((!buttons.select_pressed as u8) << 2)
+            | ((!buttons.start_pressed as u8) << 3)
     }
 
     #[inline(always)]
@@ -62,7 +118,8 @@ impl Joypad {
         let n = match self.readout_mode {
             JoypadReadoutMode::Buttons => self.button_bits(),
             JoypadReadoutMode::Directions => self.direction_bits(),
-            JoypadReadoutMode::Neither => 0xF,
+            // Games use this to tell which controller they're reading
+            JoypadReadoutMode::Neither => 0xF - self.current_player as u8,
         };
 
         n | self.selection_bits()
@@ -71,6 +128,10 @@ impl Joypad {
     pub fn new() -> Joypad {
         Joypad {
             readout_mode: JoypadReadoutMode::Buttons,
+            player_count: 1,
+            current_player: 0,
+            last_write: 0b0011_0000,
+            extra_players: [ExtraPlayerButtons::default(); MAX_SGB_PLAYERS - 1],
             up_pressed: false,
             down_pressed: false,
             left_pressed: false,
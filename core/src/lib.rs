@@ -4,6 +4,9 @@
 extern crate alloc;
 
 pub mod alu;
+#[cfg(feature = "std")]
+pub mod bgb_link;
+pub mod bus;
 pub mod callbacks;
 pub mod cartridge;
 pub mod cgb_dma;
@@ -11,12 +14,27 @@ pub mod colour; // innit bruv
 pub mod config;
 pub mod constants;
 pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod four_player_adapter;
+#[cfg(feature = "std")]
+pub mod gdb_stub;
 pub mod gpu;
 pub mod helpers;
+pub mod homebrew_debug;
 pub mod interrupts;
 pub mod joypad;
 pub mod lcd;
+pub mod link_cable;
 pub mod memory;
+pub mod printer;
 pub mod registers;
+pub mod scaling;
+pub mod screenshot;
 pub mod serial_cable;
+pub mod sgb;
 pub mod sound;
+pub mod symbols;
+pub mod test_bus;
+pub mod trace;
+pub mod video;
@@ -0,0 +1,222 @@
+// Two Gameboys in the same process, connected by a link cable.
+// Used for running multiplayer games (Tetris, Pokémon trades etc.) headless.
+use crate::constants::*;
+use crate::cpu::Cpu;
+use crate::serial_cable::SerialDevice;
+use core::cell::RefCell;
+
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, rc::Rc};
+#[cfg(feature = "std")]
+use std::rc::Rc;
+
+#[derive(Default)]
+struct PortState {
+    // Our SB, published while we're waiting on the other end's clock.
+    // None means we're not ready to be clocked.
+    waiting_with: Option<u8>,
+    // A byte the other end clocked into us that we haven't picked up yet
+    incoming: Option<u8>,
+}
+
+// Both ends of the cable see the same state. Index 0 is one end, 1 the other.
+type SharedCable = Rc<RefCell<[PortState; 2]>>;
+
+// One end of the cable, plugged into a Cpu's serial port
+pub struct LinkPort {
+    cable: SharedCable,
+    side: usize,
+}
+
+impl LinkPort {
+    fn other_side(&self) -> usize {
+        1 - self.side
+    }
+}
+
+impl SerialDevice for LinkPort {
+    // We're the master. The slave's SB and ours swap places, but only if it
+    // had a transfer pending. Otherwise it isn't listening and we see 0xFF.
+    fn exchange_byte(&mut self, outgoing: u8) -> u8 {
+        let mut cable = self.cable.borrow_mut();
+        let other = &mut cable[self.other_side()];
+
+        match other.waiting_with.take() {
+            Some(theirs) => {
+                other.incoming = Some(outgoing);
+                theirs
+            },
+            None => 0xFF,
+        }
+    }
+
+    // We're the slave, so we publish our SB and wait for the master to
+    // clock it out.
+    fn external_clock(&mut self, outgoing: u8) -> Option<u8> {
+        let mut cable = self.cable.borrow_mut();
+        let ours = &mut cable[self.side];
+
+        if let Some(incoming) = ours.incoming.take() {
+            ours.waiting_with = None;
+            return Some(incoming);
+        }
+
+        ours.waiting_with = Some(outgoing);
+        None
+    }
+
+    fn step(&mut self, _cycles: usize) {
+        let mut cable = self.cable.borrow_mut();
+        // If we're still waiting, external_clock will republish this straight
+        // after. This stops a stale SB leaking if we stop waiting.
+        cable[self.side].waiting_with = None;
+    }
+}
+
+// Creates both ends of a new cable
+pub fn new_link_cable() -> (LinkPort, LinkPort) {
+    let cable: SharedCable = Rc::new(RefCell::new(Default::default()));
+    (
+        LinkPort {
+            cable: cable.clone(),
+            side: 0,
+        },
+        LinkPort { cable, side: 1 },
+    )
+}
+
+// Plugs a new cable into both Gameboys' serial ports
+pub fn connect_link_cable(first: &mut Cpu, second: &mut Cpu) {
+    let (first_port, second_port) = new_link_cable();
+    first.connect_serial_device(Box::new(first_port));
+    second.connect_serial_device(Box::new(second_port));
+}
+
+// Two linked Gameboys stepped in lockstep. Whichever one is behind is
+// stepped next, so they never drift apart by more than one instruction.
+pub struct LinkedPair {
+    pub first: Cpu,
+    pub second: Cpu,
+
+    // Elapsed single-speed cycles for each machine. Double speed CGBs fit
+    // twice as many CPU cycles into the same time.
+    first_clock: usize,
+    second_clock: usize,
+}
+
+fn real_time_cycles(cpu: &Cpu, cycles: usize) -> usize {
+    if cpu.mem.speed_switch.current_speed_is_double {
+        cycles / 2
+    } else {
+        cycles
+    }
+}
+
+impl LinkedPair {
+    // Steps whichever machine is behind by one instruction
+    pub fn step(&mut self) {
+        if self.first_clock <= self.second_clock {
+            let cycles = self.first.step();
+            self.first_clock += real_time_cycles(&self.first, cycles);
+        } else {
+            let cycles = self.second.step();
+            self.second_clock += real_time_cycles(&self.second, cycles);
+        }
+    }
+
+    // Runs both machines until they've each covered another `cycles`
+    // single-speed cycles
+    pub fn step_cycles(&mut self, cycles: usize) {
+        let target = self.first_clock.min(self.second_clock) + cycles;
+        while self.first_clock < target || self.second_clock < target {
+            self.step();
+        }
+    }
+
+    // Runs both machines for one frame of the first machine's frame rate
+    pub fn step_one_frame(&mut self) {
+        self.step_cycles(CLOCK_SPEED / self.first.frame_rate);
+    }
+
+    // Pulls the cable out, returning both machines with nothing connected
+    pub fn unlink(mut self) -> (Cpu, Cpu) {
+        self.first.disconnect_serial_device();
+        self.second.disconnect_serial_device();
+        (self.first, self.second)
+    }
+
+    pub fn new(mut first: Cpu, mut second: Cpu) -> LinkedPair {
+        connect_link_cable(&mut first, &mut second);
+        LinkedPair {
+            first,
+            second,
+            first_clock: 0,
+            second_clock: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::memory::rom::Rom;
+
+    #[cfg(not(feature = "std"))]
+    use alloc::{vec, vec::Vec};
+
+    // A ROM that sends each byte of a script at 0x0200 with SC = `control`,
+    // saving what it gets back from 0xC000 on
+    fn scripted_cpu(script: &[u8], control: u8) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        // nop, jp $0150
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0169].copy_from_slice(&[
+            0x21, 0x00, 0xC0, // ld hl, $C000
+            0x11, 0x00, 0x02, // ld de, $0200
+            0x1A, // .next: ld a, [de]
+            0x13, // inc de
+            0xE0, 0x01, // ldh [SB], a
+            0x3E, control, // ld a, control
+            0xE0, 0x02, // ldh [SC], a
+            0xF0, 0x02, // .wait: ldh a, [SC]
+            0xCB, 0x7F, // bit 7, a
+            0x20, 0xFA, // jr nz, .wait
+            0xF0, 0x01, // ldh a, [SB]
+            0x22, // ld [hl+], a
+            0x18, 0xED, // jr .next
+        ]);
+        rom[0x0200..0x0200 + script.len()].copy_from_slice(script);
+        Cpu::from_config(Config {
+            sound_buffer_size: SOUND_BUFFER_SIZE,
+            sound_sample_rate: SOUND_SAMPLE_RATE,
+            rom: Rom::from_bytes(rom),
+            model: None,
+        })
+    }
+
+    fn received(cpu: &Cpu, count: u16) -> Vec<u8> {
+        (0..count)
+            .map(|i| cpu.mem.read(&cpu.ints, &cpu.gpu, 0xC000 + i))
+            .collect()
+    }
+
+    #[test]
+    fn bytes_cross_both_ways_whichever_end_clocks() {
+        let first_bytes = [0x11, 0x22, 0x33];
+        let second_bytes = [0xA1, 0xB2, 0xC3];
+        // Internal clock first, then external
+        for (first_control, second_control) in [(0x81, 0x80), (0x80, 0x81)] {
+            let mut pair = LinkedPair::new(
+                scripted_cpu(&first_bytes, first_control),
+                scripted_cpu(&second_bytes, second_control),
+            );
+            // Each byte takes 8 bits of 512 cycles, plus time to set up
+            pair.step_cycles(5 * 8 * 512);
+
+            let (first, second) = pair.unlink();
+            assert_eq!(received(&first, 3), second_bytes);
+            assert_eq!(received(&second, 3), first_bytes);
+        }
+    }
+}
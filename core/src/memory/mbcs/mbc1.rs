@@ -84,6 +84,12 @@ impl MBC for MBC1 {
     fn step(&mut self, ms_since_boot: usize) {
         self.ram.step(ms_since_boot)
     }
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank as u16
+    }
+    fn save_ram(&self) -> &[u8] {
+        &self.ram.ram.bytes
+    }
 }
 
 impl MBC1 {
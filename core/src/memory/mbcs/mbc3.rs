@@ -99,6 +99,15 @@ impl MBC for MBC3 {
     fn step(&mut self, ms_since_boot: usize) {
         self.ram.step(ms_since_boot)
     }
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank as u16
+    }
+    fn ram_bank(&self) -> u16 {
+        self.ram_bank as u16
+    }
+    fn save_ram(&self) -> &[u8] {
+        &self.ram.ram.bytes
+    }
 }
 
 impl MBC3 {
@@ -14,6 +14,17 @@ pub trait MBC {
 
     // Mostly used to debounce battery-backed RAM saves
     fn step(&mut self, ms_since_boot: usize);
+
+    // The bank mapped at 0x4000-0x7FFF, for debuggers
+    fn rom_bank(&self) -> u16;
+
+    // The bank mapped at 0xA000-0xBFFF. Only MBC3 and MBC5 switch these.
+    fn ram_bank(&self) -> u16 {
+        0
+    }
+
+    // All of the cartridge's RAM, for dumping it outside of the save file
+    fn save_ram(&self) -> &[u8];
 }
 
 mod mbc1;
@@ -24,7 +35,7 @@ mod none;
 
 pub fn mbc_from_info(cart_info: Cartridge, rom: Rom) -> Box<dyn MBC> {
     log!("Loading game \"{}\"", cart_info.title);
-    log!("Extra chips: {}", get_cart_type_string(&cart_info));
+    log!("Extra chips: {}", cart_type_name(&cart_info));
     log!("ROM size: {}KB", cart_info.rom_size / 1024);
     log!("RAM size: {}KB", cart_info.ram_size / 1024);
 
@@ -38,7 +49,7 @@ pub fn mbc_from_info(cart_info: Cartridge, rom: Rom) -> Box<dyn MBC> {
     }
 }
 
-fn get_cart_type_string(cart_info: &Cartridge) -> &str {
+pub fn cart_type_name(cart_info: &Cartridge) -> &'static str {
     match cart_info.cart_type {
         0x00 => "None",
         0x01 => "MBC1",
@@ -62,7 +73,6 @@ fn get_cart_type_string(cart_info: &Cartridge) -> &str {
         0x13 => "MBC3 + RAM + BATTERY",
 
         // There is no MBC4. There is superstition about the number 4 in Japan.
-
         0x19 => "MBC5",
         0x1A => "MBC5 + RAM",
         0x1B => "MBC5 + RAM + BATTERY",
@@ -70,6 +80,6 @@ fn get_cart_type_string(cart_info: &Cartridge) -> &str {
         0x1D => "MBC5 + RUMBLE + RAM",
         0x1E => "MBC5 + RUMBLE + RAM + BATTERY",
 
-        _ => panic!("gbrs doesn't know the name of this cartridge's memory controller ({:#04x}).", cart_info.cart_type)
+        _ => "Unknown",
     }
 }
@@ -26,6 +26,15 @@ impl MBC for MBCNone {
     fn step(&mut self, _ms_since_boot: usize) {
         // We don't need to do anything here
     }
+
+    fn rom_bank(&self) -> u16 {
+        // The second half of a 32KB ROM acts as bank 1
+        1
+    }
+
+    fn save_ram(&self) -> &[u8] {
+        &[]
+    }
 }
 
 impl MBCNone {
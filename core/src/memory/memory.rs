@@ -1,4 +1,5 @@
 use crate::cartridge::Cartridge;
+use crate::colour::compatibility_palettes::compatibility_palettes_for_cart;
 use crate::colour::palette_ram::PaletteRam;
 use crate::constants::*;
 use crate::cpu::EmulationTarget;
@@ -12,6 +13,7 @@ use crate::memory::ram::Ram;
 use crate::memory::rom::Rom;
 use crate::memory::vram::VRam;
 use crate::serial_cable::SerialCable;
+use crate::sgb::Sgb;
 use crate::sound::apu::APU;
 use crate::{combine_u8, split_u16};
 
@@ -35,7 +37,7 @@ pub struct Memory {
     // Used in CGB mode only
     pub palette_ram: PaletteRam,
 
-    serial_cable: SerialCable,
+    pub serial_cable: SerialCable,
 
     timer_divider_increase: u16,
     timer_divider: u8,
@@ -48,6 +50,8 @@ pub struct Memory {
     timer_control: u8,
 
     pub joypad: Joypad,
+    // Only present when running as a Super GameBoy
+    pub sgb: Option<Sgb>,
 
     pub apu: APU,
     pub speed_switch: CgbSpeedSwitch,
@@ -92,7 +96,11 @@ impl Memory {
             }
         }
 
-        self.serial_cable.step(ints, cycles);
+        self.serial_cable.step(
+            ints,
+            cycles,
+            self.speed_switch.current_speed_is_double,
+        );
 
         self.mbc.step(ms_since_boot);
     }
@@ -221,7 +229,12 @@ impl Memory {
                 self.hram.write(address - HRAM_START, value)
             },
 
-            0xFF00 => self.joypad.write(value),
+            0xFF00 => {
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.joypad_write(value, &mut self.joypad);
+                }
+                self.joypad.write(value)
+            },
 
             // Timers
             0xFF04 => self.timer_divider = 0,
@@ -281,12 +294,38 @@ impl Memory {
         self.write(ints, gpu, address + 1, b2);
     }
 
+    // Which bank is mapped at an address right now, for bank-qualified
+    // breakpoints. Unbanked regions are bank 0.
+    pub fn bank_at(&self, address: u16) -> u16 {
+        match address {
+            0x4000..=MBC_ROM_END => self.mbc.rom_bank(),
+            VRAM_START..=VRAM_END => self.vram.bank,
+            MBC_RAM_START..=MBC_RAM_END => self.mbc.ram_bank(),
+            WRAM_UPPER_BANK_START..=WRAM_UPPER_BANK_END => {
+                self.upper_wram_bank as u16
+            },
+            _ => 0,
+        }
+    }
+
+    pub fn save_ram(&self) -> &[u8] {
+        self.mbc.save_ram()
+    }
+
     pub fn from_info(
         cart_info: Cartridge,
         rom: Rom,
         target: &EmulationTarget,
     ) -> Memory {
         let cgb_features = target.has_cgb_features();
+
+        let mut palette_ram = PaletteRam::new(target);
+        if let EmulationTarget::CgbDmgMode = target {
+            palette_ram.load_compatibility_palettes(
+                compatibility_palettes_for_cart(&cart_info),
+            );
+        }
+
         Memory {
             cgb_features,
             mbc: mbc_from_info(cart_info, rom),
@@ -294,8 +333,8 @@ impl Memory {
             wram: Ram::new(WRAM_BANK_SIZE * 8),
             upper_wram_bank: 1,
             hram: Ram::new(HRAM_SIZE),
-            palette_ram: PaletteRam::new(&target),
-            serial_cable: SerialCable::new(),
+            palette_ram,
+            serial_cable: SerialCable::new(cgb_features),
             timer_divider_increase: 0,
             timer_divider: 0,
             timer_counter_increase: 0,
@@ -303,7 +342,11 @@ impl Memory {
             timer_control: 0b00000010,
             timer_modulo: 0,
             joypad: Joypad::new(),
-            apu: APU::new(),
+            sgb: match target {
+                EmulationTarget::Sgb => Some(Sgb::new()),
+                _ => None,
+            },
+            apu: APU::new(target),
             speed_switch: CgbSpeedSwitch::new(cgb_features),
         }
     }
@@ -2,6 +2,9 @@ use super::ram::Ram;
 use crate::colour::bg_map_attributes::BgMapAttributeTable;
 use crate::constants::*;
 
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
 pub struct VRam {
     cgb_features: bool,
     memory: Ram,
@@ -17,13 +20,34 @@ impl VRam {
     }
 
     pub fn raw_read(&self, address: u16) -> u8 {
-        if self.bank == 1 && address > VRAM_BG_MAP_START {
+        self.read_as_bank(self.bank, address)
+    }
+
+    // What the CPU would read with `bank` selected
+    fn read_as_bank(&self, bank: u16, address: u16) -> u8 {
+        if bank == 1 && address > VRAM_BG_MAP_START {
             return self.bg_map_attributes.read(address - VRAM_BG_MAP_START);
         }
 
         let relative_address = address - VRAM_START;
         self.memory
-            .read(self.bank * VRAM_BANK_SIZE as u16 + relative_address)
+            .read(bank * VRAM_BANK_SIZE as u16 + relative_address)
+    }
+
+    // A copy of one whole bank, as the CPU would see it
+    pub fn bank_contents(&self, bank: u16) -> Vec<u8> {
+        (VRAM_START..=VRAM_END)
+            .map(|address| self.read_as_bank(bank, address))
+            .collect()
+    }
+
+    // 2 on the CGB, 1 otherwise
+    pub fn bank_count(&self) -> u16 {
+        if self.cgb_features {
+            2
+        } else {
+            1
+        }
     }
 
     pub fn raw_write(&mut self, address: u16, value: u8) {
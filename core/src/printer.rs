@@ -0,0 +1,580 @@
+// Gameboy Printer
+// Plugs into the link port. Games send it packets of tile data and then a
+// print command, after which we hand the finished strip to a callback.
+// Protocol details: https://gbdev.io/pandocs/Gameboy_Printer.html
+use crate::constants::*;
+#[cfg(feature = "std")]
+use crate::log;
+use crate::screenshot::encode_png;
+#[cfg(feature = "std")]
+use crate::screenshot::save_png_next_to_rom;
+use crate::serial_cable::SerialDevice;
+
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, vec, vec::Vec};
+
+const MAGIC_1: u8 = 0x88;
+const MAGIC_2: u8 = 0x33;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+// Sent back in the first byte after the checksum
+const DEVICE_ID: u8 = 0x81;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b0000_0001;
+const STATUS_PRINTING: u8 = 0b0000_0010;
+const STATUS_IMAGE_FULL: u8 = 0b0000_0100;
+const STATUS_UNPROCESSED_DATA: u8 = 0b0000_1000;
+const STATUS_PACKET_ERROR: u8 = 0b0001_0000;
+
+// The printer has 8KB of RAM for image data
+const IMAGE_BUFFER_SIZE: usize = 0x2000;
+// Printouts are always one screen wide, 20 tiles of 16 bytes each
+pub const PRINT_WIDTH: usize = SCREEN_WIDTH;
+const TILES_PER_ROW: usize = PRINT_WIDTH / 8;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * 16;
+
+// How many pixel rows of blank paper one unit of margin feeds
+const MARGIN_FEED_HEIGHT: usize = 8;
+// Roughly how long the print head takes per pixel row. Games wait for the
+// printing status bit to drop before they carry on.
+const PRINT_CYCLES_PER_ROW: usize = CLOCK_SPEED / 64;
+
+// White, light grey, dark grey, black
+const GREY_LEVELS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+// The exposure that prints GREY_LEVELS as they are. Each step above or below
+// it darkens or lightens the ink by one grey level, so the extremes are
+// nearly a shade away.
+const NORMAL_EXPOSURE: u8 = 0x40;
+
+// A strip of paper that came out of the printer
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    // One greyscale byte per pixel, 0xFF is white
+    pub pixels: Vec<u8>,
+    // 0x00 is lightest, 0x7F is darkest, 0x40 is normal
+    pub exposure: u8,
+}
+
+impl PrintedImage {
+    pub fn to_png(&self) -> Vec<u8> {
+        let rgba: Vec<u8> = self
+            .pixels
+            .iter()
+            .flat_map(|&grey| [grey, grey, grey, 0xFF])
+            .collect();
+        encode_png(&rgba, self.width, self.height, &[])
+    }
+}
+
+pub type PrintCallback = Box<dyn FnMut(&PrintedImage)>;
+
+#[derive(PartialEq, Eq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+pub struct Printer {
+    state: PacketState,
+
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet_data: Vec<u8>,
+    calculated_checksum: u16,
+    received_checksum: u16,
+
+    image_data: Vec<u8>,
+    status: u8,
+    printing_cycles_left: usize,
+
+    on_print: PrintCallback,
+}
+
+impl Printer {
+    fn start_packet(&mut self) {
+        self.state = PacketState::Magic1;
+        self.packet_data.clear();
+        self.calculated_checksum = 0;
+        self.received_checksum = 0;
+    }
+
+    // Everything from the command to the end of the data is checksummed
+    fn checksum(&mut self, byte: u8) {
+        self.calculated_checksum =
+            self.calculated_checksum.wrapping_add(byte as u16);
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.state {
+            PacketState::Magic1 => {
+                if byte == MAGIC_1 {
+                    self.state = PacketState::Magic2;
+                }
+            },
+            PacketState::Magic2 => {
+                self.state = if byte == MAGIC_2 {
+                    PacketState::Command
+                } else {
+                    PacketState::Magic1
+                };
+            },
+            PacketState::Command => {
+                self.checksum(byte);
+                self.command = byte;
+                self.state = PacketState::Compression;
+            },
+            PacketState::Compression => {
+                self.checksum(byte);
+                self.compressed = (byte & 1) > 0;
+                self.state = PacketState::LengthLow;
+            },
+            PacketState::LengthLow => {
+                self.checksum(byte);
+                self.length = byte as u16;
+                self.state = PacketState::LengthHigh;
+            },
+            PacketState::LengthHigh => {
+                self.checksum(byte);
+                self.length |= (byte as u16) << 8;
+                self.state = if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                };
+            },
+            PacketState::Data => {
+                self.checksum(byte);
+                self.packet_data.push(byte);
+                if self.packet_data.len() == self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+            },
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                self.state = PacketState::ChecksumHigh;
+            },
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.run_command();
+                self.state = PacketState::DeviceId;
+            },
+            PacketState::DeviceId => {
+                self.state = PacketState::Status;
+                return DEVICE_ID;
+            },
+            PacketState::Status => {
+                let status = self.status;
+                self.start_packet();
+                return status;
+            },
+        }
+
+        0x00
+    }
+
+    fn run_command(&mut self) {
+        if self.received_checksum != self.calculated_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.image_data.clear();
+                self.status = 0;
+            },
+            COMMAND_DATA => {
+                // An empty data packet just marks the end of the image
+                if self.compressed {
+                    decompress_into(&self.packet_data, &mut self.image_data);
+                } else {
+                    self.image_data.extend_from_slice(&self.packet_data);
+                }
+                self.image_data.truncate(IMAGE_BUFFER_SIZE);
+
+                if !self.image_data.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+                if self.image_data.len() >= IMAGE_BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            },
+            COMMAND_PRINT => self.print(),
+            COMMAND_STATUS => {},
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+
+    fn print(&mut self) {
+        if self.packet_data.len() < 4 {
+            self.status |= STATUS_PACKET_ERROR;
+            return;
+        }
+
+        let sheets = self.packet_data[0];
+        let margin_before = (self.packet_data[1] >> 4) as usize;
+        let margin_after = (self.packet_data[1] & 0x0F) as usize;
+        let palette = self.packet_data[2];
+        let exposure = self.packet_data[3] & 0x7F;
+
+        // Zero sheets is just a paper feed
+        if sheets > 0 {
+            let image = self.render(
+                palette,
+                margin_before * MARGIN_FEED_HEIGHT,
+                margin_after * MARGIN_FEED_HEIGHT,
+                exposure,
+            );
+            self.printing_cycles_left = image.height * PRINT_CYCLES_PER_ROW;
+            // Nothing to print and no margins to feed
+            if image.height > 0 {
+                (self.on_print)(&image);
+            }
+        }
+
+        self.image_data.clear();
+        self.status &= !(STATUS_UNPROCESSED_DATA | STATUS_IMAGE_FULL);
+        if self.printing_cycles_left > 0 {
+            self.status |= STATUS_PRINTING;
+        }
+    }
+
+    fn render(
+        &self,
+        palette: u8,
+        blank_rows_before: usize,
+        blank_rows_after: usize,
+        exposure: u8,
+    ) -> PrintedImage {
+        let tile_rows = self.image_data.len() / BYTES_PER_TILE_ROW;
+        let image_rows = tile_rows * 8;
+        let height = blank_rows_before + image_rows + blank_rows_after;
+
+        let mut pixels = vec![GREY_LEVELS[0]; PRINT_WIDTH * height];
+
+        // The paper stays white whatever the exposure
+        let darken = exposure as i32 - NORMAL_EXPOSURE as i32;
+        let mut levels = GREY_LEVELS;
+        for level in levels.iter_mut().skip(1) {
+            *level = (*level as i32 - darken).clamp(0, 0xFF) as u8;
+        }
+
+        for y in 0..image_rows {
+            let tile_row_start = (y / 8) * BYTES_PER_TILE_ROW;
+            let line_in_tile = y % 8;
+            let out_row = (blank_rows_before + y) * PRINT_WIDTH;
+
+            for x in 0..PRINT_WIDTH {
+                // Tile data is 2bpp, the same as VRAM
+                let tile_start = tile_row_start + (x / 8) * 16;
+                let lower = self.image_data[tile_start + line_in_tile * 2];
+                let upper = self.image_data[tile_start + line_in_tile * 2 + 1];
+                let shift = 7 - (x % 8);
+                let colour_id =
+                    (((upper >> shift) & 1) << 1) | ((lower >> shift) & 1);
+
+                // The palette works like BGP
+                let shade = (palette >> (colour_id * 2)) & 0b11;
+                pixels[out_row + x] = levels[shade as usize];
+            }
+        }
+
+        PrintedImage {
+            width: PRINT_WIDTH,
+            height,
+            pixels,
+            exposure,
+        }
+    }
+
+    pub fn new(on_print: PrintCallback) -> Printer {
+        Printer {
+            state: PacketState::Magic1,
+
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet_data: vec![],
+            calculated_checksum: 0,
+            received_checksum: 0,
+
+            image_data: Vec::with_capacity(IMAGE_BUFFER_SIZE),
+            status: 0,
+            printing_cycles_left: 0,
+
+            on_print,
+        }
+    }
+}
+
+impl Printer {
+    // A printer that saves everything it prints next to the ROM as
+    // ROM_NAME-print-N.png
+    #[cfg(feature = "std")]
+    pub fn saving_next_to_rom(rom_path: &str) -> Printer {
+        let rom_path = rom_path.to_string();
+        Printer::new(Box::new(move |image| {
+            match save_png_next_to_rom(&rom_path, "print", &image.to_png()) {
+                Ok(path) => log!("Printed to {}", path.to_string_lossy()),
+                Err(e) => log!("Failed to save printout: {}", e),
+            }
+        }))
+    }
+}
+
+// The printer's RLE. A control byte with the top bit set repeats the next
+// byte (n & 0x7F) + 2 times, otherwise the next n + 1 bytes are literal.
+fn decompress_into(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if control & 0x80 > 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&byte) = data.get(i) {
+                out.resize(out.len() + count, byte);
+            }
+            i += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = (i + count).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange_byte(&mut self, outgoing: u8) -> u8 {
+        self.receive(outgoing)
+    }
+
+    fn step(&mut self, cycles: usize) {
+        if self.printing_cycles_left == 0 {
+            return;
+        }
+
+        self.printing_cycles_left =
+            self.printing_cycles_left.saturating_sub(cycles);
+        if self.printing_cycles_left == 0 {
+            self.status &= !STATUS_PRINTING;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+
+    #[cfg(not(feature = "std"))]
+    use alloc::rc::Rc;
+    #[cfg(feature = "std")]
+    use std::rc::Rc;
+
+    // (height, pixels, exposure) of everything printed
+    type Printouts = Rc<RefCell<Vec<(usize, Vec<u8>, u8)>>>;
+
+    fn printer() -> (Printer, Printouts) {
+        let printouts: Printouts = Rc::new(RefCell::new(vec![]));
+        let saved = printouts.clone();
+        let printer = Printer::new(Box::new(move |image| {
+            assert_eq!(image.width, PRINT_WIDTH);
+            saved.borrow_mut().push((
+                image.height,
+                image.pixels.clone(),
+                image.exposure,
+            ));
+        }));
+        (printer, printouts)
+    }
+
+    // A whole packet with a correct checksum, then the two bytes that clock
+    // out the printer's reply
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut body = vec![command, compressed as u8, length[0], length[1]];
+        body.extend_from_slice(data);
+        let checksum = body
+            .iter()
+            .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+
+        let mut packet = vec![MAGIC_1, MAGIC_2];
+        packet.extend_from_slice(&body);
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet
+    }
+
+    // Sends a packet a byte at a time, giving back the device ID and status
+    fn send(printer: &mut Printer, packet: &[u8]) -> (u8, u8) {
+        let replies: Vec<u8> = packet
+            .iter()
+            .map(|byte| printer.exchange_byte(*byte))
+            .collect();
+        assert!(replies[..replies.len() - 2].iter().all(|r| *r == 0));
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    // One row of 20 tiles whose pixels go through colours 0, 1, 2, 3
+    fn striped_tile_row() -> Vec<u8> {
+        [0b0101_0101, 0b0011_0011].repeat(BYTES_PER_TILE_ROW / 2)
+    }
+
+    fn print_data(
+        sheets: u8,
+        margins: u8,
+        palette: u8,
+        exposure: u8,
+    ) -> [u8; 4] {
+        [sheets, margins, palette, exposure]
+    }
+
+    #[test]
+    fn init_and_status_packets() {
+        let (mut printer, _) = printer();
+        assert_eq!(
+            send(&mut printer, &packet(COMMAND_INIT, false, &[])),
+            (DEVICE_ID, 0)
+        );
+        assert_eq!(
+            send(&mut printer, &packet(COMMAND_STATUS, false, &[])),
+            (DEVICE_ID, 0)
+        );
+    }
+
+    #[test]
+    fn bad_checksums_and_commands_set_status_bits() {
+        let (mut printer, _) = printer();
+        let mut bad = packet(COMMAND_DATA, false, &[1, 2, 3]);
+        let checksum_low = bad.len() - 4;
+        bad[checksum_low] ^= 0xFF;
+        let (_, status) = send(&mut printer, &bad);
+        assert_eq!(status, STATUS_CHECKSUM_ERROR);
+        // The data was thrown away, and the next good packet clears the error
+        let (_, status) =
+            send(&mut printer, &packet(COMMAND_STATUS, false, &[]));
+        assert_eq!(status, 0);
+
+        let (_, status) = send(&mut printer, &packet(0x7E, false, &[]));
+        assert_eq!(status, STATUS_PACKET_ERROR);
+        let (_, status) = send(&mut printer, &packet(COMMAND_INIT, false, &[]));
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn garbage_before_the_magic_bytes_is_skipped() {
+        let (mut printer, _) = printer();
+        let mut bytes = vec![0x00, 0x88, 0x00, 0x12];
+        bytes.extend_from_slice(&packet(COMMAND_STATUS, false, &[]));
+        assert_eq!(send(&mut printer, &bytes), (DEVICE_ID, 0));
+    }
+
+    #[test]
+    fn decompresses_repeats_and_literals() {
+        let mut out = vec![];
+        // Three literal bytes, then 0xAB five times
+        decompress_into(&[0x02, 1, 2, 3, 0x83, 0xAB], &mut out);
+        assert_eq!(out, [1, 2, 3, 0xAB, 0xAB, 0xAB, 0xAB, 0xAB]);
+        // Runs cut short by the end of the packet
+        decompress_into(&[0x05, 9], &mut out);
+        assert_eq!(out[8..], [9]);
+    }
+
+    #[test]
+    fn prints_raw_data_with_margins() {
+        let (mut printer, printouts) = printer();
+        send(&mut printer, &packet(COMMAND_INIT, false, &[]));
+        let (_, status) = send(
+            &mut printer,
+            &packet(COMMAND_DATA, false, &striped_tile_row()),
+        );
+        assert_eq!(status, STATUS_UNPROCESSED_DATA);
+        send(&mut printer, &packet(COMMAND_DATA, false, &[]));
+
+        // One unit of margin before, two after, the normal BGP-style palette
+        let print = print_data(1, 0x12, 0b11_10_01_00, NORMAL_EXPOSURE);
+        let (_, status) =
+            send(&mut printer, &packet(COMMAND_PRINT, false, &print));
+        assert_eq!(status, STATUS_PRINTING);
+
+        let printouts = printouts.borrow();
+        let (height, pixels, exposure) = &printouts[0];
+        assert_eq!(*height, 8 + 8 + 16);
+        assert_eq!(*exposure, NORMAL_EXPOSURE);
+        let row = |y: usize| &pixels[y * PRINT_WIDTH..(y + 1) * PRINT_WIDTH];
+        assert!(row(7).iter().all(|p| *p == 0xFF));
+        assert_eq!(
+            row(8)[..8],
+            [0xFF, 0xAA, 0x55, 0x00, 0xFF, 0xAA, 0x55, 0x00]
+        );
+        assert_eq!(row(15), row(8));
+        assert!(row(16).iter().all(|p| *p == 0xFF));
+        assert!(row(31).iter().all(|p| *p == 0xFF));
+    }
+
+    #[test]
+    fn prints_compressed_data_with_the_palette_and_exposure() {
+        let (mut printer, printouts) = printer();
+        send(&mut printer, &packet(COMMAND_INIT, false, &[]));
+
+        // The striped row as literal runs, then a row of black as repeats
+        let mut compressed = vec![];
+        for run in striped_tile_row().chunks(128) {
+            compressed.push(run.len() as u8 - 1);
+            compressed.extend_from_slice(run);
+        }
+        compressed.extend_from_slice(&[0xFF, 0xFF].repeat(2));
+        compressed.extend_from_slice(&[0x80 | (62 - 2), 0xFF]);
+        send(&mut printer, &packet(COMMAND_DATA, true, &compressed));
+
+        // An inverted palette, printed as dark as it goes
+        let print = print_data(1, 0x00, 0b00_01_10_11, 0x7F);
+        send(&mut printer, &packet(COMMAND_PRINT, false, &print));
+
+        let printouts = printouts.borrow();
+        let (height, pixels, _) = &printouts[0];
+        assert_eq!(*height, 16);
+        // 0x3F darker than normal, except the paper itself
+        assert_eq!(pixels[..4], [0x00, 0x16, 0x6B, 0xFF]);
+        assert!(pixels[8 * PRINT_WIDTH..].iter().all(|p| *p == 0xFF));
+    }
+
+    #[test]
+    fn printing_status_clears_once_the_paper_is_out() {
+        let (mut printer, printouts) = printer();
+        send(
+            &mut printer,
+            &packet(COMMAND_DATA, false, &striped_tile_row()),
+        );
+        let print = print_data(1, 0x00, 0b11_10_01_00, NORMAL_EXPOSURE);
+        send(&mut printer, &packet(COMMAND_PRINT, false, &print));
+        assert_eq!(printouts.borrow().len(), 1);
+
+        printer.step(8 * PRINT_CYCLES_PER_ROW - 1);
+        let (_, status) =
+            send(&mut printer, &packet(COMMAND_STATUS, false, &[]));
+        assert_eq!(status, STATUS_PRINTING);
+        printer.step(1);
+        let (_, status) =
+            send(&mut printer, &packet(COMMAND_STATUS, false, &[]));
+        assert_eq!(status, 0);
+    }
+}
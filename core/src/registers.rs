@@ -1,7 +1,4 @@
 use crate::cpu::EmulationTarget;
-use crate::gpu::Gpu;
-use crate::interrupts::*;
-use crate::memory::memory::Memory;
 use crate::{combine_u8, set_bit, split_u16};
 
 #[cfg(not(feature = "std"))]
@@ -159,15 +156,9 @@ impl Registers {
         self.set_combined_register_base(register, value, false)
     }
 
+    // Register 0b110 is (HL), which is memory, so the CPU handles that one
     #[inline(always)]
-    pub fn set_singular_register(
-        &mut self,
-        register: u8,
-        value: u8,
-        mem: &mut Memory,
-        ints: &mut Interrupts,
-        gpu: &mut Gpu,
-    ) {
+    pub fn set_singular_register(&mut self, register: u8, value: u8) {
         match register {
             0b000 => self.b = value,
             0b001 => self.c = value,
@@ -175,20 +166,13 @@ impl Registers {
             0b011 => self.e = value,
             0b100 => self.h = value,
             0b101 => self.l = value,
-            0b110 => mem.write(ints, gpu, self.get_hl(), value),
             0b111 => self.a = value,
             _ => panic!("Invalid singular register set"),
         }
     }
 
     #[inline(always)]
-    pub fn get_singular_register(
-        &self,
-        register: u8,
-        mem: &Memory,
-        ints: &Interrupts,
-        gpu: &Gpu,
-    ) -> u8 {
+    pub fn get_singular_register(&self, register: u8) -> u8 {
         match register {
             0b000 => self.b,
             0b001 => self.c,
@@ -196,7 +180,6 @@ impl Registers {
             0b011 => self.e,
             0b100 => self.h,
             0b101 => self.l,
-            0b110 => mem.read(ints, gpu, self.get_hl()),
             0b111 => self.a,
             _ => panic!("Invalid singular register get"),
         }
@@ -217,7 +200,9 @@ impl Registers {
         //       since we don't run that.
         // This is how games detect that they can use GameBoy Color features.
         let bootup_a_value = match emulation_target {
-            EmulationTarget::Dmg | EmulationTarget::CgbDmgMode => 0x01,
+            EmulationTarget::Dmg
+            | EmulationTarget::Sgb
+            | EmulationTarget::CgbDmgMode => 0x01,
             EmulationTarget::CgbCgbMode | EmulationTarget::GbaCgbMode => 0x11,
         };
         // This is exclusively used to detect running on the GameBoy Advance.
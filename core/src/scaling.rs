@@ -0,0 +1,425 @@
+// CPU upscalers for pixel art. They turn a frame (usually `finished_frame`)
+// into a bigger RGBA buffer that looks sharp when the frontend stretches it
+// the rest of the way, so every port gets the same output options without
+// needing shaders.
+use crate::colour::colour::Colour;
+
+#[cfg(not(feature = "std"))]
+use alloc::{vec, vec::Vec};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scaler {
+    // The frame as it is, for frontends that stretch it without filtering
+    Nearest,
+    // AdvanceMAME's Scale2x/3x. Only fills corners with copies of their
+    // neighbours, so no new colours are made.
+    // https://www.scale2x.it/algorithm
+    Scale2x,
+    Scale3x,
+    // Blends each corner by a few rules on which neighbours are similar,
+    // compared in YUV with the thresholds from Maxim Stepin's hqx. It isn't
+    // hqx itself, which has a table of 256 cases.
+    Smooth2x,
+    Smooth3x,
+    // Hyllian's xBR (level 1), which finds edges by weighing colour
+    // distances along both diagonals
+    Xbr2x,
+    Xbr3x,
+}
+
+impl Scaler {
+    pub const ALL: [Scaler; 7] = [
+        Scaler::Nearest,
+        Scaler::Scale2x,
+        Scaler::Scale3x,
+        Scaler::Smooth2x,
+        Scaler::Smooth3x,
+        Scaler::Xbr2x,
+        Scaler::Xbr3x,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scaler::Nearest => "nearest",
+            Scaler::Scale2x => "scale2x",
+            Scaler::Scale3x => "scale3x",
+            Scaler::Smooth2x => "smooth2x",
+            Scaler::Smooth3x => "smooth3x",
+            Scaler::Xbr2x => "xbr2x",
+            Scaler::Xbr3x => "xbr3x",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scaler> {
+        Scaler::ALL
+            .iter()
+            .find(|scaler| scaler.name() == name)
+            .copied()
+    }
+
+    // For frontends that cycle through scalers with a hotkey
+    pub fn next(&self) -> Scaler {
+        let index = Scaler::ALL
+            .iter()
+            .position(|scaler| scaler == self)
+            .unwrap();
+        Scaler::ALL[(index + 1) % Scaler::ALL.len()]
+    }
+
+    pub fn factor(&self) -> usize {
+        match self {
+            Scaler::Nearest => 1,
+            Scaler::Scale2x | Scaler::Smooth2x | Scaler::Xbr2x => 2,
+            Scaler::Scale3x | Scaler::Smooth3x | Scaler::Xbr3x => 3,
+        }
+    }
+
+    // The width and height of the RGBA buffer `upscale` makes
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        (width * self.factor(), height * self.factor())
+    }
+
+    // Scales a `width` x `height` frame into `out` as RGBA. `out` is resized
+    // to fit, so the same buffer can be reused every frame.
+    pub fn upscale(
+        &self,
+        frame: &[Colour],
+        width: usize,
+        height: usize,
+        out: &mut Vec<u8>,
+    ) {
+        assert_eq!(frame.len(), width * height, "Frame size mismatch");
+
+        let factor = self.factor();
+        out.resize(frame.len() * factor * factor * 4, 0);
+        let mut target = Target {
+            out,
+            width: width * factor,
+        };
+        let source = Source {
+            frame,
+            width,
+            height,
+        };
+
+        match self {
+            Scaler::Nearest => {
+                for (pixel, colour) in frame.iter().enumerate() {
+                    target.put(pixel % width, pixel / width, *colour)
+                }
+            },
+            Scaler::Scale2x => scale2x(&source, &mut target),
+            Scaler::Scale3x => scale3x(&source, &mut target),
+            Scaler::Smooth2x | Scaler::Smooth3x => {
+                smooth(&source, &mut target, factor)
+            },
+            Scaler::Xbr2x | Scaler::Xbr3x => xbr(&source, &mut target, factor),
+        }
+    }
+
+    pub fn upscale_to_vec(
+        &self,
+        frame: &[Colour],
+        width: usize,
+        height: usize,
+    ) -> Vec<u8> {
+        let mut out = vec![];
+        self.upscale(frame, width, height, &mut out);
+        out
+    }
+}
+
+struct Source<'a> {
+    frame: &'a [Colour],
+    width: usize,
+    height: usize,
+}
+
+impl Source<'_> {
+    // Pixels off the edge repeat the edge
+    fn index(&self, x: usize, y: usize, dx: isize, dy: isize) -> usize {
+        let x = (x as isize + dx).clamp(0, self.width as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, self.height as isize - 1) as usize;
+        y * self.width + x
+    }
+
+    fn at(&self, x: usize, y: usize, dx: isize, dy: isize) -> Colour {
+        self.frame[self.index(x, y, dx, dy)]
+    }
+}
+
+struct Target<'a> {
+    out: &'a mut Vec<u8>,
+    width: usize,
+}
+
+impl Target<'_> {
+    fn put(&mut self, x: usize, y: usize, colour: Colour) {
+        let start = (y * self.width + x) * 4;
+        self.out[start] = colour.red;
+        self.out[start + 1] = colour.green;
+        self.out[start + 2] = colour.blue;
+        self.out[start + 3] = 0xFF;
+    }
+}
+
+// Every corner of an output pixel, as the direction it faces
+const CORNERS: [(isize, isize); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+// Where in a `factor` sized block a corner's sub-pixel is
+fn corner_offset(direction: isize, factor: usize) -> usize {
+    if direction < 0 {
+        0
+    } else {
+        factor - 1
+    }
+}
+
+// Weighted blend of colours, weights should add up to a power of two
+fn interpolate(colours: &[(Colour, u32)]) -> Colour {
+    let total: u32 = colours.iter().map(|(_, weight)| weight).sum();
+    let channel = |get: fn(&Colour) -> u8| {
+        let sum: u32 = colours
+            .iter()
+            .map(|(colour, weight)| get(colour) as u32 * weight)
+            .sum();
+        (sum / total) as u8
+    };
+    Colour::new(
+        channel(|c| c.red),
+        channel(|c| c.green),
+        channel(|c| c.blue),
+    )
+}
+
+fn scale2x(source: &Source, target: &mut Target) {
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let e = source.at(x, y, 0, 0);
+            for (sx, sy) in CORNERS {
+                // The neighbours beside and above/below this corner, and
+                // the ones opposite them
+                let side = source.at(x, y, sx, 0);
+                let vertical = source.at(x, y, 0, sy);
+                let other_side = source.at(x, y, -sx, 0);
+                let other_vertical = source.at(x, y, 0, -sy);
+
+                let colour = if side == vertical
+                    && vertical != other_side
+                    && side != other_vertical
+                {
+                    side
+                } else {
+                    e
+                };
+                target.put(
+                    x * 2 + corner_offset(sx, 2),
+                    y * 2 + corner_offset(sy, 2),
+                    colour,
+                );
+            }
+        }
+    }
+}
+
+fn scale3x(source: &Source, target: &mut Target) {
+    for y in 0..source.height {
+        for x in 0..source.width {
+            // A B C
+            // D E F
+            // G H I
+            let at = |dx, dy| source.at(x, y, dx, dy);
+            let (a, b, c) = (at(-1, -1), at(0, -1), at(1, -1));
+            let (d, e, f) = (at(-1, 0), at(0, 0), at(1, 0));
+            let (g, h, i) = (at(-1, 1), at(0, 1), at(1, 1));
+
+            let block = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) {
+                        b
+                    } else {
+                        e
+                    },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) {
+                        d
+                    } else {
+                        e
+                    },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) {
+                        f
+                    } else {
+                        e
+                    },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) {
+                        h
+                    } else {
+                        e
+                    },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+
+            for (n, colour) in block.into_iter().enumerate() {
+                target.put(x * 3 + n % 3, y * 3 + n / 3, colour);
+            }
+        }
+    }
+}
+
+fn yuv(colour: &Colour) -> (i32, i32, i32) {
+    let (r, g, b) =
+        (colour.red as i32, colour.green as i32, colour.blue as i32);
+    (
+        (299 * r + 587 * g + 114 * b) / 1000,
+        (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+        (500 * r - 419 * g - 81 * b) / 1000 + 128,
+    )
+}
+
+// hqx's test for whether two colours are different enough to be an edge
+fn colours_differ(a: &Colour, b: &Colour) -> bool {
+    if a == b {
+        return false;
+    }
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() > 48 || (ua - ub).abs() > 7 || (va - vb).abs() > 6
+}
+
+fn smooth(source: &Source, target: &mut Target, factor: usize) {
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let e = source.at(x, y, 0, 0);
+
+            // Start from the pixel itself, then smooth the corners
+            for sub_y in 0..factor {
+                for sub_x in 0..factor {
+                    target.put(x * factor + sub_x, y * factor + sub_y, e);
+                }
+            }
+
+            for (sx, sy) in CORNERS {
+                let diagonal = source.at(x, y, sx, sy);
+                let side = source.at(x, y, sx, 0);
+                let vertical = source.at(x, y, 0, sy);
+
+                // An edge cuts across this corner when both neighbours
+                // beside it match each other but not this pixel
+                let edge = !colours_differ(&side, &vertical)
+                    && colours_differ(&e, &side)
+                    && colours_differ(&e, &vertical);
+
+                let corner = if edge {
+                    interpolate(&[(e, 2), (side, 1), (vertical, 1)])
+                } else if colours_differ(&e, &diagonal) {
+                    interpolate(&[(e, 3), (diagonal, 1)])
+                } else {
+                    e
+                };
+                let corner_x = x * factor + corner_offset(sx, factor);
+                let corner_y = y * factor + corner_offset(sy, factor);
+                target.put(corner_x, corner_y, corner);
+
+                // At 3x the edge also softens the middle of each side
+                if edge && factor == 3 {
+                    let side_blend = interpolate(&[(e, 3), (side, 1)]);
+                    let vertical_blend = interpolate(&[(e, 3), (vertical, 1)]);
+                    target.put(corner_x, y * factor + 1, side_blend);
+                    target.put(x * factor + 1, corner_y, vertical_blend);
+                }
+            }
+        }
+    }
+}
+
+// Hyllian's colour distance, weighted towards brightness like hqx
+fn xbr_distance(
+    (ya, ua, va): (i32, i32, i32),
+    (yb, ub, vb): (i32, i32, i32),
+) -> u32 {
+    (48 * (ya - yb).unsigned_abs())
+        + (7 * (ua - ub).unsigned_abs())
+        + (6 * (va - vb).unsigned_abs())
+}
+
+fn xbr(source: &Source, target: &mut Target, factor: usize) {
+    // Every distance is between two pixels' YUV, so work them out once
+    let yuvs: Vec<(i32, i32, i32)> = source.frame.iter().map(yuv).collect();
+
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let colour = source.at(x, y, 0, 0);
+
+            for sub_y in 0..factor {
+                for sub_x in 0..factor {
+                    target.put(x * factor + sub_x, y * factor + sub_y, colour);
+                }
+            }
+
+            for (sx, sy) in CORNERS {
+                // Neighbours named as if this is the bottom right corner,
+                // mirrored for the others
+                //       A1 B1 C1
+                //    A0  A  B  C C4
+                //    D0  D  E  F F4
+                //    G0  G  H  I I4
+                //       G5 H5 I5
+                let at = |dx: isize, dy: isize| {
+                    yuvs[source.index(x, y, dx * sx, dy * sy)]
+                };
+                let (b, c) = (at(0, -1), at(1, -1));
+                let (d, e, f, f4) = (at(-1, 0), at(0, 0), at(1, 0), at(2, 0));
+                let (g, h, i, i4) = (at(-1, 1), at(0, 1), at(1, 1), at(2, 1));
+                let (h5, i5) = (at(0, 2), at(1, 2));
+                let dist = xbr_distance;
+
+                // Weight of an edge running from H to F, against one from
+                // E to I. The corner is only smoothed when the first wins.
+                let across = dist(e, c)
+                    + dist(e, g)
+                    + dist(i, f4)
+                    + dist(i, h5)
+                    + 4 * dist(h, f);
+                let along = dist(h, d)
+                    + dist(h, i5)
+                    + dist(f, i4)
+                    + dist(f, b)
+                    + 4 * dist(e, i);
+                if across >= along {
+                    continue;
+                }
+
+                let new_colour = if dist(e, f) <= dist(e, h) {
+                    source.at(x, y, sx, 0)
+                } else {
+                    source.at(x, y, 0, sy)
+                };
+                let corner_x = x * factor + corner_offset(sx, factor);
+                let corner_y = y * factor + corner_offset(sy, factor);
+
+                if factor == 2 {
+                    target.put(
+                        corner_x,
+                        corner_y,
+                        interpolate(&[(colour, 1), (new_colour, 1)]),
+                    );
+                } else {
+                    let edge = interpolate(&[(colour, 3), (new_colour, 1)]);
+                    target.put(
+                        corner_x,
+                        corner_y,
+                        interpolate(&[(colour, 1), (new_colour, 3)]),
+                    );
+                    target.put(corner_x, y * factor + 1, edge);
+                    target.put(x * factor + 1, corner_y, edge);
+                }
+            }
+        }
+    }
+}
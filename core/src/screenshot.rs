@@ -0,0 +1,471 @@
+// Screenshots as PNG files. There's no image crate here so that every port,
+// no_std ones included, can save them. Pixel data is compressed with a small
+// deflate encoder: LZ77 matching with deflate's fixed Huffman codes, which
+// does well on pixel art since so many rows repeat.
+//   https://www.w3.org/TR/png/
+//   https://www.rfc-editor.org/rfc/rfc1950
+//   https://www.rfc-editor.org/rfc/rfc1951
+use crate::cartridge::crc32;
+
+#[cfg(not(feature = "std"))]
+use alloc::{vec, vec::Vec};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// 8 bits per channel, RGB. Frames are always opaque so alpha is dropped.
+const BIT_DEPTH: u8 = 8;
+const COLOUR_TYPE_RGB: u8 = 2;
+const FILTER_NONE: u8 = 0;
+
+// Deflate can look this far back for a match
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// How many earlier positions with the same hash are tried for a match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 15;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59,
+    67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5,
+    5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513,
+    769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10,
+    11, 11, 12, 12, 13, 13,
+];
+
+const END_OF_BLOCK: u16 = 256;
+
+// Encodes a `width` x `height` RGBA buffer as a PNG. Each of `text` is saved
+// as a tEXt chunk of (keyword, text), which should both be Latin-1.
+pub fn encode_png(
+    rgba: &[u8],
+    width: usize,
+    height: usize,
+    text: &[(&str, &str)],
+) -> Vec<u8> {
+    assert_eq!(rgba.len(), width * height * 4, "Image size mismatch");
+
+    let mut png = PNG_SIGNATURE.to_vec();
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Then compression, filter and interlace methods, which are all 0
+    header.extend_from_slice(&[BIT_DEPTH, COLOUR_TYPE_RGB, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    for (keyword, value) in text {
+        let mut data = keyword.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(value.as_bytes());
+        write_chunk(&mut png, b"tEXt", &data);
+    }
+
+    // Every row starts with the filter it uses
+    let mut scanlines = Vec::with_capacity(height * (width * 3 + 1));
+    for row in rgba.chunks_exact(width * 4) {
+        scanlines.push(FILTER_NONE);
+        for pixel in row.chunks_exact(4) {
+            scanlines.extend_from_slice(&pixel[..3]);
+        }
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_compress(&scanlines));
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// Saves `png` next to the ROM as ROM_NAME-KIND-N.png, using the first N that
+// isn't taken so nothing from an earlier session is overwritten
+#[cfg(feature = "std")]
+pub fn save_png_next_to_rom(
+    rom_path: &str,
+    kind: &str,
+    png: &[u8],
+) -> std::io::Result<std::path::PathBuf> {
+    let mut path = std::path::PathBuf::from(rom_path);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut number = 1;
+    loop {
+        path.set_file_name(format!("{}-{}-{}.png", stem, kind, number));
+        if !path.exists() {
+            break;
+        }
+        number += 1;
+    }
+
+    std::fs::write(&path, png)?;
+    Ok(path)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    // The CRC covers the chunk type as well as its data
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums can go this many bytes before they need wrapping
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32KB window, and a check value that makes it divide
+    // by 31 as the header has to
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Bits are packed starting from the least significant bit of each byte
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first
+    fn write_code(&mut self, code: u32, length: u32) {
+        let reversed = code.reverse_bits() >> (32 - length);
+        self.write(reversed, length);
+    }
+
+    fn write_literal(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASES
+            .iter()
+            .rposition(|base| *base as usize <= length)
+            .unwrap();
+        self.write_literal(257 + code as u16);
+        self.write(
+            (length - LENGTH_BASES[code] as usize) as u32,
+            LENGTH_EXTRA_BITS[code] as u32,
+        );
+
+        let code = DISTANCE_BASES
+            .iter()
+            .rposition(|base| *base as usize <= distance)
+            .unwrap();
+        self.write_code(code as u32, 5);
+        self.write(
+            (distance - DISTANCE_BASES[code] as usize) as u32,
+            DISTANCE_EXTRA_BITS[code] as u32,
+        );
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value =
+        (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+// The whole input as one fixed Huffman block
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 4),
+        bits: 0,
+        count: 0,
+    };
+    // BFINAL, then BTYPE 01 for fixed codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    // The latest position for each hash, and the one before each position
+    // with the same hash
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut previous = vec![usize::MAX; data.len()];
+    let insert = |head: &mut [usize], previous: &mut [usize], at: usize| {
+        if at + MIN_MATCH <= data.len() {
+            let h = hash(&data[at..]);
+            previous[at] = head[h];
+            head[h] = at;
+        }
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - position);
+            let mut candidate = head[hash(&data[position..])];
+            let mut tries = 0;
+            while candidate != usize::MAX
+                && position - candidate <= WINDOW_SIZE
+                && tries < MAX_CHAIN
+            {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = position - candidate;
+                    if length == max_length {
+                        break;
+                    }
+                }
+                candidate = previous[candidate];
+                tries += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            writer.write_match(best_length, best_distance);
+            for skipped in position..position + best_length {
+                insert(&mut head, &mut previous, skipped);
+            }
+            position += best_length;
+        } else {
+            writer.write_literal(data[position] as u16);
+            insert(&mut head, &mut previous, position);
+            position += 1;
+        }
+    }
+
+    writer.write_literal(END_OF_BLOCK);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::string::{String, ToString};
+
+    // Just enough of an inflater to read back what `deflate` writes
+    struct BitReader<'a> {
+        data: &'a [u8],
+        bit: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let byte = self.data[self.bit / 8];
+                value |= ((byte >> (self.bit % 8)) as u32 & 1) << i;
+                self.bit += 1;
+            }
+            value
+        }
+
+        // Huffman codes come most significant bit first
+        fn code(&mut self, length: u32) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        fn literal(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return (code + 256) as u16;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xBF => (code - 0x30) as u16,
+                0xC0..=0xC7 => (code - 0xC0 + 280) as u16,
+                _ => ((code << 1 | self.bits(1)) - 0x190 + 144) as u16,
+            }
+        }
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { data, bit: 0 };
+        let mut out: Vec<u8> = vec![];
+        loop {
+            let last = reader.bits(1) == 1;
+            assert_eq!(reader.bits(2), 1, "Only fixed Huffman blocks");
+            loop {
+                let symbol = reader.literal();
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    END_OF_BLOCK => break,
+                    _ => {
+                        let code = (symbol - 257) as usize;
+                        let length = LENGTH_BASES[code] as usize
+                            + reader.bits(LENGTH_EXTRA_BITS[code] as u32)
+                                as usize;
+                        let code = reader.code(5) as usize;
+                        let distance = DISTANCE_BASES[code] as usize
+                            + reader.bits(DISTANCE_EXTRA_BITS[code] as u32)
+                                as usize;
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    },
+                }
+            }
+            if last {
+                return out;
+            }
+        }
+    }
+
+    struct DecodedPng {
+        width: usize,
+        height: usize,
+        text: Vec<(String, String)>,
+        rgb: Vec<u8>,
+    }
+
+    fn decode_png(png: &[u8]) -> DecodedPng {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let (mut width, mut height) = (0, 0);
+        let mut text = vec![];
+        let mut idat = vec![];
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap());
+            let (chunk, after) = rest[4..].split_at(length as usize + 4);
+            let crc = u32::from_be_bytes(after[..4].try_into().unwrap());
+            assert_eq!(crc32(chunk), crc, "Bad chunk CRC");
+            let data = &chunk[4..];
+            match &chunk[..4] {
+                b"IHDR" => {
+                    width = u32::from_be_bytes(data[..4].try_into().unwrap());
+                    height = u32::from_be_bytes(data[4..8].try_into().unwrap());
+                    assert_eq!(data[8..], [8, COLOUR_TYPE_RGB, 0, 0, 0]);
+                },
+                b"tEXt" => {
+                    let split = data.iter().position(|b| *b == 0).unwrap();
+                    text.push((
+                        String::from_utf8(data[..split].to_vec()).unwrap(),
+                        String::from_utf8(data[split + 1..].to_vec()).unwrap(),
+                    ));
+                },
+                b"IDAT" => idat.extend_from_slice(data),
+                b"IEND" => assert!(after.len() == 4, "Data after IEND"),
+                kind => panic!("Unexpected chunk {:?}", kind),
+            }
+            rest = &after[4..];
+        }
+
+        // A zlib header that divides by 31, and a matching Adler-32
+        assert_eq!(((idat[0] as u16) << 8 | idat[1] as u16) % 31, 0);
+        let scanlines = inflate(&idat[2..idat.len() - 4]);
+        let adler =
+            u32::from_be_bytes(idat[idat.len() - 4..].try_into().unwrap());
+        assert_eq!(adler32(&scanlines), adler);
+
+        let (width, height) = (width as usize, height as usize);
+        assert_eq!(scanlines.len(), height * (width * 3 + 1));
+        let mut rgb = vec![];
+        for row in scanlines.chunks_exact(width * 3 + 1) {
+            assert_eq!(row[0], FILTER_NONE);
+            rgb.extend_from_slice(&row[1..]);
+        }
+        DecodedPng {
+            width,
+            height,
+            text,
+            rgb,
+        }
+    }
+
+    fn round_trip(rgba: &[u8], width: usize, height: usize) {
+        let png = encode_png(rgba, width, height, &[]);
+        let decoded = decode_png(&png);
+        assert_eq!((decoded.width, decoded.height), (width, height));
+        let rgb: Vec<u8> = rgba
+            .chunks_exact(4)
+            .flat_map(|pixel| pixel[..3].to_vec())
+            .collect();
+        assert_eq!(decoded.rgb, rgb);
+    }
+
+    #[test]
+    fn noisy_images_round_trip() {
+        // Mostly literals, with the odd short match
+        let mut seed = 0x1234_5678u32;
+        let rgba: Vec<u8> = (0..37 * 23 * 4)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 24) as u8 & 0xF0
+            })
+            .collect();
+        round_trip(&rgba, 37, 23);
+    }
+
+    #[test]
+    fn repetitive_images_round_trip() {
+        // Long matches, and rows that repeat from far back
+        let rgba: Vec<u8> = (0..160 * 144)
+            .flat_map(|i| {
+                let (x, y) = (i % 160, i / 160);
+                let shade = if (x / 8 + y / 8) % 2 == 0 { 0xFF } else { 0x30 };
+                [shade, shade, (y % 4) as u8 * 0x40, 0xFF]
+            })
+            .collect();
+        round_trip(&rgba, 160, 144);
+        round_trip(&[0x12, 0x34, 0x56, 0xFF], 1, 1);
+        round_trip(&vec![0; 300 * 2 * 4], 300, 2);
+    }
+
+    #[test]
+    fn text_chunks_are_kept() {
+        let png =
+            encode_png(&[0; 4], 1, 1, &[("Title", "TETRIS"), ("Frame", "60")]);
+        let decoded = decode_png(&png);
+        assert_eq!(
+            decoded.text,
+            [
+                ("Title".to_string(), "TETRIS".to_string()),
+                ("Frame".to_string(), "60".to_string())
+            ]
+        );
+    }
+}
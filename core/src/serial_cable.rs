@@ -1,27 +1,110 @@
 // Gameboy Link Cable
-// Mostly a stub, although some parts have to be emulated *somewhat* accurately
-// to emulate fussy games like Alleyway
+// Some parts have to be emulated *somewhat* accurately to emulate fussy games
+// like Alleyway. Whatever is plugged into the other end of the cable is a
+// SerialDevice.
 use crate::constants::*;
 use crate::interrupts::{InterruptReason, Interrupts};
 
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
 // Unusual serial code inspired by
 // https://github.com/rvaccarim/FrozenBoy/blob/master/FrozenBoyCore/Serial/SerialLink.cs
 
+// The internal clock runs at 8192Hz, or 262144Hz in CGB high-speed mode.
+// These are in CPU cycles, so they also hold in CGB double speed mode, where
+// the serial clock is doubled along with the CPU.
+const NORMAL_SPEED_CYCLES_PER_BIT: usize = CLOCK_SPEED / 8192;
+const FAST_SPEED_CYCLES_PER_BIT: usize = CLOCK_SPEED / 262144;
+
+// Anything that can be plugged into the link port - another Gameboy, a
+// printer, an adapter etc. Transfers are exchanged a whole byte at a time,
+// once the last of the 8 bits has been clocked.
+pub trait SerialDevice {
+    // The Gameboy is providing the clock and has just shifted out `outgoing`.
+    // Returns the byte the device shifted back in at the same time.
+    fn exchange_byte(&mut self, outgoing: u8) -> u8;
+
+    // Like `exchange_byte`, for devices whose answer can take a while to
+    // arrive (eg. over a network). Polled every step once the last bit has
+    // been clocked, and the transfer carries on until it returns the byte.
+    fn poll_exchange(&mut self, outgoing: u8) -> Option<u8> {
+        Some(self.exchange_byte(outgoing))
+    }
+
+    // Polled while the Gameboy is waiting on an external clock. If the device
+    // has clocked a whole byte, it takes `outgoing` (our SB) and returns the
+    // byte it shifted into us.
+    fn external_clock(&mut self, _outgoing: u8) -> Option<u8> {
+        None
+    }
+
+    // Called every CPU step for devices that need to keep time
+    fn step(&mut self, _cycles: usize) {}
+
+    // Called whenever SC's fast clock bit or CGB double speed changes, and
+    // when the device is plugged in. `step`'s cycles come twice as fast in
+    // double speed.
+    fn set_speed(&mut self, _fast_clock: bool, _double_speed: bool) {}
+}
+
+// The default. With nothing on the other end, the input line is pulled high,
+// so we always read 0xFF, and nobody will ever provide an external clock.
+pub struct NothingConnected;
+
+impl SerialDevice for NothingConnected {
+    fn exchange_byte(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+// A cable with its SO pin wired back into SI. Whatever we send comes back.
+pub struct Loopback;
+
+impl SerialDevice for Loopback {
+    fn exchange_byte(&mut self, outgoing: u8) -> u8 {
+        outgoing
+    }
+}
+
 pub struct SerialCable {
+    cgb_features: bool,
+
     transfer_data_byte: u8,
-    transfer_control_byte: u8,
 
-    counter: usize,
+    // Bit 7 - Transfer enable / in progress
     transfer_in_progress: bool,
+    // Bit 1 - Clock speed (CGB only)
+    fast_clock: bool,
+    // Bit 0 - Clock select (true = we are the master)
+    internal_clock: bool,
+
+    counter: usize,
+    bits_remaining: u8,
+
+    device: Box<dyn SerialDevice>,
+    // The (fast clock, double speed) the device was last told about
+    device_speed: Option<(bool, bool)>,
 }
 
 impl SerialCable {
     pub fn read(&self, address: u16) -> u8 {
         match address {
-            // When there's no gameboy on the other end, this apparently
-            // just always reads 0xFF
             LINK_CABLE_SB => self.transfer_data_byte,
-            LINK_CABLE_SC => self.transfer_control_byte | 0b1111_1110,
+            LINK_CABLE_SC => {
+                let mut value = 0b0111_1100;
+                if self.transfer_in_progress {
+                    value |= 0b1000_0000;
+                }
+                // The speed bit doesn't exist on DMG, so it reads high
+                if self.fast_clock || !self.cgb_features {
+                    value |= 0b0000_0010;
+                }
+                if self.internal_clock {
+                    value |= 0b0000_0001;
+                }
+                value
+            },
             _ => unreachable!(),
         }
     }
@@ -30,38 +113,104 @@ impl SerialCable {
         match address {
             LINK_CABLE_SB => self.transfer_data_byte = value,
             LINK_CABLE_SC => {
-                self.transfer_control_byte = value;
+                self.transfer_in_progress = (value & 0b1000_0000) > 0;
+                self.fast_clock =
+                    self.cgb_features && (value & 0b0000_0010) > 0;
+                self.internal_clock = (value & 0b0000_0001) > 0;
 
-                if value == 0x81 {
-                    self.transfer_in_progress = true;
-                    self.counter = 0;
-                }
+                // Writing bit 7 (re)starts a transfer from the first bit
+                self.counter = 0;
+                self.bits_remaining = 8;
             },
             _ => unreachable!(),
         }
     }
 
-    pub fn step(&mut self, ints: &mut Interrupts, cycles: usize) {
+    pub fn step(
+        &mut self,
+        ints: &mut Interrupts,
+        cycles: usize,
+        double_speed: bool,
+    ) {
+        let speed = (self.fast_clock, double_speed);
+        if self.device_speed != Some(speed) {
+            self.device_speed = Some(speed);
+            self.device.set_speed(speed.0, speed.1);
+        }
+        self.device.step(cycles);
+
         if !self.transfer_in_progress {
             return;
         }
 
+        if !self.internal_clock {
+            // We're the slave. The other end decides when bits move.
+            if let Some(incoming) =
+                self.device.external_clock(self.transfer_data_byte)
+            {
+                self.finish_transfer(ints, incoming);
+            }
+            return;
+        }
+
+        let cycles_per_bit = if self.fast_clock {
+            FAST_SPEED_CYCLES_PER_BIT
+        } else {
+            NORMAL_SPEED_CYCLES_PER_BIT
+        };
+
         self.counter += cycles;
+        while self.counter >= cycles_per_bit && self.bits_remaining > 0 {
+            self.counter -= cycles_per_bit;
+            self.bits_remaining -= 1;
+        }
 
-        if self.counter >= 512 {
-            self.transfer_in_progress = false;
-            self.transfer_data_byte = 0xFF;
-            ints.raise_interrupt(InterruptReason::Serial);
+        if self.bits_remaining == 0 {
+            if let Some(incoming) =
+                self.device.poll_exchange(self.transfer_data_byte)
+            {
+                self.finish_transfer(ints, incoming);
+            }
         }
     }
 
-    pub fn new() -> SerialCable {
+    fn finish_transfer(&mut self, ints: &mut Interrupts, incoming: u8) {
+        self.transfer_data_byte = incoming;
+        self.transfer_in_progress = false;
+        self.counter = 0;
+        self.bits_remaining = 8;
+        ints.raise_interrupt(InterruptReason::Serial);
+    }
+
+    // Plugs something into the link port, returning what was there before
+    pub fn connect(
+        &mut self,
+        device: Box<dyn SerialDevice>,
+    ) -> Box<dyn SerialDevice> {
+        self.device_speed = None;
+        core::mem::replace(&mut self.device, device)
+    }
+
+    // Unplugs whatever is in the link port, returning it
+    pub fn disconnect(&mut self) -> Box<dyn SerialDevice> {
+        self.connect(Box::new(NothingConnected))
+    }
+
+    pub fn new(cgb_features: bool) -> SerialCable {
         SerialCable {
+            cgb_features,
+
             transfer_data_byte: 0,
-            transfer_control_byte: 0,
 
-            counter: 0,
             transfer_in_progress: false,
+            fast_clock: false,
+            internal_clock: false,
+
+            counter: 0,
+            bits_remaining: 8,
+
+            device: Box::new(NothingConnected),
+            device_speed: None,
         }
     }
 }
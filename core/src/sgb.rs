@@ -0,0 +1,795 @@
+// Super GameBoy
+// Games talk to the SNES side by pulsing the joypad register. They can colour
+// the screen with four palettes, draw a border around it, and read more than
+// one controller.
+// Command details: https://gbdev.io/pandocs/SGB_Command_Summary.html
+use crate::colour::colour::Colour;
+use crate::constants::*;
+use crate::joypad::Joypad;
+use crate::log;
+
+#[cfg(not(feature = "std"))]
+use alloc::{vec, vec::Vec};
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+// Palettes are applied per 8x8 cell of the screen
+const ATTR_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTR_HEIGHT: usize = SCREEN_HEIGHT / 8;
+const ATTR_FILE_SIZE: usize = ATTR_WIDTH * ATTR_HEIGHT / 4;
+const ATTR_FILE_COUNT: usize = 45;
+
+const SYSTEM_PALETTE_COUNT: usize = 512;
+
+// VRAM transfers copy 4KB by reading it off the Gameboy's screen
+const TRANSFER_SIZE: usize = 0x1000;
+// Games set up the screen before they send a transfer command, but the frame
+// being drawn when the command arrives may be half old
+const TRANSFER_DELAY_FRAMES: usize = 2;
+
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_TILE_COUNT: usize = 256;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_SIZE: usize = BORDER_MAP_WIDTH * BORDER_MAP_WIDTH;
+const BORDER_PALETTE_COUNT: usize = 4;
+const BORDER_PALETTE_SIZE: usize = 16;
+
+// Where the Gameboy's screen sits inside the border
+const GAME_X: usize = (SGB_SCREEN_WIDTH - SCREEN_WIDTH) / 2;
+const GAME_Y: usize = (SGB_SCREEN_HEIGHT - SCREEN_HEIGHT) / 2;
+
+// What the SGB shows before a game sends any palettes, in RGB555
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum MaskMode {
+    Cancel,
+    // Keep showing the last frame
+    Freeze,
+    Black,
+    // Fill the screen with colour 0
+    Colour0,
+}
+
+#[derive(Clone, Copy)]
+enum Transfer {
+    Palettes,
+    BorderTiles { upper_half: bool },
+    BorderMapAndPalettes,
+    AttributeFiles,
+}
+
+pub struct Sgb {
+    // Packet receiving over P14 and P15
+    receiving: bool,
+    ready_for_pulse: bool,
+    bits_received: usize,
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>,
+    packets_left: usize,
+
+    // RGB555 colours. Colour 0 of palette 0 is shared by all four.
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    // Which palette each 8x8 cell of the screen uses
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    attribute_files: Vec<u8>,
+    mask: MaskMode,
+
+    pending_transfer: Option<Transfer>,
+    transfer_delay: usize,
+
+    // SNES 4bpp tiles, a 32x32 tile map, and palettes 4-7
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; BORDER_PALETTE_SIZE]; BORDER_PALETTE_COUNT],
+    // The border's pixels, None where they're see-through
+    border_layer: Vec<Option<Colour>>,
+
+    // The whole 256x224 SNES picture, border and all. Only updated in VBlank.
+    pub frame: Vec<Colour>,
+}
+
+impl Sgb {
+    // Games send a reset pulse (P14 & P15 low), then 128 bits, each followed
+    // by both lines going high, then a 0 stop bit.
+    pub fn joypad_write(&mut self, value: u8, joypad: &mut Joypad) {
+        match value & 0b0011_0000 {
+            0b0000_0000 => {
+                self.receiving = true;
+                self.ready_for_pulse = false;
+                self.bits_received = 0;
+                self.packet = [0; PACKET_SIZE];
+            },
+            0b0011_0000 => self.ready_for_pulse = true,
+            lines => {
+                if !self.receiving || !self.ready_for_pulse {
+                    return;
+                }
+                self.ready_for_pulse = false;
+
+                // P14 low is a 0, P15 low is a 1
+                let bit = lines == 0b0001_0000;
+
+                if self.bits_received == PACKET_BITS {
+                    self.receiving = false;
+                    if !bit {
+                        self.finish_packet(joypad);
+                    }
+                    return;
+                }
+
+                if bit {
+                    self.packet[self.bits_received / 8] |=
+                        1 << (self.bits_received % 8);
+                }
+                self.bits_received += 1;
+            },
+        }
+    }
+
+    fn finish_packet(&mut self, joypad: &mut Joypad) {
+        // The first packet says how many make up the command
+        if self.command.is_empty() {
+            self.packets_left = ((self.packet[0] & 0b111) as usize).max(1);
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+
+        if self.packets_left == 0 {
+            let command = core::mem::take(&mut self.command);
+            self.run_command(&command, joypad);
+            self.command = command;
+            self.command.clear();
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8], joypad: &mut Joypad) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palette_pair(data, 0, 1),
+            PAL23 => self.set_palette_pair(data, 2, 3),
+            PAL03 => self.set_palette_pair(data, 0, 3),
+            PAL12 => self.set_palette_pair(data, 1, 2),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_divide(data),
+            ATTR_CHR => self.attribute_characters(data),
+            PAL_SET => self.palette_set(data),
+            PAL_TRN => self.start_transfer(Transfer::Palettes),
+            MLT_REQ => {
+                // 2 isn't a valid request, the SGB treats it as 1
+                let players = [1, 2, 1, 4][(data[1] & 0b11) as usize];
+                joypad.set_player_count(players);
+            },
+            CHR_TRN => self.start_transfer(Transfer::BorderTiles {
+                upper_half: (data[1] & 1) > 0,
+            }),
+            PCT_TRN => self.start_transfer(Transfer::BorderMapAndPalettes),
+            ATTR_TRN => self.start_transfer(Transfer::AttributeFiles),
+            ATTR_SET => {
+                self.apply_attribute_file(data[1] & 0b0011_1111);
+                if (data[1] & 0b0100_0000) > 0 {
+                    self.mask = MaskMode::Cancel;
+                }
+            },
+            MASK_EN => {
+                self.mask = match data[1] & 0b11 {
+                    0 => MaskMode::Cancel,
+                    1 => MaskMode::Freeze,
+                    2 => MaskMode::Black,
+                    _ => MaskMode::Colour0,
+                }
+            },
+            // Sound, SNES code uploads and the like
+            command => log!("[SGB] Unsupported command {:#04x}", command),
+        }
+    }
+
+    fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+        self.palettes[0][0] = read_u16(data, 1);
+        for i in 0..3 {
+            self.palettes[first][i + 1] = read_u16(data, 3 + i * 2);
+            self.palettes[second][i + 1] = read_u16(data, 9 + i * 2);
+        }
+    }
+
+    fn palette_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let id =
+                (read_u16(data, 1 + i * 2) as usize) % SYSTEM_PALETTE_COUNT;
+            self.palettes[i]
+                .copy_from_slice(&self.system_palettes[id * 4..id * 4 + 4]);
+        }
+
+        let flags = data[9];
+        if (flags & 0b1000_0000) > 0 {
+            self.apply_attribute_file(flags & 0b0011_1111);
+        }
+        if (flags & 0b0100_0000) > 0 {
+            self.mask = MaskMode::Cancel;
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < ATTR_WIDTH && y < ATTR_HEIGHT {
+            self.attributes[y * ATTR_WIDTH + x] = palette & 0b11;
+        }
+    }
+
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min((data.len() - 2) / 6);
+        for set in data[2..].chunks_exact(6).take(sets) {
+            let control = set[0];
+            let inside = set[1] & 0b11;
+            let line = (set[1] >> 2) & 0b11;
+            let outside = (set[1] >> 4) & 0b11;
+            let (x1, y1) = (set[2] as usize, set[3] as usize);
+            let (x2, y2) = (set[4] as usize, set[5] as usize);
+
+            // With only inside or outside chosen, the line goes along too
+            let line = match control & 0b111 {
+                0b001 => Some(inside),
+                0b100 => Some(outside),
+                c if (c & 0b010) > 0 => Some(line),
+                _ => None,
+            };
+
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_edge =
+                        within && (x == x1 || x == x2 || y == y1 || y == y2);
+
+                    let palette = if on_edge {
+                        line
+                    } else if within && (control & 0b001) > 0 {
+                        Some(inside)
+                    } else if !within && (control & 0b100) > 0 {
+                        Some(outside)
+                    } else {
+                        None
+                    };
+
+                    if let Some(palette) = palette {
+                        self.set_attribute(x, y, palette);
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(data.len() - 2);
+        for &line in &data[2..2 + count] {
+            let number = (line & 0b0001_1111) as usize;
+            let palette = (line >> 5) & 0b11;
+            let horizontal = (line & 0b1000_0000) > 0;
+
+            if horizontal {
+                for x in 0..ATTR_WIDTH {
+                    self.set_attribute(x, number, palette);
+                }
+            } else {
+                for y in 0..ATTR_HEIGHT {
+                    self.set_attribute(number, y, palette);
+                }
+            }
+        }
+    }
+
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = (data[1] & 0b0100_0000) > 0;
+        let divider = data[2] as usize;
+
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let position = if horizontal { y } else { x };
+                let palette = match position.cmp(&divider) {
+                    core::cmp::Ordering::Less => before,
+                    core::cmp::Ordering::Equal => on_line,
+                    core::cmp::Ordering::Greater => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = (data[3] as usize | (data[4] as usize) << 8)
+            .min(ATTR_WIDTH * ATTR_HEIGHT)
+            .min((data.len() - 6) * 4);
+        let vertical = data[5] == 1;
+
+        for i in 0..count {
+            if x >= ATTR_WIDTH || y >= ATTR_HEIGHT {
+                break;
+            }
+
+            // Four cells per byte, first cell in the top bits
+            let shift = 6 - (i % 4) * 2;
+            let palette = (data[6 + i / 4] >> shift) & 0b11;
+            self.set_attribute(x, y, palette);
+
+            if vertical {
+                y += 1;
+                if y == ATTR_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTR_FILE_COUNT {
+            return;
+        }
+
+        let start = file * ATTR_FILE_SIZE;
+        for i in 0..ATTR_WIDTH * ATTR_HEIGHT {
+            let byte = self.attribute_files[start + i / 4];
+            let shift = 6 - (i % 4) * 2;
+            self.attributes[i] = (byte >> shift) & 0b11;
+        }
+    }
+
+    fn start_transfer(&mut self, transfer: Transfer) {
+        self.pending_transfer = Some(transfer);
+        self.transfer_delay = TRANSFER_DELAY_FRAMES;
+    }
+
+    fn finish_transfer(&mut self, shades: &[u8; SCREEN_BUFFER_SIZE]) {
+        let transfer = match self.pending_transfer {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        self.transfer_delay -= 1;
+        if self.transfer_delay > 0 {
+            return;
+        }
+        self.pending_transfer = None;
+
+        let data = vram_from_screen(shades);
+        match transfer {
+            Transfer::Palettes => {
+                for (i, colour) in self.system_palettes.iter_mut().enumerate() {
+                    *colour = read_u16(&data, i * 2);
+                }
+            },
+            Transfer::BorderTiles { upper_half } => {
+                let start = if upper_half { TRANSFER_SIZE } else { 0 };
+                self.border_tiles[start..start + TRANSFER_SIZE]
+                    .copy_from_slice(&data);
+                self.render_border();
+            },
+            Transfer::BorderMapAndPalettes => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = read_u16(&data, i * 2);
+                }
+                let palettes_start = BORDER_MAP_SIZE * 2;
+                for (p, palette) in self.border_palettes.iter_mut().enumerate()
+                {
+                    for (c, colour) in palette.iter_mut().enumerate() {
+                        let offset = (p * BORDER_PALETTE_SIZE + c) * 2;
+                        *colour = read_u16(&data, palettes_start + offset);
+                    }
+                }
+                self.render_border();
+            },
+            Transfer::AttributeFiles => {
+                let length = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..length]);
+            },
+        }
+    }
+
+    fn render_border(&mut self) {
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                let entry = self.border_map[(y / 8) * BORDER_MAP_WIDTH + x / 8];
+                let tile = (entry & 0xFF) as usize;
+                // The map uses SNES palettes 4-7
+                let palette = ((entry >> 10) as usize).wrapping_sub(4)
+                    % BORDER_PALETTE_COUNT;
+                let x_flip = (entry & 0x4000) > 0;
+                let y_flip = (entry & 0x8000) > 0;
+
+                let subx = if x_flip { 7 - x % 8 } else { x % 8 };
+                let suby = if y_flip { 7 - y % 8 } else { y % 8 };
+
+                // 4bpp SNES tiles keep bitplanes 0 & 1 in the first 16 bytes
+                // and 2 & 3 in the next 16
+                let row = tile * BORDER_TILE_SIZE + suby * 2;
+                let shift = 7 - subx;
+                let mut colour_id = 0;
+                for (plane, offset) in [0, 1, 16, 17].iter().enumerate() {
+                    let bit = (self.border_tiles[row + offset] >> shift) & 1;
+                    colour_id |= (bit as usize) << plane;
+                }
+
+                // Colour 0 is see-through
+                self.border_layer[y * SGB_SCREEN_WIDTH + x] = if colour_id == 0
+                {
+                    None
+                } else {
+                    Some(Colour::from_16_bit_colour(
+                        self.border_palettes[palette][colour_id],
+                    ))
+                };
+            }
+        }
+    }
+
+    // Called every VBlank with the DMG shades (0-3) of the frame that was just
+    // drawn. Colours `finished_frame` and composites the full SGB frame.
+    pub fn finish_frame(
+        &mut self,
+        shades: &[u8; SCREEN_BUFFER_SIZE],
+        finished_frame: &mut [Colour; SCREEN_BUFFER_SIZE],
+    ) {
+        self.finish_transfer(shades);
+
+        let backdrop = Colour::from_16_bit_colour(self.palettes[0][0]);
+        match self.mask {
+            MaskMode::Freeze => return,
+            MaskMode::Black => {
+                finished_frame.fill(Colour::from_16_bit_colour(0));
+            },
+            MaskMode::Colour0 => finished_frame.fill(backdrop),
+            MaskMode::Cancel => {
+                let mut colours = [[backdrop; 4]; 4];
+                for (p, palette) in self.palettes.iter().enumerate() {
+                    for shade in 1..4 {
+                        colours[p][shade] =
+                            Colour::from_16_bit_colour(palette[shade]);
+                    }
+                }
+
+                for (i, pixel) in finished_frame.iter_mut().enumerate() {
+                    let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+                    let palette =
+                        self.attributes[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+                    *pixel = colours[palette][shades[i] as usize];
+                }
+            },
+        }
+
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                let i = y * SGB_SCREEN_WIDTH + x;
+                let in_game = (GAME_X..GAME_X + SCREEN_WIDTH).contains(&x)
+                    && (GAME_Y..GAME_Y + SCREEN_HEIGHT).contains(&y);
+
+                self.frame[i] = if in_game {
+                    finished_frame[(y - GAME_Y) * SCREEN_WIDTH + x - GAME_X]
+                } else {
+                    self.border_layer[i].unwrap_or(backdrop)
+                };
+            }
+        }
+    }
+
+    pub fn get_rgba_frame(&self) -> Vec<u8> {
+        let mut out = vec![0; SGB_SCREEN_BUFFER_SIZE * 4];
+        for (i, colour) in self.frame.iter().enumerate() {
+            let start = i * 4;
+            out[start] = colour.red;
+            out[start + 1] = colour.green;
+            out[start + 2] = colour.blue;
+            out[start + 3] = 0xFF;
+        }
+        out
+    }
+
+    pub fn new() -> Sgb {
+        Sgb {
+            receiving: false,
+            ready_for_pulse: false,
+            bits_received: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::with_capacity(PACKET_SIZE * 7),
+            packets_left: 0,
+
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTE_COUNT * 4],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            attribute_files: vec![0; ATTR_FILE_SIZE * ATTR_FILE_COUNT],
+            mask: MaskMode::Cancel,
+
+            pending_transfer: None,
+            transfer_delay: 0,
+
+            border_tiles: vec![0; BORDER_TILE_COUNT * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_MAP_SIZE],
+            border_palettes: [[0; BORDER_PALETTE_SIZE]; BORDER_PALETTE_COUNT],
+            border_layer: vec![None; SGB_SCREEN_BUFFER_SIZE],
+
+            frame: vec![
+                Colour::from_16_bit_colour(DEFAULT_PALETTE[0]);
+                SGB_SCREEN_BUFFER_SIZE
+            ],
+        }
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read_u16(data: &[u8], index: usize) -> u16 {
+    data[index] as u16 | (data[index + 1] as u16) << 8
+}
+
+// Turns the top of the screen back into the 4KB of tile data it was drawn
+// from. Transfers are 256 tiles laid out 20 to a row.
+fn vram_from_screen(shades: &[u8; SCREEN_BUFFER_SIZE]) -> Vec<u8> {
+    let tiles_per_row = SCREEN_WIDTH / 8;
+    let mut data = vec![0; TRANSFER_SIZE];
+
+    for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+        let tile_x = (tile % tiles_per_row) * 8;
+        let tile_y = (tile / tiles_per_row) * 8;
+
+        for row in 0..8 {
+            let line = (tile_y + row) * SCREEN_WIDTH + tile_x;
+            for px in 0..8 {
+                let shade = shades[line + px];
+                bytes[row * 2] |= (shade & 1) << (7 - px);
+                bytes[row * 2 + 1] |= ((shade >> 1) & 1) << (7 - px);
+            }
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::ExtraPlayerButtons;
+
+    #[cfg(not(feature = "std"))]
+    use alloc::boxed::Box;
+
+    // Writes to the joypad register the way Memory does
+    fn write(sgb: &mut Sgb, joypad: &mut Joypad, value: u8) {
+        sgb.joypad_write(value, joypad);
+        joypad.write(value);
+    }
+
+    fn send_packet(sgb: &mut Sgb, joypad: &mut Joypad, packet: &[u8]) {
+        write(sgb, joypad, 0x00);
+        write(sgb, joypad, 0x30);
+        for bit in 0..PACKET_BITS {
+            let one = (packet[bit / 8] >> (bit % 8)) & 1 > 0;
+            write(sgb, joypad, if one { 0x10 } else { 0x20 });
+            write(sgb, joypad, 0x30);
+        }
+        // Stop bit
+        write(sgb, joypad, 0x20);
+        write(sgb, joypad, 0x30);
+    }
+
+    // Splits a command's parameters into as many packets as they need
+    fn send_command(sgb: &mut Sgb, joypad: &mut Joypad, id: u8, params: &[u8]) {
+        let packets = (params.len() + 1).div_ceil(PACKET_SIZE).max(1);
+        let mut data = vec![0; packets * PACKET_SIZE];
+        data[0] = id << 3 | packets as u8;
+        data[1..1 + params.len()].copy_from_slice(params);
+        for packet in data.chunks(PACKET_SIZE) {
+            send_packet(sgb, joypad, packet);
+        }
+    }
+
+    fn rgb(colour: Colour) -> (u8, u8, u8) {
+        (colour.red, colour.green, colour.blue)
+    }
+
+    fn attribute(sgb: &Sgb, x: usize, y: usize) -> u8 {
+        sgb.attributes[y * ATTR_WIDTH + x]
+    }
+
+    fn blank_frame() -> Box<[Colour; SCREEN_BUFFER_SIZE]> {
+        Box::new([Colour::new(1, 2, 3); SCREEN_BUFFER_SIZE])
+    }
+
+    #[test]
+    fn pal01_sets_both_palettes_and_the_shared_colour() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        let params = [
+            0x1F, 0x00, // Shared colour 0, red
+            0xE0, 0x03, 0x00, 0x7C, 0xFF, 0x7F, // Palette 0
+            0x01, 0x00, 0x02, 0x00, 0x03, 0x00, // Palette 1
+        ];
+        send_command(&mut sgb, &mut joypad, PAL01, &params);
+
+        assert_eq!(sgb.palettes[0], [0x001F, 0x03E0, 0x7C00, 0x7FFF]);
+        assert_eq!(sgb.palettes[1][1..], [0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[2], DEFAULT_PALETTE);
+
+        // Every palette uses colour 0 from palette 0
+        let shades = [0; SCREEN_BUFFER_SIZE];
+        let mut frame = blank_frame();
+        sgb.attributes[0] = 2;
+        sgb.finish_frame(&shades, &mut frame);
+        assert_eq!(rgb(frame[0]), (0xFF, 0, 0));
+        assert_eq!(rgb(frame[SCREEN_WIDTH - 1]), (0xFF, 0, 0));
+    }
+
+    #[test]
+    fn packets_with_a_bad_stop_bit_are_dropped() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = PAL01 << 3 | 1;
+        packet[1] = 0x1F;
+
+        write(&mut sgb, &mut joypad, 0x00);
+        write(&mut sgb, &mut joypad, 0x30);
+        for bit in 0..PACKET_BITS {
+            let one = (packet[bit / 8] >> (bit % 8)) & 1 > 0;
+            write(&mut sgb, &mut joypad, if one { 0x10 } else { 0x20 });
+            write(&mut sgb, &mut joypad, 0x30);
+        }
+        write(&mut sgb, &mut joypad, 0x10);
+        write(&mut sgb, &mut joypad, 0x30);
+
+        assert_eq!(sgb.palettes[0][0], DEFAULT_PALETTE[0]);
+    }
+
+    #[test]
+    fn attr_blk_colours_inside_the_line_and_outside() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        // Three sets spill into a second packet. The first colours inside 1,
+        // the line 2 and outside 3. The others only colour inside or outside,
+        // so the line follows along.
+        #[rustfmt::skip]
+        let params = [
+            3,
+            0b111, 0b11_10_01, 2, 2, 5, 4,
+            0b001, 0b00_00_10, 10, 10, 12, 12,
+            0b100, 0b01_00_00, 0, 0, 19, 15,
+        ];
+        send_command(&mut sgb, &mut joypad, ATTR_BLK, &params);
+
+        assert_eq!(attribute(&sgb, 3, 3), 1);
+        assert_eq!(attribute(&sgb, 2, 2), 2);
+        assert_eq!(attribute(&sgb, 5, 3), 2);
+        assert_eq!(attribute(&sgb, 6, 3), 3);
+        assert_eq!(attribute(&sgb, 10, 10), 2);
+        assert_eq!(attribute(&sgb, 11, 11), 2);
+        assert_eq!(attribute(&sgb, 0, 0), 1);
+        assert_eq!(attribute(&sgb, 19, 17), 1);
+
+        // Cells pick their palette when the frame's coloured
+        sgb.palettes[1] = [0, 0x001F, 0, 0];
+        sgb.palettes[3] = [0, 0x7C00, 0, 0];
+        let shades = [1; SCREEN_BUFFER_SIZE];
+        let mut frame = blank_frame();
+        sgb.finish_frame(&shades, &mut frame);
+        assert_eq!(rgb(frame[3 * 8 * SCREEN_WIDTH + 3 * 8]), (0xFF, 0, 0));
+        assert_eq!(rgb(frame[3 * 8 * SCREEN_WIDTH + 6 * 8]), (0, 0, 0xFF));
+    }
+
+    #[test]
+    fn attr_lin_and_attr_div_split_the_screen() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        // Horizontal, 2 above row 9, 1 on it and 3 below
+        send_command(&mut sgb, &mut joypad, ATTR_DIV, &[0b0101_1011, 9]);
+        assert_eq!(attribute(&sgb, 0, 8), 2);
+        assert_eq!(attribute(&sgb, 0, 9), 1);
+        assert_eq!(attribute(&sgb, 0, 10), 3);
+
+        // Vertical line 4 in palette 2, horizontal line 0 in palette 1
+        send_command(
+            &mut sgb,
+            &mut joypad,
+            ATTR_LIN,
+            &[2, 0b0100_0100, 0b1010_0000],
+        );
+        assert_eq!(attribute(&sgb, 4, 5), 2);
+        assert_eq!(attribute(&sgb, 7, 0), 1);
+        assert_eq!(attribute(&sgb, 5, 5), 2);
+    }
+
+    #[test]
+    fn mask_en_blanks_freezes_and_uncovers_the_screen() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        let shades = [3; SCREEN_BUFFER_SIZE];
+        let mut frame = blank_frame();
+        let darkest = rgb(Colour::from_16_bit_colour(DEFAULT_PALETTE[3]));
+
+        send_command(&mut sgb, &mut joypad, MASK_EN, &[2]);
+        sgb.finish_frame(&shades, &mut frame);
+        assert_eq!(rgb(frame[0]), (0, 0, 0));
+
+        send_command(&mut sgb, &mut joypad, MASK_EN, &[1]);
+        frame[0] = Colour::new(1, 2, 3);
+        sgb.finish_frame(&shades, &mut frame);
+        assert_eq!(rgb(frame[0]), (1, 2, 3));
+
+        send_command(&mut sgb, &mut joypad, MASK_EN, &[3]);
+        sgb.finish_frame(&shades, &mut frame);
+        let backdrop = rgb(Colour::from_16_bit_colour(DEFAULT_PALETTE[0]));
+        assert_eq!(rgb(frame[0]), backdrop);
+
+        send_command(&mut sgb, &mut joypad, MASK_EN, &[0]);
+        sgb.finish_frame(&shades, &mut frame);
+        assert_eq!(rgb(frame[0]), darkest);
+        // The game sits in the middle of the border
+        let centre = GAME_Y * SGB_SCREEN_WIDTH + GAME_X;
+        assert_eq!(rgb(sgb.frame[centre]), darkest);
+        assert_eq!(rgb(sgb.frame[0]), backdrop);
+    }
+
+    #[test]
+    fn mlt_req_multiplexes_the_joypad() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        joypad.extra_players[0] = ExtraPlayerButtons {
+            a_pressed: true,
+            ..Default::default()
+        };
+
+        send_command(&mut sgb, &mut joypad, MLT_REQ, &[1]);
+
+        // Neither line selected reads back the controller number
+        write(&mut sgb, &mut joypad, 0x30);
+        assert_eq!(joypad.read() & 0xF, 0xF);
+        write(&mut sgb, &mut joypad, 0x10);
+        assert_eq!(joypad.read() & 0xF, 0xF);
+
+        // P15 going high moves on to player 2, who's holding A
+        write(&mut sgb, &mut joypad, 0x30);
+        assert_eq!(joypad.read() & 0xF, 0xE);
+        write(&mut sgb, &mut joypad, 0x10);
+        assert_eq!(joypad.read() & 0xF, 0b1110);
+
+        // And back round to player 1
+        write(&mut sgb, &mut joypad, 0x30);
+        assert_eq!(joypad.read() & 0xF, 0xF);
+
+        // Asking for 2 players without 4 gets 1
+        send_command(&mut sgb, &mut joypad, MLT_REQ, &[2]);
+        write(&mut sgb, &mut joypad, 0x10);
+        write(&mut sgb, &mut joypad, 0x30);
+        assert_eq!(joypad.read() & 0xF, 0xF);
+
+        send_command(&mut sgb, &mut joypad, MLT_REQ, &[3]);
+        for player in 1..4 {
+            write(&mut sgb, &mut joypad, 0x10);
+            write(&mut sgb, &mut joypad, 0x30);
+            assert_eq!(joypad.read() & 0xF, 0xF - player);
+        }
+    }
+}
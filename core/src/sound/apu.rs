@@ -1,17 +1,114 @@
+use super::audio_sink::{AudioSink, InterleavedBuffer};
 use super::channel1::APUChannel1;
 use super::channel2::APUChannel2;
 use super::channel3::APUChannel3;
 use super::channel4::APUChannel4;
 use super::registers::*;
 use crate::constants::*;
+use crate::cpu::EmulationTarget;
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
 
 pub trait APUChannel {
     fn step(&mut self);
+    // The channel's analog output, after its DAC
     fn sample(&self) -> f32;
+    fn dac_enabled(&self) -> bool;
     fn read(&self, address: u16) -> u8;
     fn write(&mut self, address: u16, value: u8);
 }
 
+// Each channel's DAC turns its 4-bit output into an analog level, from 1 at
+// 0 down to -1 at 15. A DAC that's switched off outputs nothing at all.
+pub fn dac_output(digital: usize, dac_enabled: bool) -> f32 {
+    if !dac_enabled {
+        return 0.;
+    }
+    1. - digital as f32 / 7.5
+}
+
+// How much charge the output capacitor keeps each clock. It's what stops the
+// DACs' DC offset reaching the speaker.
+// https://gbdev.io/pandocs/Audio_details.html#obscure-behavior
+const DMG_CAPACITOR_CHARGE: f64 = 0.999958;
+const CGB_CAPACITOR_CHARGE: f64 = 0.998943;
+// The GBA's sound hardware is different, but its Game Boy side seems to filter
+// like the CGB's
+const AGB_CAPACITOR_CHARGE: f64 = CGB_CAPACITOR_CHARGE;
+
+// The four channels, for the per-channel output controls
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Wave => "wave",
+            Channel::Noise => "noise",
+        }
+    }
+
+    // Channels are numbered 1 to 4, like `channel1` to `channel4`
+    pub fn from_number(number: usize) -> Option<Channel> {
+        Channel::ALL.get(number.checked_sub(1)?).copied()
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+// The high-pass filter the mixed output goes through on each side
+struct HighPassFilter {
+    capacitor: f32,
+    // How much charge is left after a whole sample's worth of clocks
+    charge_factor: f32,
+}
+
+impl HighPassFilter {
+    fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
+
+    fn new(target: &EmulationTarget) -> HighPassFilter {
+        let charge_per_clock = match target {
+            EmulationTarget::Dmg | EmulationTarget::Sgb => DMG_CAPACITOR_CHARGE,
+            EmulationTarget::CgbDmgMode | EmulationTarget::CgbCgbMode => {
+                CGB_CAPACITOR_CHARGE
+            },
+            EmulationTarget::GbaCgbMode => AGB_CAPACITOR_CHARGE,
+        };
+
+        // charge_per_clock ^ APU_SAMPLE_CLOCKS, without needing std for powi
+        let mut charge_factor = 1.;
+        for _ in 0..APU_SAMPLE_CLOCKS {
+            charge_factor *= charge_per_clock;
+        }
+
+        HighPassFilter {
+            capacitor: 0.,
+            charge_factor: charge_factor as f32,
+        }
+    }
+}
+
 // Audio processing unit
 // NOTE: Max APU frequency seems to be 131072 Hz
 pub struct APU {
@@ -27,12 +124,21 @@ pub struct APU {
     pub channel3: APUChannel3,
     pub channel4: APUChannel4,
 
+    left_filter: HighPassFilter,
+    right_filter: HighPassFilter,
+
+    // Per-channel output controls, indexed by `Channel`. These only change
+    // what's mixed into the output, never the emulated state.
+    channel_gains: [f32; 4],
+    channel_muted: [bool; 4],
+    channel_soloed: [bool; 4],
+
     pub sample_counter: usize,
-    // This could be a Vec that we check len() against, but we can save the
-    // allocation because we know the size it's always going to be.
-    pub buffer: [i16; SOUND_BUFFER_SIZE],
-    pub buffer_idx: usize,
-    pub buffer_full: bool,
+    // Filled with SOUND_BUFFER_SIZE interleaved samples at a time, for ports
+    // that poll for audio
+    pub buffer: InterleavedBuffer<i16>,
+    // Pushed every sample as it's made, for ports that want that instead
+    sink: Option<Box<dyn AudioSink>>,
 }
 
 impl APU {
@@ -54,8 +160,15 @@ impl APU {
         let mut left_sample = 0.;
         let mut right_sample = 0.;
 
+        let channels = [
+            self.channel1.sample(),
+            self.channel2.sample(),
+            self.channel3.sample(),
+            self.channel4.sample(),
+        ];
+        let [chan1, chan2, chan3, chan4] = self.mixer_levels(channels);
+
         // TODO: Maybe we could generate these with a macro?
-        let chan1 = self.channel1.sample();
         if self.stereo_panning.channel1_left {
             left_sample += chan1;
         }
@@ -63,7 +176,6 @@ impl APU {
             right_sample += chan1;
         }
 
-        let chan2 = self.channel2.sample();
         if self.stereo_panning.channel2_left {
             left_sample += chan2;
         }
@@ -71,7 +183,6 @@ impl APU {
             right_sample += chan2;
         }
 
-        let chan3 = self.channel3.sample();
         if self.stereo_panning.channel3_left {
             left_sample += chan3;
         }
@@ -79,7 +190,6 @@ impl APU {
             right_sample += chan3;
         }
 
-        let chan4 = self.channel4.sample();
         if self.stereo_panning.channel4_left {
             left_sample += chan4;
         }
@@ -95,18 +205,76 @@ impl APU {
         left_sample *= self.stereo_left_volume;
         right_sample *= self.stereo_right_volume;
 
-        let left_sample_int = (left_sample * 30_000.) as i16;
-        let right_sample_int = (right_sample * 30_000.) as i16;
+        // The capacitors only see a signal while a DAC is powering them
+        let any_dac_enabled = self.channel1.dac_enabled()
+            || self.channel2.dac_enabled()
+            || self.channel3.dac_enabled()
+            || self.channel4.dac_enabled();
+        if any_dac_enabled {
+            left_sample = self.left_filter.filter(left_sample);
+            right_sample = self.right_filter.filter(right_sample);
+        } else {
+            left_sample = 0.;
+            right_sample = 0.;
+        }
+
+        self.buffer.push_sample(left_sample, right_sample);
 
-        self.buffer[self.buffer_idx] = left_sample_int;
-        self.buffer_idx += 1;
-        self.buffer[self.buffer_idx] = right_sample_int;
-        self.buffer_idx += 1;
+        if let Some(sink) = &mut self.sink {
+            if sink.wants_channel_samples() {
+                sink.push_channel_samples(channels);
+            }
+            sink.push_sample(left_sample, right_sample);
+        }
+    }
 
-        if self.buffer_idx == SOUND_BUFFER_SIZE {
-            self.buffer_idx = 0;
-            self.buffer_full = true;
+    fn mixer_levels(&self, channels: [f32; 4]) -> [f32; 4] {
+        let any_soloed = self.channel_soloed.contains(&true);
+        let mut levels = channels;
+        for (i, level) in levels.iter_mut().enumerate() {
+            let audible = !self.channel_muted[i]
+                && (!any_soloed || self.channel_soloed[i]);
+            *level *= if audible { self.channel_gains[i] } else { 0. };
         }
+        levels
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.channel_muted[channel.index()] = muted;
+    }
+
+    pub fn is_channel_muted(&self, channel: Channel) -> bool {
+        self.channel_muted[channel.index()]
+    }
+
+    // While any channel is soloed, only soloed channels are heard
+    pub fn set_channel_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.channel_soloed[channel.index()] = soloed;
+    }
+
+    pub fn is_channel_soloed(&self, channel: Channel) -> bool {
+        self.channel_soloed[channel.index()]
+    }
+
+    // 1.0 is normal volume
+    pub fn set_channel_gain(&mut self, channel: Channel, gain: f32) {
+        self.channel_gains[channel.index()] = gain;
+    }
+
+    pub fn channel_gain(&self, channel: Channel) -> f32 {
+        self.channel_gains[channel.index()]
+    }
+
+    // Returns the old sink, if there was one
+    pub fn set_audio_sink(
+        &mut self,
+        sink: Box<dyn AudioSink>,
+    ) -> Option<Box<dyn AudioSink>> {
+        self.sink.replace(sink)
+    }
+
+    pub fn remove_audio_sink(&mut self) -> Option<Box<dyn AudioSink>> {
+        self.sink.take()
     }
 
     #[allow(unused_variables)]
@@ -160,19 +328,19 @@ impl APU {
         let right_vol = nr50 & 0b111;
         let left_vol = (nr50 & 0b111_0_000) >> 4;
 
-        self.stereo_left_volume = (left_vol as f32) / 7.;
-        self.stereo_right_volume = (right_vol as f32) / 7.;
+        // Volume 0 is quiet, not silent
+        self.stereo_left_volume = (left_vol + 1) as f32 / 8.;
+        self.stereo_right_volume = (right_vol + 1) as f32 / 8.;
     }
     fn serialise_nr50(&self) -> u8 {
-        // These might turn out 1 level too low because of float flooring
-        // TODO: Test this
-        let right_vol = (self.stereo_right_volume * 7.) as u8;
-        let left_vol = (self.stereo_left_volume * 7.) as u8;
+        // Eighths are exact in floats, so these round trip
+        let right_vol = (self.stereo_right_volume * 8.) as u8 - 1;
+        let left_vol = (self.stereo_left_volume * 8.) as u8 - 1;
 
-        (left_vol << 4) & right_vol
+        (left_vol << 4) | right_vol
     }
 
-    pub fn new() -> APU {
+    pub fn new(target: &EmulationTarget) -> APU {
         APU {
             // These might be meant to start 0, not sure
             stereo_left_volume: 1.,
@@ -185,10 +353,51 @@ impl APU {
             channel3: APUChannel3::new(),
             channel4: APUChannel4::new(),
 
+            left_filter: HighPassFilter::new(target),
+            right_filter: HighPassFilter::new(target),
+
+            channel_gains: [1.; 4],
+            channel_muted: [false; 4],
+            channel_soloed: [false; 4],
+
             sample_counter: 0,
-            buffer: [0; SOUND_BUFFER_SIZE],
-            buffer_idx: 0,
-            buffer_full: false,
+            buffer: InterleavedBuffer::new(SOUND_BUFFER_SIZE),
+            sink: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_are_numbered_from_one() {
+        assert!(Channel::from_number(0).is_none());
+        assert!(Channel::from_number(1) == Some(Channel::Pulse1));
+        assert!(Channel::from_number(4) == Some(Channel::Noise));
+        assert!(Channel::from_number(5).is_none());
+    }
+
+    #[test]
+    fn muting_and_soloing_only_change_the_mix() {
+        let mut apu = APU::new(&EmulationTarget::Dmg);
+        let loud = [1.; 4];
+
+        apu.set_channel_gain(Channel::Pulse2, 0.5);
+        apu.set_channel_muted(Channel::Wave, true);
+        assert_eq!(apu.mixer_levels(loud), [1., 0.5, 0., 1.]);
+
+        // Soloing silences everything else, but muting still wins
+        apu.set_channel_soloed(Channel::Pulse2, true);
+        apu.set_channel_soloed(Channel::Wave, true);
+        assert_eq!(apu.mixer_levels(loud), [0., 0.5, 0., 0.]);
+
+        apu.set_channel_soloed(Channel::Pulse2, false);
+        apu.set_channel_soloed(Channel::Wave, false);
+        apu.set_channel_muted(Channel::Wave, false);
+        assert_eq!(apu.mixer_levels(loud), [1., 0.5, 1., 1.]);
+        assert!(!apu.is_channel_soloed(Channel::Pulse2));
+        assert_eq!(apu.channel_gain(Channel::Pulse2), 0.5);
+    }
+}
@@ -0,0 +1,84 @@
+// Where the APU's samples go. The APU always fills its own `buffer`, and a
+// port can also hand it a sink to be pushed every sample as it's made, which
+// suits callback-driven audio backends and visualisers.
+#[cfg(not(feature = "std"))]
+use alloc::{vec, vec::Vec};
+
+pub trait AudioSink {
+    // One stereo sample at SOUND_SAMPLE_RATE, each side from -1 to 1
+    fn push_sample(&mut self, left: f32, right: f32);
+
+    // Sinks that return true here are also given the four channels' outputs
+    // (pulse 1, pulse 2, wave and noise) before they're panned and mixed.
+    // They arrive just before the mixed sample they make up.
+    fn wants_channel_samples(&self) -> bool {
+        false
+    }
+
+    fn push_channel_samples(&mut self, _channels: [f32; 4]) {}
+}
+
+// The sample types ports tend to want
+pub trait Sample: Copy + Default {
+    fn from_f32(sample: f32) -> Self;
+}
+
+impl Sample for i16 {
+    fn from_f32(sample: f32) -> i16 {
+        // Leave a bit of headroom below i16::MAX
+        (sample * 30_000.) as i16
+    }
+}
+
+impl Sample for f32 {
+    fn from_f32(sample: f32) -> f32 {
+        sample
+    }
+}
+
+// Interleaved stereo (left, right, left...) that fills up, flags that it's
+// full, then starts again from the beginning
+pub struct InterleavedBuffer<S: Sample> {
+    samples: Vec<S>,
+    position: usize,
+    full: bool,
+}
+
+impl<S: Sample> InterleavedBuffer<S> {
+    pub fn samples(&self) -> &[S] {
+        &self.samples
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    // Whether the buffer has filled up since this was last called
+    pub fn take_full(&mut self) -> bool {
+        let full = self.full;
+        self.full = false;
+        full
+    }
+
+    // `size` counts left and right separately, so it should be even
+    pub fn new(size: usize) -> InterleavedBuffer<S> {
+        InterleavedBuffer {
+            samples: vec![S::default(); size],
+            position: 0,
+            full: false,
+        }
+    }
+}
+
+impl<S: Sample> AudioSink for InterleavedBuffer<S> {
+    fn push_sample(&mut self, left: f32, right: f32) {
+        self.samples[self.position] = S::from_f32(left);
+        self.samples[self.position + 1] = S::from_f32(right);
+        self.position += 2;
+
+        if self.position == self.samples.len() {
+            self.position = 0;
+            self.full = true;
+        }
+    }
+}
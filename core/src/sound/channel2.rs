@@ -1,4 +1,4 @@
-use super::apu::APUChannel;
+use super::apu::{dac_output, APUChannel};
 use super::length_function::LengthFunction;
 use super::volume_envelope::VolumeEnvelope;
 
@@ -102,9 +102,15 @@ impl APUChannel for APUChannel2 {
         }
     }
 
+    fn dac_enabled(&self) -> bool {
+        self.volume_envelope.dac_enabled
+    }
+
     fn sample(&self) -> f32 {
+        let dac_enabled = self.volume_envelope.dac_enabled;
+        // A channel that's off still feeds its DAC a 0
         if !self.length_function.channel_enabled {
-            return 0.;
+            return dac_output(0, dac_enabled);
         }
 
         let wave_pattern = WAVEFORM_TABLE[self.wave_duty];
@@ -112,7 +118,6 @@ impl APUChannel for APUChannel2 {
             >> self.wave_duty_position;
 
         let dac_input = amplitude_bit as usize * self.volume_envelope.volume;
-        // The DAC in the Gameboy outputs between -1.0 and 1.0
-        (dac_input as f32 / 7.5) - 1.0
+        dac_output(dac_input, dac_enabled)
     }
 }
@@ -1,4 +1,4 @@
-use super::apu::APUChannel;
+use super::apu::{dac_output, APUChannel};
 use super::length_function::LengthFunction;
 use crate::constants::*;
 use crate::memory::ram::Ram;
@@ -98,9 +98,16 @@ impl APUChannel for APUChannel3 {
         }
     }
 
+    fn dac_enabled(&self) -> bool {
+        self.master_enable
+    }
+
     fn sample(&self) -> f32 {
+        // NR30's enable bit is really this channel's DAC switch
+        let dac_enabled = self.master_enable;
+        // A channel that's off still feeds its DAC a 0
         if !self.length_function.channel_enabled {
-            return 0.;
+            return dac_output(0, dac_enabled);
         }
 
         // This implementation is a bit guessed for now :)
@@ -121,6 +128,6 @@ impl APUChannel for APUChannel3 {
                 _ => unreachable!(),
             };
 
-        (wave_nibble as f32 / 7.5) - 1.0
+        dac_output(wave_nibble as usize, dac_enabled)
     }
 }
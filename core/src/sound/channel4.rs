@@ -1,4 +1,4 @@
-use super::apu::APUChannel;
+use super::apu::{dac_output, APUChannel};
 use super::length_function::LengthFunction;
 use super::volume_envelope::VolumeEnvelope;
 
@@ -106,14 +106,20 @@ impl APUChannel for APUChannel4 {
         }
     }
 
+    fn dac_enabled(&self) -> bool {
+        self.volume_envelope.dac_enabled
+    }
+
     fn sample(&self) -> f32 {
+        let dac_enabled = self.volume_envelope.dac_enabled;
+        // A channel that's off still feeds its DAC a 0
         if !self.length_function.channel_enabled {
-            return 0.;
+            return dac_output(0, dac_enabled);
         }
 
         let lfsr_bit = !(self.lfsr) & 1;
 
         let dac_input = lfsr_bit as usize * self.volume_envelope.volume;
-        (dac_input as f32 / 7.5) - 1.0
+        dac_output(dac_input, dac_enabled)
     }
 }
@@ -1,4 +1,5 @@
 pub mod apu;
+pub mod audio_sink;
 pub mod channel1;
 pub mod channel2;
 pub mod channel3;
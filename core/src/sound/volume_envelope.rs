@@ -14,6 +14,8 @@ pub struct VolumeEnvelope {
     period_timer: usize,
     volume_timer: usize,
     pub volume: usize,
+    // The channel's DAC is only on while the top 5 bits of NRx2 aren't all 0
+    pub dac_enabled: bool,
 }
 
 impl VolumeEnvelope {
@@ -38,6 +40,7 @@ impl VolumeEnvelope {
             EnvelopeDirection::Down
         };
         self.sweep_period = value as usize & 0b0000_0111;
+        self.dac_enabled = (value & 0b1111_1000) != 0;
     }
 
     // Called at 64Hz
@@ -70,6 +73,7 @@ impl VolumeEnvelope {
             period_timer: 0,
             volume: 0,
             volume_timer: 0,
+            dac_enabled: false,
         }
     }
 }
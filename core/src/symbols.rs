@@ -0,0 +1,274 @@
+// Labels from the .sym files RGBDS writes alongside a ROM (`rgblink -n`), so
+// the debugger, traces and disassembly can talk about `Main.loop` rather
+// than 01:4123. Each line is `BANK:ADDRESS Label` in hex, and `;` starts a
+// comment.
+//   https://rgbds.gbdev.io/sym/
+use crate::log;
+
+#[cfg(not(feature = "std"))]
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Symbol {
+    pub bank: u16,
+    pub address: u16,
+    pub name: String,
+}
+
+// Labels are only near addresses in the same bit of the memory map. A WRAM
+// address shouldn't come out as the last label in ROM plus 0x8000.
+fn region(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xFE00..=0xFE9F => 0xFE00,
+        0xFF80..=0xFFFE => 0xFF80,
+        // Echo RAM, unusable memory and IO registers don't get labels
+        _ => address,
+    }
+}
+
+#[derive(Clone)]
+pub struct SymbolTable {
+    // Sorted by bank, then address
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut symbols = Vec::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parsed = line.split_once(char::is_whitespace).and_then(
+                |(location, name)| {
+                    let (bank, address) = location.split_once(':')?;
+                    Some((
+                        u16::from_str_radix(bank, 16).ok()?,
+                        u16::from_str_radix(address, 16).ok()?,
+                        name.trim(),
+                    ))
+                },
+            );
+
+            match parsed {
+                Some((mut bank, address, name)) => {
+                    // ROMs linked with `rgblink -t` have no MBC and put
+                    // 0x4000-0x7FFF in bank 0, but that's bank 1 to us
+                    if bank == 0 && region(address) == 0x4000 {
+                        bank = 1;
+                    }
+                    symbols.push(Symbol {
+                        bank,
+                        address,
+                        name: name.to_string(),
+                    });
+                },
+                None => log!("[WARN] Ignoring bad symbol line \"{}\"", line),
+            }
+        }
+
+        symbols.sort_by_key(|symbol| (symbol.bank, symbol.address));
+        SymbolTable { symbols }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load(path: &str) -> std::io::Result<SymbolTable> {
+        Ok(SymbolTable::parse(&std::fs::read_to_string(path)?))
+    }
+
+    // RGBDS projects build game.gb and game.sym side by side. Gives an
+    // empty table if there isn't one, or there's no filesystem to look in.
+    pub fn load_for_rom(rom_path: &str) -> SymbolTable {
+        #[cfg(feature = "std")]
+        if !rom_path.is_empty() {
+            let path = std::path::Path::new(rom_path).with_extension("sym");
+            let path = path.to_string_lossy();
+            if let Ok(table) = SymbolTable::load(&path) {
+                log!("Loaded {} symbols from {}", table.len(), path);
+                return table;
+            }
+        }
+
+        #[cfg(not(feature = "std"))]
+        let _ = rom_path;
+        SymbolTable::new()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // Where a label is
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // The first label at exactly this address. `None` for the bank matches
+    // a label in any bank.
+    pub fn label_at(&self, bank: Option<u16>, address: u16) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|symbol| {
+                symbol.address == address
+                    && bank.is_none_or(|bank| bank == symbol.bank)
+            })
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    // The closest label at or before an address, and how far past it the
+    // address is
+    pub fn nearest(
+        &self,
+        bank: Option<u16>,
+        address: u16,
+    ) -> Option<(&Symbol, u16)> {
+        let symbol = match bank {
+            Some(bank) => self.nearest_in_bank(bank, address),
+            // Each bank's closest label, and then the closest of those
+            None => self
+                .symbols
+                .chunk_by(|a, b| a.bank == b.bank)
+                .filter_map(|bank| self.nearest_in_bank(bank[0].bank, address))
+                .max_by_key(|symbol| symbol.address),
+        }?;
+        Some((symbol, address - symbol.address))
+    }
+
+    // The table's sorted, so the closest label is just before where this
+    // address would go, as long as it's in the same bank and region
+    fn nearest_in_bank(&self, bank: u16, address: u16) -> Option<&Symbol> {
+        let end = self.symbols.partition_point(|symbol| {
+            (symbol.bank, symbol.address) <= (bank, address)
+        });
+        let symbol = self.symbols[..end].last()?;
+        (symbol.bank == bank && region(symbol.address) == region(address))
+            .then_some(symbol)
+    }
+
+    // `Label` or `Label+$3`, as RGBDS would write it
+    pub fn describe(&self, bank: Option<u16>, address: u16) -> Option<String> {
+        self.nearest(bank, address)
+            .map(|(symbol, offset)| match offset {
+                0 => symbol.name.clone(),
+                _ => format!("{}+${:X}", symbol.name, offset),
+            })
+    }
+
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: Vec::new(),
+        }
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+00:0150 Start
+00:0150 Start.entry
+00:0160 Start.loop ; the main loop
+01:4000 Bank1Code
+02:4000 Bank2Code
+02:4080 Bank2Code.end
+00:c000 wBuffer
+01:a000 sSave
+not a symbol
+zz:0100 BadBank
+";
+
+    #[test]
+    fn parses_sym_files() {
+        let table = SymbolTable::parse(SYM);
+        assert_eq!(table.len(), 8);
+
+        let symbol = table.lookup("Start.loop").unwrap();
+        assert_eq!((symbol.bank, symbol.address), (0, 0x0160));
+        assert_eq!(table.lookup("wBuffer").unwrap().address, 0xC000);
+        assert!(table.lookup("BadBank").is_none());
+
+        // Sorted by bank, then address
+        let keys: Vec<_> = table
+            .symbols()
+            .iter()
+            .map(|symbol| (symbol.bank, symbol.address))
+            .collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn rgblink_tiny_roms_move_to_bank_1() {
+        let table = SymbolTable::parse("00:4000 Upper\n00:3FFF Lower");
+        assert_eq!(table.lookup("Upper").unwrap().bank, 1);
+        assert_eq!(table.lookup("Lower").unwrap().bank, 0);
+    }
+
+    #[test]
+    fn describes_addresses_as_labels() {
+        let table = SymbolTable::parse(SYM);
+        assert_eq!(table.describe(Some(0), 0x0150).unwrap(), "Start.entry");
+        assert_eq!(table.describe(Some(0), 0x015F).unwrap(), "Start.entry+$F");
+        assert_eq!(
+            table.describe(Some(0), 0x3FFF).unwrap(),
+            "Start.loop+$3E9F"
+        );
+        assert_eq!(table.describe(Some(0), 0x0100), None);
+        assert_eq!(table.describe(None, 0xC010).unwrap(), "wBuffer+$10");
+    }
+
+    #[test]
+    fn labels_stay_in_their_region() {
+        let table = SymbolTable::parse(SYM);
+        // The last label in bank 0 ROM isn't near bank 0 WRAM upper
+        assert_eq!(table.describe(Some(0), 0xD000), None);
+        // Nor is bank 1's ROM label near its cartridge RAM
+        assert_eq!(table.describe(Some(1), 0x9FFF), None);
+        assert_eq!(table.describe(Some(1), 0xA001).unwrap(), "sSave+$1");
+        assert_eq!(table.describe(None, 0xFF80), None);
+    }
+
+    #[test]
+    fn describes_by_bank() {
+        let table = SymbolTable::parse(SYM);
+        assert_eq!(table.describe(Some(1), 0x4010).unwrap(), "Bank1Code+$10");
+        assert_eq!(
+            table.describe(Some(2), 0x4090).unwrap(),
+            "Bank2Code.end+$10"
+        );
+        assert_eq!(table.describe(Some(3), 0x4000), None);
+        // Any bank gives whichever label is closest
+        assert_eq!(table.describe(None, 0x4090).unwrap(), "Bank2Code.end+$10");
+        assert_eq!(table.label_at(Some(1), 0x4000), Some("Bank1Code"));
+    }
+}
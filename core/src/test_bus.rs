@@ -0,0 +1,139 @@
+// 64KB of plain RAM for testing the CPU on its own. A `Cpu<TestBus>` (see
+// `Cpu::with_test_bus`) sends every fetch, read and write here, and has no
+// GPU, APU or anything else to step. Accesses are recorded so tests can
+// check an instruction's bus activity.
+use crate::bus::Bus;
+use crate::interrupts::Interrupts;
+
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, vec::Vec};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BusAccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BusAccess {
+    pub kind: BusAccessKind,
+    pub address: u16,
+    pub value: u8,
+}
+
+pub struct TestBus {
+    memory: Box<[u8; 0x10000]>,
+    // In the order the CPU made them
+    pub accesses: Vec<BusAccess>,
+}
+
+impl TestBus {
+    pub fn read(&mut self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        self.accesses.push(BusAccess {
+            kind: BusAccessKind::Read,
+            address,
+            value,
+        });
+        value
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+        self.accesses.push(BusAccess {
+            kind: BusAccessKind::Write,
+            address,
+            value,
+        });
+    }
+
+    // Reads and writes that aren't recorded, for setting up and checking
+    // tests
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+
+    pub fn new() -> TestBus {
+        TestBus {
+            memory: Box::new([0; 0x10000]),
+            accesses: Vec::new(),
+        }
+    }
+}
+
+impl Bus for TestBus {
+    // There's nothing behind the RAM
+    type Gpu = ();
+
+    #[inline(always)]
+    fn read(&mut self, _: &Interrupts, _: &(), address: u16) -> u8 {
+        TestBus::read(self, address)
+    }
+    #[inline(always)]
+    fn write(
+        &mut self,
+        _: &mut Interrupts,
+        _: &mut (),
+        address: u16,
+        value: u8,
+    ) {
+        TestBus::write(self, address, value)
+    }
+    fn peek(&self, _: &Interrupts, _: &(), address: u16) -> u8 {
+        TestBus::peek(self, address)
+    }
+
+    fn stop(&mut self) -> usize {
+        4
+    }
+
+    fn bank_at(&self, _: u16) -> u16 {
+        0
+    }
+    fn ly(&self, _: &()) -> u8 {
+        0
+    }
+    fn double_speed(&self) -> bool {
+        false
+    }
+}
+
+impl Default for TestBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn cpu_runs_on_the_test_bus() {
+        let mut cpu = Cpu::with_test_bus();
+        cpu.regs.pc = 0x0100;
+        // LD A, $42 then LD ($C000), A
+        for (offset, byte) in [0x3E, 0x42, 0xEA, 0x00, 0xC0].iter().enumerate()
+        {
+            cpu.mem.poke(0x0100 + offset as u16, *byte);
+        }
+
+        assert_eq!(cpu.single_speed_step(), 8);
+        assert_eq!(cpu.single_speed_step(), 16);
+        assert_eq!(cpu.mem.peek(0xC000), 0x42);
+        assert_eq!(
+            cpu.mem.accesses.last(),
+            Some(&BusAccess {
+                kind: BusAccessKind::Write,
+                address: 0xC000,
+                value: 0x42,
+            })
+        );
+        assert_eq!(cpu.mem.accesses.len(), 6);
+    }
+}
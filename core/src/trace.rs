@@ -0,0 +1,364 @@
+// One line per instruction, for diffing gbrs against other emulators.
+// The default format is Gameboy Doctor's:
+//   https://github.com/robert/gameboy-doctor
+// Doctor expects LY (0xFF44) to always read 0x90, so set `Gpu::stub_ly` (or
+// use `Cpu::start_doctor_trace`) for logs that match its references.
+use crate::log;
+use crate::registers::Registers;
+use crate::symbols::SymbolTable;
+use core::fmt::{self, Write};
+use core::ops::RangeInclusive;
+
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceFormat {
+    // A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 PCMEM:00,00,00,00
+    GameboyDoctor,
+    // The same, followed by CY (cycles since tracing started), LY, BANK
+    // (mapped at PC) and IME, then the nearest label to PC if the ROM has
+    // symbols, eg. `; Main.loop+$3`
+    Extended,
+}
+
+impl TraceFormat {
+    pub const ALL: [TraceFormat; 2] =
+        [TraceFormat::GameboyDoctor, TraceFormat::Extended];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceFormat::GameboyDoctor => "doctor",
+            TraceFormat::Extended => "extended",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        TraceFormat::ALL
+            .iter()
+            .find(|format| format.name() == name)
+            .copied()
+    }
+}
+
+// The CPU's state just before an instruction runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceEntry {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    // The 4 bytes from PC onwards
+    pub pcmem: [u8; 4],
+    pub cycles: u64,
+    pub ly: u8,
+    pub bank: u16,
+    pub ime: bool,
+}
+
+impl TraceEntry {
+    pub fn new(
+        regs: &Registers,
+        pcmem: [u8; 4],
+        cycles: u64,
+        ly: u8,
+        bank: u16,
+        ime: bool,
+    ) -> TraceEntry {
+        TraceEntry {
+            a: regs.a,
+            f: regs.f,
+            b: regs.b,
+            c: regs.c,
+            d: regs.d,
+            e: regs.e,
+            h: regs.h,
+            l: regs.l,
+            sp: regs.sp,
+            pc: regs.pc,
+            pcmem,
+            cycles,
+            ly,
+            bank,
+            ime,
+        }
+    }
+
+    // Writes the line without a trailing newline
+    pub fn write(
+        &self,
+        format: TraceFormat,
+        symbols: &SymbolTable,
+        out: &mut impl Write,
+    ) -> fmt::Result {
+        write!(
+            out,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} \
+             L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.a,
+            self.f,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.sp,
+            self.pc,
+            self.pcmem[0],
+            self.pcmem[1],
+            self.pcmem[2],
+            self.pcmem[3],
+        )?;
+
+        if format == TraceFormat::Extended {
+            write!(
+                out,
+                " CY:{} LY:{:02X} BANK:{:02X} IME:{}",
+                self.cycles, self.ly, self.bank, self.ime as u8
+            )?;
+            if let Some(label) = symbols.describe(Some(self.bank), self.pc) {
+                write!(out, " ; {}", label)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_line(
+        &self,
+        format: TraceFormat,
+        symbols: &SymbolTable,
+    ) -> String {
+        let mut line = String::new();
+        // Writing to a String can't fail
+        self.write(format, symbols, &mut line).unwrap();
+        line
+    }
+}
+
+// Where traced lines are streamed as they happen
+pub trait TraceWriter {
+    // `line` has no trailing newline. An error stops the trace going to
+    // this writer.
+    fn write_line(&mut self, line: &str) -> Result<(), String>;
+}
+
+// Streams to anything std can write to, eg. a BufWriter around a file
+#[cfg(feature = "std")]
+pub struct IoTraceWriter<W: std::io::Write> {
+    writer: W,
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> IoTraceWriter<W> {
+    pub fn new(writer: W) -> IoTraceWriter<W> {
+        IoTraceWriter { writer }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> TraceWriter for IoTraceWriter<W> {
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.writer, "{}", line).map_err(|e| e.to_string())
+    }
+}
+
+// Keeps the last `capacity` entries, for dumping after something goes wrong
+struct RingBuffer {
+    entries: Vec<TraceEntry>,
+    capacity: usize,
+    // Where the next entry goes once the buffer is full
+    next: usize,
+}
+
+impl RingBuffer {
+    fn push(&mut self, entry: TraceEntry) {
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+        } else {
+            self.entries[self.next] = entry;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &TraceEntry> {
+        let (newer, older) = if self.entries.len() < self.capacity {
+            (&self.entries[..], &self.entries[..0])
+        } else {
+            self.entries.split_at(self.next)
+        };
+        older.iter().chain(newer.iter())
+    }
+}
+
+// Set `Cpu::tracer` to start tracing. Entries can be streamed to a writer,
+// kept in a ring buffer, or both.
+pub struct Tracer {
+    format: TraceFormat,
+    pc_range: Option<RangeInclusive<u16>>,
+    bank: Option<u16>,
+    ring_buffer: Option<RingBuffer>,
+    writer: Option<Box<dyn TraceWriter>>,
+    // Counted even when instructions are filtered out
+    pub(crate) cycles: u64,
+    line: String,
+}
+
+impl Tracer {
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: TraceFormat) {
+        self.format = format;
+    }
+
+    // Only trace instructions with PC in `range`. `None` traces everything.
+    pub fn set_pc_range(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.pc_range = range;
+    }
+
+    // Only trace instructions running from this bank (see `Memory::bank_at`)
+    pub fn set_bank(&mut self, bank: Option<u16>) {
+        self.bank = bank;
+    }
+
+    // Keeps the last `capacity` entries. 0 turns the ring buffer off.
+    pub fn set_ring_buffer_size(&mut self, capacity: usize) {
+        self.ring_buffer = if capacity == 0 {
+            None
+        } else {
+            Some(RingBuffer {
+                entries: Vec::with_capacity(capacity),
+                capacity,
+                next: 0,
+            })
+        };
+    }
+
+    // Returns the old writer if there was one
+    pub fn set_writer(
+        &mut self,
+        writer: Box<dyn TraceWriter>,
+    ) -> Option<Box<dyn TraceWriter>> {
+        self.writer.replace(writer)
+    }
+
+    pub fn remove_writer(&mut self) -> Option<Box<dyn TraceWriter>> {
+        self.writer.take()
+    }
+
+    // The ring buffer's entries, oldest first
+    pub fn recent_entries(&self) -> Vec<TraceEntry> {
+        match &self.ring_buffer {
+            Some(ring_buffer) => ring_buffer.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Writes the ring buffer out, oldest first, eg. after a crash
+    pub fn dump_recent(
+        &self,
+        writer: &mut dyn TraceWriter,
+        symbols: &SymbolTable,
+    ) -> Result<(), String> {
+        let mut line = String::new();
+        for entry in self.recent_entries() {
+            line.clear();
+            entry.write(self.format, symbols, &mut line).unwrap();
+            writer.write_line(&line)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn wants(&self, pc: u16, bank: u16) -> bool {
+        self.pc_range
+            .as_ref()
+            .is_none_or(|range| range.contains(&pc))
+            && self.bank.is_none_or(|wanted| wanted == bank)
+    }
+
+    pub(crate) fn record(&mut self, entry: TraceEntry, symbols: &SymbolTable) {
+        if let Some(ring_buffer) = &mut self.ring_buffer {
+            ring_buffer.push(entry);
+        }
+        if let Some(writer) = &mut self.writer {
+            self.line.clear();
+            entry.write(self.format, symbols, &mut self.line).unwrap();
+            // A trace that's missing lines is useless for diffing, so stop
+            // rather than carry on with gaps
+            if let Err(e) = writer.write_line(&self.line) {
+                log!("[WARN] Stopped writing the trace: {}", e);
+                self.writer = None;
+            }
+        }
+    }
+
+    pub fn new(format: TraceFormat) -> Tracer {
+        Tracer {
+            format,
+            pc_range: None,
+            bank: None,
+            ring_buffer: None,
+            writer: None,
+            cycles: 0,
+            line: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::EmulationTarget;
+    #[cfg(not(feature = "std"))]
+    use alloc::string::ToString;
+
+    struct FullDisk;
+
+    impl TraceWriter for FullDisk {
+        fn write_line(&mut self, _line: &str) -> Result<(), String> {
+            Err("No space left on device".to_string())
+        }
+    }
+
+    #[test]
+    fn failing_writers_are_dropped() {
+        let mut tracer = Tracer::new(TraceFormat::GameboyDoctor);
+        tracer.set_writer(Box::new(FullDisk));
+        tracer.set_ring_buffer_size(2);
+
+        let entry = TraceEntry::new(
+            &Registers::new(&EmulationTarget::Dmg),
+            [0; 4],
+            0,
+            0,
+            0,
+            false,
+        );
+        tracer.record(entry, &SymbolTable::new());
+        assert!(tracer.remove_writer().is_none());
+        // Entries still go to the ring buffer
+        tracer.record(entry, &SymbolTable::new());
+        assert_eq!(tracer.recent_entries().len(), 2);
+    }
+
+    #[test]
+    fn doctor_lines_match_its_format() {
+        let mut regs = Registers::new(&EmulationTarget::Dmg);
+        regs.pc = 0x0100;
+        let entry =
+            TraceEntry::new(&regs, [0x00, 0xC3, 0x50, 0x01], 0, 0, 0, false);
+        assert_eq!(
+            entry.to_line(TraceFormat::GameboyDoctor, &SymbolTable::new()),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 \
+             PCMEM:00,C3,50,01"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+// Getting finished frames out of the core in the format a port draws with,
+// so every port doesn't have to convert `Colour`s itself
+use crate::colour::colour::Colour;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // Bytes in R, G, B, A order
+    Rgba8888,
+    // Little-endian 32-bit 0x00RRGGBB, what libretro calls XRGB8888
+    Xrgb8888,
+    // Little-endian 16-bit, red in the top 5 bits
+    Rgb565,
+    // Little-endian 16-bit, like the CGB's own palette RAM (red in the bottom
+    // 5 bits)
+    Bgr555,
+    // One byte per pixel with the DMG shade (0-3), for ports with a fixed
+    // 4-colour display. CGB games' colours can't be shown this way.
+    Indexed,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgba8888 | PixelFormat::Xrgb8888 => 4,
+            PixelFormat::Rgb565 | PixelFormat::Bgr555 => 2,
+            PixelFormat::Indexed => 1,
+        }
+    }
+
+    // Writes one pixel into the start of `out`
+    pub fn encode(&self, colour: &Colour, shade: u8, out: &mut [u8]) {
+        let (r, g, b) = (colour.red, colour.green, colour.blue);
+        match self {
+            PixelFormat::Rgba8888 => {
+                out[..4].copy_from_slice(&[r, g, b, 0xFF]);
+            },
+            PixelFormat::Xrgb8888 => {
+                out[..4].copy_from_slice(&xrgb8888(colour).to_le_bytes());
+            },
+            PixelFormat::Rgb565 => {
+                let value = (r as u16 >> 3) << 11
+                    | (g as u16 >> 2) << 5
+                    | (b as u16 >> 3);
+                out[..2].copy_from_slice(&value.to_le_bytes());
+            },
+            PixelFormat::Bgr555 => {
+                let value = (b as u16 >> 3) << 10
+                    | (g as u16 >> 3) << 5
+                    | (r as u16 >> 3);
+                out[..2].copy_from_slice(&value.to_le_bytes());
+            },
+            PixelFormat::Indexed => out[0] = shade,
+        }
+    }
+}
+
+// For ports that keep XRGB8888 pixels as u32s rather than bytes
+pub fn xrgb8888(colour: &Colour) -> u32 {
+    (colour.red as u32) << 16 | (colour.green as u32) << 8 | colour.blue as u32
+}
+
+// Ports can hand the GPU one of these to be given each frame the moment it's
+// finished, at the start of VBlank, in the format they want
+pub trait VideoSink {
+    fn pixel_format(&self) -> PixelFormat;
+
+    // `frame` is SCREEN_WIDTH x SCREEN_HEIGHT pixels in `pixel_format`. It's
+    // only valid until the next frame, so copy it if it needs to be kept.
+    fn frame_ready(&mut self, frame: &[u8]);
+}
@@ -1,18 +1,48 @@
+use gbrs_core::colour::colour_correction::ColourCorrection;
+use gbrs_core::colour::dmg_palettes::DmgPalettePreset;
 use gbrs_core::config::Config;
 use gbrs_core::constants::*;
 use gbrs_core::cpu::Cpu;
 use gbrs_core::memory::rom::Rom;
+use gbrs_core::video::xrgb8888;
 use libretro_rs::c_utf8::{c_utf8, CUtf8};
 use libretro_rs::ffi::retro_log_level::*;
-use libretro_rs::retro::env::{Init, UnloadGame};
+use libretro_rs::retro::env::{Environment, Init, UnloadGame};
 use libretro_rs::retro::pixel::{Format, XRGB8888};
 use libretro_rs::retro::*;
 use libretro_rs::{ext, libretro_core};
 use spin::{mutex::SpinMutex, Once};
 
+// Core options, which the frontend shows in its menu and remembers between
+// sessions. Each is "Description; value|value|...", default first.
+const COLOUR_CORRECTION_OPTION: &CUtf8 = c_utf8!("gbrs_colour_correction");
+const COLOUR_CORRECTION_VALUES: &CUtf8 =
+    c_utf8!("Colour correction for CGB games; raw|cgb|agb");
+const DMG_PALETTE_OPTION: &CUtf8 = c_utf8!("gbrs_dmg_palette");
+const DMG_PALETTE_VALUES: &CUtf8 = c_utf8!(
+    "DMG palette; grey|dmg-green|pocket|light|high-contrast|colour-blind"
+);
+
+fn option_value<'e>(env: &'e impl Environment, key: &CUtf8) -> Option<&'e str> {
+    env.get_variable(key).ok()
+}
+
+fn new_gameboy(
+    config: Config,
+    colour_correction: ColourCorrection,
+    dmg_palette: DmgPalettePreset,
+) -> Cpu {
+    let mut gameboy = Cpu::from_config(config);
+    gameboy.gpu.set_colour_correction(colour_correction);
+    gameboy.gpu.set_dmg_palettes(dmg_palette.palettes());
+    gameboy
+}
+
 struct LibretroCore {
     gameboy: Cpu,
     last_cpu_config: Config,
+    colour_correction: ColourCorrection,
+    dmg_palette: DmgPalettePreset,
     rendering_mode: SoftwareRenderEnabled,
     frame_buffer: [XRGB8888; SCREEN_WIDTH * SCREEN_HEIGHT],
     pixel_format: Format<XRGB8888>,
@@ -20,6 +50,25 @@ struct LibretroCore {
 
 static LOGGER: Once<SpinMutex<PlatformLogger>> = Once::new();
 
+impl LibretroCore {
+    // Picks up core options, when the game loads and whenever they're
+    // changed in the frontend's menu
+    fn apply_options(&mut self, env: &impl Environment) {
+        if let Some(mode) = option_value(env, COLOUR_CORRECTION_OPTION)
+            .and_then(ColourCorrection::from_name)
+        {
+            self.colour_correction = mode;
+            self.gameboy.gpu.set_colour_correction(mode);
+        }
+        if let Some(preset) = option_value(env, DMG_PALETTE_OPTION)
+            .and_then(DmgPalettePreset::from_name)
+        {
+            self.dmg_palette = preset;
+            self.gameboy.gpu.set_dmg_palettes(preset.palettes());
+        }
+    }
+}
+
 impl<'a> Core<'a> for LibretroCore {
     type Init = ();
 
@@ -31,6 +80,14 @@ impl<'a> Core<'a> for LibretroCore {
         )
     }
 
+    fn set_environment(env: &mut impl env::SetEnvironment) {
+        env.set_variables(&[
+            Variable::new(COLOUR_CORRECTION_OPTION, COLOUR_CORRECTION_VALUES),
+            Variable::new(DMG_PALETTE_OPTION, DMG_PALETTE_VALUES),
+        ])
+        .ok();
+    }
+
     fn init(env: &mut impl Init) -> Self::Init {
         LOGGER.call_once(|| SpinMutex::new(env.get_log_interface().unwrap()));
 
@@ -62,20 +119,20 @@ impl<'a> Core<'a> for LibretroCore {
 
     fn run(
         &mut self,
-        _env: &mut impl env::Run,
+        env: &mut impl env::Run,
         runtime: &mut impl Callbacks,
     ) -> InputsPolled {
+        if env.get_variable_update() {
+            self.apply_options(env);
+        }
         let gb = &mut self.gameboy;
 
-        for i in 0..SCREEN_BUFFER_SIZE {
-            let mut pixel: u32 = 0;
-            let r = gb.gpu.finished_frame[i].red;
-            let g = gb.gpu.finished_frame[i].green;
-            let b = gb.gpu.finished_frame[i].blue;
-            pixel |= b as u32;
-            pixel |= (g as u32) << 8;
-            pixel |= (r as u32) << 16;
-            self.frame_buffer[i] = XRGB8888::new_with_raw_value(pixel);
+        for (pixel, colour) in self
+            .frame_buffer
+            .iter_mut()
+            .zip(gb.gpu.finished_frame.iter())
+        {
+            *pixel = XRGB8888::new_with_raw_value(xrgb8888(colour));
         }
 
         let frame = Frame::new(
@@ -88,7 +145,7 @@ impl<'a> Core<'a> for LibretroCore {
             &self.pixel_format,
             &frame,
         );
-        runtime.upload_audio_frame(&gb.mem.apu.buffer);
+        runtime.upload_audio_frame(gb.mem.apu.buffer.samples());
 
         let inputs_polled = runtime.poll_inputs();
         let port = DevicePort::new(0);
@@ -109,10 +166,7 @@ impl<'a> Core<'a> for LibretroCore {
         gb.mem.joypad.down_pressed =
             runtime.is_joypad_button_pressed(port, JoypadButton::Down);
 
-        while !gb.mem.apu.buffer_full {
-            gb.step();
-        }
-        gb.mem.apu.buffer_full = false;
+        gb.step_until_full_audio_buffer();
 
         inputs_polled
     }
@@ -133,18 +187,33 @@ impl<'a> Core<'a> for LibretroCore {
             sound_buffer_size: SOUND_BUFFER_SIZE,
             sound_sample_rate: SOUND_SAMPLE_RATE,
             rom: Rom::from_bytes(data.to_vec()),
+            model: None,
         };
-        Ok(Self {
+        let colour_correction = ColourCorrection::Raw;
+        let dmg_palette = DmgPalettePreset::ALL[0];
+        let mut core = Self {
             rendering_mode,
             pixel_format,
-            gameboy: Cpu::from_config(config.clone()),
+            gameboy: new_gameboy(
+                config.clone(),
+                colour_correction,
+                dmg_palette,
+            ),
             last_cpu_config: config,
+            colour_correction,
+            dmg_palette,
             frame_buffer: [XRGB8888::DEFAULT; SCREEN_WIDTH * SCREEN_HEIGHT],
-        })
+        };
+        core.apply_options(env);
+        Ok(core)
     }
 
     fn reset(&mut self, _env: &mut impl env::Reset) {
-        self.gameboy = Cpu::from_config(self.last_cpu_config.clone());
+        self.gameboy = new_gameboy(
+            self.last_cpu_config.clone(),
+            self.colour_correction,
+            self.dmg_palette,
+        );
     }
 
     fn unload_game(self, _env: &mut impl UnloadGame) -> Self::Init {
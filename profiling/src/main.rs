@@ -16,6 +16,7 @@ fn main() {
         rom: Rom::from_file(&rom_path),
         sound_buffer_size: SOUND_BUFFER_SIZE,
         sound_sample_rate: SOUND_SAMPLE_RATE,
+        model: None,
     });
 
     // Just run the CPU forever so we can profile hot areas of emulation.
@@ -1,18 +1,31 @@
+use gbrs_core::colour::dmg_palettes::*;
 use gbrs_core::constants::*;
 use gbrs_core::cpu::Cpu;
+use gbrs_core::scaling::Scaler;
 
 use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
-use sdl2::keyboard::Scancode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::pixels::{Color, PixelFormatEnum};
 
 // NOTE: The SDL port does not currently perform non-integer scaling.
 //   Please choose a multiple of 160x144
 const WINDOW_WIDTH: u32 = 800;
 const WINDOW_HEIGHT: u32 = 720;
 
-pub fn run_gui(mut gameboy: Cpu) {
+fn cycle_colour_correction(gameboy: &mut Cpu) {
+    let next = gameboy.gpu.colour_correction().next();
+    gameboy.gpu.set_colour_correction(next);
+    println!("Colour correction: {}", next.name());
+}
+
+fn cycle_frame_blending(gameboy: &mut Cpu) {
+    let next = gameboy.gpu.frame_blending().next();
+    gameboy.gpu.set_frame_blending(next);
+    println!("Frame blending: {}", next.name());
+}
+
+pub fn run_gui(mut gameboy: Cpu, mut scaler: Scaler) {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
@@ -23,9 +36,6 @@ pub fn run_gui(mut gameboy: Cpu) {
         .build()
         .unwrap();
 
-    let square_width = WINDOW_WIDTH as usize / SCREEN_WIDTH;
-    let square_height = WINDOW_HEIGHT as usize / SCREEN_HEIGHT;
-
     let mut canvas = window
         .into_canvas()
         // TODO: This option fixes visual tearing, but it messes up our sound
@@ -37,6 +47,22 @@ pub fn run_gui(mut gameboy: Cpu) {
     canvas.set_draw_color(Color::RGB(255, 255, 255));
     canvas.clear();
     canvas.present();
+
+    // The scaled frame is stretched over the window
+    let texture_creator = canvas.texture_creator();
+    let create_texture = |scaler: Scaler| {
+        let (width, height) = scaler.output_size(SCREEN_WIDTH, SCREEN_HEIGHT);
+        texture_creator
+            .create_texture_streaming(
+                PixelFormatEnum::RGBA32,
+                width as u32,
+                height as u32,
+            )
+            .unwrap()
+    };
+    let mut texture = create_texture(scaler);
+    let mut scaled_frame = vec![];
+
     let mut event_pump = sdl_context.event_pump().unwrap();
 
     let audio_subsystem = sdl_context.audio().unwrap();
@@ -56,35 +82,64 @@ pub fn run_gui(mut gameboy: Cpu) {
     );
 
     gameboy.step_until_full_audio_buffer();
-    // gameboy.mem.apu.buffer_full = true;
 
     'running: loop {
         for event in event_pump.poll_iter() {
-            if matches!(event, Event::Quit { .. }) {
-                break 'running;
+            match event {
+                Event::Quit { .. } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => {
+                    let palette =
+                        cycle_palette(&mut gameboy.gpu, &gameboy.cart_info);
+                    println!("Palette: {}", palette.name());
+                },
+                Event::KeyDown {
+                    keycode: Some(Keycode::C),
+                    repeat: false,
+                    ..
+                } => cycle_colour_correction(&mut gameboy),
+                Event::KeyDown {
+                    keycode: Some(Keycode::B),
+                    repeat: false,
+                    ..
+                } => cycle_frame_blending(&mut gameboy),
+                Event::KeyDown {
+                    keycode: Some(Keycode::S),
+                    repeat: false,
+                    ..
+                } => {
+                    scaler = scaler.next();
+                    texture = create_texture(scaler);
+                    println!("Scaler: {}", scaler.name());
+                },
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => match gameboy.save_screenshot(scaler) {
+                    Ok(path) => println!(
+                        "Saved screenshot to {}",
+                        path.to_string_lossy()
+                    ),
+                    Err(e) => println!("Failed to save screenshot: {}", e),
+                },
+                _ => {},
             }
         }
 
         // Draw the screen
-        for x in 0..SCREEN_WIDTH {
-            for y in 0..SCREEN_HEIGHT {
-                let i = (y * 160 + x) as usize;
-                let colour = &gameboy.gpu.finished_frame[i];
-                canvas.set_draw_color(Color::RGB(
-                    colour.red,
-                    colour.green,
-                    colour.blue,
-                ));
-                canvas
-                    .fill_rect(Rect::new(
-                        (x * square_width) as i32,
-                        (y * square_height) as i32,
-                        square_width as u32,
-                        square_height as u32,
-                    ))
-                    .unwrap();
-            }
-        }
+        scaler.upscale(
+            &gameboy.gpu.finished_frame[..],
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+            &mut scaled_frame,
+        );
+        let pitch = scaler.output_size(SCREEN_WIDTH, SCREEN_HEIGHT).0 * 4;
+        texture.update(None, &scaled_frame, pitch).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
         canvas.present();
 
         gameboy.mem.joypad.start_pressed = event_pump
@@ -113,7 +168,9 @@ pub fn run_gui(mut gameboy: Cpu) {
         gameboy.step_until_full_audio_buffer();
 
         let pre = audio_queue.size();
-        audio_queue.queue_audio(&gameboy.mem.apu.buffer).unwrap();
+        audio_queue
+            .queue_audio(gameboy.mem.apu.buffer.samples())
+            .unwrap();
         audio_queue.resume();
         let diff = audio_queue.size() - pre;
 
@@ -122,7 +179,7 @@ pub fn run_gui(mut gameboy: Cpu) {
             //   speed or sound issues. It is an attempt to help out slower
             //   machines, but you may not need it if your machine is fast
             //   enough.
-            if !gameboy.mem.apu.buffer_full {
+            if !gameboy.mem.apu.buffer.is_full() {
                 gameboy.step();
             }
             std::hint::spin_loop();
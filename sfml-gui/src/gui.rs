@@ -1,7 +1,9 @@
 use crate::control::*;
 
+use gbrs_core::colour::dmg_palettes::*;
 use gbrs_core::constants::*;
 use gbrs_core::cpu::Cpu;
+use gbrs_core::scaling::Scaler;
 
 use sfml::audio::{Sound, SoundBuffer, SoundStatus};
 use sfml::graphics::*;
@@ -16,9 +18,19 @@ pub const DRAW_FPS: bool = false;
 static SOUND_BACKING_STORE: SpinMutex<[i16; SOUND_BUFFER_SIZE]> =
     SpinMutex::new([0; SOUND_BUFFER_SIZE]);
 
-pub fn run_gui(mut gameboy: Cpu) {
-    let sw = SCREEN_WIDTH as u32;
-    let sh = SCREEN_HEIGHT as u32;
+fn cycle_colour_correction(gameboy: &mut Cpu) {
+    let next = gameboy.gpu.colour_correction().next();
+    gameboy.gpu.set_colour_correction(next);
+    println!("Colour correction: {}", next.name());
+}
+
+fn cycle_frame_blending(gameboy: &mut Cpu) {
+    let next = gameboy.gpu.frame_blending().next();
+    gameboy.gpu.set_frame_blending(next);
+    println!("Frame blending: {}", next.name());
+}
+
+pub fn run_gui(mut gameboy: Cpu, mut scaler: Scaler) {
     let window_width: u32 = 640;
     let window_height: u32 = 512;
 
@@ -32,16 +44,21 @@ pub fn run_gui(mut gameboy: Cpu) {
     .unwrap();
     // window.set_framerate_limit(gameboy.frame_rate as u32);
 
-    let mut screen_texture = Texture::new().unwrap();
-    screen_texture
-        .create(sw, sh)
-        .expect("Failed to create screen texture");
-
-    // Scale the 160x144 image to the appropriate resolution
-    let sprite_scale = Vector2f::new(
-        window_width as f32 / sw as f32,
-        window_height as f32 / sh as f32,
-    );
+    // The scaled frame is stretched over the window
+    let create_texture = |scaler: Scaler| {
+        let (width, height) = scaler.output_size(SCREEN_WIDTH, SCREEN_HEIGHT);
+        let mut texture = Texture::new().unwrap();
+        texture
+            .create(width as u32, height as u32)
+            .expect("Failed to create screen texture");
+        let scale = Vector2f::new(
+            window_width as f32 / width as f32,
+            window_height as f32 / height as f32,
+        );
+        (texture, scale)
+    };
+    let (mut screen_texture, mut sprite_scale) = create_texture(scaler);
+    let mut scaled_frame = vec![];
 
     let mut clock = Clock::start().unwrap();
 
@@ -64,9 +81,37 @@ pub fn run_gui(mut gameboy: Cpu) {
         let secs = clock.restart().as_seconds();
 
         while let Some(ev) = window.poll_event() {
-            if ev == Event::Closed {
-                window.close();
-                return;
+            match ev {
+                Event::Closed => {
+                    window.close();
+                    return;
+                },
+                Event::KeyPressed { code: Key::P, .. } => {
+                    let palette =
+                        cycle_palette(&mut gameboy.gpu, &gameboy.cart_info);
+                    println!("Palette: {}", palette.name());
+                },
+                Event::KeyPressed { code: Key::C, .. } => {
+                    cycle_colour_correction(&mut gameboy)
+                },
+                Event::KeyPressed { code: Key::B, .. } => {
+                    cycle_frame_blending(&mut gameboy)
+                },
+                Event::KeyPressed { code: Key::S, .. } => {
+                    scaler = scaler.next();
+                    (screen_texture, sprite_scale) = create_texture(scaler);
+                    println!("Scaler: {}", scaler.name());
+                },
+                Event::KeyPressed { code: Key::F12, .. } => {
+                    match gameboy.save_screenshot(scaler) {
+                        Ok(path) => println!(
+                            "Saved screenshot to {}",
+                            path.to_string_lossy()
+                        ),
+                        Err(e) => println!("Failed to save screenshot: {}", e),
+                    }
+                },
+                _ => {},
             }
         }
 
@@ -74,10 +119,17 @@ pub fn run_gui(mut gameboy: Cpu) {
         // gameboy.step_until_full_audio_buffer();
 
         // Draw the previous frame
+        scaler.upscale(
+            &gameboy.gpu.finished_frame[..],
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+            &mut scaled_frame,
+        );
+        let (width, height) = scaler.output_size(SCREEN_WIDTH, SCREEN_HEIGHT);
         screen_texture.update_from_pixels(
-            &gameboy.gpu.get_rgba_frame(),
-            sw,
-            sh,
+            &scaled_frame,
+            width as u32,
+            height as u32,
             0,
             0,
         );
@@ -96,12 +148,12 @@ pub fn run_gui(mut gameboy: Cpu) {
 
         // Play the audio while creating the next frame and sound buffer
         // This way we're not idling, we're actively computing the next event.
-        // let sound_buffer = SoundBuffer::from_samples(&gameboy.mem.apu.buffer, 2, SOUND_SAMPLE_RATE as u32).unwrap();
+        // let sound_buffer = SoundBuffer::from_samples(gameboy.mem.apu.buffer.samples(), 2, SOUND_SAMPLE_RATE as u32).unwrap();
         // let mut sound = Sound::with_buffer(&sound_buffer);
         // sound.play();
 
         let mut sound_backing_store = SOUND_BACKING_STORE.lock();
-        *sound_backing_store = gameboy.mem.apu.buffer;
+        sound_backing_store.copy_from_slice(gameboy.mem.apu.buffer.samples());
         let sound_buffer = SoundBuffer::from_samples(
             &*sound_backing_store,
             2,
@@ -113,7 +165,7 @@ pub fn run_gui(mut gameboy: Cpu) {
         // sound.set_volume(0.);
         sound.play();
         while sound.status() == SoundStatus::PLAYING {
-            if !gameboy.mem.apu.buffer_full {
+            if !gameboy.mem.apu.buffer.is_full() {
                 gameboy.step();
             } else {
                 // We're finished with this frame. Let's just wait for audio
@@ -125,9 +177,8 @@ pub fn run_gui(mut gameboy: Cpu) {
         // Just in-case we're running too slow, let's catch up.
         // This may be when you get a small audio pop. It happens more often
         // on slower machines.
-        while !gameboy.mem.apu.buffer_full {
+        while !gameboy.mem.apu.buffer.take_full() {
             gameboy.step();
         }
-        gameboy.mem.apu.buffer_full = false;
     }
 }
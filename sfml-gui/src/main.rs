@@ -3,21 +3,94 @@ pub mod gui;
 
 use std::env;
 
-use gbrs_core::config::Config;
+use gbrs_core::colour::colour_correction::ColourCorrection;
+use gbrs_core::colour::dmg_palettes::*;
+use gbrs_core::colour::frame_blending::FrameBlending;
+use gbrs_core::config::{Config, Model};
 use gbrs_core::cpu::Cpu;
 use gbrs_core::memory::rom::Rom;
+use gbrs_core::printer::Printer;
+use gbrs_core::scaling::Scaler;
 use gui::run_gui;
 
 // TODO: Get these from an SFML audio device
 const SOUND_BUFFER_SIZE: usize = 1024;
 const SOUND_SAMPLE_RATE: usize = 48000;
 
+const USAGE: &str =
+    "Usage: gbrs-sfml-gui ROM_PATH [--printer] [--palette NAME] [--colour-correction raw|cgb|agb] [--frame-blending off|mix|lcd] [--scaler NAME] [--model dmg|sgb|cgb|agb]";
+
 fn main() {
-    let rom_path = env::args().nth(1).expect("Pass a ROM path as an argument");
-    let processor = Cpu::from_config(Config {
+    let mut args = env::args().skip(1);
+    let rom_path = args.next().expect(USAGE);
+
+    // The model has to be known before the CPU is made, so it's picked out
+    // ahead of the other arguments
+    let model =
+        env::args()
+            .skip_while(|arg| arg != "--model")
+            .nth(1)
+            .map(|name| {
+                Model::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown model \"{}\"", name))
+            });
+
+    let mut processor = Cpu::from_config(Config {
         sound_buffer_size: SOUND_BUFFER_SIZE,
         sound_sample_rate: SOUND_SAMPLE_RATE,
         rom: Rom::from_file(&rom_path),
+        model,
     });
-    run_gui(processor);
+
+    if let Some(palette) = load_palette_choice(&processor.cart_info) {
+        processor.gpu.set_dmg_palettes(palette.palettes());
+    }
+
+    let mut scaler = Scaler::Nearest;
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--palette" => {
+                let name = args.next().expect(USAGE);
+                let preset = DmgPalettePreset::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown palette \"{}\"", name));
+                processor.gpu.set_dmg_palettes(preset.palettes());
+                save_palette_choice(
+                    &processor.cart_info,
+                    ProfilePalette::Preset(preset),
+                )
+                .expect("Failed to save palette choice");
+            },
+            "--model" => {
+                args.next().expect(USAGE);
+            },
+            "--colour-correction" => {
+                let name = args.next().expect(USAGE);
+                let mode =
+                    ColourCorrection::from_name(&name).unwrap_or_else(|| {
+                        panic!("Unknown colour correction \"{}\"", name)
+                    });
+                processor.gpu.set_colour_correction(mode);
+            },
+            "--scaler" => {
+                let name = args.next().expect(USAGE);
+                scaler = Scaler::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown scaler \"{}\"", name));
+            },
+            "--frame-blending" => {
+                let name = args.next().expect(USAGE);
+                let mode =
+                    FrameBlending::from_name(&name).unwrap_or_else(|| {
+                        panic!("Unknown frame blending \"{}\"", name)
+                    });
+                processor.gpu.set_frame_blending(mode);
+            },
+            "--printer" => {
+                processor.connect_serial_device(Box::new(
+                    Printer::saving_next_to_rom(&rom_path),
+                ));
+            },
+            _ => panic!("Unknown argument \"{}\"\n{}", arg, USAGE),
+        }
+    }
+    run_gui(processor, scaler);
 }
@@ -1,17 +1,38 @@
+use gbrs_core::colour::dmg_palettes::*;
 use gbrs_core::config::Config;
 use gbrs_core::constants;
 use gbrs_core::cpu::Cpu;
 use gbrs_core::memory::rom::Rom;
+use gbrs_core::scaling::Scaler;
 use gbrs_core::{callbacks, callbacks::Callbacks, constants::*};
 use wasm_bindgen::prelude::*;
 use web_sys::{console, window, Storage};
 
 static mut CPU: Option<Cpu> = None;
+static mut SCALER: Scaler = Scaler::Nearest;
+
+// Palette profiles are kept in the same format as the gbrs-palettes.txt
+// files the desktop ports use
+const PALETTE_PROFILES_KEY: &str = "gbrs-palettes";
 
 fn local_storage() -> Storage {
     window().unwrap().local_storage().unwrap().unwrap()
 }
 
+fn load_palette_profiles() -> PaletteProfiles {
+    let text = local_storage()
+        .get_item(PALETTE_PROFILES_KEY)
+        .expect("Failed to read palettes in localStorage")
+        .unwrap_or_default();
+    PaletteProfiles::parse(&text)
+}
+
+fn save_palette_profiles(profiles: &PaletteProfiles) {
+    local_storage()
+        .set_item(PALETTE_PROFILES_KEY, &profiles.serialise())
+        .expect("Failed to save palettes in localStorage");
+}
+
 #[wasm_bindgen]
 pub fn create_gameboy() {
     console_error_panic_hook::set_once();
@@ -48,10 +69,76 @@ pub fn create_gameboy() {
             rom: Rom::from_bytes(
                 include_bytes!("../../roms/dmg-acid2.gb").to_vec(),
             ),
+            model: None,
         }));
+
+        let cpu = CPU.as_mut().unwrap();
+        if let Some(palette) = load_palette_profiles().find(&cpu.cart_info) {
+            cpu.gpu.set_dmg_palettes(palette.palettes());
+        }
     }
 }
 
+// Moves on to the next DMG palette and remembers it for this game. Returns
+// the palette's name.
+#[wasm_bindgen]
+pub fn cycle_palette() -> String {
+    let cpu = unsafe { CPU.as_mut().unwrap() };
+    let mut profiles = load_palette_profiles();
+    let next = profiles.next_palette(&cpu.cart_info, &cpu.gpu.dmg_palettes());
+    cpu.gpu.set_dmg_palettes(next.palettes());
+
+    profiles.choose(&cpu.cart_info, next);
+    save_palette_profiles(&profiles);
+    next.name().to_string()
+}
+
+// Picks a DMG palette preset by name, eg. "pocket", and remembers it for this
+// game. Returns false if there's no preset with that name.
+#[wasm_bindgen]
+pub fn set_palette(name: &str) -> bool {
+    let Some(preset) = DmgPalettePreset::from_name(name) else {
+        return false;
+    };
+    let cpu = unsafe { CPU.as_mut().unwrap() };
+    cpu.gpu.set_dmg_palettes(preset.palettes());
+
+    let mut profiles = load_palette_profiles();
+    profiles.choose(&cpu.cart_info, ProfilePalette::Preset(preset));
+    save_palette_profiles(&profiles);
+    true
+}
+
+// Picks an upscaler by name, eg. "xbr2x". Returns false if there's no scaler
+// with that name.
+#[wasm_bindgen]
+pub fn set_scaler(name: &str) -> bool {
+    let Some(scaler) = Scaler::from_name(name) else {
+        return false;
+    };
+    unsafe { SCALER = scaler };
+    true
+}
+
+// Moves on to the next upscaler. Returns the scaler's name.
+#[wasm_bindgen]
+pub fn cycle_scaler() -> String {
+    let next = unsafe { SCALER }.next();
+    unsafe { SCALER = next };
+    next.name().to_string()
+}
+
+// The size of the frames `get_finished_frame` returns with the current scaler
+#[wasm_bindgen]
+pub fn frame_width() -> usize {
+    unsafe { SCALER }.output_size(SCREEN_WIDTH, SCREEN_HEIGHT).0
+}
+
+#[wasm_bindgen]
+pub fn frame_height() -> usize {
+    unsafe { SCALER }.output_size(SCREEN_WIDTH, SCREEN_HEIGHT).1
+}
+
 #[wasm_bindgen]
 pub fn step_one_frame() {
     unsafe {
@@ -59,20 +146,15 @@ pub fn step_one_frame() {
     }
 }
 
+// RGBA bytes, upscaled and ready to go straight into an ImageData
 #[wasm_bindgen]
-pub fn get_finished_frame() -> Vec<String> {
-    let frame = unsafe { CPU.as_mut().unwrap().gpu.finished_frame };
-    // TODO: Re-use a buffer instead
-    let mut int_frame = Vec::with_capacity(SCREEN_BUFFER_SIZE);
-
-    for i in 0..SCREEN_BUFFER_SIZE {
-        int_frame.push(format!(
-            "rgb({},{},{})",
-            frame[i].red, frame[i].green, frame[i].blue
-        ));
-    }
-
-    int_frame
+pub fn get_finished_frame() -> Vec<u8> {
+    let cpu = unsafe { CPU.as_ref().unwrap() };
+    unsafe { SCALER }.upscale_to_vec(
+        &cpu.gpu.finished_frame[..],
+        SCREEN_WIDTH,
+        SCREEN_HEIGHT,
+    )
 }
 
 #[wasm_bindgen]